@@ -0,0 +1,286 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Archiving artifacts from failed runs
+//!
+//! Per-run datasets are cheap to keep around, but they live on one machine's
+//! pool and tend to get destroyed.  With `--archive-dir`, gocrash copies the
+//! interesting parts of each failed run into a plain directory.  A whole clone
+//! of goroot is mostly build products we don't need, so we copy only:
+//!
+//! * files at the top level of the clone (stdout, stderr, and other files
+//!   written by gocrash)
+//! * paths matching one of the "include" patterns (e.g., `goroot/bin`, test
+//!   binaries, and core files, which are needed to symbolize stacks later, and
+//!   whatever the workload saved in `GOCRASH_OUTPUT_DIR`)
+//!
+//! We don't descend into directories matching an "exclude" pattern (e.g.,
+//! `goroot/pkg`), so those are never copied unless included explicitly.
+//!
+//! Files are stored in the archive directory's content-addressed store (see
+//! `store.rs`), so a file that's identical to one archived before (like the
+//! toolchain binaries) takes no extra space.  Archives of thousands of runs
+//! (especially with `--keep-success`) are mostly the same output over and over,
+//! so the files at the top level of the clone (the logs) are also pruned on
+//! the way in: empty files are skipped and ANSI escape sequences are stripped,
+//! which makes many more of them identical.
+
+use crate::store::ManifestEntry;
+use crate::store::Store;
+use crate::watch::glob_match;
+use anyhow::Context;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Set on failed per-run datasets when `--archive-dir` is used: where the
+/// run's artifacts were copied
+pub const ARCHIVE_PROPERTY: &str = "gocrash:archive";
+
+/// Paths (relative to the clone) that are archived by default
+pub const DEFAULT_INCLUDE: &[&str] = &[
+    "goroot/bin",
+    "gocrash_output",
+    "*.test",
+    "core",
+    "core.*",
+    "*/core",
+    "*/core.*",
+];
+
+/// Paths (relative to the clone) that are skipped by default
+pub const DEFAULT_EXCLUDE: &[&str] = &["goroot/pkg", "*/obj"];
+
+/// Describes which paths in a failed clone get archived
+pub struct ArchivePolicy {
+    /// patterns for paths that are always copied (with everything under them)
+    include: Vec<String>,
+    /// patterns for directories that we don't look inside
+    exclude: Vec<String>,
+}
+
+/// Describes what got archived
+#[derive(Default)]
+pub struct ArchiveSummary {
+    pub nfiles: usize,
+    pub nbytes: u64,
+    /// number of files that were already in the store (these are included in
+    /// `nfiles` but not `nbytes`)
+    pub nshared: usize,
+}
+
+/// Tracks what's been archived for one run
+struct Archiving<'a> {
+    store: &'a Store,
+    /// the run's archive directory
+    target: &'a Path,
+    summary: ArchiveSummary,
+    manifest: Vec<ManifestEntry>,
+}
+
+impl Archiving<'_> {
+    /// Records that `relative` (a path in the run's archive directory) was
+    /// added to the store
+    fn added(&mut self, relative: &Path, added: crate::store::Added) {
+        self.summary.nfiles += 1;
+        if added.existed {
+            self.summary.nshared += 1;
+        } else {
+            self.summary.nbytes += added.bytes;
+        }
+        self.manifest.push(ManifestEntry {
+            path: relative.to_string_lossy().into_owned(),
+            sha3_256: added.hash,
+            bytes: added.bytes,
+        });
+    }
+}
+
+impl ArchivePolicy {
+    /// Returns a policy using the default patterns plus the given ones
+    pub fn new(include: &[String], exclude: &[String]) -> ArchivePolicy {
+        let with_defaults = |defaults: &[&str], extra: &[String]| {
+            defaults
+                .iter()
+                .map(|s| s.to_string())
+                .chain(extra.iter().cloned())
+                .collect()
+        };
+        ArchivePolicy {
+            include: with_defaults(DEFAULT_INCLUDE, include),
+            exclude: with_defaults(DEFAULT_EXCLUDE, exclude),
+        }
+    }
+
+    /// Archives the artifacts from the clone mounted at `source` into
+    /// `target` (which must not exist yet), storing their contents in `store`
+    pub fn archive(
+        &self,
+        source: &Path,
+        target: &Path,
+        store: &Store,
+    ) -> Result<ArchiveSummary, anyhow::Error> {
+        std::fs::create_dir_all(target)
+            .with_context(|| format!("create {:?}", target))?;
+        let mut archiving = Archiving {
+            store,
+            target,
+            summary: ArchiveSummary::default(),
+            manifest: Vec::new(),
+        };
+        self.archive_dir(source, Path::new(""), true, &mut archiving)?;
+        crate::store::write_manifest(target, &archiving.manifest)?;
+        Ok(archiving.summary)
+    }
+
+    /// Archives the contents of `source.join(relative)`
+    fn archive_dir(
+        &self,
+        source: &Path,
+        relative: &Path,
+        top: bool,
+        archiving: &mut Archiving,
+    ) -> Result<(), anyhow::Error> {
+        let dir = source.join(relative);
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("read directory {:?}", dir))?;
+        for entry in entries {
+            let entry =
+                entry.with_context(|| format!("read directory {:?}", dir))?;
+            let path = relative.join(entry.file_name());
+            let name = path.to_string_lossy();
+            let file_type = entry
+                .file_type()
+                .with_context(|| format!("stat {:?}", entry.path()))?;
+            if self.include.iter().any(|p| glob_match(p, &name)) {
+                store_tree(source, &path, archiving)?;
+            } else if top && file_type.is_file() {
+                archive_log(source, &path, archiving)?;
+            } else if file_type.is_dir()
+                && !self.exclude.iter().any(|p| glob_match(p, &name))
+            {
+                self.archive_dir(source, &path, false, archiving)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Archives the log file `source.join(relative)`, pruning it as described
+/// above
+fn archive_log(
+    source: &Path,
+    relative: &Path,
+    archiving: &mut Archiving,
+) -> Result<(), anyhow::Error> {
+    let path = source.join(relative);
+    let contents =
+        std::fs::read(&path).with_context(|| format!("read {:?}", path))?;
+    if contents.is_empty() {
+        return Ok(());
+    }
+    let added = archiving
+        .store
+        .add_bytes(&strip_ansi(&contents), &archiving.target.join(relative))?;
+    archiving.added(relative, added);
+    Ok(())
+}
+
+/// Archives the file, symlink, or directory tree at `source.join(relative)`
+fn store_tree(
+    source: &Path,
+    relative: &Path,
+    archiving: &mut Archiving,
+) -> Result<(), anyhow::Error> {
+    let path = source.join(relative);
+    let target = archiving.target.join(relative);
+    // As in `copy_tree()`, symlinks are followed on Windows.
+    let metadata = if cfg!(unix) {
+        std::fs::symlink_metadata(&path)
+    } else {
+        std::fs::metadata(&path)
+    }
+    .with_context(|| format!("stat {:?}", path))?;
+    if metadata.is_symlink() {
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("create {:?}", parent))?;
+        }
+        let link = std::fs::read_link(&path)
+            .with_context(|| format!("readlink {:?}", path))?;
+        crate::symlink(&link, &target)
+            .with_context(|| format!("create symlink {:?}", target))?;
+    } else if metadata.is_dir() {
+        std::fs::create_dir_all(&target)
+            .with_context(|| format!("create {:?}", target))?;
+        let entries = std::fs::read_dir(&path)
+            .with_context(|| format!("read directory {:?}", path))?;
+        for entry in entries {
+            let entry =
+                entry.with_context(|| format!("read directory {:?}", path))?;
+            store_tree(source, &relative.join(entry.file_name()), archiving)?;
+        }
+    } else if metadata.is_file() {
+        let added = archiving.store.add_file(&path, &target)?;
+        archiving.added(relative, added);
+    }
+    Ok(())
+}
+
+/// Returns `contents` without ANSI escape sequences (colors, cursor movement,
+/// terminal titles, and so on)
+fn strip_ansi(contents: &[u8]) -> std::borrow::Cow<'_, [u8]> {
+    // CSI sequences ("ESC [ ... final byte"), OSC sequences ("ESC ] ...",
+    // ended by BEL or "ESC \"), and other two-byte escapes
+    static PATTERN: OnceLock<regex::bytes::Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| {
+        regex::bytes::Regex::new(
+            r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)|\x1b[@-_]",
+        )
+        .unwrap()
+    });
+    pattern.replace_all(contents, &b""[..])
+}
+
+/// Copies the file, symlink, or directory tree at `source` to `target`,
+/// creating parent directories as needed
+pub fn copy_tree(
+    source: &Path,
+    target: &Path,
+    summary: &mut ArchiveSummary,
+) -> Result<(), anyhow::Error> {
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("create {:?}", parent))?;
+    }
+    // Creating symlinks generally requires privileges on Windows, so there we
+    // copy whatever they point to instead.
+    let metadata = if cfg!(unix) {
+        std::fs::symlink_metadata(source)
+    } else {
+        std::fs::metadata(source)
+    }
+    .with_context(|| format!("stat {:?}", source))?;
+    if metadata.is_symlink() {
+        let link = std::fs::read_link(source)
+            .with_context(|| format!("readlink {:?}", source))?;
+        crate::symlink(&link, target)
+            .with_context(|| format!("create symlink {:?}", target))?;
+    } else if metadata.is_dir() {
+        std::fs::create_dir_all(target)
+            .with_context(|| format!("create {:?}", target))?;
+        let entries = std::fs::read_dir(source)
+            .with_context(|| format!("read directory {:?}", source))?;
+        for entry in entries {
+            let entry = entry
+                .with_context(|| format!("read directory {:?}", source))?;
+            copy_tree(&entry.path(), &target.join(entry.file_name()), summary)?;
+        }
+    } else if metadata.is_file() {
+        summary.nbytes += std::fs::copy(source, target)
+            .with_context(|| format!("copy {:?} to {:?}", source, target))?;
+        summary.nfiles += 1;
+    }
+    Ok(())
+}
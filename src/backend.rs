@@ -0,0 +1,1215 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Where each run's copy of the source tree comes from
+//!
+//! By default, the source tree is a ZFS snapshot and each run gets its own
+//! clone of it.  Clones are cheap, and they carry the state of each run (in
+//! ZFS properties; see `session.rs`) so that failed runs can be inspected and
+//! `gocrash postmortem` can make sense of a session after a host crash.
+//!
+//! On systems without ZFS, the source tree is a plain directory instead.  The
+//! "copy" backend copies the whole directory for each run.  That's a lot
+//! slower, and there's no per-run state or pool health checking, but it works
+//! anywhere (including Windows).  On Linux, the "overlay" backend instead
+//! mounts an overlay filesystem for each run, with the source directory as the
+//! read-only lower layer, so that nothing gets copied up front.
+//!
+//! Each backend implements `CloneBackend`.  Backends talk about "datasets"
+//! throughout: for ZFS these are real datasets, and for the others they're
+//! the paths of directories.
+//!
+//! Not every backend can do everything (see `Capability`).  Options that
+//! can't work without a capability are rejected when the session starts (see
+//! `Backend::require()`), and things that are merely nice to have (like
+//! reporting how much space each run used) are skipped.
+
+use crate::run_command;
+use crate::session;
+use crate::session::SESSION_PROPERTY;
+use crate::zcp;
+use crate::zfs;
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+/// Describes how we make a copy of the source tree for each run
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    clap::ValueEnum,
+    Deserialize,
+    Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// clone a ZFS snapshot for each run
+    #[default]
+    Zfs,
+    /// copy a directory for each run
+    Copy,
+    /// mount an overlay filesystem on top of a directory for each run (Linux
+    /// only)
+    Overlay,
+}
+
+/// Something that only some backends can do
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Capability {
+    /// taking snapshots of a run's copy while it's running
+    Snapshots,
+    /// limiting how much space a run's copy can use
+    Quotas,
+    /// recording state (see `session.rs`) on each run's copy
+    Properties,
+    /// reporting how much space each run's copy uses without walking it
+    SpaceAccounting,
+    /// checking on and trimming the storage pool that the copies live in
+    Pool,
+    /// rolling a run's copy back so that the next run can use it (see
+    /// `--reuse-clones`)
+    Rollback,
+    /// renaming a run's copy, so that it can be made before we know which run
+    /// it's for (see `--preclone`)
+    Rename,
+}
+
+/// How a working dataset was destroyed
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Teardown {
+    /// with one ZFS channel program (see `zcp.rs`)
+    ChannelProgram,
+    /// with one `zfs destroy` for each dataset
+    Commands,
+    /// by removing the directory (with the copy and overlay backends)
+    Directory,
+}
+
+impl Capability {
+    /// every capability, in the order they're reported
+    pub const ALL: &'static [Capability] = &[
+        Capability::Snapshots,
+        Capability::Quotas,
+        Capability::Properties,
+        Capability::SpaceAccounting,
+        Capability::Pool,
+        Capability::Rollback,
+        Capability::Rename,
+    ];
+
+    /// Returns a short name for this capability
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Capability::Snapshots => "snapshots",
+            Capability::Quotas => "quotas",
+            Capability::Properties => "per-run state",
+            Capability::SpaceAccounting => "space accounting",
+            Capability::Pool => "pool health",
+            Capability::Rollback => "reusing copies",
+            Capability::Rename => "cloning ahead",
+        }
+    }
+
+    /// Describes what can't be done without this capability
+    fn describe_missing(self) -> &'static str {
+        match self {
+            Capability::Snapshots => "it can't take snapshots",
+            Capability::Quotas => "it can't limit how much space a run uses",
+            Capability::Properties => "it can't record state on each run",
+            Capability::SpaceAccounting => {
+                "it can't tell how much space a run uses"
+            }
+            Capability::Pool => "there's no storage pool to manage",
+            Capability::Rollback => "it can't roll a run's copy back",
+            Capability::Rename => "it can't rename a run's copy",
+        }
+    }
+}
+
+impl Backend {
+    /// Returns the name of this backend as given to `--backend`
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Zfs => "zfs",
+            Backend::Copy => "copy",
+            Backend::Overlay => "overlay",
+        }
+    }
+
+    /// Returns whether this backend can do `capability`
+    pub fn supports(self, capability: Capability) -> bool {
+        self.clones().supports(capability)
+    }
+
+    /// Returns an error saying that `what` can't be used if this backend
+    /// can't do `capability`
+    pub fn require(
+        self,
+        capability: Capability,
+        what: &str,
+    ) -> Result<(), anyhow::Error> {
+        if !self.supports(capability) {
+            bail!(
+                "{} is not supported by the {} backend ({})",
+                what,
+                self.name(),
+                capability.describe_missing()
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns an error if this backend can't be used on this system
+    pub fn check_supported(&self) -> Result<(), anyhow::Error> {
+        if *self == Backend::Zfs && !cfg!(unix) {
+            bail!(
+                "the zfs backend is only supported on Unix-like systems (use \
+                 \"--backend copy\" with a directory containing \"goroot\", \
+                 or run gocrash under WSL)"
+            );
+        }
+        if *self == Backend::Overlay && !cfg!(target_os = "linux") {
+            bail!(
+                "the overlay backend is only supported on Linux (use \
+                 \"--backend copy\" instead)"
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the implementation of this backend
+    pub fn clones(self) -> &'static dyn CloneBackend {
+        match self {
+            Backend::Zfs => &ZfsBackend,
+            Backend::Copy => &CopyBackend,
+            Backend::Overlay => &OverlayBackend,
+        }
+    }
+}
+
+/// Operations on the source tree and its copies
+pub trait CloneBackend: Sync {
+    /// Given the source (`snapshot`) that the user gave us, returns the name
+    /// of the dataset (or directory) it's in and the name of the working
+    /// dataset for session `session_key`
+    fn working_dataset(
+        &self,
+        snapshot: &str,
+        session_key: &str,
+    ) -> Result<(String, String), anyhow::Error>;
+
+    /// Creates working dataset `working`, limited to `quota` bytes (for
+    /// backends that support quotas), returning its mountpoint
+    fn create_working_dataset(
+        &self,
+        working: &str,
+        session_key: &str,
+        snapshot: &str,
+        quota: Option<u64>,
+    ) -> Result<PathBuf, anyhow::Error>;
+
+    /// Destroys working dataset `working` and all of the per-run datasets in
+    /// it, using up to `nthreads` threads, returning how it was done
+    fn destroy_working_dataset(
+        &self,
+        working: &str,
+        session_key: &str,
+        nthreads: u8,
+    ) -> Result<Teardown, anyhow::Error>;
+
+    /// Returns the name and session key of each working dataset that sessions
+    /// created for `source` (a dataset, snapshot, or directory)
+    fn find_working_datasets(
+        &self,
+        source: &str,
+    ) -> Result<Vec<(String, String)>, anyhow::Error>;
+
+    /// Creates `run`, a fresh copy of `snapshot` to be found at `mountpoint`
+    /// (see `run_mountpoint()`), recording `properties` on it (see
+    /// `set_properties()`)
+    fn clone_run(
+        &self,
+        snapshot: &str,
+        run: &str,
+        mountpoint: &Path,
+        properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error>;
+
+    /// Returns where the contents of `run`, a per-run dataset in the working
+    /// dataset mounted at `working_mountpoint`, will be found once it's been
+    /// created
+    ///
+    /// Unlike `mountpoint()`, this doesn't need to ask the system, so it can
+    /// be used before the run exists (or after it's gone).
+    fn run_mountpoint(&self, working_mountpoint: &Path, run: &str) -> PathBuf;
+
+    /// Returns where the contents of `run` (which must exist) can be found
+    fn mountpoint(&self, run: &str) -> Result<PathBuf, anyhow::Error>;
+
+    /// Destroys `run` (along with any snapshots of it)
+    fn destroy_run(&self, run: &str) -> Result<(), anyhow::Error>;
+
+    /// Records the state of `run`, which was just created by `clone_run()`,
+    /// so that `reuse_run()` can return it to that state later
+    ///
+    /// `properties` are set on the record itself (if the backend has
+    /// properties), as `clone_run()` sets them on `run`.
+    fn mark_pristine(
+        &self,
+        _run: &str,
+        _properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        bail!("reusing copies is not supported by this backend")
+    }
+
+    /// Turns `old`, a per-run dataset marked with `mark_pristine()` whose run
+    /// is over, into `run`, as though `run` had been created by `clone_run()`
+    /// with the same arguments
+    ///
+    /// If this fails, `old` (or `run`) may be left in any state.
+    fn reuse_run(
+        &self,
+        _old: &str,
+        _run: &str,
+        _mountpoint: &Path,
+        _properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        bail!("reusing copies is not supported by this backend")
+    }
+
+    /// Turns `old`, a per-run dataset created by `clone_run()` that hasn't
+    /// been used, into `run`, as though `run` had been created by
+    /// `clone_run()` with `mountpoint` and `properties`
+    ///
+    /// `old` keeps whatever properties it was created with, except those
+    /// replaced by `properties`.
+    fn rename_run(
+        &self,
+        _old: &str,
+        _run: &str,
+        _mountpoint: &Path,
+        _properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        bail!("renaming copies is not supported by this backend")
+    }
+
+    /// Sets gocrash properties on `dataset` (all at once, where the backend
+    /// can)
+    ///
+    /// Only ZFS has somewhere to keep these, so by default they're dropped.
+    fn set_properties(
+        &self,
+        _dataset: &str,
+        _properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// Returns whether `snapshot` exists, or `None` if we couldn't tell
+    fn source_exists(&self, snapshot: &str) -> Option<bool>;
+
+    /// Returns properties that identify `snapshot` beyond its name (so that
+    /// sessions on different hosts can tell whether they tested the same
+    /// thing)
+    ///
+    /// Only ZFS has these, so by default there are none.
+    fn source_identity(
+        &self,
+        _snapshot: &str,
+    ) -> Result<BTreeMap<String, String>, anyhow::Error> {
+        Ok(BTreeMap::new())
+    }
+
+    /// Returns whether this backend can do `capability`
+    fn supports(&self, _capability: Capability) -> bool {
+        false
+    }
+}
+
+/// Clones a ZFS snapshot for each run
+struct ZfsBackend;
+
+/// Name of the snapshot of each run's clone taken right after it's created
+/// with `--reuse-clones` (see `CloneBackend::mark_pristine()`)
+const PRISTINE_SNAPSHOT: &str = "gocrash-pristine";
+
+impl CloneBackend for ZfsBackend {
+    /// The working dataset is a child of the snapshot's dataset.
+    fn working_dataset(
+        &self,
+        snapshot: &str,
+        session_key: &str,
+    ) -> Result<(String, String), anyhow::Error> {
+        let (dataset_name, _) = snapshot.split_once('@').ok_or_else(|| {
+            anyhow!("bad syntax for snapshot name (missing '@')")
+        })?;
+        Ok((
+            dataset_name.to_string(),
+            format!("{}/{}", dataset_name, session_key),
+        ))
+    }
+
+    fn create_working_dataset(
+        &self,
+        working: &str,
+        session_key: &str,
+        snapshot: &str,
+        quota: Option<u64>,
+    ) -> Result<PathBuf, anyhow::Error> {
+        zfs_create_working_dataset(working, session_key, snapshot, quota)
+    }
+
+    fn destroy_working_dataset(
+        &self,
+        working: &str,
+        session_key: &str,
+        nthreads: u8,
+    ) -> Result<Teardown, anyhow::Error> {
+        zfs_destroy_working_dataset(working, session_key, nthreads)
+    }
+
+    fn find_working_datasets(
+        &self,
+        source: &str,
+    ) -> Result<Vec<(String, String)>, anyhow::Error> {
+        let source =
+            source.split_once('@').map_or(source, |(dataset, _)| dataset);
+        let output = zfs::run(
+            Command::new("zfs")
+                .arg("list")
+                .arg("-H")
+                .arg("-oname")
+                .arg("-tfilesystem")
+                .arg("-d1")
+                .arg(source),
+        )?;
+        Ok(output
+            .lines()
+            .filter_map(|name| {
+                let key = name.strip_prefix(source)?.strip_prefix('/')?;
+                Some((name.to_string(), key.to_string()))
+            })
+            .collect())
+    }
+
+    fn clone_run(
+        &self,
+        snapshot: &str,
+        run: &str,
+        mountpoint: &Path,
+        properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        let mut cmd = Command::new("pfexec");
+        cmd.arg("zfs").arg("clone");
+        cmd.arg("-o").arg(format!("mountpoint={}", mountpoint.display()));
+        for (property, value) in properties {
+            cmd.args(session::property_arg(property, value));
+        }
+        zfs::run(cmd.arg(snapshot).arg(run))?;
+        Ok(())
+    }
+
+    /// Each run is mounted under the working dataset, as it would be by
+    /// default, but we set the mountpoint explicitly when we clone it (rather
+    /// than inheriting it) so that it's always there.
+    fn run_mountpoint(&self, working_mountpoint: &Path, run: &str) -> PathBuf {
+        working_mountpoint.join(run.rsplit('/').next().unwrap_or(run))
+    }
+
+    fn mountpoint(&self, run: &str) -> Result<PathBuf, anyhow::Error> {
+        crate::zfs_mountpoint(run)
+    }
+
+    /// "-r" also destroys any live snapshots of the run (see
+    /// --snapshot-interval).
+    fn destroy_run(&self, run: &str) -> Result<(), anyhow::Error> {
+        zfs::run(
+            Command::new("pfexec").arg("zfs").arg("destroy").arg("-r").arg(run),
+        )?;
+        Ok(())
+    }
+
+    /// The snapshot gets the session property, like any other snapshot in
+    /// the working dataset, so that teardown recognizes it as ours.
+    fn mark_pristine(
+        &self,
+        run: &str,
+        properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        zfs::run(
+            Command::new("pfexec")
+                .arg("zfs")
+                .arg("snapshot")
+                .args(properties.iter().flat_map(|(property, value)| {
+                    session::property_arg(property, value)
+                }))
+                .arg(format!("{}@{}", run, PRISTINE_SNAPSHOT)),
+        )?;
+        Ok(())
+    }
+
+    /// Rolling back discards everything the old run wrote (and its live
+    /// snapshots), but not its properties, so we clear those ourselves.  Then
+    /// it's just like a new clone (see `rename_run()`).
+    fn reuse_run(
+        &self,
+        old: &str,
+        run: &str,
+        mountpoint: &Path,
+        properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        zfs::run(
+            Command::new("pfexec")
+                .arg("zfs")
+                .arg("rollback")
+                .arg("-r")
+                .arg(format!("{}@{}", old, PRISTINE_SNAPSHOT)),
+        )?;
+        let stale =
+            session::dataset_properties(old)?.remove(old).unwrap_or_default();
+        for property in stale.keys() {
+            if properties.iter().all(|(p, _)| p != property) {
+                zfs::run(
+                    Command::new("pfexec")
+                        .arg("zfs")
+                        .arg("inherit")
+                        .arg(property)
+                        .arg(old),
+                )?;
+            }
+        }
+        self.rename_run(old, run, mountpoint, properties)
+    }
+
+    /// We rename the dataset and then move its mountpoint to where `run`
+    /// belongs.
+    fn rename_run(
+        &self,
+        old: &str,
+        run: &str,
+        mountpoint: &Path,
+        properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        zfs::run(
+            Command::new("pfexec").arg("zfs").arg("rename").arg(old).arg(run),
+        )?;
+        zfs::run(
+            Command::new("pfexec")
+                .arg("zfs")
+                .arg("set")
+                .arg(format!("mountpoint={}", mountpoint.display()))
+                .args(
+                    properties.iter().map(|(property, value)| {
+                        format!("{}={}", property, value)
+                    }),
+                )
+                .arg(run),
+        )?;
+        Ok(())
+    }
+
+    fn set_properties(
+        &self,
+        dataset: &str,
+        properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        if properties.is_empty() {
+            return Ok(());
+        }
+        zfs::run(
+            Command::new("pfexec")
+                .arg("zfs")
+                .arg("set")
+                .args(
+                    properties.iter().map(|(property, value)| {
+                        format!("{}={}", property, value)
+                    }),
+                )
+                .arg(dataset),
+        )?;
+        Ok(())
+    }
+
+    fn source_exists(&self, snapshot: &str) -> Option<bool> {
+        match zfs::run(
+            Command::new("zfs")
+                .arg("list")
+                .arg("-H")
+                .arg("-oname")
+                .arg(snapshot),
+        ) {
+            Ok(_) => Some(true),
+            Err(error) if format!("{:#}", error).contains("does not exist") => {
+                Some(false)
+            }
+            // Other errors (e.g., failing to run "zfs" at all) don't tell us
+            // anything about the snapshot.
+            Err(_) => None,
+        }
+    }
+
+    /// A snapshot's GUID stays the same when it's sent to another pool, and
+    /// its creation time and txg tell snapshots with the same name apart.
+    fn source_identity(
+        &self,
+        snapshot: &str,
+    ) -> Result<BTreeMap<String, String>, anyhow::Error> {
+        let output = zfs::run(
+            Command::new("zfs")
+                .arg("get")
+                .arg("-Hp")
+                .arg("-oproperty,value")
+                .arg("guid,createtxg,creation")
+                .arg(snapshot),
+        )?;
+        Ok(output
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(property, value)| (property.to_string(), value.to_string()))
+            .collect())
+    }
+
+    /// ZFS can do everything.
+    fn supports(&self, _capability: Capability) -> bool {
+        true
+    }
+}
+
+/// Creates our working dataset (with quota `quota`, if any), recording what
+/// we'll need to make sense of it later if we don't get to clean up (e.g.,
+/// because the host panics), and returns its mountpoint
+fn zfs_create_working_dataset(
+    working: &str,
+    session_key: &str,
+    snapshot: &str,
+    quota: Option<u64>,
+) -> Result<PathBuf, anyhow::Error> {
+    let mut create_cmd = Command::new("pfexec");
+    create_cmd
+        .arg("zfs")
+        .arg("create")
+        .args(session::property_arg(SESSION_PROPERTY, session_key))
+        .args(session::property_arg(session::SNAPSHOT_PROPERTY, snapshot))
+        .args(session::property_arg(
+            session::START_TIME_PROPERTY,
+            &chrono::Utc::now().to_rfc3339(),
+        ));
+    if let Some(quota) = quota {
+        create_cmd.arg("-o").arg(format!("quota={}", quota));
+    }
+    match session::host_boot_time() {
+        Ok(boot_time) => {
+            create_cmd.args(session::property_arg(
+                session::BOOT_TIME_PROPERTY,
+                &boot_time.to_string(),
+            ));
+        }
+        Err(error) => {
+            eprintln!("warning: determining host boot time: {:#}", error);
+        }
+    }
+    let _ = zfs::run(create_cmd.arg(working))?;
+
+    text_println!("created zfs dataset {:?}", working);
+
+    // We created the dataset with privileges, but we want to be able to write
+    // to it.
+    let working_mountpoint = crate::zfs_mountpoint(working)?;
+    #[cfg(unix)]
+    {
+        // Safety: getuid() and getgid() are always safe to call.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        run_command(
+            Command::new("pfexec")
+                .arg("chown")
+                .arg(format!("{}:{}", uid, gid))
+                .arg(&working_mountpoint),
+        )?;
+    }
+    Ok(working_mountpoint)
+}
+
+/// Destroys the working dataset and all of the per-run datasets in it, with a
+/// channel program where possible (see `zcp.rs`) and otherwise using
+/// `nthreads` threads to destroy the per-run datasets in parallel
+///
+/// This refuses to destroy anything unless every dataset in the tree was
+/// created by the gocrash session identified by `session_key`.
+fn zfs_destroy_working_dataset(
+    gocrash_dataset: &str,
+    session_key: &str,
+    nthreads: u8,
+) -> Result<Teardown, anyhow::Error> {
+    verify_session_tree(gocrash_dataset, session_key)?;
+
+    let children_output = zfs::run(
+        Command::new("zfs")
+            .arg("list")
+            .arg("-H")
+            .arg("-oname")
+            .arg("-d1")
+            .arg(gocrash_dataset),
+    )?;
+    let children = children_output
+        .lines()
+        .filter(|name| *name != gocrash_dataset)
+        .collect::<Vec<_>>();
+
+    text_println!(
+        "destroying {} dataset{} in {:?}",
+        children.len(),
+        if children.len() == 1 { "" } else { "s" },
+        gocrash_dataset
+    );
+
+    match zcp::destroy_tree(gocrash_dataset) {
+        Ok(true) => {
+            text_println!(
+                "destroyed zfs dataset {:?} (with a channel program)",
+                gocrash_dataset
+            );
+            return Ok(Teardown::ChannelProgram);
+        }
+        Ok(false) => (),
+        Err(error) => {
+            eprintln!(
+                "warning: destroying {:?} with a channel program: {:#} \
+                 (destroying one dataset at a time instead)",
+                gocrash_dataset, error
+            );
+        }
+    }
+
+    let queue = Mutex::new(children.iter());
+    let ndone = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        let handles = (0..nthreads.max(1))
+            .map(|_| {
+                scope.spawn(|| -> Result<(), anyhow::Error> {
+                    loop {
+                        let Some(child) = queue.lock().unwrap().next() else {
+                            return Ok(());
+                        };
+                        // "-r" also destroys any live snapshots of the run
+                        // (see --snapshot-interval).
+                        zfs::run(
+                            Command::new("pfexec")
+                                .arg("zfs")
+                                .arg("destroy")
+                                .arg("-r")
+                                .arg(child),
+                        )?;
+                        let n = ndone.fetch_add(1, Ordering::SeqCst) + 1;
+                        ts_println!(
+                            "destroyed {} ({}/{})",
+                            child,
+                            n,
+                            children.len()
+                        );
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for h in handles {
+            h.join()
+                .map_err(|error| anyhow!("thread panicked: {:?}", error))??;
+        }
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    zfs::run(
+        Command::new("pfexec").arg("zfs").arg("destroy").arg(gocrash_dataset),
+    )?;
+    text_println!("destroyed zfs dataset {:?}", gocrash_dataset);
+    Ok(Teardown::Commands)
+}
+
+/// Verifies that every dataset and snapshot in the tree rooted at `dataset` was
+/// created by the gocrash session identified by `session_key`
+///
+/// We check that each one has `SESSION_PROPERTY` set locally (not inherited,
+/// which would be the case for a dataset that somebody else created inside
+/// ours) and that it has the expected value.
+fn verify_session_tree(
+    dataset: &str,
+    session_key: &str,
+) -> Result<(), anyhow::Error> {
+    let output = zfs::run(
+        Command::new("zfs")
+            .arg("get")
+            .arg("-H")
+            .arg("-r")
+            .arg("-tfilesystem,volume,snapshot")
+            .arg("-oname,value,source")
+            .arg(SESSION_PROPERTY)
+            .arg(dataset),
+    )?;
+
+    let foreign = output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, '\t');
+            let name = parts.next()?;
+            let value = parts.next().unwrap_or("");
+            let source = parts.next().unwrap_or("");
+            if value == session_key && source == "local" {
+                None
+            } else {
+                Some(name)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if foreign.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "refusing to destroy {:?}: found datasets not created by gocrash \
+             session {:?} (destroy these by hand if that's what you want): {}",
+            dataset,
+            session_key,
+            foreign.join(", ")
+        ))
+    }
+}
+
+/// Copies a directory for each run
+struct CopyBackend;
+
+impl CloneBackend for CopyBackend {
+    fn working_dataset(
+        &self,
+        snapshot: &str,
+        session_key: &str,
+    ) -> Result<(String, String), anyhow::Error> {
+        Ok(dir_working_dataset(snapshot, session_key))
+    }
+
+    fn create_working_dataset(
+        &self,
+        working: &str,
+        session_key: &str,
+        _snapshot: &str,
+        _quota: Option<u64>,
+    ) -> Result<PathBuf, anyhow::Error> {
+        dir_create_working_dataset(working, session_key)
+    }
+
+    fn destroy_working_dataset(
+        &self,
+        working: &str,
+        session_key: &str,
+        _nthreads: u8,
+    ) -> Result<Teardown, anyhow::Error> {
+        dir_verify_session(working, session_key)?;
+        copy_destroy(Path::new(working))?;
+        Ok(Teardown::Directory)
+    }
+
+    fn find_working_datasets(
+        &self,
+        source: &str,
+    ) -> Result<Vec<(String, String)>, anyhow::Error> {
+        dir_find_working_datasets(source)
+    }
+
+    fn clone_run(
+        &self,
+        snapshot: &str,
+        run: &str,
+        _mountpoint: &Path,
+        _properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        copy_clone(Path::new(snapshot), Path::new(run))
+    }
+
+    fn run_mountpoint(&self, _working_mountpoint: &Path, run: &str) -> PathBuf {
+        PathBuf::from(run)
+    }
+
+    fn mountpoint(&self, run: &str) -> Result<PathBuf, anyhow::Error> {
+        Ok(PathBuf::from(run))
+    }
+
+    fn destroy_run(&self, run: &str) -> Result<(), anyhow::Error> {
+        copy_destroy(Path::new(run))
+    }
+
+    /// Each run's copy is mounted where it is (see `run_mountpoint()`), so
+    /// renaming the directory moves it to where `run` belongs.
+    fn rename_run(
+        &self,
+        old: &str,
+        run: &str,
+        _mountpoint: &Path,
+        _properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        std::fs::rename(old, run)
+            .with_context(|| format!("rename {:?} to {:?}", old, run))
+    }
+
+    fn source_exists(&self, snapshot: &str) -> Option<bool> {
+        Some(Path::new(snapshot).exists())
+    }
+
+    fn supports(&self, capability: Capability) -> bool {
+        capability == Capability::Rename
+    }
+}
+
+/// Copies the directory tree at `source` to `target`, which must not exist
+fn copy_clone(source: &Path, target: &Path) -> Result<(), anyhow::Error> {
+    if target.exists() {
+        bail!("{:?} already exists", target);
+    }
+    crate::archive::copy_tree(source, target, &mut Default::default())
+        .with_context(|| format!("copy {:?} to {:?}", source, target))
+}
+
+/// Removes a directory tree created by `copy_clone()`
+fn copy_destroy(target: &Path) -> Result<(), anyhow::Error> {
+    std::fs::remove_dir_all(target)
+        .with_context(|| format!("remove {:?}", target))
+}
+
+/// Mounts an overlay filesystem on top of a directory for each run
+///
+/// Each run's "dataset" is a directory in the working directory containing
+/// the overlay's upper layer ("upper", where everything the run writes ends
+/// up), its work directory ("work"), and the mounted overlay ("merged").
+/// Mounting requires privileges, and this only works on Linux, which has no
+/// pfexec(1), so we use sudo(8) instead (see `privileged()`).
+struct OverlayBackend;
+
+impl CloneBackend for OverlayBackend {
+    fn working_dataset(
+        &self,
+        snapshot: &str,
+        session_key: &str,
+    ) -> Result<(String, String), anyhow::Error> {
+        Ok(dir_working_dataset(snapshot, session_key))
+    }
+
+    fn create_working_dataset(
+        &self,
+        working: &str,
+        session_key: &str,
+        _snapshot: &str,
+        _quota: Option<u64>,
+    ) -> Result<PathBuf, anyhow::Error> {
+        dir_create_working_dataset(working, session_key)
+    }
+
+    fn destroy_working_dataset(
+        &self,
+        working: &str,
+        session_key: &str,
+        _nthreads: u8,
+    ) -> Result<Teardown, anyhow::Error> {
+        dir_verify_session(working, session_key)?;
+        let entries = std::fs::read_dir(working)
+            .with_context(|| format!("read directory {:?}", working))?;
+        for entry in entries {
+            let entry = entry
+                .with_context(|| format!("read directory {:?}", working))?;
+            if entry.path().join(OVERLAY_MERGED).is_dir() {
+                self.destroy_run(&entry.path().display().to_string())?;
+            }
+        }
+        privileged_remove(Path::new(working))?;
+        Ok(Teardown::Directory)
+    }
+
+    fn find_working_datasets(
+        &self,
+        source: &str,
+    ) -> Result<Vec<(String, String)>, anyhow::Error> {
+        dir_find_working_datasets(source)
+    }
+
+    fn clone_run(
+        &self,
+        snapshot: &str,
+        run: &str,
+        _mountpoint: &Path,
+        _properties: &[(&str, &str)],
+    ) -> Result<(), anyhow::Error> {
+        // The overlay options are separated by commas and colons, and the
+        // kernel wants absolute paths.
+        let lower = std::fs::canonicalize(snapshot)
+            .with_context(|| format!("resolve {:?}", snapshot))?;
+        let run = std::path::absolute(run)
+            .with_context(|| format!("resolve {:?}", run))?;
+        let upper = run.join(OVERLAY_UPPER);
+        let work = run.join(OVERLAY_WORK);
+        let merged = run.join(OVERLAY_MERGED);
+        let paths = [&lower, &upper, &work];
+        if let Some(bad) =
+            paths.iter().find(|p| p.to_string_lossy().contains([',', ':']))
+        {
+            bail!("overlay backend: path {:?} contains \",\" or \":\"", bad);
+        }
+        if run.exists() {
+            bail!("{:?} already exists", run);
+        }
+        for dir in [&upper, &work, &merged] {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("create {:?}", dir))?;
+        }
+        run_command(
+            privileged("mount")
+                .arg("-t")
+                .arg("overlay")
+                .arg("overlay")
+                .arg("-o")
+                .arg(format!(
+                    "lowerdir={},upperdir={},workdir={}",
+                    lower.display(),
+                    upper.display(),
+                    work.display()
+                ))
+                .arg(&merged),
+        )?;
+        Ok(())
+    }
+
+    fn run_mountpoint(&self, _working_mountpoint: &Path, run: &str) -> PathBuf {
+        Path::new(run).join(OVERLAY_MERGED)
+    }
+
+    fn mountpoint(&self, run: &str) -> Result<PathBuf, anyhow::Error> {
+        Ok(Path::new(run).join(OVERLAY_MERGED))
+    }
+
+    fn destroy_run(&self, run: &str) -> Result<(), anyhow::Error> {
+        let merged = Path::new(run).join(OVERLAY_MERGED);
+        if is_mount_point(&merged)? {
+            run_command(privileged("umount").arg(&merged))?;
+        }
+        privileged_remove(Path::new(run))
+    }
+
+    fn source_exists(&self, snapshot: &str) -> Option<bool> {
+        Some(Path::new(snapshot).exists())
+    }
+}
+
+/// Subdirectories of each run's directory with the overlay backend
+const OVERLAY_UPPER: &str = "upper";
+const OVERLAY_WORK: &str = "work";
+const OVERLAY_MERGED: &str = "merged";
+
+/// Returns whether something is mounted at `path` (according to
+/// /proc/self/mountinfo)
+fn is_mount_point(path: &Path) -> Result<bool, anyhow::Error> {
+    let path = std::path::absolute(path)
+        .with_context(|| format!("resolve {:?}", path))?;
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+        .context("read /proc/self/mountinfo")?;
+    // The fifth field is the mount point, with spaces, tabs, newlines, and
+    // backslashes escaped as octal.
+    let path = path.to_string_lossy();
+    Ok(mountinfo.lines().any(|line| {
+        line.split(' ').nth(4).is_some_and(|m| unescape_mountinfo(m) == path)
+    }))
+}
+
+/// Undoes the escaping of a field in /proc/self/mountinfo (e.g., "\040" for
+/// a space)
+fn unescape_mountinfo(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut rv = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match escaped {
+            Some(byte) => {
+                rv.push(byte);
+                i += 4;
+            }
+            None => {
+                rv.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&rv).into_owned()
+}
+
+/// Removes a directory tree that may contain files we can't remove ourselves
+///
+/// The overlay's work directory gets a subdirectory that only root can
+/// remove.  This never crosses into other filesystems, so that if a run's
+/// overlay is somehow still mounted, we fail rather than removing what's in
+/// it (which would be the source tree, as seen through the overlay).
+fn privileged_remove(path: &Path) -> Result<(), anyhow::Error> {
+    run_command(
+        privileged("rm").arg("-rf").arg("--one-file-system").arg(path),
+    )?;
+    Ok(())
+}
+
+/// Returns a command that runs `program` with privileges (for the overlay
+/// backend)
+///
+/// If we already have them (e.g., with `--allow-root`), we run `program`
+/// directly.  Otherwise, we use `sudo -n`, which fails rather than waiting
+/// for a password that nobody's there to type.
+fn privileged(program: &str) -> Command {
+    #[cfg(unix)]
+    // Safety: geteuid(2) has no preconditions.
+    if unsafe { libc::geteuid() } == 0 {
+        return Command::new(program);
+    }
+    let mut command = Command::new("sudo");
+    command.arg("-n").arg(program);
+    command
+}
+
+/// With the directory-based backends, the working directory goes next to the
+/// source directory, since anything inside it would be copied into each run.
+fn dir_working_dataset(snapshot: &str, session_key: &str) -> (String, String) {
+    let source = snapshot.trim_end_matches(['/', '\\']);
+    (source.to_string(), format!("{}-{}", source, session_key))
+}
+
+/// Name of the file in each working directory that says which session
+/// created it (see `dir_verify_session()`)
+const SESSION_MARKER: &str = ".gocrash-session";
+
+/// Creates working directory `working` for session `session_key`, returning
+/// its path
+fn dir_create_working_dataset(
+    working: &str,
+    session_key: &str,
+) -> Result<PathBuf, anyhow::Error> {
+    let path = PathBuf::from(working);
+    std::fs::create_dir(&path).with_context(|| format!("mkdir {:?}", path))?;
+    let marker = path.join(SESSION_MARKER);
+    std::fs::write(&marker, session_key)
+        .with_context(|| format!("write {:?}", marker))?;
+    text_println!("created directory {:?}", path);
+    Ok(path)
+}
+
+/// Verifies that working directory `working` was created by the gocrash
+/// session identified by `session_key`
+///
+/// This is the counterpart of `verify_session_tree()` for directories.  Its
+/// name alone doesn't tell us: anything next to the source directory with a
+/// name like `<source>-gocrash-<timestamp>` would otherwise be fair game for
+/// `gocrash clean`.
+fn dir_verify_session(
+    working: &str,
+    session_key: &str,
+) -> Result<(), anyhow::Error> {
+    let marker = Path::new(working).join(SESSION_MARKER);
+    let found = std::fs::read_to_string(&marker).ok();
+    if found.as_deref().map(str::trim) != Some(session_key) {
+        bail!(
+            "refusing to destroy {:?}: {:?} doesn't say that it was created \
+             by gocrash session {:?} (remove it by hand if that's what you \
+             want)",
+            working,
+            marker,
+            session_key
+        );
+    }
+    Ok(())
+}
+
+/// Returns the name and session key of each working directory next to source
+/// directory `source` (see `dir_working_dataset()`)
+fn dir_find_working_datasets(
+    source: &str,
+) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let source = Path::new(source.trim_end_matches(['/', '\\']));
+    let (Some(parent), Some(source_name)) =
+        (source.parent(), source.file_name())
+    else {
+        bail!("{:?} has no parent directory", source);
+    };
+    let parent =
+        if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+    let prefix = format!("{}-", source_name.to_string_lossy());
+    let mut found = Vec::new();
+    let entries = std::fs::read_dir(parent)
+        .with_context(|| format!("read directory {:?}", parent))?;
+    for entry in entries {
+        let entry =
+            entry.with_context(|| format!("read directory {:?}", parent))?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let Some(session_key) = file_name.strip_prefix(&prefix) else {
+            continue;
+        };
+        let path = parent.join(&file_name);
+        if path.is_dir() {
+            found.push((path.display().to_string(), session_key.to_string()));
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dir_create_working_dataset;
+    use super::dir_verify_session;
+    use super::unescape_mountinfo;
+
+    #[test]
+    fn test_unescape_mountinfo() {
+        for (field, expected) in [
+            ("/tmp/src-gocrash-1/thread-0-run-1/merged", None),
+            ("/tmp/my\\040src/merged", Some("/tmp/my src/merged")),
+            ("/a\\011b\\012c\\134d", Some("/a\tb\nc\\d")),
+            // not escapes
+            ("/a\\04", None),
+            ("/a\\x41b", None),
+        ] {
+            let expected = expected.unwrap_or(field);
+            assert_eq!(unescape_mountinfo(field), expected);
+        }
+    }
+
+    #[test]
+    fn test_dir_verify_session() {
+        let parent = std::env::temp_dir()
+            .join(format!("gocrash-test-{}", std::process::id()));
+        std::fs::create_dir_all(&parent).unwrap();
+        let working = parent.join("src-gocrash-1").display().to_string();
+        dir_create_working_dataset(&working, "gocrash-1").unwrap();
+        dir_verify_session(&working, "gocrash-1").unwrap();
+        let error = dir_verify_session(&working, "gocrash-2").unwrap_err();
+        assert!(format!("{:#}", error).starts_with("refusing to destroy"));
+
+        // a directory with the right kind of name that a session didn't create
+        let other = parent.join("src-gocrash-3");
+        std::fs::create_dir(&other).unwrap();
+        let other = other.display().to_string();
+        let error = dir_verify_session(&other, "gocrash-3").unwrap_err();
+        assert!(format!("{:#}", error).starts_with("refusing to destroy"));
+
+        std::fs::remove_dir_all(&parent).unwrap();
+    }
+}
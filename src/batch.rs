@@ -0,0 +1,137 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Grouping runs into batches with maintenance in between (`--batch-size` and
+//! `--between-batches`)
+//!
+//! Long sessions sometimes need periodic upkeep: printing a summary of how
+//! things are going, trimming the pool, rotating logs, and so on.  Rather than
+//! having cron jobs do this while runs are in progress, runs can be grouped
+//! into batches of a fixed size.  Once all the runs in a batch have finished,
+//! the actions given with `--between-batches` are carried out (with no runs in
+//! progress), and then the next batch starts.
+
+use anyhow::anyhow;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How often threads waiting for the next batch check whether the session is
+/// stopping
+const STOP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Something to do between batches
+#[derive(Clone, Debug)]
+pub enum BatchAction {
+    /// print a summary of the session so far
+    Summary,
+    /// trim the pool (and wait for that to finish)
+    Trim,
+    /// run a shell command
+    Run(String),
+}
+
+impl std::str::FromStr for BatchAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "summary" => Ok(BatchAction::Summary),
+            "trim" => Ok(BatchAction::Trim),
+            _ => match s.strip_prefix("run:") {
+                Some(command) if !command.trim().is_empty() => {
+                    Ok(BatchAction::Run(command.to_string()))
+                }
+                _ => Err(anyhow!(
+                    "between-batch action {:?}: expected \"summary\", \
+                     \"trim\", or \"run:COMMAND\"",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for BatchAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchAction::Summary => f.write_str("summary"),
+            BatchAction::Trim => f.write_str("trim"),
+            BatchAction::Run(command) => write!(f, "run:{}", command),
+        }
+    }
+}
+
+/// Keeps runs from starting in the next batch until the current one is done
+pub struct Batches {
+    /// number of runs in each batch
+    pub size: usize,
+    /// what to do between batches
+    pub actions: Vec<BatchAction>,
+    state: Mutex<BatchState>,
+    next: Condvar,
+}
+
+struct BatchState {
+    /// which batch we're on (starting from 1)
+    number: usize,
+    /// number of runs started in this batch
+    nstarted: usize,
+    /// number of those runs still in progress
+    nrunning: usize,
+}
+
+impl Batches {
+    pub fn new(size: usize, actions: Vec<BatchAction>) -> Batches {
+        Batches {
+            size,
+            actions,
+            state: Mutex::new(BatchState {
+                number: 1,
+                nstarted: 0,
+                nrunning: 0,
+            }),
+            next: Condvar::new(),
+        }
+    }
+
+    /// Waits until another run can start in the current batch and counts it
+    ///
+    /// Returns false (without counting a run) if `stop()` says that the
+    /// session is stopping while we're waiting.
+    pub fn start_run(&self, stop: impl Fn() -> bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while state.nstarted >= self.size {
+            if stop() {
+                return false;
+            }
+            state =
+                self.next.wait_timeout(state, STOP_POLL_INTERVAL).unwrap().0;
+        }
+        state.nstarted += 1;
+        state.nrunning += 1;
+        true
+    }
+
+    /// Notes that a run finished
+    ///
+    /// If that was the last run in its batch, this returns the batch's number,
+    /// and the caller should do whatever is needed between batches and then
+    /// call `next_batch()`.
+    pub fn finish_run(&self) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.nrunning -= 1;
+        (state.nstarted >= self.size && state.nrunning == 0)
+            .then_some(state.number)
+    }
+
+    /// Lets runs start in the next batch
+    pub fn next_batch(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.number += 1;
+        state.nstarted = 0;
+        self.next.notify_all();
+    }
+}
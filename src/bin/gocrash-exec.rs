@@ -0,0 +1,11 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! The `gocrash-exec` command: just the parts of gocrash that need to run on
+//! the machine being tested (see `lib.rs`)
+
+fn main() {
+    gocrash::exec_main()
+}
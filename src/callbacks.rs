@@ -0,0 +1,111 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Callbacks for programs that use gocrash as a library
+//!
+//! Hook commands (see `hooks.rs`) are convenient from the command line, but
+//! each one costs a process, and a program that embeds gocrash (running it
+//! with `run_with_args()`) would rather find out what's happening directly.
+//! Such a program can `register()` an implementation of `Callbacks`, whose
+//! methods are then called from the threads doing the runs, at the same
+//! points that the corresponding events are emitted (see `events.rs`):
+//!
+//! * `on_run_start()`: before a run starts
+//! * `on_failure()`: when a run ends because the test suite failed (or hung,
+//!   or was otherwise judged to have failed; see `Failure`)
+//! * `on_run_end()`: when any run ends, after `on_failure()`
+//!
+//! Callbacks hold up the run that they're called for, so they should be
+//! quick.  Callbacks apply to every session in the process from then on.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Every registered set of callbacks
+static CALLBACKS: Mutex<Vec<Arc<dyn Callbacks>>> = Mutex::new(Vec::new());
+
+/// Things to do at points in each run
+///
+/// Every method does nothing by default, so implementations need only
+/// provide the ones they care about.
+pub trait Callbacks: Send + Sync {
+    /// Called before `run` starts
+    fn on_run_start(&self, _run: &Run) {}
+
+    /// Called when `run` ends because the test suite failed
+    fn on_failure(&self, _run: &Run, _failure: &Failure) {}
+
+    /// Called when `run` ends, however it ended
+    fn on_run_end(&self, _run: &Run, _end: &RunEnd) {}
+}
+
+/// Identifies a run
+pub struct Run<'a> {
+    /// the session's unique name
+    pub session: &'a str,
+    pub thread: u8,
+    /// which attempt this is for its thread
+    pub attempt: usize,
+    pub run_id: usize,
+    /// per-run dataset (or directory)
+    pub dataset: &'a str,
+}
+
+/// Describes how a run ended
+pub struct RunEnd<'a> {
+    /// "passed", "failed", "hung", "runaway", "suspect", "infra-error", or
+    /// "interrupted"
+    pub result: &'a str,
+    pub duration: Duration,
+    /// whether the run's dataset was kept
+    pub kept: bool,
+    /// what went wrong, for runs that didn't pass
+    pub error: Option<&'a anyhow::Error>,
+}
+
+/// Describes a failed run (one with result "failed", "hung", "runaway", or
+/// "suspect")
+pub struct Failure<'a> {
+    /// failure signature (see `--signature-profile`)
+    pub signature: &'a str,
+    /// "crash", "panic", "assertion", "build", or "unknown" (see
+    /// `--severity-rule`)
+    pub severity: &'a str,
+    /// name of the known failure that this one matched, if any (see
+    /// `--signature-catalog`)
+    pub known_failure: Option<&'a str>,
+}
+
+/// Arranges for `callbacks` to be called for every run from now on
+pub fn register(callbacks: Arc<dyn Callbacks>) {
+    CALLBACKS.lock().unwrap().push(callbacks);
+}
+
+/// Calls `f` for each registered set of callbacks
+fn each(f: impl Fn(&dyn Callbacks)) {
+    // Don't hold the lock while calling out, in case a callback registers
+    // more callbacks.
+    let callbacks = CALLBACKS.lock().unwrap().clone();
+    for c in &callbacks {
+        f(c.as_ref());
+    }
+}
+
+/// Calls `on_run_start()` for each registered set of callbacks
+pub(crate) fn run_started(run: &Run) {
+    each(|c| c.on_run_start(run));
+}
+
+/// Calls `on_failure()` (if `failure` is given) and `on_run_end()` for each
+/// registered set of callbacks
+pub(crate) fn run_ended(run: &Run, end: &RunEnd, failure: Option<&Failure>) {
+    each(|c| {
+        if let Some(failure) = failure {
+            c.on_failure(run, failure);
+        }
+        c.on_run_end(run, end);
+    });
+}
@@ -0,0 +1,746 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Running the test suite and capturing its output
+//!
+//! Rather than have the test suite write directly to its output files, we
+//! read its stdout and stderr ourselves and copy each line to the
+//! corresponding file.  This lets us watch the output as it goes by: for
+//! example, to note when each phase of the test suite starts.
+//!
+//! While the command runs, we can also periodically check on it with a
+//! `Watchdog` and kill it (along with all of its descendants) if something
+//! has gone wrong.  The command runs in its own process group so that we can
+//! find descendants even after they've been orphaned (e.g., by a daemonizing
+//! double fork).
+//!
+//! When a watchdog kills the command, we finish copying whatever output was
+//! still in the pipes and then append a few lines to each output file (each
+//! starting with `NOTE_PREFIX`) saying when and why it was killed and what it
+//! last printed.  Otherwise, a log that just stops looks the same as one from
+//! a test suite that exited, and you'd have to find the console output from
+//! the session to tell the difference.  Anything that interprets the output
+//! should remove these first (see `strip_notes()`).
+
+use crate::command_error;
+use crate::command_label;
+use crate::run_command;
+use crate::signature::normalize;
+use anyhow::anyhow;
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Prefixes of output lines that mark the start of a phase of the Go test
+/// suite (e.g., "Building Go toolchain1 using ..." from make.bash or
+/// "##### Testing packages." from `go tool dist test`)
+const PHASE_MARKERS: &[&str] = &["Building ", "##### "];
+
+/// Prefixes of output lines that report a failed test (from `go test -v`)
+const FAILURE_MARKERS: &[&str] = &["--- FAIL: "];
+
+/// Describes one phase of a run of the test suite
+pub struct Phase {
+    /// the line that started the phase (normalized as for signatures so that
+    /// the same phase has the same name across runs)
+    pub name: String,
+    pub duration: Duration,
+}
+
+/// How long to give Go programs to dump their stacks after sending them
+/// SIGQUIT (see `Watchdog::diagnose`)
+#[cfg(unix)]
+const QUIT_GRACE: Duration = Duration::from_secs(10);
+
+/// Decides whether a running command should be killed, returning the reason
+/// if so
+pub type WatchdogCheck<'a> =
+    Box<dyn Fn(&Progress) -> Option<String> + Send + Sync + 'a>;
+
+/// Periodically checks on a running command
+pub struct Watchdog<'a> {
+    /// identifies this watchdog in `Killed`
+    pub name: &'static str,
+    /// how often to check
+    pub interval: Duration,
+    /// returns a reason to kill the command, if it should be killed
+    pub check: WatchdogCheck<'a>,
+    /// before killing the command, send SIGQUIT to its descendants and give
+    /// them a little while to exit (Go programs dump all goroutines' stacks
+    /// when they get SIGQUIT)
+    pub diagnose: bool,
+}
+
+/// Describes a running command's progress, for use by watchdogs
+#[derive(Clone)]
+pub struct Progress {
+    /// when the command last wrote any output
+    pub last_output: Instant,
+    /// the last line that the command wrote (to stdout or stderr)
+    pub last_line: Option<String>,
+    /// the phase that the command is in (see `PHASE_MARKERS`) and when it
+    /// started
+    pub phase: Option<(String, Instant)>,
+    /// the first line that reported a failed test (see `FAILURE_MARKERS`) and
+    /// when it appeared
+    pub failure: Option<(String, Instant)>,
+}
+
+/// Describes how a command run with `run_captured()` went
+pub struct Captured {
+    /// the result of the command (an error if it couldn't be run or failed)
+    pub result: Result<(), anyhow::Error>,
+    /// the phases that the command went through
+    pub phases: Vec<Phase>,
+    /// why the command was killed by a watchdog, if it was
+    pub killed: Option<Killed>,
+}
+
+/// Describes why a watchdog killed a command
+pub struct Killed {
+    /// which watchdog killed it (see `Watchdog::name`)
+    pub watchdog: &'static str,
+    pub reason: String,
+    /// the command's progress when the watchdog decided to kill it
+    pub progress: Progress,
+    /// when the watchdog decided to kill it
+    pub when: Instant,
+    pub time: chrono::DateTime<chrono::Utc>,
+}
+
+/// Prefix of the lines that we append to a killed command's output (see
+/// `kill_notes()`)
+pub const NOTE_PREFIX: &str = "[gocrash] ";
+
+/// Whether to save a combined log of stdout and stderr
+#[derive(
+    Clone, Copy, Deserialize, Eq, PartialEq, Serialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum CombinedLog {
+    /// save the combined log in addition to separate stdout and stderr files
+    Also,
+    /// save only the combined log
+    Only,
+}
+
+/// Describes where to save a command's output
+pub struct Outputs {
+    /// file for stdout alone
+    pub stdout: Option<std::fs::File>,
+    /// file for stderr alone
+    pub stderr: Option<std::fs::File>,
+    /// file for stdout and stderr interleaved, with each line tagged with
+    /// which stream it came from and when (see `combined_line()`)
+    pub combined: Option<std::fs::File>,
+    /// whether to prefix each line in the separate stdout and stderr files
+    /// with when it was printed (see `timestamped_line()`)
+    pub timestamps: bool,
+    /// directory in which to save the command's process tree and the stacks
+    /// of its processes before a watchdog with `diagnose` kills it (see
+    /// `save_processes()`)
+    pub diagnostics: Option<std::path::PathBuf>,
+}
+
+/// Runs `cmd` to completion, saving its stdout and stderr to the given files
+/// and checking on it with the given watchdogs
+pub fn run_captured(
+    cmd: &mut Command,
+    outputs: Outputs,
+    watchdogs: &[Watchdog],
+) -> Captured {
+    let label = command_label(cmd);
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(cmd, 0);
+    let mut child = match cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to exec {}", label))
+    {
+        Ok(child) => child,
+        Err(error) => {
+            return Captured {
+                result: Err(error),
+                phases: Vec::new(),
+                killed: None,
+            }
+        }
+    };
+
+    let pid = child.id();
+    let start = Instant::now();
+    let combined = outputs.combined.map(Mutex::new);
+    let markers = Mutex::new(Vec::new());
+    let progress = Mutex::new(Progress {
+        last_output: start,
+        last_line: None,
+        phase: None,
+        failure: None,
+    });
+    let killed = Mutex::new(None);
+    let done = AtomicBool::new(false);
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    // The copying threads take the separate output files, so keep our own
+    // handles for appending notes if the command is killed.
+    let separate = [&outputs.stdout, &outputs.stderr]
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, file)| match file.as_ref()?.try_clone() {
+            Ok(file) => Some((i, file)),
+            Err(error) => {
+                eprintln!(
+                    "warning: {}: duplicate output file: {}",
+                    label, error
+                );
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    let (copy_results, wait_result) = std::thread::scope(|s| {
+        let copy = |input, output, stream| {
+            let sink = Sink {
+                output,
+                combined: combined.as_ref(),
+                timestamps: outputs.timestamps,
+                stream,
+                start,
+                markers: &markers,
+                progress: &progress,
+            };
+            s.spawn(move || copy_lines(input, sink))
+        };
+        let out = copy(
+            Box::new(stdout) as Box<dyn Read + Send>,
+            outputs.stdout,
+            STREAM_STDOUT,
+        );
+        let err = copy(Box::new(stderr), outputs.stderr, STREAM_STDERR);
+        let monitors = watchdogs
+            .iter()
+            .map(|watchdog| {
+                let (killed, done, label) = (&killed, &done, &label);
+                let (progress, diagnostics) =
+                    (&progress, outputs.diagnostics.as_deref());
+                s.spawn(move || {
+                    let command = Watched { pid, label, diagnostics };
+                    watch(watchdog, &command, progress, killed, done)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let copy_results = [out.join().unwrap(), err.join().unwrap()];
+        let wait_result = child.wait();
+        done.store(true, Ordering::SeqCst);
+        for monitor in monitors {
+            monitor.thread().unpark();
+            monitor.join().unwrap();
+        }
+        (copy_results, wait_result)
+    });
+    // If we can't tell where the output stopped, assume it was partway
+    // through a line.
+    let unfinished = copy_results
+        .iter()
+        .map(|result| *result.as_ref().unwrap_or(&true))
+        .collect::<Vec<_>>();
+    let [out_result, err_result] = copy_results;
+    let copy_result = out_result.and(err_result).map(|_| ());
+    let end = Instant::now();
+
+    let mut markers = markers.into_inner().unwrap();
+    markers.sort_by_key(|(when, _)| *when);
+    let phases = markers
+        .iter()
+        .enumerate()
+        .map(|(i, (start, name))| {
+            let phase_end = markers.get(i + 1).map_or(end, |(next, _)| *next);
+            Phase { name: name.clone(), duration: phase_end - *start }
+        })
+        .collect();
+
+    let killed = killed.into_inner().unwrap();
+    if let Some(killed) = &killed {
+        // The notes come after everything the command printed, even if it
+        // printed more after the watchdog decided to kill it (e.g., stacks
+        // after SIGQUIT), so that's when we say they were written.
+        let notes = kill_notes(killed, start);
+        let elapsed = end - start;
+        let mut written = Ok(());
+        for (i, mut file) in separate {
+            // Without timestamps, the command's last line may not have been
+            // finished, in which case we start the notes on a line of their
+            // own.
+            if !outputs.timestamps && unfinished[i] {
+                written = written.and(file.write_all(b"\n"));
+            }
+            for note in &notes {
+                let line = if outputs.timestamps {
+                    timestamped_line(elapsed, note.as_bytes())
+                } else {
+                    format!("{}\n", note).into_bytes()
+                };
+                written = written.and(file.write_all(&line));
+            }
+        }
+        if let Some(combined) = &combined {
+            let mut combined = combined.lock().unwrap();
+            for note in &notes {
+                let line = combined_line(elapsed, STREAM_NOTE, note.as_bytes());
+                written = written.and(combined.write_all(&line));
+            }
+        }
+        if let Err(error) = written {
+            eprintln!(
+                "warning: {}: saving notes about why it was killed: {}",
+                label, error
+            );
+        }
+    }
+    let result =
+        match wait_result {
+            Err(error) => Err(anyhow::Error::new(error)
+                .context(format!("wait for {}", label))),
+            Ok(_) if killed.is_some() => Err(anyhow!(
+                "command killed: {}: {}",
+                label,
+                killed.as_ref().unwrap().reason
+            )),
+            Ok(status) if !status.success() => {
+                Err(command_error(&label, status, b"", b""))
+            }
+            Ok(_) => {
+                copy_result.with_context(|| format!("save output of {}", label))
+            }
+        };
+    Captured { result, phases, killed }
+}
+
+/// Describes the command that a watchdog is watching
+struct Watched<'a> {
+    pid: u32,
+    label: &'a str,
+    /// see `Outputs::diagnostics`
+    diagnostics: Option<&'a std::path::Path>,
+}
+
+/// Body of a thread that runs one watchdog until the command is done or the
+/// watchdog kills it
+fn watch(
+    watchdog: &Watchdog,
+    command: &Watched,
+    progress: &Mutex<Progress>,
+    killed: &Mutex<Option<Killed>>,
+    done: &AtomicBool,
+) {
+    let Watched { pid, label, .. } = *command;
+    while !done.load(Ordering::SeqCst) {
+        std::thread::park_timeout(watchdog.interval);
+        if done.load(Ordering::SeqCst) {
+            return;
+        }
+        let progress = progress.lock().unwrap().clone();
+        let Some(reason) = (watchdog.check)(&progress) else {
+            continue;
+        };
+
+        {
+            let mut killed = killed.lock().unwrap();
+            if killed.is_some() {
+                // Another watchdog got here first.
+                return;
+            }
+            *killed = Some(Killed {
+                watchdog: watchdog.name,
+                reason,
+                progress,
+                when: Instant::now(),
+                time: chrono::Utc::now(),
+            });
+        }
+
+        if watchdog.diagnose {
+            if let Some(dir) = command.diagnostics {
+                save_processes(pid, dir);
+            }
+            request_stacks(pid, label);
+        }
+        if let Err(error) = kill_tree(pid) {
+            eprintln!("warning: killing {}: {:#}", label, error);
+        }
+        return;
+    }
+}
+
+/// Asks the processes under process `pid` to dump their stacks (by sending
+/// them SIGQUIT) and gives them a little while to do so and exit
+#[cfg(unix)]
+fn request_stacks(pid: u32, label: &str) {
+    // The top-level process is usually a shell, which we leave alone so that
+    // it can't exit before the rest of the tree is gone.
+    if let Err(error) = signal_descendants(pid, libc::SIGQUIT) {
+        eprintln!("warning: sending SIGQUIT to {}: {:#}", label, error);
+    }
+    std::thread::park_timeout(QUIT_GRACE);
+}
+
+/// Asks the processes under process `pid` to dump their stacks (there's no
+/// SIGQUIT on this system, so this does nothing)
+#[cfg(not(unix))]
+fn request_stacks(_pid: u32, _label: &str) {}
+
+/// File (in `Outputs::diagnostics`) listing the processes under a command
+/// when it was killed
+#[cfg(unix)]
+const PTREE_FILE: &str = "test_run_ptree";
+
+/// File (in `Outputs::diagnostics`) with the native stacks of the processes
+/// under a command when it was killed
+#[cfg(unix)]
+const PSTACK_FILE: &str = "test_run_pstack";
+
+/// Saves a listing of process `pid` and its descendants and the native stack
+/// of each one (using pstack(1)) into `dir`
+///
+/// This shows where processes that aren't Go programs (and so don't dump
+/// their stacks on SIGQUIT) were stuck, as well as any Go programs that
+/// are too wedged to handle SIGQUIT.  It's best-effort: problems are noted
+/// in the files rather than reported.
+#[cfg(unix)]
+fn save_processes(pid: u32, dir: &std::path::Path) {
+    let pids = match process_tree(pid) {
+        Ok(pids) => pids,
+        Err(error) => {
+            eprintln!("warning: listing processes to save: {:#}", error);
+            return;
+        }
+    };
+    let list = pids.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(",");
+    let ptree = run_command(
+        Command::new("ps")
+            .arg("-o")
+            .arg("pid,ppid,etime,args")
+            .arg("-p")
+            .arg(list),
+    )
+    .unwrap_or_else(|error| format!("ps: {:#}\n", error));
+    let pstack = pids
+        .iter()
+        .map(|p| {
+            let stack = run_command(Command::new("pstack").arg(p.to_string()))
+                .unwrap_or_else(|error| format!("{:#}\n", error));
+            format!("==> process {} <==\n{}\n", p, stack)
+        })
+        .collect::<String>();
+    for (name, contents) in [(PTREE_FILE, ptree), (PSTACK_FILE, pstack)] {
+        let path = dir.join(name);
+        if let Err(error) = std::fs::write(&path, contents) {
+            eprintln!("warning: write {:?}: {:#}", path, error);
+        }
+    }
+}
+
+/// Saves a listing of process `pid` and its descendants and their stacks
+/// (there's no pstack(1) on this system, so this does nothing)
+#[cfg(not(unix))]
+fn save_processes(_pid: u32, _dir: &std::path::Path) {}
+
+/// Returns process `pid` (first) and all of its descendants
+///
+/// We find descendants by walking the process tree as reported by ps(1),
+/// which works the same way on illumos and Linux.  Processes that have been
+/// orphaned (and so reparented) are no longer in the tree, but unless they've
+/// started their own process group, they're still in `pid`'s, so we include
+/// those (and their descendants) too.
+#[cfg(unix)]
+fn process_tree(pid: u32) -> Result<Vec<u32>, anyhow::Error> {
+    let output = run_command(
+        Command::new("ps").arg("-A").arg("-o").arg("pid=,ppid=,pgid="),
+    )?;
+    let processes = output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pid = fields.next()?.parse::<u32>().ok()?;
+            let ppid = fields.next()?.parse::<u32>().ok()?;
+            let pgid = fields.next()?.parse::<u32>().ok()?;
+            Some((pid, ppid, pgid))
+        })
+        .collect::<Vec<_>>();
+
+    let mut tree = vec![pid];
+    tree.extend(
+        processes
+            .iter()
+            .filter(|(p, _, pgid)| *pgid == pid && *p != pid)
+            .map(|(p, _, _)| *p),
+    );
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        let children = processes
+            .iter()
+            .filter(|(c, p, _)| *p == parent && !tree.contains(c))
+            .map(|(c, _, _)| *c)
+            .collect::<Vec<_>>();
+        tree.extend(children);
+        i += 1;
+    }
+    Ok(tree)
+}
+
+/// Sends `signal` to each of the given processes, returning how many we were
+/// able to signal
+#[cfg(unix)]
+fn signal_all(pids: &[u32], signal: libc::c_int) -> usize {
+    pids.iter()
+        .filter_map(|p| libc::pid_t::try_from(*p).ok())
+        // Safety: kill(2) has no memory safety requirements.
+        .filter(|p| unsafe { libc::kill(*p, signal) } == 0)
+        .count()
+}
+
+/// Sends `signal` to all descendants of process `pid` (but not `pid` itself)
+#[cfg(unix)]
+pub fn signal_descendants(
+    pid: u32,
+    signal: libc::c_int,
+) -> Result<(), anyhow::Error> {
+    signal_all(&process_tree(pid)?[1..], signal);
+    Ok(())
+}
+
+/// Kills process `pid` and all of its descendants with SIGKILL
+#[cfg(unix)]
+pub fn kill_tree(pid: u32) -> Result<(), anyhow::Error> {
+    // Since processes can fork while we're doing this, we go around a few
+    // times.
+    for _ in 0..5 {
+        let nkilled = signal_all(&process_tree(pid)?, libc::SIGKILL);
+        // The top-level process remains (as a zombie) until we wait for it,
+        // so there's nothing left once that's the only one we could kill.
+        if nkilled <= 1 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Kills process `pid` and all of its descendants
+#[cfg(not(unix))]
+pub fn kill_tree(pid: u32) -> Result<(), anyhow::Error> {
+    run_command(
+        Command::new("taskkill")
+            .arg("/T")
+            .arg("/F")
+            .arg("/PID")
+            .arg(pid.to_string()),
+    )?;
+    Ok(())
+}
+
+/// Tags for each stream in the combined log (and for our own notes, which
+/// aren't from either stream)
+const STREAM_STDOUT: &str = "out";
+const STREAM_STDERR: &str = "err";
+const STREAM_NOTE: &str = "gcr";
+
+/// Returns the lines to append to the output of a command that `killed`
+/// describes, which started at `start`, like:
+///
+/// ```text
+/// [gocrash] killed by watchdog "output-stall" at 2022-06-01T12:34:56.789Z
+///     (912.345s after it started): no output for 10m
+/// [gocrash] last output (600.012s before that): ok  net/http  1.2s
+/// ```
+///
+/// (except that the first line isn't wrapped)
+fn kill_notes(killed: &Killed, start: Instant) -> Vec<String> {
+    let last_output = match &killed.progress.last_line {
+        Some(line) => format!(
+            "last output ({:.3}s before that): {}",
+            (killed.when - killed.progress.last_output).as_secs_f64(),
+            line
+        ),
+        None => String::from("no output before that"),
+    };
+    [
+        format!(
+            "killed by watchdog {:?} at {} ({:.3}s after it started): {}",
+            killed.watchdog,
+            killed.time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            (killed.when - start).as_secs_f64(),
+            killed.reason
+        ),
+        last_output,
+    ]
+    .into_iter()
+    .map(|note| format!("{}{}", NOTE_PREFIX, note))
+    .collect()
+}
+
+/// Removes the lines added by `kill_notes()`
+pub fn strip_notes(contents: &str) -> String {
+    let mut rv = String::with_capacity(contents.len());
+    for line in contents.lines() {
+        if !line.starts_with(NOTE_PREFIX) {
+            rv.push_str(line);
+            rv.push('\n');
+        }
+    }
+    rv
+}
+
+/// Describes where the lines from one of a command's output streams go
+struct Sink<'a> {
+    /// file for this stream alone
+    output: Option<std::fs::File>,
+    /// file for both streams together
+    combined: Option<&'a Mutex<std::fs::File>>,
+    /// whether to prefix lines in `output` with when they were printed
+    timestamps: bool,
+    /// tag for this stream in the combined file
+    stream: &'static str,
+    /// when the command started
+    start: Instant,
+    /// when each phase started
+    markers: &'a Mutex<Vec<(Instant, String)>>,
+    /// when the last output appeared, and what it was
+    progress: &'a Mutex<Progress>,
+}
+
+/// Copies lines from `input` to the files in `sink`, noting the time at which
+/// we see any line that starts a phase, and returns whether the output ended
+/// partway through a line
+fn copy_lines(
+    input: Box<dyn Read + Send>,
+    mut sink: Sink,
+) -> Result<bool, std::io::Error> {
+    let mut input = std::io::BufReader::new(input);
+    let mut line = Vec::new();
+    let mut unfinished = false;
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(unfinished);
+        }
+        unfinished = !line.ends_with(b"\n");
+        let now = Instant::now();
+
+        // Write each line as we get it so that people can watch the output
+        // files while the test suite runs.
+        if let Some(output) = &mut sink.output {
+            if sink.timestamps {
+                output.write_all(&timestamped_line(now - sink.start, &line))?;
+            } else {
+                output.write_all(&line)?;
+            }
+        }
+        if let Some(combined) = sink.combined {
+            let tagged = combined_line(now - sink.start, sink.stream, &line);
+            combined.lock().unwrap().write_all(&tagged)?;
+        }
+
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim();
+        let mut progress = sink.progress.lock().unwrap();
+        progress.last_output = now;
+        if !text.is_empty() {
+            progress.last_line = Some(text.to_string());
+        }
+        if PHASE_MARKERS.iter().any(|m| text.starts_with(m)) {
+            let phase = normalize(text);
+            progress.phase = Some((phase.clone(), now));
+            sink.markers.lock().unwrap().push((now, phase));
+        }
+        if progress.failure.is_none()
+            && FAILURE_MARKERS.iter().any(|m| text.starts_with(m))
+        {
+            progress.failure = Some((text.to_string(), now));
+        }
+    }
+}
+
+/// Formats a line for the combined log, which looks like:
+///
+/// ```text
+///     12.345 out | ok      archive/tar     0.015s
+///     12.351 err | panic: runtime error: ...
+/// ```
+///
+/// where the first column is the number of seconds since the command started.
+fn combined_line(elapsed: Duration, stream: &str, line: &[u8]) -> Vec<u8> {
+    let mut rv =
+        format!("{:>10.3} {} | ", elapsed.as_secs_f64(), stream).into_bytes();
+    rv.extend_from_slice(line);
+    if !line.ends_with(b"\n") {
+        rv.push(b'\n');
+    }
+    rv
+}
+
+/// Formats a line for a stdout or stderr file when timestamps are enabled,
+/// which looks like:
+///
+/// ```text
+///     12.345 | ok      archive/tar     0.015s
+/// ```
+///
+/// where the first column is the number of seconds since the command started.
+fn timestamped_line(elapsed: Duration, line: &[u8]) -> Vec<u8> {
+    let mut rv = format!("{:>10.3} | ", elapsed.as_secs_f64()).into_bytes();
+    rv.extend_from_slice(line);
+    if !line.ends_with(b"\n") {
+        rv.push(b'\n');
+    }
+    rv
+}
+
+/// Removes the timestamps added by `timestamped_line()`
+pub fn strip_timestamps(contents: &str) -> String {
+    let mut rv = String::with_capacity(contents.len());
+    for line in contents.lines() {
+        let text = match line.split_once(" | ") {
+            Some((prefix, text)) if prefix.trim().parse::<f64>().is_ok() => {
+                text
+            }
+            _ => line,
+        };
+        rv.push_str(text);
+        rv.push('\n');
+    }
+    rv
+}
+
+/// Splits the contents of a combined log back into stdout and stderr
+pub fn split_combined(contents: &str) -> (String, String) {
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    for line in contents.lines() {
+        let Some((prefix, text)) = line.split_once(" | ") else {
+            continue;
+        };
+        let output = match prefix.split_whitespace().nth(1) {
+            Some(STREAM_STDOUT) => &mut stdout,
+            Some(STREAM_STDERR) => &mut stderr,
+            _ => continue,
+        };
+        output.push_str(text);
+        output.push('\n');
+    }
+    (stdout, stderr)
+}
@@ -0,0 +1,327 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Catalogs of known failures (`--signature-catalog`)
+//!
+//! Long campaigns keep hitting failures that already have bug reports.  A
+//! catalog describes these so that they can be labeled as such in reports
+//! (and so that `--stop-on-new-failure` can keep going past them).  Catalogs
+//! are TOML files (or YAML, if the file name ends in ".yaml" or ".yml") with
+//! one entry per known failure:
+//!
+//! ```toml
+//! [[failure]]
+//! name = "go#55167"
+//! pattern = "fatal error: found bad pointer in Go heap"
+//! links = ["https://github.com/golang/go/issues/55167"]
+//! keep = 2
+//! ```
+//!
+//! A failed run matches an entry if the entry's pattern (a regular expression)
+//! matches the run's signature or any line of its stdout or stderr.  If several
+//! entries match, the first one wins.  If `keep` is given, at most that many
+//! matching runs are kept (see `retention.rs`).
+//!
+//! So that a whole lab can share one catalog, a catalog can also be fetched
+//! when the session starts, either from a URL (with curl(1)) or from a file in
+//! a git repository (written "git+REPO#PATH", e.g.,
+//! "git+https://github.com/example/soak.git#catalog.toml").  Each fetched
+//! catalog is cached, and if fetching it fails, the cached copy is used
+//! instead (with a warning).
+
+use crate::run_command;
+use crate::store::hash_bytes;
+use anyhow::anyhow;
+use anyhow::Context;
+use serde::Deserialize;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// How long to wait for a catalog to download before falling back to the
+/// cached copy
+const FETCH_TIMEOUT_SECS: u64 = 60;
+
+/// Describes a whole catalog file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CatalogFile {
+    #[serde(default)]
+    failure: Vec<CatalogEntry>,
+}
+
+/// Describes one known failure, as written in a catalog file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CatalogEntry {
+    /// identifies the failure in reports (e.g., a bug id)
+    name: String,
+    /// regular expression matching the failure's signature or output
+    pattern: String,
+    /// links to bug reports or other information about the failure
+    #[serde(default)]
+    links: Vec<String>,
+    /// keep at most this many runs with the failure
+    keep: Option<usize>,
+}
+
+/// A known failure
+#[derive(Clone, Debug)]
+pub struct KnownFailure {
+    /// identifies the failure in reports (e.g., a bug id)
+    pub name: String,
+    /// links to bug reports or other information about the failure
+    pub links: Vec<String>,
+    /// keep at most this many runs with the failure
+    pub keep: Option<usize>,
+}
+
+/// Known failures loaded from one or more catalog files
+#[derive(Default)]
+pub struct Catalog {
+    entries: Vec<(regex::Regex, KnownFailure)>,
+}
+
+impl Catalog {
+    /// Returns whether any entry limits how many runs to keep (with `keep`)
+    pub fn has_keep_limits(&self) -> bool {
+        self.entries.iter().any(|(_, known)| known.keep.is_some())
+    }
+
+    /// Loads catalogs from each of `sources` (local paths, URLs, or files in
+    /// git repositories), in order
+    pub fn load(sources: &[String]) -> Result<Catalog, anyhow::Error> {
+        let mut catalog = Catalog::default();
+        for source in sources {
+            let path = resolve(source)?;
+            catalog
+                .load_file(&path)
+                .with_context(|| format!("load catalog {:?}", source))?;
+        }
+        Ok(catalog)
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<(), anyhow::Error> {
+        let contents = std::fs::read_to_string(path)?;
+        let yaml = path.extension().is_some_and(|e| e == "yaml" || e == "yml");
+        let file: CatalogFile = if yaml {
+            serde_yaml::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        for entry in file.failure {
+            // Patterns are matched against each line of output, so "^" and "$"
+            // should match at line boundaries.
+            let pattern = regex::RegexBuilder::new(&entry.pattern)
+                .multi_line(true)
+                .build()
+                .with_context(|| {
+                    format!("failure {:?}: pattern", entry.name)
+                })?;
+            self.entries.push((
+                pattern,
+                KnownFailure {
+                    name: entry.name,
+                    links: entry.links,
+                    keep: entry.keep,
+                },
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of known failures in the catalog
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the known failure matching a failed run with the given
+    /// signature and output, if any
+    pub fn lookup(
+        &self,
+        signature: &str,
+        stdout: &str,
+        stderr: &str,
+    ) -> Option<&KnownFailure> {
+        self.entries
+            .iter()
+            .find(|(pattern, _)| {
+                [signature, stderr, stdout].iter().any(|s| pattern.is_match(s))
+            })
+            .map(|(_, known)| known)
+    }
+}
+
+/// Describes where a catalog comes from
+enum Source<'a> {
+    /// a local file
+    Path(&'a str),
+    /// a URL
+    Url(&'a str),
+    /// a file (`path`) in a git repository (`repo`)
+    Git { repo: &'a str, path: &'a str },
+}
+
+impl<'a> Source<'a> {
+    fn parse(source: &'a str) -> Result<Source<'a>, anyhow::Error> {
+        if let Some(git) = source.strip_prefix("git+") {
+            let (repo, path) = git.rsplit_once('#').ok_or_else(|| {
+                anyhow!(
+                    "catalog {:?}: expected \"git+REPO#PATH\" (e.g., \
+                     \"git+https://github.com/example/soak.git#catalog.toml\")",
+                    source
+                )
+            })?;
+            // The path is looked up within our clone of the repository, so
+            // it mustn't lead anywhere else.
+            let components = Path::new(path).components().collect::<Vec<_>>();
+            if components.is_empty()
+                || components
+                    .iter()
+                    .any(|c| !matches!(c, std::path::Component::Normal(_)))
+            {
+                return Err(anyhow!(
+                    "catalog {:?}: PATH must be a relative path within the \
+                     repository (without \"..\")",
+                    source
+                ));
+            }
+            Ok(Source::Git { repo, path })
+        } else if source.starts_with("http://")
+            || source.starts_with("https://")
+        {
+            Ok(Source::Url(source))
+        } else {
+            Ok(Source::Path(source))
+        }
+    }
+
+    /// Returns where the local copy of this catalog lives within `cache_dir`
+    fn cached_path(&self, cache_dir: &Path) -> PathBuf {
+        match self {
+            Source::Path(path) => PathBuf::from(path),
+            Source::Git { path, .. } => cache_dir.join("repo").join(path),
+            // Keep the file's extension so that YAML catalogs are still
+            // recognized as such.
+            Source::Url(url) => match Path::new(url).extension() {
+                Some(extension) => {
+                    cache_dir.join("catalog").with_extension(extension)
+                }
+                None => cache_dir.join("catalog"),
+            },
+        }
+    }
+
+    /// Updates the local copy of this catalog within `cache_dir`
+    fn fetch(&self, cache_dir: &Path) -> Result<(), anyhow::Error> {
+        match self {
+            Source::Path(_) => Ok(()),
+            Source::Url(url) => fetch_url(url, &self.cached_path(cache_dir)),
+            Source::Git { repo, .. } => {
+                fetch_git(repo, &cache_dir.join("repo"))
+            }
+        }
+    }
+}
+
+/// Returns the path of a local copy of the catalog `source`, fetching it
+/// first if it's a URL or in a git repository
+fn resolve(source: &str) -> Result<PathBuf, anyhow::Error> {
+    let parsed = Source::parse(source)?;
+    if let Source::Path(path) = parsed {
+        return Ok(PathBuf::from(path));
+    }
+
+    let cache_dir = cache_dir()?.join(cache_key(source));
+    std::fs::create_dir_all(&cache_dir)
+        .with_context(|| format!("mkdir {:?}", cache_dir))?;
+    let cached = parsed.cached_path(&cache_dir);
+    match parsed.fetch(&cache_dir) {
+        Ok(()) => Ok(cached),
+        Err(error) if cached.exists() => {
+            eprintln!(
+                "warning: fetch catalog {:?} (using cached copy {:?}): {:#}",
+                source, cached, error
+            );
+            Ok(cached)
+        }
+        Err(error) => Err(error.context(format!("fetch catalog {:?}", source))),
+    }
+}
+
+/// Returns the directory under which fetched catalogs are cached
+fn cache_dir() -> Result<PathBuf, anyhow::Error> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("LOCALAPPDATA"))
+            .map(|home| PathBuf::from(home).join(".cache"))
+            .ok_or_else(|| {
+                anyhow!("no place to cache catalogs (HOME is not set)")
+            })?,
+    };
+    Ok(base.join("gocrash").join("catalogs"))
+}
+
+/// Returns a directory name for caching `source` that's unique to it and safe
+/// to use in a path
+///
+/// The name starts with a readable version of `source`, but different sources
+/// can look the same that way (e.g., "a/b" and "a_b"), so it ends with a hash
+/// of the whole thing.
+fn cache_key(source: &str) -> String {
+    let readable = source
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '_' })
+        .take(64)
+        .collect::<String>();
+    format!("{}-{}", readable, &hash_bytes(source.as_bytes())[..16])
+}
+
+/// Downloads `url` to `path`
+fn fetch_url(url: &str, path: &Path) -> Result<(), anyhow::Error> {
+    // Download to a temporary file first so that a failed download doesn't
+    // clobber the cached copy.
+    let tmp_path = path.with_extension("tmp");
+    run_command(
+        Command::new("curl")
+            .arg("--fail")
+            .arg("--silent")
+            .arg("--show-error")
+            .arg("--location")
+            .arg("--max-time")
+            .arg(FETCH_TIMEOUT_SECS.to_string())
+            .arg("--output")
+            .arg(&tmp_path)
+            .arg(url),
+    )?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("rename {:?} to {:?}", tmp_path, path))
+}
+
+/// Clones git repository `repo` into `clone_dir`, or updates an existing clone
+fn fetch_git(repo: &str, clone_dir: &Path) -> Result<(), anyhow::Error> {
+    if clone_dir.join(".git").exists() {
+        run_command(
+            Command::new("git")
+                .arg("-C")
+                .arg(clone_dir)
+                .args(["fetch", "--quiet", "--depth", "1", "origin"]),
+        )?;
+        run_command(Command::new("git").arg("-C").arg(clone_dir).args([
+            "reset",
+            "--quiet",
+            "--hard",
+            "FETCH_HEAD",
+        ]))?;
+    } else {
+        run_command(
+            Command::new("git")
+                .args(["clone", "--quiet", "--depth", "1", repo])
+                .arg(clone_dir),
+        )?;
+    }
+    Ok(())
+}
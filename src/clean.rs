@@ -0,0 +1,231 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! `gocrash clean`: destroy working datasets left behind by earlier sessions
+//!
+//! Each session creates a working dataset named `gocrash-<timestamp>` under
+//! the source dataset (or, with the other backends, a directory named
+//! `<source>-gocrash-<timestamp>` next to the source directory), where the
+//! timestamp is when the session started, in milliseconds since the epoch.
+//! Sessions normally leave these behind so that failed runs can be examined,
+//! and sessions that crash or get killed can't clean up at all, so they pile
+//! up.  This command finds them and destroys them.
+//!
+//! Working datasets of sessions that are still running are left alone.  On
+//! Unix systems, we tell by connecting to the session's control socket (see
+//! `control.rs`), which is removed when the session ends (and refuses
+//! connections if the session died without removing it).  Elsewhere, we
+//! can't tell, so `--older-than` is required there.  ZFS datasets are only
+//! destroyed if everything in them was created by the session (see
+//! `zfs_destroy_working_dataset()` in `backend.rs`), and directories only if
+//! they contain the marker that the session left in them (see
+//! `dir_verify_session()`).
+
+use crate::backend;
+use crate::timing;
+use anyhow::anyhow;
+use anyhow::bail;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// Number of threads used to destroy each working dataset's per-run datasets
+const DESTROY_THREADS: u8 = 4;
+
+/// Destroy working datasets left behind by earlier sessions
+#[derive(clap::Args)]
+pub struct CleanArgs {
+    /// how runs' copies of the source tree were made
+    #[arg(long, value_enum, default_value_t)]
+    backend: backend::Backend,
+
+    /// only destroy working datasets for sessions that started at least this
+    /// long ago (e.g., "2d")
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = timing::parse_duration_secs
+    )]
+    older_than: Option<u64>,
+
+    /// list what would be destroyed without destroying anything
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// dataset (or snapshot of it) that the sessions used (with the copy or
+    /// overlay backend, the source directory)
+    source: String,
+}
+
+/// Describes a working dataset left behind by a session
+struct Leftover {
+    /// name of the dataset (or, without ZFS, path of the directory)
+    name: String,
+    /// the session's key (which is also the last component of `name`)
+    session_key: String,
+    /// how long ago the session started
+    age: Duration,
+    /// whether the session is still running
+    live: bool,
+}
+
+/// Runs the `gocrash clean` command
+pub fn clean(args: &CleanArgs) -> Result<(), anyhow::Error> {
+    args.backend.check_supported()?;
+    if !cfg!(unix) && args.older_than.is_none() {
+        return Err(anyhow!(
+            "--older-than is required on this system (gocrash can't tell \
+             whether a session is still running here)"
+        ));
+    }
+    let now = SystemTime::now();
+    let older_than = Duration::from_secs(args.older_than.unwrap_or(0));
+    let clones = args.backend.clones();
+    let mut leftovers = clones
+        .find_working_datasets(&args.source)?
+        .into_iter()
+        .filter_map(|(name, session_key)| {
+            leftover(clones, name, session_key, now)
+        })
+        .collect::<Vec<_>>();
+    leftovers.sort_by(|a, b| a.name.cmp(&b.name));
+    let (doomed, kept): (Vec<_>, Vec<_>) =
+        leftovers.into_iter().partition(|l| l.age >= older_than && !l.live);
+
+    let nlive = kept.iter().filter(|l| l.live).count();
+    let nrecent = kept.len() - nlive;
+    let mut notes = Vec::new();
+    if nlive > 0 {
+        notes.push(format!("{} still running", nlive));
+    }
+    if nrecent > 0 {
+        notes.push(format!(
+            "{} started less than {} ago",
+            nrecent,
+            timing::format_duration(older_than)
+        ));
+    }
+    println!(
+        "found {} working dataset{} for {:?}{}",
+        doomed.len() + kept.len(),
+        if doomed.len() + kept.len() == 1 { "" } else { "s" },
+        args.source,
+        if notes.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", notes.join(", "))
+        },
+    );
+
+    let mut nfailed = 0;
+    for leftover in &doomed {
+        let age = timing::format_duration(leftover.age);
+        if args.dry_run {
+            println!("would destroy {} (started {} ago)", leftover.name, age);
+            continue;
+        }
+        println!("destroying {} (started {} ago)", leftover.name, age);
+        if let Err(error) = clones.destroy_working_dataset(
+            &leftover.name,
+            &leftover.session_key,
+            DESTROY_THREADS,
+        ) {
+            eprintln!("warning: destroying {}: {:#}", leftover.name, error);
+            nfailed += 1;
+        }
+    }
+
+    if nfailed > 0 {
+        bail!(
+            "failed to destroy {} working dataset{}",
+            nfailed,
+            if nfailed == 1 { "" } else { "s" }
+        );
+    }
+    Ok(())
+}
+
+/// Returns a `Leftover` for `name` if `session_key` looks like the key of a
+/// gocrash session
+fn leftover(
+    clones: &dyn backend::CloneBackend,
+    name: String,
+    session_key: String,
+    now: SystemTime,
+) -> Option<Leftover> {
+    let started = session_start(&session_key)?;
+    let live = is_live(clones, &name);
+    Some(Leftover {
+        name,
+        session_key,
+        age: now.duration_since(started).unwrap_or_default(),
+        live,
+    })
+}
+
+/// Returns whether the session that created working dataset `name` is still
+/// running (i.e., whether its control socket accepts connections)
+#[cfg(unix)]
+fn is_live(clones: &dyn backend::CloneBackend, name: &str) -> bool {
+    match clones.mountpoint(name) {
+        Ok(mountpoint) => std::os::unix::net::UnixStream::connect(
+            mountpoint.join(crate::control::SOCKET_NAME),
+        )
+        .is_ok(),
+        Err(error) => {
+            // If we can't find the socket, don't risk it.
+            eprintln!(
+                "warning: {}: checking whether its session is running: {:#}",
+                name, error
+            );
+            true
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn is_live(_clones: &dyn backend::CloneBackend, _name: &str) -> bool {
+    false
+}
+
+/// Returns when the session with key `session_key` started, if it looks like
+/// the key of a gocrash session
+pub fn session_start(session_key: &str) -> Option<SystemTime> {
+    let digits = session_key.strip_prefix("gocrash-")?;
+    // Only what sessions create: parse() would also accept "+123".
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let millis = digits.parse::<u64>().ok()?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::session_start;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_session_start() {
+        for (key, expected) in [
+            ("gocrash-0", Some(0)),
+            ("gocrash-1792051154864", Some(1792051154864)),
+            ("gocrash-", None),
+            ("gocrash-+5", None),
+            ("gocrash--5", None),
+            ("gocrash-12x", None),
+            ("gocrash-99999999999999999999", None),
+            ("gocrash-thread-0", None),
+            ("backup", None),
+            ("Gocrash-1", None),
+            ("1792051154864", None),
+        ] {
+            let expected = expected.map(|millis| {
+                SystemTime::UNIX_EPOCH + Duration::from_millis(millis)
+            });
+            assert_eq!(session_start(key), expected, "key {:?}", key);
+        }
+    }
+}
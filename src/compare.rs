@@ -0,0 +1,417 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! `gocrash compare-sessions`: show what's different between two sessions
+//!
+//! "It reproduces on the lab machine but not on mine" usually starts a long
+//! round of comparing notes from memory.  Each session's `session.json`
+//! already records most of what matters: the options it ran with, the host it
+//! ran on (see `fingerprint.rs`), what it tested (the snapshot's name and,
+//! with ZFS, its GUID, which stays the same wherever the snapshot is sent),
+//! the toolchain its runs used (see `provenance.rs`), and how the runs turned
+//! out.  This command reads two of those and lays them side by side.  For the
+//! environment, it shows only what differs (unless `--all` is given); the
+//! results are always shown in full, since that's what's being explained.
+//!
+//! Sessions recorded by older versions of gocrash are missing some of this,
+//! which shows up as "-".
+
+use crate::session;
+use anyhow::Context;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Runs with these results count as failing when computing the failure rate
+/// (as in `gocrash correlate`)
+const FAILING_RESULTS: &[&str] = &["failed", "hung", "runaway"];
+/// Runs with this result count as passing
+const PASSING_RESULT: &str = "passed";
+
+/// Shown for values that one session didn't record
+const MISSING: &str = "-";
+
+/// Show what's different between two sessions
+#[derive(clap::Args)]
+pub struct CompareSessionsArgs {
+    /// show every recorded value, not just the ones that differ
+    #[arg(long)]
+    all: bool,
+
+    /// first session's summary ("session.json"), or the directory containing
+    /// it (e.g., its working dataset's mountpoint)
+    a: PathBuf,
+
+    /// second session's summary, or the directory containing it
+    b: PathBuf,
+}
+
+/// One row of a comparison: a label and each session's value, if it has one
+struct Row {
+    label: String,
+    a: Option<String>,
+    b: Option<String>,
+}
+
+impl Row {
+    fn new(
+        label: impl Into<String>,
+        a: Option<String>,
+        b: Option<String>,
+    ) -> Row {
+        Row { label: label.into(), a, b }
+    }
+
+    fn differs(&self) -> bool {
+        self.a != self.b
+    }
+}
+
+/// Runs the `gocrash compare-sessions` command
+pub fn compare_sessions(
+    args: &CompareSessionsArgs,
+) -> Result<(), anyhow::Error> {
+    let a = load(&args.a)?;
+    let b = load(&args.b)?;
+    for (which, (path, record)) in [("A", &a), ("B", &b)] {
+        println!(
+            "{}: {} (started {}, {}): {}",
+            which,
+            string_at(record, &["session"]).as_deref().unwrap_or(MISSING),
+            string_at(record, &["start_time"]).as_deref().unwrap_or(MISSING),
+            string_at(record, &["outcome"]).as_deref().unwrap_or("unfinished"),
+            path.display()
+        );
+    }
+    let (a, b) = (&a.1, &b.1);
+
+    let mut source = vec![Row::new(
+        "snapshot",
+        string_at(a, &["snapshot"]),
+        string_at(b, &["snapshot"]),
+    )];
+    source.extend(compare_objects(
+        &flatten(a.get("source_identity")),
+        &flatten(b.get("source_identity")),
+    ));
+    let same_snapshot = match (
+        string_at(a, &["source_identity", "guid"]),
+        string_at(b, &["source_identity", "guid"]),
+    ) {
+        (Some(a), Some(b)) if a == b => " (the same snapshot)",
+        (Some(_), Some(_)) => " (different snapshots)",
+        _ => "",
+    };
+    print_section(&format!("source{}", same_snapshot), &source, args.all);
+
+    print_section(
+        "host",
+        &compare_objects(&flatten(a.get("host")), &flatten(b.get("host"))),
+        args.all,
+    );
+    print_section(
+        "toolchain",
+        &compare_objects(
+            &flatten_toolchain(a.get("toolchain")),
+            &flatten_toolchain(b.get("toolchain")),
+        ),
+        args.all,
+    );
+    print_section(
+        "parameters",
+        &compare_objects(
+            &flatten(a.get("parameters")),
+            &flatten(b.get("parameters")),
+        ),
+        args.all,
+    );
+
+    print_section("results", &result_rows(a, b), true);
+    print_section(
+        "failures (runs with each signature)",
+        &failure_rows(a, b),
+        true,
+    );
+    print_section(
+        "timing (median seconds per phase)",
+        &timing_rows(a, b),
+        true,
+    );
+    Ok(())
+}
+
+/// Reads the session summary at `path` (or in the directory `path`)
+fn load(path: &Path) -> Result<(PathBuf, Value), anyhow::Error> {
+    let path = if path.is_dir() {
+        path.join(session::SESSION_JSON)
+    } else {
+        path.to_path_buf()
+    };
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("read {:?}", path))?;
+    let record = serde_json::from_str(&contents)
+        .with_context(|| format!("parse {:?}", path))?;
+    Ok((path, record))
+}
+
+/// Returns the value at `keys` in `record` as a string, if it's there
+fn string_at(record: &Value, keys: &[&str]) -> Option<String> {
+    keys.iter()
+        .try_fold(record, |value, key| value.get(key))
+        .filter(|value| !value.is_null())
+        .map(render)
+}
+
+/// Renders a JSON value for display (strings without quotes)
+fn render(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::from("(none)"),
+        other => other.to_string(),
+    }
+}
+
+/// Returns the fields of a JSON object, rendered for display
+fn flatten(value: Option<&Value>) -> BTreeMap<String, String> {
+    value
+        .and_then(Value::as_object)
+        .map(|object| {
+            object
+                .iter()
+                .map(|(key, value)| (key.clone(), render(value)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the parts of a toolchain description worth comparing: its
+/// version, the hashes of its binaries, and the settings it was built with
+/// (from `go version -m`)
+fn flatten_toolchain(value: Option<&Value>) -> BTreeMap<String, String> {
+    let mut rv = BTreeMap::new();
+    let Some(value) = value.filter(|value| !value.is_null()) else {
+        return rv;
+    };
+    if let Some(version) = value.get("version") {
+        rv.insert(String::from("version"), render(version));
+    }
+    for (path, hash) in flatten(value.get("binaries")) {
+        rv.insert(path, hash);
+    }
+    let buildinfo =
+        value.get("buildinfo").and_then(Value::as_str).unwrap_or("");
+    for line in buildinfo.lines() {
+        // Settings look like "\tbuild\tKEY=VALUE".
+        if let Some((key, value)) = line
+            .trim_start()
+            .strip_prefix("build\t")
+            .and_then(|s| s.split_once('='))
+        {
+            rv.insert(format!("build {}", key), value.to_string());
+        }
+    }
+    rv
+}
+
+/// Returns a row for each key in either `a` or `b`
+fn compare_objects(
+    a: &BTreeMap<String, String>,
+    b: &BTreeMap<String, String>,
+) -> Vec<Row> {
+    a.keys()
+        .chain(b.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|key| {
+            Row::new(key.clone(), a.get(key).cloned(), b.get(key).cloned())
+        })
+        .collect()
+}
+
+/// Returns rows summarizing each session's results
+fn result_rows(a: &Value, b: &Value) -> Vec<Row> {
+    let counts = |record: &Value| -> BTreeMap<String, u64> {
+        record
+            .get("results")
+            .and_then(Value::as_object)
+            .map(|results| {
+                results
+                    .iter()
+                    .filter_map(|(result, n)| {
+                        Some((result.clone(), n.as_u64()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let (a_counts, b_counts) = (counts(a), counts(b));
+    let total = |counts: &BTreeMap<String, u64>| counts.values().sum::<u64>();
+    let rate = |counts: &BTreeMap<String, u64>| {
+        let nfailing = FAILING_RESULTS
+            .iter()
+            .filter_map(|result| counts.get(*result))
+            .sum::<u64>();
+        let npassing = counts.get(PASSING_RESULT).copied().unwrap_or(0);
+        let n = nfailing + npassing;
+        if n == 0 {
+            String::from(MISSING)
+        } else {
+            format!(
+                "{:.1}% ({} of {})",
+                100.0 * nfailing as f64 / n as f64,
+                nfailing,
+                n
+            )
+        }
+    };
+
+    let mut rows = vec![Row::new(
+        "runs",
+        Some(total(&a_counts).to_string()),
+        Some(total(&b_counts).to_string()),
+    )];
+    rows.extend(
+        a_counts
+            .keys()
+            .chain(b_counts.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|result| {
+                Row::new(
+                    format!("  {}", result),
+                    Some(
+                        a_counts.get(result).copied().unwrap_or(0).to_string(),
+                    ),
+                    Some(
+                        b_counts.get(result).copied().unwrap_or(0).to_string(),
+                    ),
+                )
+            }),
+    );
+    rows.push(Row::new(
+        "failure rate",
+        Some(rate(&a_counts)),
+        Some(rate(&b_counts)),
+    ));
+    rows.push(Row::new(
+        "stopped because",
+        string_at(a, &["stop_reason", "condition"]),
+        string_at(b, &["stop_reason", "condition"]),
+    ));
+    rows
+}
+
+/// Returns a row for each failure signature seen in either session, most
+/// common first
+fn failure_rows(a: &Value, b: &Value) -> Vec<Row> {
+    let counts = |record: &Value| -> BTreeMap<String, u64> {
+        record
+            .get("failures")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|failure| {
+                let signature = failure.get("signature")?.as_str()?;
+                let nruns = failure
+                    .get("results")?
+                    .as_object()?
+                    .values()
+                    .filter_map(Value::as_u64)
+                    .sum::<u64>();
+                Some((signature.to_string(), nruns))
+            })
+            .collect()
+    };
+    let (a_counts, b_counts) = (counts(a), counts(b));
+    let mut signatures = a_counts
+        .keys()
+        .chain(b_counts.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|signature| {
+            let a = a_counts.get(signature).copied().unwrap_or(0);
+            let b = b_counts.get(signature).copied().unwrap_or(0);
+            (signature, a, b)
+        })
+        .collect::<Vec<_>>();
+    signatures.sort_by_key(|(_, a, b)| std::cmp::Reverse(a + b));
+    signatures
+        .into_iter()
+        .map(|(signature, a, b)| {
+            Row::new(
+                signature.clone(),
+                Some(a.to_string()),
+                Some(b.to_string()),
+            )
+        })
+        .collect()
+}
+
+/// Returns a row for each phase timed in either session
+fn timing_rows(a: &Value, b: &Value) -> Vec<Row> {
+    let medians = |record: &Value| -> BTreeMap<String, String> {
+        record
+            .get("timings")
+            .and_then(Value::as_object)
+            .map(|timings| {
+                timings
+                    .iter()
+                    .filter_map(|(phase, summary)| {
+                        let p50 = summary.get("p50_secs")?.as_f64()?;
+                        let count = summary.get("count")?.as_u64()?;
+                        Some((
+                            phase.clone(),
+                            format!("{:.1} (n = {})", p50, count),
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    compare_objects(&medians(a), &medians(b))
+}
+
+/// Prints a section of the comparison, leaving out rows whose values are the
+/// same unless `all` is set
+fn print_section(title: &str, rows: &[Row], all: bool) {
+    let shown =
+        rows.iter().filter(|row| all || row.differs()).collect::<Vec<_>>();
+    let nsame = rows.len() - shown.len();
+    println!("\n{}:", title);
+    if rows.is_empty() {
+        println!("    (nothing recorded)");
+        return;
+    }
+    let label_width =
+        shown.iter().map(|row| row.label.len()).max().unwrap_or(0);
+    let a_width = shown
+        .iter()
+        .map(|row| row.a.as_deref().unwrap_or(MISSING).len())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    if !shown.is_empty() {
+        println!("    {:label_width$}  {:a_width$}  B", "", "A");
+    }
+    for row in &shown {
+        println!(
+            "    {:label_width$}  {:a_width$}  {}",
+            row.label,
+            row.a.as_deref().unwrap_or(MISSING),
+            row.b.as_deref().unwrap_or(MISSING),
+        );
+    }
+    if shown.is_empty() {
+        println!("    (all the same)");
+    } else if nsame > 0 {
+        println!(
+            "    ({} other{} the same)",
+            nsame,
+            if nsame == 1 { "" } else { "s" }
+        );
+    }
+}
@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Recording where each of a session's options came from
+//!
+//! A session's options can come from the command line, from a pipeline file
+//! (see `pipeline.rs`), or from gocrash's defaults, and weeks later it's hard
+//! to tell which: was `--concurrency 2` chosen, or just never changed?  And
+//! the defaults themselves change between versions of gocrash.  So besides
+//! each option's value (`parameters` in `session.json`), we record where it
+//! came from (`configuration`), and `--print-config` prints the same thing
+//! when the session starts.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Where an option's value came from
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    /// gocrash's default (or unset, for options without one)
+    #[default]
+    Default,
+    CommandLine,
+    PipelineFile,
+}
+
+impl Source {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Source::Default => "default",
+            Source::CommandLine => "command line",
+            Source::PipelineFile => "pipeline file",
+        }
+    }
+}
+
+/// Where each option that didn't come from the defaults came from, by its
+/// name in `session.json` (e.g., "stop-after")
+#[derive(Clone, Debug, Default)]
+pub struct Sources(BTreeMap<String, Source>);
+
+impl Sources {
+    /// Returns where each option in `matches` came from
+    pub fn from_matches(matches: &clap::ArgMatches) -> Sources {
+        Sources(
+            matches
+                .ids()
+                .filter_map(|id| {
+                    let source = match matches.value_source(id.as_str())? {
+                        clap::parser::ValueSource::DefaultValue => {
+                            return None;
+                        }
+                        _ => Source::CommandLine,
+                    };
+                    Some((id.as_str().replace('_', "-"), source))
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns sources saying that the options named `names` came from
+    /// `source`
+    pub fn from_names<'a>(
+        names: impl IntoIterator<Item = &'a str>,
+        source: Source,
+    ) -> Sources {
+        Sources(
+            names.into_iter().map(|name| (name.to_string(), source)).collect(),
+        )
+    }
+
+    fn get(&self, name: &str) -> Source {
+        self.0.get(name).copied().unwrap_or_default()
+    }
+}
+
+/// One option's effective value and where it came from
+#[derive(Clone, Serialize)]
+pub struct Setting {
+    pub value: serde_json::Value,
+    pub source: Source,
+}
+
+/// Returns the effective value of every option in `parameters` (as recorded
+/// in `session.json`) along with where it came from
+pub fn effective(
+    parameters: &serde_json::Value,
+    sources: &Sources,
+) -> BTreeMap<String, Setting> {
+    parameters
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, value)| {
+            (
+                name.clone(),
+                Setting { value: value.clone(), source: sources.get(name) },
+            )
+        })
+        .collect()
+}
+
+/// Prints the effective configuration, one option per line
+pub fn print(configuration: &BTreeMap<String, Setting>) {
+    let width = configuration.keys().map(|name| name.len()).max().unwrap_or(0);
+    text_println!("effective configuration:");
+    for (name, setting) in configuration {
+        let value = match &setting.value {
+            serde_json::Value::String(s) => s.clone(),
+            serde_json::Value::Null => String::from("(none)"),
+            other => other.to_string(),
+        };
+        text_println!(
+            "    {:width$}  {} ({})",
+            name,
+            value,
+            setting.source.as_str()
+        );
+    }
+}
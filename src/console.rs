@@ -0,0 +1,110 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Console output from many threads
+//!
+//! Anything that worker threads print should go through the macros here.  Each
+//! message is formatted in full first and then written to stdout in one write
+//! with stdout locked, so that messages from different threads never
+//! interleave (even when a message spans multiple lines).
+//!
+//! With `--format json`, stdout is reserved for events (see `events.rs`), so
+//! these messages go to stderr instead.  Anything else that's part of a
+//! session's output should use `text_println!` for the same reason.
+
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+/// Whether stdout is reserved for events
+static EVENTS_ON_STDOUT: AtomicBool = AtomicBool::new(false);
+
+/// Prints a message about one run of the test suite, prefixed on each line
+/// with the current time and which thread and attempt it's about
+macro_rules! run_println {
+    ($thread:expr, $run:expr, $($arg:tt)*) => {
+        $crate::console::write_lines(
+            &format!(
+                "{}: thread {}: attempt {}: ",
+                chrono::Utc::now(),
+                $thread,
+                $run
+            ),
+            &format!($($arg)*),
+        )
+    };
+}
+
+/// Prints a message prefixed on each line with the current time
+macro_rules! ts_println {
+    ($($arg:tt)*) => {
+        $crate::console::write_lines(
+            &format!("{}: ", chrono::Utc::now()),
+            &format!($($arg)*),
+        )
+    };
+}
+
+/// Prints a message like `println!()`, except that it goes to stderr when
+/// stdout is reserved for events
+macro_rules! text_println {
+    () => {
+        $crate::console::write_lines("", "")
+    };
+    ($($arg:tt)*) => {
+        $crate::console::write_lines("", &format!($($arg)*))
+    };
+}
+
+/// Reserves stdout for events (or not), sending other messages to stderr
+pub fn set_events_on_stdout(events: bool) {
+    EVENTS_ON_STDOUT.store(events, Ordering::SeqCst);
+}
+
+/// Returns whether stdout is reserved for events
+pub fn events_on_stdout() -> bool {
+    EVENTS_ON_STDOUT.load(Ordering::SeqCst)
+}
+
+/// Writes one event (already serialized on a single line) to stdout
+pub fn write_event(event: &str) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{}", event);
+    let _ = stdout.flush();
+}
+
+/// Writes each line of `message` to stdout (or stderr, if stdout is reserved
+/// for events), prefixed with `prefix`, as a single write
+pub fn write_lines(prefix: &str, message: &str) {
+    let mut buf = String::with_capacity(message.len() + prefix.len() + 1);
+    let mut lines = message.lines().peekable();
+    if lines.peek().is_none() {
+        buf.push_str(prefix);
+        buf.push('\n');
+    }
+    for line in lines {
+        buf.push_str(prefix);
+        buf.push_str(line);
+        buf.push('\n');
+    }
+
+    // With a dashboard up (see `tui.rs`), messages are shown there instead.
+    #[cfg(feature = "tui")]
+    if crate::tui::capture(&buf) {
+        return;
+    }
+
+    // Like println!(), there's not much to do if we can't write to stdout.
+    // Unlike println!(), we don't panic in that case.
+    if events_on_stdout() {
+        let mut stderr = std::io::stderr().lock();
+        let _ = stderr.write_all(buf.as_bytes());
+        let _ = stderr.flush();
+    } else {
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(buf.as_bytes());
+        let _ = stdout.flush();
+    }
+}
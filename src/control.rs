@@ -0,0 +1,582 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Controlling a running session over a Unix domain socket
+//!
+//! Sessions can go on for days, and killing one just to change how it's
+//! running would lose its progress.  Instead, each session listens on a Unix
+//! domain socket called "control.sock" in its working dataset.  Clients
+//! connect, send commands (one per line), and get back one line for each:
+//!
+//! * `status`: a JSON object describing the session (see `Status`)
+//! * `pause`: stop starting new runs (runs in progress carry on)
+//! * `resume`: start runs again
+//! * `stop`: stop the session once the runs in progress finish
+//! * `set concurrency N`: only run on N threads (at most `--concurrency`)
+//!
+//! Other than `status`, replies start with "ok" or "error:".  Commands are
+//! handled one connection at a time, so clients shouldn't hold connections
+//! open.
+//!
+//! `gocrash status` is one such client: it asks for the status of the session
+//! with a given working dataset and prints it.
+
+#[cfg(unix)]
+use crate::backend;
+#[cfg(unix)]
+use crate::shutdown;
+#[cfg(unix)]
+use crate::timing;
+#[cfg(unix)]
+use anyhow::anyhow;
+#[cfg(unix)]
+use anyhow::bail;
+#[cfg(unix)]
+use serde::Deserialize;
+#[cfg(unix)]
+use serde::Serialize;
+#[cfg(unix)]
+use std::collections::BTreeMap;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Show what a running session is doing
+#[cfg(unix)]
+#[derive(clap::Args)]
+pub struct StatusArgs {
+    /// how runs' copies of the source tree were made
+    #[arg(long, value_enum, default_value_t)]
+    backend: backend::Backend,
+
+    /// print the status as JSON (as sent on the control socket)
+    #[arg(long)]
+    json: bool,
+
+    /// the session's working dataset (with the copy or overlay backend, its
+    /// directory)
+    working_dataset: String,
+}
+
+/// Name of the control socket within the working dataset
+#[cfg(unix)]
+pub const SOCKET_NAME: &str = "control.sock";
+
+/// How often threads waiting to start a run check whether the session is
+/// stopping
+const STOP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the control thread checks for new connections and signals (and
+/// whether the session is over)
+#[cfg(unix)]
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long we wait for a client to send a command before giving up on it
+#[cfg(unix)]
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A command received on the control socket
+#[cfg(unix)]
+#[derive(Debug)]
+pub enum Command {
+    Status,
+    Pause,
+    Resume,
+    Stop,
+    SetConcurrency(u8),
+}
+
+#[cfg(unix)]
+impl std::str::FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["status"] => Ok(Command::Status),
+            ["pause"] => Ok(Command::Pause),
+            ["resume"] => Ok(Command::Resume),
+            ["stop"] => Ok(Command::Stop),
+            ["set", "concurrency", n] => {
+                let n = n.parse().map_err(|_| {
+                    anyhow!("concurrency {:?}: expected a number of threads", n)
+                })?;
+                Ok(Command::SetConcurrency(n))
+            }
+            _ => Err(anyhow!(
+                "unknown command {:?}: expected \"status\", \"pause\", \
+                 \"resume\", \"stop\", or \"set concurrency N\"",
+                s.trim()
+            )),
+        }
+    }
+}
+
+/// Describes a session (the reply to `status`)
+#[cfg(unix)]
+#[derive(Deserialize, Serialize)]
+pub struct Status {
+    pub session: String,
+    pub working_dataset: String,
+    pub elapsed_secs: u64,
+    pub paused: bool,
+    /// number of threads currently allowed to start runs
+    pub concurrency: u8,
+    /// number of threads that the session started with
+    pub max_concurrency: u8,
+    /// number of runs with each result so far
+    pub results: BTreeMap<String, usize>,
+    /// number of failed runs with each signature so far
+    pub failures: BTreeMap<String, usize>,
+    /// what each thread is doing
+    pub threads: Vec<ThreadStatus>,
+    /// why the session is stopping, if it is
+    pub stopping: Option<String>,
+}
+
+/// Describes what one thread is doing (see `Status`)
+#[cfg(unix)]
+#[derive(Deserialize, Serialize)]
+pub struct ThreadStatus {
+    pub thread: u8,
+    /// see `Phase::as_str()`
+    pub phase: String,
+    /// the run that the thread is on (or last did)
+    pub run_id: Option<usize>,
+    pub attempt: usize,
+    /// how long the thread has been in this phase
+    pub phase_secs: u64,
+}
+
+/// What a thread is doing
+#[derive(Clone, Copy)]
+pub enum Phase {
+    /// between runs
+    Idle,
+    /// cloning the source and setting up the clone
+    Clone,
+    /// running the test suite
+    Test,
+    /// saving or destroying the clone
+    Cleanup,
+    /// the thread has finished
+    Done,
+}
+
+impl Phase {
+    #[cfg_attr(not(any(unix, feature = "tui")), allow(dead_code))]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Idle => "idle",
+            Phase::Clone => "clone",
+            Phase::Test => "test",
+            Phase::Cleanup => "cleanup",
+            Phase::Done => "done",
+        }
+    }
+}
+
+/// Decides when each thread may start a run, according to `pause`, `resume`,
+/// and `set concurrency`, and keeps track of what each one is doing
+pub struct Control {
+    /// number of threads that the session started with
+    #[cfg_attr(not(unix), allow(dead_code))]
+    max_concurrency: u8,
+    state: Mutex<ControlState>,
+    changed: Condvar,
+}
+
+struct ControlState {
+    /// when we paused, if we're paused
+    paused_since: Option<Instant>,
+    /// how long we were paused before that
+    paused_before: Duration,
+    /// threads numbered below this may start runs
+    concurrency: u8,
+    threads: Vec<ThreadState>,
+}
+
+#[cfg_attr(not(unix), allow(dead_code))]
+struct ThreadState {
+    run_id: Option<usize>,
+    attempt: usize,
+    phase: Phase,
+    phase_start: Instant,
+}
+
+impl Control {
+    pub fn new(concurrency: u8) -> Control {
+        let threads = (0..concurrency)
+            .map(|_| ThreadState {
+                run_id: None,
+                attempt: 0,
+                phase: Phase::Idle,
+                phase_start: Instant::now(),
+            })
+            .collect();
+        Control {
+            max_concurrency: concurrency,
+            state: Mutex::new(ControlState {
+                paused_since: None,
+                paused_before: Duration::ZERO,
+                concurrency,
+                threads,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Records that thread `thread` started run `run_id` (its attempt
+    /// `attempt`)
+    pub fn run_started(&self, thread: u8, attempt: usize, run_id: usize) {
+        self.update_thread(thread, |state| {
+            state.run_id = Some(run_id);
+            state.attempt = attempt;
+        });
+        self.set_phase(thread, Phase::Clone);
+    }
+
+    /// Records that thread `thread` has moved on to `phase`
+    pub fn set_phase(&self, thread: u8, phase: Phase) {
+        self.update_thread(thread, |state| {
+            state.phase = phase;
+            state.phase_start = Instant::now();
+        });
+    }
+
+    fn update_thread(&self, thread: u8, f: impl FnOnce(&mut ThreadState)) {
+        if let Some(state) =
+            self.state.lock().unwrap().threads.get_mut(usize::from(thread))
+        {
+            f(state);
+        }
+    }
+
+    /// Waits until thread `which` may start a run
+    ///
+    /// Returns false if `stop()` says that the session is stopping while
+    /// we're waiting.
+    pub fn wait_to_start(&self, which: u8, stop: impl Fn() -> bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while state.paused_since.is_some() || which >= state.concurrency {
+            if stop() {
+                return false;
+            }
+            state =
+                self.changed.wait_timeout(state, STOP_POLL_INTERVAL).unwrap().0;
+        }
+        true
+    }
+
+    /// Returns how long new runs have been held back (by `pause` or SIGUSR1)
+    pub fn paused_time(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        state.paused_before
+            + state.paused_since.map_or(Duration::ZERO, |t| t.elapsed())
+    }
+}
+
+/// Changes made from the control socket (or with SIGUSR1 and SIGUSR2)
+#[cfg(unix)]
+impl Control {
+    pub fn paused(&self) -> bool {
+        self.state.lock().unwrap().paused_since.is_some()
+    }
+
+    pub fn concurrency(&self) -> u8 {
+        self.state.lock().unwrap().concurrency
+    }
+
+    pub fn max_concurrency(&self) -> u8 {
+        self.max_concurrency
+    }
+
+    /// Describes what each thread is doing
+    pub fn threads(&self) -> Vec<ThreadStatus> {
+        self.state
+            .lock()
+            .unwrap()
+            .threads
+            .iter()
+            .enumerate()
+            .map(|(i, state)| ThreadStatus {
+                thread: u8::try_from(i).unwrap(),
+                phase: state.phase.as_str().to_string(),
+                run_id: state.run_id,
+                attempt: state.attempt,
+                phase_secs: state.phase_start.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
+    /// Sets whether new runs are held back, returning whether that's a
+    /// change
+    pub fn set_paused(&self, paused: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match (state.paused_since, paused) {
+            (None, true) => state.paused_since = Some(Instant::now()),
+            (Some(since), false) => {
+                state.paused_before += since.elapsed();
+                state.paused_since = None;
+            }
+            _ => return false,
+        }
+        self.changed.notify_all();
+        true
+    }
+
+    /// Pauses or resumes if SIGUSR1 or SIGUSR2 has arrived since we last
+    /// checked
+    fn check_signals(&self) {
+        match shutdown::take_pause_signal() {
+            Some(true) if self.set_paused(true) => {
+                ts_println!("paused (SIGUSR1): runs in progress will finish");
+            }
+            Some(false) if self.set_paused(false) => {
+                ts_println!("resumed (SIGUSR2)");
+            }
+            _ => (),
+        }
+    }
+
+    /// Sets how many threads may start runs
+    pub fn set_concurrency(&self, n: u8) -> Result<(), anyhow::Error> {
+        if n == 0 {
+            bail!("concurrency must be at least 1 (use \"pause\" instead)");
+        }
+        if n > self.max_concurrency {
+            bail!(
+                "concurrency can be at most {} (the number of threads that \
+                 the session started with)",
+                self.max_concurrency
+            );
+        }
+        self.state.lock().unwrap().concurrency = n;
+        self.changed.notify_all();
+        Ok(())
+    }
+}
+
+/// Listens for control commands on a Unix domain socket
+#[cfg(unix)]
+pub struct ControlSocket {
+    path: std::path::PathBuf,
+    listener: std::os::unix::net::UnixListener,
+}
+
+#[cfg(unix)]
+impl ControlSocket {
+    /// Creates the control socket in directory `dir`
+    pub fn bind(dir: &std::path::Path) -> Result<ControlSocket, anyhow::Error> {
+        use anyhow::Context;
+        let path = dir.join(SOCKET_NAME);
+        let listener = std::os::unix::net::UnixListener::bind(&path)
+            .with_context(|| format!("bind {:?}", path))?;
+        listener
+            .set_nonblocking(true)
+            .with_context(|| format!("set {:?} non-blocking", path))?;
+        Ok(ControlSocket { path, listener })
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// Handles the connections that are waiting to be accepted with
+    /// `handle()`
+    fn accept(
+        &self,
+        handle: impl Fn(Command) -> Result<String, anyhow::Error>,
+    ) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(error) = serve_client(stream, &handle) {
+                        eprintln!("warning: control socket: {:#}", error);
+                    }
+                }
+                Err(error)
+                    if error.kind() == std::io::ErrorKind::WouldBlock =>
+                {
+                    return;
+                }
+                Err(error) => {
+                    eprintln!("warning: control socket: accept: {:#}", error);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Body of the session's control thread: acts on SIGUSR1 and SIGUSR2 and on
+/// commands from `socket` (if we have one) until `done()` returns true
+///
+/// `handle()` carries out a command and returns the reply.
+#[cfg(unix)]
+pub fn serve(
+    control: &Control,
+    socket: Option<&ControlSocket>,
+    done: impl Fn() -> bool,
+    handle: impl Fn(Command) -> Result<String, anyhow::Error>,
+) {
+    while !done() {
+        control.check_signals();
+        if let Some(socket) = socket {
+            socket.accept(&handle);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        // Don't leave the socket behind in working datasets that we keep.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Carries out each command sent on `stream` until the client hangs up
+#[cfg(unix)]
+fn serve_client(
+    stream: std::os::unix::net::UnixStream,
+    handle: impl Fn(Command) -> Result<String, anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    use std::io::BufRead;
+    use std::io::Write;
+
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = &stream;
+    for line in std::io::BufReader::new(&stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = line.parse().and_then(&handle).unwrap_or_else(|error| {
+            format!("error: {:#}", error).replace('\n', " ")
+        });
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
+
+/// Runs the `gocrash status` command
+#[cfg(unix)]
+pub fn status(args: &StatusArgs) -> Result<(), anyhow::Error> {
+    use anyhow::Context;
+
+    args.backend.check_supported()?;
+    let mountpoint = args.backend.clones().mountpoint(&args.working_dataset)?;
+    let reply = send(&mountpoint.join(SOCKET_NAME), "status")?;
+    if args.json {
+        println!("{}", reply);
+        return Ok(());
+    }
+    let status: Status = serde_json::from_str(&reply)
+        .with_context(|| format!("parse status {:?}", reply))?;
+
+    println!(
+        "session:         {} (running for {})",
+        status.session,
+        timing::format_duration(Duration::from_secs(status.elapsed_secs))
+    );
+    println!("working dataset: {}", status.working_dataset);
+    println!(
+        "state:           {}",
+        match &status.stopping {
+            Some(reason) => format!("stopping ({})", reason),
+            None if status.paused => String::from("paused"),
+            None => String::from("running"),
+        }
+    );
+    println!(
+        "concurrency:     {} (of {} thread{})",
+        status.concurrency,
+        status.max_concurrency,
+        if status.max_concurrency == 1 { "" } else { "s" }
+    );
+    let nruns: usize = status.results.values().sum();
+    println!(
+        "runs finished:   {}{}",
+        nruns,
+        if nruns == 0 {
+            String::new()
+        } else {
+            format!(
+                " ({})",
+                status
+                    .results
+                    .iter()
+                    .map(|(result, n)| format!("{} {}", n, result))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+    );
+    let nfailures: usize = status.failures.values().sum();
+    println!(
+        "failures:        {} run{} with {} signature{}",
+        nfailures,
+        if nfailures == 1 { "" } else { "s" },
+        status.failures.len(),
+        if status.failures.len() == 1 { "" } else { "s" }
+    );
+    let mut failures = status.failures.iter().collect::<Vec<_>>();
+    failures.sort_by(|a, b| b.1.cmp(a.1));
+    for (signature, n) in failures {
+        println!("    {:5}  {}", n, signature);
+    }
+
+    println!();
+    println!(
+        "{:>6}  {:7}  {:>5}  {:>7}  {:>10}",
+        "THREAD", "PHASE", "RUN", "ATTEMPT", "FOR"
+    );
+    for thread in &status.threads {
+        println!(
+            "{:>6}  {:7}  {:>5}  {:>7}  {:>10}",
+            thread.thread,
+            thread.phase,
+            thread
+                .run_id
+                .map_or_else(|| String::from("-"), |id| id.to_string()),
+            thread.attempt,
+            timing::format_duration(Duration::from_secs(thread.phase_secs))
+        );
+    }
+    Ok(())
+}
+
+/// Sends `command` to the control socket at `path`, returning the reply
+#[cfg(unix)]
+fn send(
+    path: &std::path::Path,
+    command: &str,
+) -> Result<String, anyhow::Error> {
+    use anyhow::Context;
+    use std::io::BufRead;
+    use std::io::Write;
+
+    let stream =
+        std::os::unix::net::UnixStream::connect(path).with_context(|| {
+            format!("connect to {:?} (is the session still running?)", path)
+        })?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    writeln!(&stream, "{}", command)
+        .with_context(|| format!("send to {:?}", path))?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = String::new();
+    std::io::BufReader::new(&stream)
+        .read_line(&mut reply)
+        .with_context(|| format!("read from {:?}", path))?;
+    let reply = reply.trim_end();
+    if let Some(error) = reply.strip_prefix("error: ") {
+        bail!("{}", error);
+    }
+    Ok(reply.to_string())
+}
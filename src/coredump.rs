@@ -0,0 +1,296 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! `gocrash analyze-core`: produce stack listings from core files
+//!
+//! When a Go program crashes with `GOTRACEBACK=crash`, it may leave a core
+//! file somewhere in the clone.  Making sense of it requires the binary that
+//! dumped it, which is usually a test binary buried somewhere in the build
+//! tree.  This command finds both (in a kept per-run dataset or an archive
+//! directory) and runs a debugger to save a stack listing next to the core.
+
+use crate::run_command;
+use crate::zfs_mountpoint;
+use anyhow::anyhow;
+use anyhow::Context;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+/// Produce stack listings for core files left by a failed run
+#[derive(clap::Args)]
+pub struct AnalyzeCoreArgs {
+    /// debugger used to produce the stack listing
+    #[arg(long, value_enum, default_value_t = Debugger::default_for_host())]
+    debugger: Debugger,
+
+    /// binary that dumped core (by default, this is found by looking for a
+    /// file in the run with the same name as the program that dumped core)
+    #[arg(long, value_name = "PATH")]
+    binary: Option<PathBuf>,
+
+    /// per-run dataset (e.g., "rpool/gocrash-1234/thread-0-run-5") or archive
+    /// directory (see --archive-dir) for the run
+    run: String,
+}
+
+/// Debuggers we know how to drive
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Debugger {
+    Mdb,
+    Gdb,
+    Delve,
+}
+
+impl Debugger {
+    fn default_for_host() -> Debugger {
+        if cfg!(target_os = "illumos") {
+            Debugger::Mdb
+        } else {
+            Debugger::Gdb
+        }
+    }
+
+    /// Returns the command to produce a stack listing for `core`, plus what to
+    /// write to the debugger's stdin
+    fn command(&self, binary: &Path, core: &Path) -> (Command, &'static str) {
+        match self {
+            Debugger::Mdb => {
+                let mut cmd = Command::new("mdb");
+                cmd.arg(binary).arg(core);
+                (cmd, "::status\n::walk thread | ::findstack -v\n")
+            }
+            Debugger::Gdb => {
+                let mut cmd = Command::new("gdb");
+                cmd.args(["-batch", "-nx"])
+                    .args(["-ex", "info threads"])
+                    .args(["-ex", "thread apply all bt"])
+                    .arg(binary)
+                    .arg(core);
+                (cmd, "")
+            }
+            Debugger::Delve => {
+                let mut cmd = Command::new("dlv");
+                cmd.arg("core").arg(binary).arg(core);
+                (cmd, "goroutines -t\nexit\n")
+            }
+        }
+    }
+}
+
+/// Runs the `gocrash analyze-core` command
+pub fn analyze_core(args: &AnalyzeCoreArgs) -> Result<(), anyhow::Error> {
+    let root = if Path::new(&args.run).is_dir() {
+        PathBuf::from(&args.run)
+    } else {
+        zfs_mountpoint(&args.run)?
+    };
+
+    let mut cores = Vec::new();
+    find_files(&root, &mut cores, &|name| {
+        name == "core" || name.starts_with("core.")
+    })?;
+    // Plenty of other files are called "core.something" (e.g., "core.go", or
+    // the listings we wrote ourselves on a previous invocation), so only keep
+    // the ones that are really core files.
+    cores.retain(|path| is_elf_core(path));
+    if cores.is_empty() {
+        return Err(anyhow!("no core files found under {:?}", root));
+    }
+
+    let mut nfailed = 0;
+    for core in &cores {
+        println!("core file: {}", core.display());
+        match analyze_one(args, &root, core) {
+            Ok(listing_path) => {
+                println!("    stacks: {}", listing_path.display())
+            }
+            Err(error) => {
+                println!("    error: {:#}", error);
+                nfailed += 1;
+            }
+        }
+    }
+
+    if nfailed == 0 {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "failed to analyze {} of {} core file{}",
+            nfailed,
+            cores.len(),
+            if cores.len() == 1 { "" } else { "s" }
+        ))
+    }
+}
+
+/// ELF file type for core files (`e_type` in the ELF header)
+const ET_CORE: u16 = 4;
+
+/// Returns whether `path` is an ELF core file
+fn is_elf_core(path: &Path) -> bool {
+    // The ELF header starts with the magic number, then the class, then the
+    // byte order (1 for little-endian, 2 for big-endian), and the file type
+    // is at offset 16.
+    let mut header = [0u8; 18];
+    let Ok(mut file) = std::fs::File::open(path) else {
+        return false;
+    };
+    if std::io::Read::read_exact(&mut file, &mut header).is_err()
+        || &header[0..4] != b"\x7fELF"
+    {
+        return false;
+    }
+    let e_type = [header[16], header[17]];
+    let e_type = match header[5] {
+        1 => u16::from_le_bytes(e_type),
+        2 => u16::from_be_bytes(e_type),
+        _ => return false,
+    };
+    e_type == ET_CORE
+}
+
+/// Produces a stack listing for one core file, returning where it was saved
+fn analyze_one(
+    args: &AnalyzeCoreArgs,
+    root: &Path,
+    core: &Path,
+) -> Result<PathBuf, anyhow::Error> {
+    let binary = match &args.binary {
+        Some(binary) => binary.clone(),
+        None => find_binary(root, core)?,
+    };
+    println!("    binary: {}", binary.display());
+
+    let (mut cmd, input) = args.debugger.command(&binary, core);
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to exec {:?}", cmd.get_program()))?;
+    // Like a human at the debugger's prompt, we don't much care if it exits
+    // without reading all of its input.
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    let output = child.wait_with_output()?;
+
+    let listing_path =
+        PathBuf::from(format!("{}.stacks.txt", core.to_string_lossy()));
+    let listing = format!(
+        "core:     {}\nbinary:   {}\ndebugger: {:?}\nstatus:   {}\n\n{}{}",
+        core.display(),
+        binary.display(),
+        cmd.get_program(),
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr),
+    );
+    std::fs::write(&listing_path, listing)
+        .with_context(|| format!("write {:?}", listing_path))?;
+    if !output.status.success() {
+        eprintln!(
+            "warning: {:?} exited with {}",
+            cmd.get_program(),
+            output.status
+        );
+    }
+    Ok(listing_path)
+}
+
+/// Finds the binary that dumped `core` by asking file(1) what program it was
+/// and then looking for a file with that name under `root`
+fn find_binary(root: &Path, core: &Path) -> Result<PathBuf, anyhow::Error> {
+    let description = run_command(Command::new("file").arg("-b").arg(core))?;
+    let program = program_from_file_output(&description).ok_or_else(|| {
+        anyhow!(
+            "could not tell what program dumped core from file(1) output \
+             {:?} (use --binary)",
+            description.trim()
+        )
+    })?;
+    let name = Path::new(&program)
+        .file_name()
+        .ok_or_else(|| anyhow!("unexpected program name {:?}", program))?
+        .to_string_lossy()
+        .into_owned();
+
+    let mut candidates = Vec::new();
+    find_files(root, &mut candidates, &|n| n == name)?;
+    match candidates.len() {
+        0 => Err(anyhow!(
+            "core was dumped by {:?}, but no file called {:?} was found under \
+             {:?} (use --binary)",
+            program,
+            name,
+            root
+        )),
+        1 => Ok(candidates.pop().unwrap()),
+        _ => {
+            // Prefer a candidate whose path looks like the original.
+            candidates
+                .iter()
+                .find(|c| {
+                    program.ends_with(
+                        &*c.strip_prefix(root).unwrap().to_string_lossy(),
+                    )
+                })
+                .cloned()
+                .ok_or_else(|| {
+                    anyhow!(
+                        "core was dumped by {:?}, but several files with that \
+                         name were found (use --binary): {:?}",
+                        program,
+                        candidates
+                    )
+                })
+        }
+    }
+}
+
+/// Extracts the name of the program that dumped core from file(1) output,
+/// which looks like one of:
+///
+/// ```text
+/// ELF 64-bit LSB core file, x86-64, ..., from './net.test', ...,
+///     execfn: './net.test', platform: 'x86_64'
+/// ELF 64-bit LSB core file AMD64 Version 1, from 'net.test'
+/// ```
+fn program_from_file_output(output: &str) -> Option<String> {
+    for marker in ["execfn: '", "from '"] {
+        if let Some((_, rest)) = output.split_once(marker) {
+            let program = rest.split('\'').next()?;
+            // Linux's "from" field can include arguments.
+            let program = program.split_whitespace().next()?;
+            return Some(program.to_string());
+        }
+    }
+    None
+}
+
+/// Appends to `found` the regular files under `dir` (recursively) whose names
+/// satisfy `matches`
+fn find_files(
+    dir: &Path,
+    found: &mut Vec<PathBuf>,
+    matches: &dyn Fn(&str) -> bool,
+) -> Result<(), anyhow::Error> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("read directory {:?}", dir))?;
+    for entry in entries {
+        let entry =
+            entry.with_context(|| format!("read directory {:?}", dir))?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_files(&entry.path(), found, matches)?;
+        } else if file_type.is_file()
+            && matches(&entry.file_name().to_string_lossy())
+        {
+            found.push(entry.path());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,285 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Rank recorded factors by how much they differ between failing and passing
+//! runs (`gocrash correlate`)
+//!
+//! The history database records various things about the conditions each run
+//! ran under: its run metadata (CPU weight, pressure, etc.), the host and
+//! thread it ran on, and the options its session used that affect the runs
+//! (see `history::CORRELATED_PARAMETERS`).  For each of these, we
+//! compute a score from 0 (failing and passing runs look the same) to 1 (the
+//! factor completely separates failing runs from passing ones):
+//!
+//! * for numeric factors, |2 * AUC - 1|, where AUC is the probability that a
+//!   randomly chosen failing run has a larger value than a randomly chosen
+//!   passing run (so this doesn't depend on the scale of the values)
+//! * for other factors, the total variation distance between the
+//!   distributions of values among failing and passing runs
+//!
+//! This is a starting point for an investigation, not a conclusion: factors
+//! are often related to each other (e.g., pressure and concurrency), and with
+//! few failures, some factor will look significant by chance.
+
+use crate::history;
+use crate::history::RunFactors;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Runs with these results count as failing
+const FAILING_RESULTS: &[&str] = &["failed", "hung", "runaway"];
+/// Runs with this result count as passing (runs with other results, like
+/// "infra-error", are ignored)
+const PASSING_RESULT: &str = "passed";
+
+/// Factors whose values look numeric but are really identifiers
+const CATEGORICAL_FACTORS: &[&str] = &["thread"];
+
+/// Don't score a factor unless it was recorded for at least this many failing
+/// runs and this many passing runs
+const MIN_RUNS_PER_GROUP: usize = 3;
+
+/// Rank recorded factors by how much they differ between failing and passing
+/// runs
+#[derive(clap::Args)]
+pub struct CorrelateArgs {
+    /// only consider runs from this session (may be repeated)
+    #[arg(long)]
+    session: Vec<String>,
+
+    /// only count runs that failed with this signature as failing (runs that
+    /// failed some other way are ignored)
+    #[arg(long)]
+    signature: Option<String>,
+
+    /// show at most this many factors
+    #[arg(long, default_value_t = 20)]
+    top: usize,
+
+    /// history database (see --history-db)
+    history_db: PathBuf,
+}
+
+/// Describes how one factor differs between failing and passing runs
+struct FactorScore {
+    name: String,
+    score: f64,
+    /// summary of the factor's values among failing runs
+    failing: String,
+    /// summary of the factor's values among passing runs
+    passing: String,
+}
+
+/// Runs the `gocrash correlate` command
+pub fn correlate(args: &CorrelateArgs) -> Result<(), anyhow::Error> {
+    let runs = history::load_runs(&args.history_db)?
+        .into_iter()
+        .filter(|run| {
+            args.session.is_empty() || args.session.contains(&run.session)
+        })
+        .collect::<Vec<_>>();
+
+    let is_failing = |run: &RunFactors| {
+        FAILING_RESULTS.contains(&run.result.as_str())
+            && args
+                .signature
+                .as_ref()
+                .is_none_or(|sig| run.signature.as_ref() == Some(sig))
+    };
+    let (failing, passing): (Vec<_>, Vec<_>) = runs
+        .iter()
+        .filter(|run| is_failing(run) || run.result == PASSING_RESULT)
+        .partition(|run| is_failing(run));
+    let nignored = runs.len() - failing.len() - passing.len();
+    println!(
+        "{} failing run{}, {} passing run{} ({} other run{} ignored)",
+        failing.len(),
+        if failing.len() == 1 { "" } else { "s" },
+        passing.len(),
+        if passing.len() == 1 { "" } else { "s" },
+        nignored,
+        if nignored == 1 { "" } else { "s" },
+    );
+    if failing.is_empty() || passing.is_empty() {
+        anyhow::bail!("need both failing and passing runs to compare");
+    }
+
+    let names = runs
+        .iter()
+        .flat_map(|run| run.factors.keys())
+        .collect::<std::collections::BTreeSet<_>>();
+    let mut scores = names
+        .into_iter()
+        .filter_map(|name| {
+            score_factor(
+                name,
+                &factor_values(&failing, name),
+                &factor_values(&passing, name),
+            )
+        })
+        .collect::<Vec<_>>();
+    scores.sort_by(|a, b| {
+        b.score.total_cmp(&a.score).then_with(|| a.name.cmp(&b.name))
+    });
+    scores.truncate(args.top);
+
+    if scores.is_empty() {
+        println!(
+            "no factors were recorded for at least {} failing and {} passing \
+             runs with more than one value",
+            MIN_RUNS_PER_GROUP, MIN_RUNS_PER_GROUP
+        );
+        return Ok(());
+    }
+
+    let width = scores.iter().map(|s| s.name.len()).max().unwrap().max(6);
+    let fwidth = scores.iter().map(|s| s.failing.len()).max().unwrap().max(7);
+    println!(
+        "{:>5}  {:width$}  {:fwidth$}  PASSING",
+        "SCORE",
+        "FACTOR",
+        "FAILING",
+        width = width,
+        fwidth = fwidth
+    );
+    for score in &scores {
+        println!(
+            "{:>5.2}  {:width$}  {:fwidth$}  {}",
+            score.score,
+            score.name,
+            score.failing,
+            score.passing,
+            width = width,
+            fwidth = fwidth
+        );
+    }
+    println!(
+        "(score 0: no difference between failing and passing runs; score 1: \
+         the factor separates them completely)"
+    );
+    Ok(())
+}
+
+/// Returns the values of factor `name` for those of `runs` that recorded it
+fn factor_values<'a>(runs: &[&'a RunFactors], name: &str) -> Vec<&'a str> {
+    runs.iter()
+        .filter_map(|run| run.factors.get(name))
+        .map(String::as_str)
+        .collect()
+}
+
+/// Returns the fraction of `values` that are each distinct value
+fn fractions<'a>(values: &[&'a str]) -> BTreeMap<&'a str, f64> {
+    let mut counts: BTreeMap<&str, f64> = BTreeMap::new();
+    for v in values {
+        *counts.entry(*v).or_insert(0.0) += 1.0;
+    }
+    counts.values_mut().for_each(|c| *c /= values.len() as f64);
+    counts
+}
+
+/// Scores one factor given its values among failing and passing runs,
+/// returning `None` if there's not enough to go on
+fn score_factor(
+    name: &str,
+    failing: &[&str],
+    passing: &[&str],
+) -> Option<FactorScore> {
+    if failing.len() < MIN_RUNS_PER_GROUP || passing.len() < MIN_RUNS_PER_GROUP
+    {
+        return None;
+    }
+    let first = failing[0];
+    if failing.iter().chain(passing).all(|v| *v == first) {
+        return None;
+    }
+
+    let parse = |values: &[&str]| {
+        values.iter().map(|v| v.parse::<f64>().ok()).collect::<Option<Vec<_>>>()
+    };
+    if let (false, Some(failing), Some(passing)) =
+        (CATEGORICAL_FACTORS.contains(&name), parse(failing), parse(passing))
+    {
+        let mean = |values: &[f64]| {
+            format!(
+                "mean {:.2} (n = {})",
+                values.iter().sum::<f64>() / values.len() as f64,
+                values.len()
+            )
+        };
+        return Some(FactorScore {
+            name: name.to_string(),
+            score: (2.0 * auc(&failing, &passing) - 1.0).abs(),
+            failing: mean(&failing),
+            passing: mean(&passing),
+        });
+    }
+
+    // For categorical factors, summarize using the value that's most
+    // over-represented among failing runs.
+    let failing_fractions = fractions(failing);
+    let passing_fractions = fractions(passing);
+    let all_values = failing_fractions
+        .keys()
+        .chain(passing_fractions.keys())
+        .collect::<std::collections::BTreeSet<_>>();
+    let mut distance = 0.0;
+    let mut most_over = ("", f64::NEG_INFINITY);
+    for value in all_values {
+        let f = failing_fractions.get(value).copied().unwrap_or(0.0);
+        let p = passing_fractions.get(value).copied().unwrap_or(0.0);
+        distance += (f - p).abs();
+        if f - p > most_over.1 {
+            most_over = (value, f - p);
+        }
+    }
+
+    let (value, _) = most_over;
+    let describe = |fractions: &BTreeMap<&str, f64>, n: usize| {
+        format!(
+            "{} in {:.0}% (n = {})",
+            value,
+            100.0 * fractions.get(value).copied().unwrap_or(0.0),
+            n
+        )
+    };
+    Some(FactorScore {
+        name: name.to_string(),
+        score: distance / 2.0,
+        failing: describe(&failing_fractions, failing.len()),
+        passing: describe(&passing_fractions, passing.len()),
+    })
+}
+
+/// Returns the probability that a value chosen at random from `a` is larger
+/// than one chosen from `b` (counting ties as half)
+///
+/// This is the Mann-Whitney U statistic divided by `a.len() * b.len()`, which
+/// we compute from the ranks of the values in `a` among all the values.
+fn auc(a: &[f64], b: &[f64]) -> f64 {
+    let mut all = a
+        .iter()
+        .map(|v| (*v, true))
+        .chain(b.iter().map(|v| (*v, false)))
+        .collect::<Vec<_>>();
+    all.sort_by(|x, y| x.0.total_cmp(&y.0));
+
+    // Tied values all get the average of the ranks they span.
+    let mut rank_sum = 0.0;
+    let mut i = 0;
+    while i < all.len() {
+        let mut j = i;
+        while j < all.len() && all[j].0 == all[i].0 {
+            j += 1;
+        }
+        let rank = (i + 1 + j) as f64 / 2.0;
+        rank_sum += rank
+            * all[i..j].iter().filter(|(_, from_a)| *from_a).count() as f64;
+        i = j;
+    }
+
+    let (na, nb) = (a.len() as f64, b.len() as f64);
+    (rank_sum - na * (na + 1.0) / 2.0) / (na * nb)
+}
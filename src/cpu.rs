@@ -0,0 +1,100 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! CPU shares for each worker's runs (`--cpu-weight`)
+//!
+//! Rather than confining each worker to its own CPUs, we give each one a
+//! relative weight.  When the system is busy, CPU time is divided in
+//! proportion to the weights, but a run can use idle CPUs regardless.  On
+//! Linux, each run goes in its own systemd scope with that `CPUWeight`.  On
+//! illumos, each worker's runs go in their own project, whose fair share
+//! scheduler (FSS) shares are set to the weight.
+
+use anyhow::anyhow;
+
+/// Smallest weight accepted by both cgroup `cpu.weight` and FSS
+pub const MIN_WEIGHT: u32 = 1;
+/// Largest weight accepted by cgroup `cpu.weight` (FSS allows more)
+pub const MAX_WEIGHT: u32 = 10000;
+/// Weight for threads that aren't given one when others are (cgroup's default
+/// `cpu.weight`)
+pub const DEFAULT_WEIGHT: u32 = 100;
+
+/// Returns the weight for the given worker thread
+///
+/// Weights are assigned to threads in order, starting over at the beginning
+/// if there are more threads than weights.
+pub fn weight_for_thread(weights: &[u32], which_thread: u8) -> Option<u32> {
+    if weights.is_empty() {
+        None
+    } else {
+        Some(weights[usize::from(which_thread) % weights.len()])
+    }
+}
+
+/// Returns an error if `--cpu-weight` can't be used on this system
+pub fn check_supported() -> Result<(), anyhow::Error> {
+    if cfg!(target_os = "linux") || cfg!(target_os = "illumos") {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "--cpu-weight is only supported on Linux (with systemd) and \
+             illumos"
+        ))
+    }
+}
+
+/// Returns the name of the illumos project used for the given thread's runs
+///
+/// These must be created ahead of time (e.g., `projadd -U $USER
+/// gocrash-thread-0`).
+pub fn project_name(which_thread: u8) -> String {
+    format!("gocrash-thread-{}", which_thread)
+}
+
+/// Returns arguments to prepend to the test suite's command line in order to
+/// run it with the given CPU weight
+pub fn command_prefix(weight: u32, which_thread: u8) -> Vec<String> {
+    if cfg!(target_os = "illumos") {
+        // FSS shares belong to the project, which only exists (as far as
+        // prctl(1) is concerned) once a process is running in it.  So we set
+        // them from inside the new task before starting the test suite.
+        let project = project_name(which_thread);
+        let script = format!(
+            "pfexec prctl -n project.cpu-shares -r -v {} -i project {} && \
+             exec \"$@\"",
+            weight, project
+        );
+        ["newtask", "-p", &project, "bash", "-c", &script, "gocrash-cpu"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    } else {
+        // A scope runs the command directly (rather than asking systemd to
+        // start it), so it remains our child process as usual.
+        // Safety: geteuid() is always safe to call.
+        #[cfg(unix)]
+        let user = unsafe { libc::geteuid() } != 0;
+        #[cfg(not(unix))]
+        let user = false;
+        let mut rv = vec![String::from("systemd-run")];
+        if user {
+            rv.push(String::from("--user"));
+        }
+        rv.extend(
+            [
+                "--scope",
+                "--quiet",
+                "--collect",
+                "-p",
+                &format!("CPUWeight={}", weight),
+                "--",
+            ]
+            .into_iter()
+            .map(String::from),
+        );
+        rv
+    }
+}
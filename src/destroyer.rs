@@ -0,0 +1,110 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Destroying runs' datasets in the background
+//!
+//! Destroying a clone after a run that built the toolchain can take many
+//! seconds, and there's no reason for a worker to wait for that before it
+//! starts its next run.  So datasets that don't need to be kept are handed to
+//! a dedicated thread that destroys them one at a time while the workers move
+//! on.  The queue holds at most one dataset per worker: if destroying falls
+//! that far behind, workers wait for it to catch up rather than letting the
+//! backlog (and the space it's using) grow without bound.
+//!
+//! When the session ends, we stop accepting new datasets, wait for the thread
+//! to get through the ones it already has, and report any that it failed to
+//! destroy.  Those are left behind for `gocrash clean`.
+
+use serde::Serialize;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Mutex;
+
+/// Describes a dataset that we failed to destroy
+#[derive(Clone, Serialize)]
+pub struct DestroyFailure {
+    pub dataset: String,
+    /// first line of the error
+    pub error: String,
+}
+
+pub struct Destroyer {
+    /// where datasets are queued (`None` once we've stopped accepting them)
+    sender: Mutex<Option<mpsc::SyncSender<String>>>,
+    /// where the destroyer thread gets them (taken by `run()`)
+    receiver: Mutex<Option<mpsc::Receiver<String>>>,
+    /// number of datasets queued and not yet destroyed (or failed)
+    npending: AtomicUsize,
+    failures: Mutex<Vec<DestroyFailure>>,
+}
+
+impl Destroyer {
+    /// Returns a destroyer whose queue holds up to `bound` datasets
+    pub fn new(bound: usize) -> Destroyer {
+        let (sender, receiver) = mpsc::sync_channel(bound);
+        Destroyer {
+            sender: Mutex::new(Some(sender)),
+            receiver: Mutex::new(Some(receiver)),
+            npending: AtomicUsize::new(0),
+            failures: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Queues `dataset` to be destroyed, waiting if the queue is full
+    ///
+    /// Returns the dataset back if we've stopped accepting them, in which case
+    /// the caller should destroy it itself.
+    pub fn enqueue(&self, dataset: String) -> Result<(), String> {
+        // Don't hold the lock while we wait for room in the queue, so that
+        // `close()` isn't held up behind us.
+        let Some(sender) = self.sender.lock().unwrap().clone() else {
+            return Err(dataset);
+        };
+        self.npending.fetch_add(1, Ordering::SeqCst);
+        sender.send(dataset).map_err(|error| {
+            self.npending.fetch_sub(1, Ordering::SeqCst);
+            error.0
+        })
+    }
+
+    /// Body of the destroyer thread: destroys each queued dataset with
+    /// `destroy` until `close()` is called and the queue is empty
+    pub fn run(&self, destroy: impl Fn(&str) -> Result<(), anyhow::Error>) {
+        let receiver = self
+            .receiver
+            .lock()
+            .unwrap()
+            .take()
+            .expect("destroyer thread started more than once");
+        for dataset in receiver {
+            if let Err(error) = destroy(&dataset) {
+                let error = crate::first_line_of(&format!("{:#}", error));
+                eprintln!("warning: destroying {}: {}", dataset, error);
+                self.failures
+                    .lock()
+                    .unwrap()
+                    .push(DestroyFailure { dataset, error });
+            }
+            self.npending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Stops accepting datasets, so that `run()` returns once it's destroyed
+    /// the ones already queued
+    pub fn close(&self) {
+        self.sender.lock().unwrap().take();
+    }
+
+    /// Returns how many datasets are waiting to be destroyed
+    pub fn npending(&self) -> usize {
+        self.npending.load(Ordering::SeqCst)
+    }
+
+    /// Returns the datasets that we failed to destroy
+    pub fn failures(&self) -> Vec<DestroyFailure> {
+        self.failures.lock().unwrap().clone()
+    }
+}
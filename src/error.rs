@@ -0,0 +1,175 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Errors reported to the gocrash command's caller
+//!
+//! Internally, errors are `anyhow::Error`s with context added as they go up
+//! the stack, and the few that callers need to tell apart are marked with
+//! their own types (`SetupError`, `SourceGone`, `TestFailed`, and
+//! `shutdown::Shutdown`).  Programs that use gocrash as a library shouldn't
+//! have to dig through that, so `run_with_args()` returns a `GocrashError`
+//! instead.  This is also what decides the `gocrash` command's exit status.
+
+use crate::shutdown::Shutdown;
+use crate::SetupError;
+use crate::SourceGone;
+
+/// Exit status when a session was cut short by a problem outside the test
+/// suite (the source snapshot not existing or disappearing, or running out of
+/// space).  This is distinct from 2, which clap uses for usage errors.
+const EXIT_INFRA_ERROR: i32 = 3;
+
+/// Messages from the operating system or from `zfs` that mean we ran out of
+/// space
+const STORAGE_EXHAUSTED_MESSAGES: &[&str] = &[
+    "out of space",
+    "No space left on device",
+    "Disk quota exceeded",
+    "Disc quota exceeded",
+];
+
+/// Describes why gocrash failed
+#[derive(Debug)]
+pub enum GocrashError {
+    /// the command-line arguments were invalid
+    Usage(clap::Error),
+    /// the source snapshot (or directory) doesn't exist or disappeared during
+    /// the session
+    SnapshotNotFound(String),
+    /// runs couldn't be set up (e.g., the snapshot doesn't contain the test
+    /// suite)
+    ProvisioningFailed(String),
+    /// the test suite failed
+    WorkloadFailed {
+        /// number of failed runs (including those that hung)
+        failures: usize,
+        /// signatures of those failures (see `--signature-profile`)
+        signatures: Vec<String>,
+    },
+    /// the test suite hung (every failed run timed out)
+    Timeout {
+        /// number of runs that hung
+        runs: usize,
+        /// signatures of those runs
+        signatures: Vec<String>,
+    },
+    /// the session was stopped by a signal or by cancelling its
+    /// `CancellationToken`
+    Interrupted(Shutdown),
+    /// we ran out of space for clones (or anything else)
+    StorageExhausted(String),
+    /// anything else
+    Other(String),
+}
+
+impl GocrashError {
+    /// Classifies an error returned by one of our commands
+    pub(crate) fn from_anyhow(error: anyhow::Error) -> GocrashError {
+        let error = match error.downcast::<TestFailed>() {
+            Ok(failed)
+                if failed.hangs > 0 && failed.hangs == failed.failures =>
+            {
+                return GocrashError::Timeout {
+                    runs: failed.hangs,
+                    signatures: failed.signatures,
+                }
+            }
+            Ok(failed) => {
+                return GocrashError::WorkloadFailed {
+                    failures: failed.failures,
+                    signatures: failed.signatures,
+                }
+            }
+            Err(error) => error,
+        };
+        let error = match error.downcast::<Shutdown>() {
+            Ok(shutdown) => return GocrashError::Interrupted(shutdown),
+            Err(error) => error,
+        };
+        let message = format!("{:#}", error);
+        if error.is::<SourceGone>() {
+            GocrashError::SnapshotNotFound(message)
+        } else if error.is::<SetupError>() {
+            GocrashError::ProvisioningFailed(message)
+        } else if storage_exhausted(&error) {
+            GocrashError::StorageExhausted(message)
+        } else {
+            GocrashError::Other(message)
+        }
+    }
+
+    /// Returns the exit status of the `gocrash` command for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // This is what clap uses when it exits by itself.
+            GocrashError::Usage(error) if error.use_stderr() => 2,
+            GocrashError::Usage(_) => 0,
+            GocrashError::Interrupted(Shutdown::Signal(signal)) => 128 + signal,
+            GocrashError::SnapshotNotFound(_)
+            | GocrashError::StorageExhausted(_) => EXIT_INFRA_ERROR,
+            GocrashError::ProvisioningFailed(_)
+            | GocrashError::WorkloadFailed { .. }
+            | GocrashError::Timeout { .. }
+            | GocrashError::Interrupted(Shutdown::Cancelled)
+            | GocrashError::Other(_) => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for GocrashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GocrashError::Usage(error) => write!(f, "{}", error),
+            GocrashError::WorkloadFailed { .. } => f.write_str("test failed"),
+            GocrashError::Timeout { .. } => f.write_str("test hung"),
+            GocrashError::Interrupted(shutdown) => write!(f, "{}", shutdown),
+            GocrashError::SnapshotNotFound(message)
+            | GocrashError::ProvisioningFailed(message)
+            | GocrashError::StorageExhausted(message)
+            | GocrashError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for GocrashError {}
+
+/// Describes a session that ended because the test suite failed (as opposed
+/// to some problem running it)
+#[derive(Debug)]
+pub(crate) struct TestFailed {
+    /// number of failed runs (including those that hung)
+    pub failures: usize,
+    /// number of runs that hung
+    pub hangs: usize,
+    /// signatures of the failures
+    pub signatures: Vec<String>,
+}
+
+impl std::fmt::Display for TestFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("test failed")
+    }
+}
+
+impl std::error::Error for TestFailed {}
+
+/// Returns whether `error` (or anything that led to it) says that we ran out
+/// of space
+pub(crate) fn storage_exhausted(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        let io_full = cause.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::StorageFull
+                    | std::io::ErrorKind::QuotaExceeded
+            )
+        });
+        let message = cause.to_string();
+        io_full
+            || STORAGE_EXHAUSTED_MESSAGES
+                .iter()
+                .any(|pattern| message.contains(pattern))
+    })
+}
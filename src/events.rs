@@ -0,0 +1,175 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Machine-readable events for `--format json`
+//!
+//! With `--format json`, stdout carries one JSON object per line for each of
+//! these events (and nothing else; the usual messages go to stderr instead).
+//! Every event has these fields:
+//!
+//! * `schema-version`: see `SCHEMA_VERSION`
+//! * `time`: when the event happened (RFC 3339)
+//! * `session`: the session's unique name (see `session::SESSION_PROPERTY`)
+//! * `event`: which kind of event this is (see `Event`)
+//!
+//! plus the fields described for each kind of event below.  Every
+//! `run-start` is followed (eventually) by a `run-success` or `run-failure`
+//! for the same `run-id`.  Fields may be added to events, and new kinds of
+//! events may be added, without changing `SCHEMA_VERSION`, so consumers
+//! should ignore what they don't recognize.
+//!
+//! The same events are also sent to clients of `--live-listen` (see
+//! `live.rs`) and `--grpc-listen` (see `grpc.rs`).
+
+use crate::backend;
+use crate::severity;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Version of the event schema (bump this for incompatible changes)
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How to report what's happening on stdout
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    clap::ValueEnum,
+    Deserialize,
+    Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// messages for people to read
+    #[default]
+    Text,
+    /// one JSON object per event (JSON Lines)
+    Json,
+}
+
+/// Something that happened during a session
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    /// the session has set up its working dataset and is about to start runs
+    #[serde(rename_all = "kebab-case")]
+    SessionStart {
+        /// snapshot (or, without ZFS, directory) being tested
+        source: &'a str,
+        working_dataset: &'a str,
+        backend: backend::Backend,
+        concurrency: u8,
+        /// options that the session was run with (as in "session.json")
+        parameters: &'a serde_json::Value,
+    },
+    /// a run is starting
+    #[serde(rename_all = "kebab-case")]
+    RunStart {
+        thread: u8,
+        attempt: usize,
+        run_id: usize,
+        /// per-run dataset (or directory)
+        dataset: &'a str,
+    },
+    /// the test suite passed
+    #[serde(rename_all = "kebab-case")]
+    RunSuccess {
+        thread: u8,
+        attempt: usize,
+        run_id: usize,
+        dataset: &'a str,
+        duration_secs: f64,
+        /// whether the run's dataset was kept
+        kept: bool,
+    },
+    /// the run didn't pass
+    #[serde(rename_all = "kebab-case")]
+    RunFailure {
+        thread: u8,
+        attempt: usize,
+        run_id: usize,
+        dataset: &'a str,
+        duration_secs: f64,
+        /// how the run ended ("failed", "hung", "runaway", "suspect",
+        /// "infra-error", or "interrupted")
+        result: &'a str,
+        /// failure signature (only when the test suite itself failed)
+        signature: Option<&'a str>,
+        severity: Option<severity::Severity>,
+        /// name of the known failure that this one matched, if any
+        known_failure: Option<&'a str>,
+        /// whether the run's dataset was kept
+        kept: bool,
+        /// first line of the error describing what happened
+        error: Option<String>,
+    },
+    /// the session is over
+    #[serde(rename_all = "kebab-case")]
+    SessionEnd {
+        /// "passed", "failed", or "error" (as in "session.json")
+        outcome: Option<&'a str>,
+        /// error that ended the session, if any
+        error: Option<&'a str>,
+        /// which condition stopped the session, and what happened (as
+        /// `stop_reason` in "session.json")
+        stop_condition: Option<&'a str>,
+        stop_detail: Option<&'a str>,
+        /// number of runs with each result
+        results: &'a BTreeMap<String, usize>,
+        /// where the full summary of the session was saved
+        session_json: &'a [PathBuf],
+    },
+}
+
+/// What's actually written for each event
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Envelope<'a> {
+    schema_version: u32,
+    time: String,
+    session: &'a str,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Writes `event` for session `session` to stdout, if we're reporting events
+/// there, and sends it to any clients of `--live-listen` or `--grpc-listen`
+pub fn emit(session: &str, event: &Event) {
+    let on_stdout = crate::console::events_on_stdout();
+    #[cfg(feature = "http")]
+    let on_live = crate::live::has_subscribers();
+    #[cfg(not(feature = "http"))]
+    let on_live = false;
+    #[cfg(all(unix, feature = "http"))]
+    let on_grpc = crate::grpc::has_subscribers();
+    #[cfg(not(all(unix, feature = "http")))]
+    let on_grpc = false;
+    if !on_stdout && !on_live && !on_grpc {
+        return;
+    }
+    let envelope = Envelope {
+        schema_version: SCHEMA_VERSION,
+        time: chrono::Utc::now().to_rfc3339(),
+        session,
+        event,
+    };
+    match serde_json::to_string(&envelope) {
+        Ok(line) => {
+            if on_stdout {
+                crate::console::write_event(&line);
+            }
+            #[cfg(feature = "http")]
+            crate::live::publish(&line);
+            #[cfg(all(unix, feature = "http"))]
+            crate::grpc::publish(&line);
+        }
+        Err(error) => eprintln!("warning: serializing event: {:#}", error),
+    }
+}
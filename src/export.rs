@@ -0,0 +1,227 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Export the history database for offline analysis (`gocrash export`)
+//!
+//! Each table in the history database (sessions, runs, phase timings, run
+//! metadata, and the result of each test in each run) is written to its own
+//! file in the output directory, with the same columns.  Parquet files can be
+//! loaded directly by pandas, polars, DuckDB, Spark, and so on, which is
+//! handier for analysis across many sessions than the SQLite database.
+
+use crate::history;
+use crate::history::ColumnInfo;
+use anyhow::bail;
+use anyhow::Context;
+use parquet::basic::Compression;
+use parquet::basic::LogicalType;
+use parquet::basic::Repetition;
+use parquet::basic::Type as PhysicalType;
+use parquet::data_type::ByteArray;
+use parquet::data_type::ByteArrayType;
+use parquet::data_type::DoubleType;
+use parquet::data_type::Int64Type;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedColumnWriter;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use rusqlite::types::Value;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Maximum number of rows in each Parquet row group
+const ROWS_PER_GROUP: usize = 100_000;
+
+/// Output format for `gocrash export`
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// one Parquet file per table
+    Parquet,
+}
+
+/// Export the history database for offline analysis
+#[derive(clap::Args)]
+pub struct ExportArgs {
+    /// output format
+    #[arg(long, value_enum, default_value_t = ExportFormat::Parquet)]
+    format: ExportFormat,
+
+    /// directory in which to write the output files (created if needed)
+    #[arg(short, long, value_name = "DIR")]
+    output: PathBuf,
+
+    /// history database (see --history-db)
+    history_db: PathBuf,
+}
+
+/// Runs the `gocrash export` command
+pub fn export(args: &ExportArgs) -> Result<(), anyhow::Error> {
+    let (conn, version) = history::open_input(&args.history_db)?;
+    std::fs::create_dir_all(&args.output)
+        .with_context(|| format!("create {:?}", args.output))?;
+    for (table, _) in history::TABLES {
+        let Some((columns, rows)) = history::dump_table(&conn, version, table)?
+        else {
+            println!(
+                "{}: not in this database (it predates that table)",
+                table
+            );
+            continue;
+        };
+        let path = match args.format {
+            ExportFormat::Parquet => {
+                let path = args.output.join(format!("{}.parquet", table));
+                write_parquet(&path, table, &columns, &rows)
+                    .with_context(|| format!("write {:?}", path))?;
+                path
+            }
+        };
+        println!(
+            "wrote {} row{} to {}",
+            rows.len(),
+            if rows.len() == 1 { "" } else { "s" },
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the Parquet schema for one column of a table
+fn parquet_column(column: &ColumnInfo) -> Result<Arc<Type>, anyhow::Error> {
+    let repetition = if column.not_null {
+        Repetition::REQUIRED
+    } else {
+        Repetition::OPTIONAL
+    };
+    let builder = match column.sql_type.to_uppercase().as_str() {
+        "TEXT" => {
+            Type::primitive_type_builder(&column.name, PhysicalType::BYTE_ARRAY)
+                .with_logical_type(Some(LogicalType::String))
+        }
+        "INTEGER" => {
+            Type::primitive_type_builder(&column.name, PhysicalType::INT64)
+        }
+        "REAL" => {
+            Type::primitive_type_builder(&column.name, PhysicalType::DOUBLE)
+        }
+        other => {
+            bail!("column {:?}: unsupported type {:?}", column.name, other)
+        }
+    };
+    Ok(Arc::new(builder.with_repetition(repetition).build()?))
+}
+
+/// Writes the rows of one table to a Parquet file at `path`
+fn write_parquet(
+    path: &Path,
+    table: &str,
+    columns: &[ColumnInfo],
+    rows: &[Vec<Value>],
+) -> Result<(), anyhow::Error> {
+    let fields =
+        columns.iter().map(parquet_column).collect::<Result<Vec<_>, _>>()?;
+    let schema =
+        Arc::new(Type::group_type_builder(table).with_fields(fields).build()?);
+    let props = Arc::new(
+        WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_created_by(format!("gocrash {}", env!("CARGO_PKG_VERSION")))
+            .build(),
+    );
+    let file = std::fs::File::create(path)?;
+    let mut writer = SerializedFileWriter::new(file, schema, props)?;
+
+    for chunk in rows.chunks(ROWS_PER_GROUP) {
+        let mut row_group = writer.next_row_group()?;
+        for (i, column) in columns.iter().enumerate() {
+            let mut column_writer = row_group
+                .next_column()?
+                .expect("schema has a column for each table column");
+            let values = chunk.iter().map(|row| &row[i]);
+            write_column(&mut column_writer, column, values)?;
+            column_writer.close()?;
+        }
+        row_group.close()?;
+    }
+
+    writer.close()?;
+    Ok(())
+}
+
+/// Writes the values of one column in one row group
+fn write_column<'a>(
+    writer: &mut SerializedColumnWriter<'_>,
+    column: &ColumnInfo,
+    values: impl Iterator<Item = &'a Value>,
+) -> Result<(), anyhow::Error> {
+    let sql_type = column.sql_type.to_uppercase();
+    let is_real = sql_type == "REAL";
+    // Definition levels say which values are present (1) and which are null
+    // (0).  Null values are left out of the values themselves.
+    let mut def_levels = Vec::new();
+    let mut texts = Vec::new();
+    let mut ints = Vec::new();
+    let mut reals = Vec::new();
+    for value in values {
+        match value {
+            Value::Null if column.not_null => {
+                bail!("column {:?}: unexpected null", column.name)
+            }
+            Value::Null => {
+                def_levels.push(0);
+                continue;
+            }
+            Value::Text(s) => texts.push(ByteArray::from(s.as_str())),
+            // SQLite stores integral values in REAL columns as integers.
+            Value::Integer(n) if is_real => reals.push(*n as f64),
+            Value::Integer(n) => ints.push(*n),
+            Value::Real(x) => reals.push(*x),
+            Value::Blob(_) => {
+                bail!("column {:?}: unexpected blob", column.name)
+            }
+        }
+        def_levels.push(1);
+    }
+
+    let def_levels =
+        if column.not_null { None } else { Some(def_levels.as_slice()) };
+    match sql_type.as_str() {
+        "TEXT" => {
+            check_only(column, [ints.is_empty(), reals.is_empty()])?;
+            writer
+                .typed::<ByteArrayType>()
+                .write_batch(&texts, def_levels, None)?;
+        }
+        "INTEGER" => {
+            check_only(column, [texts.is_empty(), reals.is_empty()])?;
+            writer.typed::<Int64Type>().write_batch(&ints, def_levels, None)?;
+        }
+        _ => {
+            check_only(column, [texts.is_empty(), ints.is_empty()])?;
+            writer
+                .typed::<DoubleType>()
+                .write_batch(&reals, def_levels, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns an error unless all of `empty` are true (i.e., a column contained
+/// only values of its declared type)
+fn check_only<const N: usize>(
+    column: &ColumnInfo,
+    empty: [bool; N],
+) -> Result<(), anyhow::Error> {
+    if empty.iter().all(|e| *e) {
+        Ok(())
+    } else {
+        bail!(
+            "column {:?}: found values not of declared type {}",
+            column.name,
+            column.sql_type
+        )
+    }
+}
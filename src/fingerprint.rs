@@ -0,0 +1,134 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Recording which host a session ran on
+//!
+//! When a failure reproduces on one machine and not another, the first
+//! question is what's different about them, and by then nobody remembers
+//! exactly what either one was running.  So each session records a
+//! description of its host in `session.json` (along with identifying
+//! properties of the source, for backends that have them), and
+//! `gocrash compare-sessions` lays two sessions' descriptions side by side
+//! (see `compare.rs`).
+
+use anyhow::Context;
+use serde::Serialize;
+
+/// Describes the host that a session ran on
+#[derive(Clone, Serialize)]
+pub struct HostFingerprint {
+    pub hostname: Option<String>,
+    /// operating system name (as from `uname -s`)
+    pub os: String,
+    /// operating system release and version (as from `uname -r` and
+    /// `uname -v`)
+    pub os_release: Option<String>,
+    pub os_version: Option<String>,
+    /// hardware type (as from `uname -m`)
+    pub machine: String,
+    /// number of CPUs available to gocrash
+    pub ncpus: Option<usize>,
+    /// physical memory, in bytes
+    pub memory_bytes: Option<u64>,
+    /// version of gocrash itself
+    pub gocrash_version: String,
+}
+
+impl HostFingerprint {
+    /// Describes this host, leaving out whatever we can't find out
+    pub fn capture() -> HostFingerprint {
+        let uname = uname().ok();
+        HostFingerprint {
+            hostname: hostname().ok(),
+            os: uname.as_ref().map_or_else(
+                || std::env::consts::OS.to_string(),
+                |u| u.sysname.clone(),
+            ),
+            os_release: uname.as_ref().map(|u| u.release.clone()),
+            os_version: uname.as_ref().map(|u| u.version.clone()),
+            machine: uname.as_ref().map_or_else(
+                || std::env::consts::ARCH.to_string(),
+                |u| u.machine.clone(),
+            ),
+            ncpus: std::thread::available_parallelism().ok().map(|n| n.get()),
+            memory_bytes: memory_bytes(),
+            gocrash_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// Returns the name of this host
+#[cfg(unix)]
+pub fn hostname() -> Result<String, anyhow::Error> {
+    let mut buf = [0u8; 256];
+    // Safety: we pass a valid buffer along with its length.
+    let rv = unsafe {
+        libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+    };
+    if rv != 0 {
+        return Err(std::io::Error::last_os_error()).context("gethostname");
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/// Returns the name of this host
+#[cfg(not(unix))]
+pub fn hostname() -> Result<String, anyhow::Error> {
+    std::env::var("COMPUTERNAME").context("read COMPUTERNAME")
+}
+
+/// What uname(2) says about this host
+struct Uname {
+    sysname: String,
+    release: String,
+    version: String,
+    machine: String,
+}
+
+#[cfg(unix)]
+fn uname() -> Result<Uname, anyhow::Error> {
+    // Safety: utsname is plain old data, so all zeroes is a valid value, and
+    // uname(2) fills it in with NUL-terminated strings.
+    let mut utsname: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut utsname) } < 0 {
+        return Err(std::io::Error::last_os_error()).context("uname");
+    }
+    let field = |chars: &[libc::c_char]| {
+        let bytes = chars
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8)
+            .collect::<Vec<_>>();
+        String::from_utf8_lossy(&bytes).into_owned()
+    };
+    Ok(Uname {
+        sysname: field(&utsname.sysname),
+        release: field(&utsname.release),
+        version: field(&utsname.version),
+        machine: field(&utsname.machine),
+    })
+}
+
+#[cfg(not(unix))]
+fn uname() -> Result<Uname, anyhow::Error> {
+    anyhow::bail!("uname is only available on Unix")
+}
+
+#[cfg(unix)]
+fn memory_bytes() -> Option<u64> {
+    // Safety: sysconf(3C) has no preconditions.
+    let (pages, page_size) = unsafe {
+        (libc::sysconf(libc::_SC_PHYS_PAGES), libc::sysconf(libc::_SC_PAGESIZE))
+    };
+    let pages = u64::try_from(pages).ok()?;
+    let page_size = u64::try_from(page_size).ok()?;
+    pages.checked_mul(page_size)
+}
+
+#[cfg(not(unix))]
+fn memory_bytes() -> Option<u64> {
+    None
+}
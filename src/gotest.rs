@@ -0,0 +1,423 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Making sense of `go test` failures
+//!
+//! When a run fails, we pick out which packages and tests `go test` (or `go
+//! tool dist test`, which runs `go test` for each package) reported as
+//! failing and the first panic message, so that people don't have to dig
+//! through the output for them (see `FailureDetails`).
+//!
+//! With `--collect-go-test-artifacts`, we also collect what `go test` leaves
+//! behind after a failure.
+//!
+//! `go test` builds each package's test binary in a temporary "go-build"
+//! directory, which it removes when it's done, whether or not the tests
+//! passed.  We add `-work` to GOFLAGS so that it keeps these directories
+//! instead.  Tests create their own temporary directories, too (with
+//! `t.TempDir()`, named after the test).  The testing package removes these
+//! when the test finishes, even if it failed or panicked, so they're only
+//! left behind when the test binary itself is killed or exits early (as when
+//! `go test -timeout` fires, or a test calls `os.Exit`).  To make sure
+//! everything ends up somewhere we can find it, we point TMPDIR at a
+//! directory in the run's clone.  Then, after a failure, we move the pieces
+//! related to the packages and tests that failed into the run's output
+//! directory:
+//!
+//! * `bin/`: test binaries for the failed packages (needed to make sense of
+//!   core files and stack traces)
+//! * `tmp/`: temporary directories left by the failed tests (if any)
+//!
+//! Everything's moved within the clone, so this takes no extra space.
+
+use anyhow::Context;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Prefixes of lines with the message from a Go panic or fatal error
+const PANIC_PREFIXES: &[&str] = &["panic: ", "fatal error: "];
+
+/// Number of failed tests listed when describing a failure
+const MAX_TESTS_DESCRIBED: usize = 3;
+
+/// Directory (in the clone) used as TMPDIR for the test suite
+pub const TMP_DIR: &str = "gocrash_tmp";
+
+/// Subdirectory of the run's output directory where artifacts are collected
+pub const OUTPUT_SUBDIR: &str = "go-test";
+
+/// Returns the value of GOFLAGS to use for the test suite: whatever's in our
+/// environment, plus `-work` so that `go test` keeps its "go-build"
+/// directories
+pub fn goflags() -> std::ffi::OsString {
+    let mut flags = std::env::var_os("GOFLAGS").unwrap_or_default();
+    if !flags.is_empty() {
+        flags.push(" ");
+    }
+    flags.push("-work");
+    flags
+}
+
+/// What `go test` reported about a failed run
+#[derive(Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FailureDetails {
+    /// packages reported as failing
+    #[serde(rename = "failed-packages")]
+    pub packages: Vec<String>,
+    /// tests (and subtests) reported as failing, in the order they were
+    /// reported
+    #[serde(rename = "failed-tests")]
+    pub tests: Vec<String>,
+    /// the first panic or fatal error message, if any
+    pub panic: Option<String>,
+}
+
+impl FailureDetails {
+    /// Returns whether we found nothing
+    pub fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+            && self.tests.is_empty()
+            && self.panic.is_none()
+    }
+}
+
+impl std::fmt::Display for FailureDetails {
+    /// Describes the failure on one line, like "package runtime: TestFoo,
+    /// TestBar; panic: boom"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if !self.packages.is_empty() {
+            parts.push(format!(
+                "package{} {}",
+                if self.packages.len() == 1 { "" } else { "s" },
+                self.packages.join(", ")
+            ));
+        }
+        if !self.tests.is_empty() {
+            let mut tests = self
+                .tests
+                .iter()
+                .take(MAX_TESTS_DESCRIBED)
+                .cloned()
+                .collect::<Vec<_>>();
+            if self.tests.len() > MAX_TESTS_DESCRIBED {
+                tests.push(format!(
+                    "and {} more",
+                    self.tests.len() - MAX_TESTS_DESCRIBED
+                ));
+            }
+            parts.push(format!(
+                "test{} {}",
+                if self.tests.len() == 1 { "" } else { "s" },
+                tests.join(", ")
+            ));
+        }
+        if let Some(panic) = &self.panic {
+            parts.push(panic.clone());
+        }
+        f.write_str(&parts.join("; "))
+    }
+}
+
+/// Picks out what `go test` reported about a failure from a run's stdout and
+/// stderr
+pub fn failure_details(stdout: &str, stderr: &str) -> FailureDetails {
+    let mut packages = Vec::new();
+    let mut tests = Vec::new();
+    for output in [stdout, stderr] {
+        for line in output.lines() {
+            let line = line.trim_start();
+            let (list, name) = if let Some(rest) = line.strip_prefix("FAIL\t") {
+                (&mut packages, rest.split('\t').next().unwrap_or("").trim())
+            } else if let Some(rest) = line.strip_prefix("--- FAIL: ") {
+                (&mut tests, rest.split_whitespace().next().unwrap_or(""))
+            } else {
+                continue;
+            };
+            if !name.is_empty() && !list.iter().any(|n| n == name) {
+                list.push(name.to_string());
+            }
+        }
+    }
+    let panic = [stderr, stdout].iter().find_map(|output| {
+        output.lines().map(str::trim).find_map(|line| {
+            PANIC_PREFIXES
+                .iter()
+                .any(|prefix| line.starts_with(prefix))
+                .then(|| line.to_string())
+        })
+    });
+    FailureDetails { packages, tests, panic }
+}
+
+/// What `go test` reported about one test (see `test_results()`)
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub struct TestResult {
+    /// name of the test (or subtest, as in "TestFoo/bar")
+    pub test: String,
+    /// "passed", "failed", or "skipped"
+    pub result: &'static str,
+    /// how long the test took, as reported
+    pub duration_secs: Option<f64>,
+}
+
+/// Picks out every test result that `go test` reported in a run's output, in
+/// the order they were reported
+///
+/// Without `-v`, `go test` only reports tests that fail (and tests that skip
+/// when they fail), so passing runs may have no results at all.
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub fn test_results(stdout: &str, stderr: &str) -> Vec<TestResult> {
+    let mut rv = Vec::new();
+    for output in [stdout, stderr] {
+        for line in output.lines() {
+            let line = line.trim_start();
+            let (result, rest) =
+                if let Some(rest) = line.strip_prefix("--- PASS: ") {
+                    ("passed", rest)
+                } else if let Some(rest) = line.strip_prefix("--- FAIL: ") {
+                    ("failed", rest)
+                } else if let Some(rest) = line.strip_prefix("--- SKIP: ") {
+                    ("skipped", rest)
+                } else {
+                    continue;
+                };
+            // e.g., "TestFoo (0.25s)"
+            let mut words = rest.split_whitespace();
+            let Some(test) = words.next() else {
+                continue;
+            };
+            let duration_secs = words.next().and_then(|d| {
+                d.strip_prefix('(')?.strip_suffix("s)")?.parse().ok()
+            });
+            rv.push(TestResult {
+                test: test.to_string(),
+                result,
+                duration_secs,
+            });
+        }
+    }
+    rv
+}
+
+/// Returns the prefix of the directory name that `t.TempDir()` uses for the
+/// given test
+///
+/// Go leaves out characters that don't belong in a file name (notably the
+/// "/" separating subtests).  We only compare a prefix in case the name was
+/// truncated.
+fn temp_dir_prefix(test: &str) -> String {
+    const ALLOWED: &str = "!#$%&()+,-.=@^_{}~ ";
+    test.chars()
+        .filter(|c| {
+            if c.is_ascii() {
+                c.is_ascii_alphanumeric() || ALLOWED.contains(*c)
+            } else {
+                c.is_alphanumeric()
+            }
+        })
+        .take(64)
+        .collect()
+}
+
+/// Describes what was collected
+#[derive(Default)]
+pub struct Collected {
+    pub nbinaries: usize,
+    pub ntempdirs: usize,
+}
+
+/// Moves artifacts for the packages and tests that failed (according to
+/// `stdout` and `stderr`) from `tmp_dir` into `output_dir`
+pub fn collect(
+    tmp_dir: &Path,
+    output_dir: &Path,
+    stdout: &str,
+    stderr: &str,
+) -> Result<Collected, anyhow::Error> {
+    let details = failure_details(stdout, stderr);
+    let binaries = details
+        .packages
+        .iter()
+        .filter_map(|pkg| pkg.rsplit('/').next())
+        .map(|name| format!("{}.test", name))
+        .collect::<BTreeSet<_>>();
+    let tests = details
+        .tests
+        .iter()
+        .map(|test| temp_dir_prefix(test))
+        .collect::<Vec<_>>();
+
+    let mut collected = Collected::default();
+    let entries = std::fs::read_dir(tmp_dir)
+        .with_context(|| format!("read directory {:?}", tmp_dir))?;
+    for entry in entries {
+        let entry =
+            entry.with_context(|| format!("read directory {:?}", tmp_dir))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with("go-build") {
+            let mut found = Vec::new();
+            find_files(&entry.path(), &binaries, &mut found)?;
+            for path in found {
+                // Binaries for the same package from different "go test"
+                // invocations would have the same name, so keep the name of
+                // the directory they came from, too.
+                let target = output_dir
+                    .join("bin")
+                    .join(&name)
+                    .join(path.file_name().expect("found files have names"));
+                move_path(&path, &target)?;
+                collected.nbinaries += 1;
+            }
+        } else if tests.iter().any(|prefix| name.starts_with(prefix.as_str())) {
+            move_path(&entry.path(), &output_dir.join("tmp").join(&name))?;
+            collected.ntempdirs += 1;
+        }
+    }
+    Ok(collected)
+}
+
+/// Finds files under `dir` whose names are in `names`, appending their paths
+/// to `found`
+fn find_files(
+    dir: &Path,
+    names: &BTreeSet<String>,
+    found: &mut Vec<std::path::PathBuf>,
+) -> Result<(), anyhow::Error> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("read directory {:?}", dir))?;
+    for entry in entries {
+        let entry =
+            entry.with_context(|| format!("read directory {:?}", dir))?;
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("stat {:?}", entry.path()))?;
+        if file_type.is_dir() {
+            find_files(&entry.path(), names, found)?;
+        } else if names.contains(entry.file_name().to_string_lossy().as_ref()) {
+            found.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Moves `source` to `target`, creating `target`'s parent directories
+fn move_path(source: &Path, target: &Path) -> Result<(), anyhow::Error> {
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("create {:?}", parent))?;
+    }
+    std::fs::rename(source, target)
+        .with_context(|| format!("rename {:?} to {:?}", source, target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::failure_details;
+    use super::temp_dir_prefix;
+    use super::test_results;
+
+    /// Output of `go test -v` for a package with a failing subtest, a
+    /// skipped test, and a passing test, followed by a package that panics
+    const OUTPUT: &str = "\
+=== RUN   TestFoo
+=== RUN   TestFoo/bar
+    foo_test.go:12: wrong answer
+--- FAIL: TestFoo (0.25s)
+    --- FAIL: TestFoo/bar (0.25s)
+=== RUN   TestSkipped
+    foo_test.go:20: not today
+--- SKIP: TestSkipped (0.00s)
+=== RUN   TestOk
+--- PASS: TestOk (1.50s)
+FAIL
+FAIL\texample.com/foo\t2.003s
+=== RUN   TestPanics
+--- FAIL: TestPanics (0.00s)
+FAIL\texample.com/bar\t0.010s
+FAIL
+";
+
+    #[test]
+    fn test_failure_details() {
+        let stderr = "panic: boom [recovered]\n\tpanic: boom\n\n\
+                      goroutine 6 [running]:\n";
+        let details = failure_details(OUTPUT, stderr);
+        assert_eq!(details.packages, ["example.com/foo", "example.com/bar"]);
+        assert_eq!(details.tests, ["TestFoo", "TestFoo/bar", "TestPanics"]);
+        assert_eq!(details.panic.as_deref(), Some("panic: boom [recovered]"));
+        assert_eq!(
+            details.to_string(),
+            "packages example.com/foo, example.com/bar; tests TestFoo, \
+             TestFoo/bar, TestPanics; panic: boom [recovered]"
+        );
+
+        // Failures reported twice (e.g., in both stdout and stderr) are only
+        // listed once, and long lists are cut short when described.
+        let stdout = "--- FAIL: TestA (0.00s)\n--- FAIL: TestB (0.00s)\n\
+                      --- FAIL: TestC (0.00s)\n--- FAIL: TestD (0.00s)\n\
+                      --- FAIL: TestE (0.00s)\n";
+        let details = failure_details(stdout, "--- FAIL: TestA (0.00s)\n");
+        assert_eq!(
+            details.tests,
+            ["TestA", "TestB", "TestC", "TestD", "TestE"]
+        );
+        assert_eq!(
+            details.to_string(),
+            "tests TestA, TestB, TestC, and 2 more"
+        );
+
+        // A fatal error in stdout counts if stderr has nothing.
+        let details =
+            failure_details("fatal error: all goroutines are asleep", "");
+        assert!(details.packages.is_empty() && details.tests.is_empty());
+        assert_eq!(
+            details.to_string(),
+            "fatal error: all goroutines are asleep"
+        );
+
+        let details = failure_details("ok\texample.com/foo\t0.5s\n", "");
+        assert!(details.is_empty());
+        assert_eq!(details.to_string(), "");
+    }
+
+    #[test]
+    fn test_test_results() {
+        let results = test_results(OUTPUT, "--- FAIL: TestWeird\n--- PASS: \n")
+            .into_iter()
+            .map(|r| (r.test, r.result, r.duration_secs))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                (String::from("TestFoo"), "failed", Some(0.25)),
+                (String::from("TestFoo/bar"), "failed", Some(0.25)),
+                (String::from("TestSkipped"), "skipped", Some(0.0)),
+                (String::from("TestOk"), "passed", Some(1.5)),
+                (String::from("TestPanics"), "failed", Some(0.0)),
+                // no duration
+                (String::from("TestWeird"), "failed", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_temp_dir_prefix() {
+        for (test, expected) in [
+            ("TestFoo", "TestFoo"),
+            ("TestFoo/bar", "TestFoobar"),
+            ("TestFoo/with_spaces_and-dashes", "TestFoowith_spaces_and-dashes"),
+            ("TestFoo/a:b*c?d<e>f|g\"h\\i", "TestFooabcdefghi"),
+            ("TestFoo/#01", "TestFoo#01"),
+            ("TestFoo/héllo→wörld", "TestFoohéllowörld"),
+        ] {
+            assert_eq!(temp_dir_prefix(test), expected, "test {:?}", test);
+        }
+        let long = format!("Test{}", "x".repeat(100));
+        assert_eq!(temp_dir_prefix(&long), long[..64]);
+    }
+}
@@ -0,0 +1,650 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Controlling a session and following its events over gRPC
+//!
+//! Lab orchestration wants to drive sessions from a program elsewhere on the
+//! network, and the control socket (see `control.rs`) is only reachable from
+//! the machine running the session.  With `--grpc-listen`, we serve the
+//! `gocrash.v1.Control` service described in "proto/gocrash.proto": the same
+//! operations as the control socket (status, pause, resume, stop, and set
+//! concurrency), plus a stream of the events described in `events.rs`.
+//!
+//! As with `--live-listen`, there's one server per process, which carries on
+//! from one session to the next in a pipeline.  Commands go to whichever
+//! session is running (see `serve_session()`); between sessions, they fail
+//! with UNAVAILABLE.  Event streams only see what happens after they start.
+//!
+//! The messages are simple enough that we encode and decode them by hand
+//! rather than generating code from the .proto file.  Compressed messages
+//! aren't supported (clients don't compress unless told the server accepts
+//! it).
+
+use crate::control;
+use crate::h2;
+use crate::http;
+use anyhow::bail;
+use anyhow::Context;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Name of the service (as used in request paths)
+const SERVICE: &str = "gocrash.v1.Control";
+
+/// Number of events that can be waiting to go to one client before we give up
+/// on it
+const MAX_QUEUED_EVENTS: usize = 1000;
+
+/// How often event streams with no events check whether the client has gone
+/// away
+const EVENTS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the session checks for commands (and whether it's over)
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long a call waits for the session to carry out its command
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// gRPC status codes that we use
+const STATUS_OK: u32 = 0;
+const STATUS_INVALID_ARGUMENT: u32 = 3;
+const STATUS_RESOURCE_EXHAUSTED: u32 = 8;
+const STATUS_UNIMPLEMENTED: u32 = 12;
+const STATUS_INTERNAL: u32 = 13;
+const STATUS_UNAVAILABLE: u32 = 14;
+const STATUS_UNAUTHENTICATED: u32 = 16;
+
+/// Where `serve_session()` sends each command's reply
+type ReplySender = mpsc::Sender<Result<String, anyhow::Error>>;
+
+/// State shared between sessions and the server
+static HUB: Mutex<Hub> =
+    Mutex::new(Hub { listen: None, session: None, subscribers: Vec::new() });
+
+struct Hub {
+    /// where the server is listening, once it's started
+    listen: Option<SocketAddr>,
+    /// where to send commands for the session that's running, if any
+    session: Option<mpsc::Sender<(control::Command, ReplySender)>>,
+    /// one for each event stream
+    subscribers: Vec<mpsc::SyncSender<String>>,
+}
+
+/// A call that failed, with its gRPC status code
+struct GrpcError {
+    code: u32,
+    message: String,
+}
+
+impl GrpcError {
+    fn new(code: u32, message: impl Into<String>) -> GrpcError {
+        GrpcError { code, message: message.into() }
+    }
+}
+
+/// Starts serving gRPC on `addr` (unless we already are)
+pub fn start(
+    addr: SocketAddr,
+    token_file: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    let mut hub = HUB.lock().unwrap();
+    match hub.listen {
+        Some(listen) if listen == addr => return Ok(()),
+        Some(listen) => bail!("already serving gRPC on {}", listen),
+        None => (),
+    }
+    let token = token_file.map(http::read_token).transpose()?;
+    if token.is_none() && !addr.ip().is_loopback() {
+        eprintln!(
+            "warning: listening on {} without --grpc-token-file: anyone who \
+             can reach this host can control the session",
+            addr
+        );
+    }
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("listen on {}", addr))?;
+    let local_addr = listener.local_addr().unwrap_or(addr);
+    text_println!("serving gRPC at {} (see proto/gocrash.proto)", local_addr);
+    std::thread::spawn(move || {
+        h2::serve(listener, |request, response| {
+            handle(request, response, token.as_deref())
+        })
+    });
+    hub.listen = Some(addr);
+    Ok(())
+}
+
+/// Body of the session thread that carries out commands sent over gRPC (with
+/// `handle()`) until `done()` returns true
+///
+/// Returns right away if we're not serving gRPC.
+pub fn serve_session(
+    done: impl Fn() -> bool,
+    handle: impl Fn(control::Command) -> Result<String, anyhow::Error>,
+) {
+    let (sender, receiver) = mpsc::channel();
+    {
+        let mut hub = HUB.lock().unwrap();
+        if hub.listen.is_none() {
+            return;
+        }
+        hub.session = Some(sender);
+    }
+    while !done() {
+        if let Ok((command, reply)) =
+            receiver.recv_timeout(COMMAND_POLL_INTERVAL)
+        {
+            // The caller may have given up waiting.
+            let _ = reply.send(handle(command));
+        }
+    }
+    HUB.lock().unwrap().session = None;
+}
+
+/// Returns whether any clients are waiting for events
+pub fn has_subscribers() -> bool {
+    !HUB.lock().unwrap().subscribers.is_empty()
+}
+
+/// Sends `event` (already serialized as JSON) to every event stream
+pub fn publish(event: &str) {
+    // Drop clients that have gone away or fallen too far behind.
+    HUB.lock()
+        .unwrap()
+        .subscribers
+        .retain(|subscriber| subscriber.try_send(event.to_string()).is_ok());
+}
+
+fn handle(request: h2::Request, response: &h2::Response, token: Option<&str>) {
+    if let Err(error) = respond(request, response, token) {
+        // Don't complain about clients that have simply gone away.
+        if response.is_open() {
+            eprintln!("warning: gRPC: {:#}", error);
+        }
+    }
+}
+
+fn respond(
+    request: h2::Request,
+    response: &h2::Response,
+    token: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    if request.method != "POST" {
+        return response.headers(&[(":status", "405")], true);
+    }
+    let grpc = request.headers.get("content-type").is_some_and(|t| {
+        t == "application/grpc"
+            || t.starts_with("application/grpc+")
+            || t.starts_with("application/grpc;")
+    });
+    if !grpc {
+        return response.headers(&[(":status", "415")], true);
+    }
+    if token.is_some_and(|t| !http::authorized(&request.headers, t)) {
+        return send_error(
+            response,
+            GrpcError::new(STATUS_UNAUTHENTICATED, "missing or wrong token"),
+        );
+    }
+
+    let Some(method) = request
+        .path
+        .strip_prefix('/')
+        .and_then(|path| path.strip_prefix(SERVICE))
+        .and_then(|path| path.strip_prefix('/'))
+    else {
+        return send_error(
+            response,
+            GrpcError::new(
+                STATUS_UNIMPLEMENTED,
+                format!("no such service: {:?}", request.path),
+            ),
+        );
+    };
+    let message = match request_message(&request.body) {
+        Ok(message) => message,
+        Err(error) => return send_error(response, error),
+    };
+    if method == "Events" {
+        return events(response);
+    }
+    match unary(method, message) {
+        Ok(reply) => {
+            response.headers(
+                &[(":status", "200"), ("content-type", "application/grpc")],
+                false,
+            )?;
+            response.data(&frame_message(&reply))?;
+            response.headers(&[("grpc-status", &STATUS_OK.to_string())], true)
+        }
+        Err(error) => send_error(response, error),
+    }
+}
+
+/// Ends the response with just the status of a call that failed
+fn send_error(
+    response: &h2::Response,
+    error: GrpcError,
+) -> Result<(), anyhow::Error> {
+    let code = error.code.to_string();
+    response.headers(
+        &[
+            (":status", "200"),
+            ("content-type", "application/grpc"),
+            ("grpc-status", &code),
+            ("grpc-message", &percent_encode(&error.message)),
+        ],
+        true,
+    )
+}
+
+/// Carries out a call with a single reply, returning the reply message
+fn unary(method: &str, message: &[u8]) -> Result<Vec<u8>, GrpcError> {
+    let command = match method {
+        "Status" => control::Command::Status,
+        "Pause" => control::Command::Pause,
+        "Resume" => control::Command::Resume,
+        "Stop" => control::Command::Stop,
+        "SetConcurrency" => {
+            let n = decode_uint(message, 1).map_err(|error| {
+                GrpcError::new(STATUS_INVALID_ARGUMENT, format!("{:#}", error))
+            })?;
+            // Let the session explain what's allowed.
+            control::Command::SetConcurrency(u8::try_from(n).unwrap_or(u8::MAX))
+        }
+        _ => {
+            return Err(GrpcError::new(
+                STATUS_UNIMPLEMENTED,
+                format!("no such method: {:?}", method),
+            ));
+        }
+    };
+    let is_status = matches!(command, control::Command::Status);
+    let is_set_concurrency =
+        matches!(command, control::Command::SetConcurrency(_));
+
+    let reply = send_command(command).map_err(|error| {
+        let mut error = GrpcError::from(error);
+        if is_set_concurrency && error.code == STATUS_INTERNAL {
+            error.code = STATUS_INVALID_ARGUMENT;
+        }
+        error
+    })?;
+    if is_status {
+        let status: control::Status =
+            serde_json::from_str(&reply).map_err(|error| {
+                GrpcError::new(
+                    STATUS_INTERNAL,
+                    format!("parse status {:?}: {}", reply, error),
+                )
+            })?;
+        Ok(encode_status(&status))
+    } else {
+        let mut rv = Vec::new();
+        put_string(&mut rv, 1, reply.strip_prefix("ok: ").unwrap_or(&reply));
+        Ok(rv)
+    }
+}
+
+/// Has the session that's running carry out `command`, returning the reply
+fn send_command(command: control::Command) -> Result<String, anyhow::Error> {
+    let unavailable = || {
+        anyhow::Error::new(SessionUnavailable)
+            .context("no session is running (or it's finishing up)")
+    };
+    let Some(session) = HUB.lock().unwrap().session.clone() else {
+        return Err(unavailable());
+    };
+    let (sender, receiver) = mpsc::channel();
+    session.send((command, sender)).map_err(|_| unavailable())?;
+    receiver.recv_timeout(COMMAND_TIMEOUT).map_err(|_| unavailable())?
+}
+
+/// Marks errors from `send_command()` that mean there was no session to carry
+/// out the command
+#[derive(Debug)]
+struct SessionUnavailable;
+
+impl std::fmt::Display for SessionUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("session unavailable")
+    }
+}
+
+impl std::error::Error for SessionUnavailable {}
+
+impl From<anyhow::Error> for GrpcError {
+    fn from(error: anyhow::Error) -> GrpcError {
+        let code = if error.is::<SessionUnavailable>() {
+            STATUS_UNAVAILABLE
+        } else {
+            STATUS_INTERNAL
+        };
+        GrpcError::new(code, format!("{:#}", error))
+    }
+}
+
+/// Sends events as they happen until the client goes away
+fn events(response: &h2::Response) -> Result<(), anyhow::Error> {
+    let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_EVENTS);
+    HUB.lock().unwrap().subscribers.push(sender);
+    response.headers(
+        &[(":status", "200"), ("content-type", "application/grpc")],
+        false,
+    )?;
+    loop {
+        match receiver.recv_timeout(EVENTS_POLL_INTERVAL) {
+            Ok(event) => {
+                response.data(&frame_message(&encode_event(&event)))?
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => response_open(response)?,
+            // We fell too far behind (see `publish()`).
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return response.headers(
+                    &[
+                        ("grpc-status", &STATUS_RESOURCE_EXHAUSTED.to_string()),
+                        (
+                            "grpc-message",
+                            &percent_encode("client fell too far behind"),
+                        ),
+                    ],
+                    true,
+                );
+            }
+        }
+    }
+}
+
+/// Returns an error if the client has gone away
+fn response_open(response: &h2::Response) -> Result<(), anyhow::Error> {
+    if !response.is_open() {
+        bail!("client went away");
+    }
+    Ok(())
+}
+
+/// Returns the one message in a request body
+fn request_message(body: &[u8]) -> Result<&[u8], GrpcError> {
+    let malformed = || {
+        GrpcError::new(
+            STATUS_INVALID_ARGUMENT,
+            "expected exactly one length-prefixed message",
+        )
+    };
+    if body.len() < 5 {
+        return Err(malformed());
+    }
+    if body[0] != 0 {
+        return Err(GrpcError::new(
+            STATUS_UNIMPLEMENTED,
+            "compressed messages are not supported",
+        ));
+    }
+    let length = usize::try_from(u32::from_be_bytes([
+        body[1], body[2], body[3], body[4],
+    ]))
+    .unwrap();
+    if body.len() - 5 != length {
+        return Err(malformed());
+    }
+    Ok(&body[5..])
+}
+
+/// Returns `message` with the prefix that gRPC puts before each message
+fn frame_message(message: &[u8]) -> Vec<u8> {
+    let mut rv = Vec::with_capacity(5 + message.len());
+    rv.push(0);
+    rv.extend_from_slice(&u32::try_from(message.len()).unwrap().to_be_bytes());
+    rv.extend_from_slice(message);
+    rv
+}
+
+/// Percent-encodes `message` for the "grpc-message" trailer
+fn percent_encode(message: &str) -> String {
+    message
+        .bytes()
+        .map(|b| {
+            if (b' '..=b'~').contains(&b) && b != b'%' {
+                char::from(b).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+fn encode_status(status: &control::Status) -> Vec<u8> {
+    let mut rv = Vec::new();
+    put_string(&mut rv, 1, &status.session);
+    put_string(&mut rv, 2, &status.working_dataset);
+    put_uint(&mut rv, 3, status.elapsed_secs);
+    put_uint(&mut rv, 4, u64::from(status.paused));
+    put_uint(&mut rv, 5, u64::from(status.concurrency));
+    put_uint(&mut rv, 6, u64::from(status.max_concurrency));
+    for (field, map) in [(7, &status.results), (8, &status.failures)] {
+        for (key, n) in map {
+            let mut entry = Vec::new();
+            put_string(&mut entry, 1, key);
+            put_uint(&mut entry, 2, *n as u64);
+            put_bytes(&mut rv, field, &entry);
+        }
+    }
+    for thread in &status.threads {
+        let mut entry = Vec::new();
+        put_uint(&mut entry, 1, u64::from(thread.thread));
+        put_string(&mut entry, 2, &thread.phase);
+        if let Some(run_id) = thread.run_id {
+            // This field is optional, so it's sent even when it's zero.
+            put_key(&mut entry, 3, 0);
+            put_varint(&mut entry, run_id as u64);
+        }
+        put_uint(&mut entry, 4, thread.attempt as u64);
+        put_uint(&mut entry, 5, thread.phase_secs);
+        put_bytes(&mut rv, 9, &entry);
+    }
+    put_string(&mut rv, 10, status.stopping.as_deref().unwrap_or(""));
+    rv
+}
+
+/// Encodes an `Event` message from the event as `events::emit()` serializes
+/// it
+fn encode_event(event: &str) -> Vec<u8> {
+    let fields: serde_json::Value =
+        serde_json::from_str(event).unwrap_or_default();
+    let mut rv = Vec::new();
+    put_uint(&mut rv, 1, fields["schema-version"].as_u64().unwrap_or_default());
+    for (field, name) in [(2, "time"), (3, "session"), (4, "event")] {
+        put_string(&mut rv, field, fields[name].as_str().unwrap_or_default());
+    }
+    put_string(&mut rv, 5, event);
+    rv
+}
+
+/// Appends the key for field `field`, with wire type `wire_type`
+fn put_key(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(out, (u64::from(field) << 3) | u64::from(wire_type));
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Appends an integer (or bool) field, unless it has the default value
+fn put_uint(out: &mut Vec<u8>, field: u32, value: u64) {
+    if value != 0 {
+        put_key(out, field, 0);
+        put_varint(out, value);
+    }
+}
+
+/// Appends a string field, unless it has the default value
+fn put_string(out: &mut Vec<u8>, field: u32, value: &str) {
+    if !value.is_empty() {
+        put_bytes(out, field, value.as_bytes());
+    }
+}
+
+/// Appends a length-delimited field (a string or a nested message)
+fn put_bytes(out: &mut Vec<u8>, field: u32, value: &[u8]) {
+    put_key(out, field, 2);
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+fn get_varint(input: &mut &[u8]) -> Result<u64, anyhow::Error> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let Some((byte, rest)) = input.split_first() else {
+            bail!("truncated message");
+        };
+        *input = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("varint too long")
+}
+
+/// Returns the value of integer field `field` in `message` (zero if it's
+/// missing), skipping other fields
+fn decode_uint(mut message: &[u8], field: u64) -> Result<u64, anyhow::Error> {
+    let mut rv = 0;
+    while !message.is_empty() {
+        let key = get_varint(&mut message)?;
+        let skip = match key & 0x7 {
+            0 => {
+                let value = get_varint(&mut message)?;
+                if key >> 3 == field {
+                    rv = value;
+                }
+                0
+            }
+            1 => 8,
+            2 => usize::try_from(get_varint(&mut message)?)?,
+            5 => 4,
+            wire_type => bail!("unsupported wire type {}", wire_type),
+        };
+        if skip > message.len() {
+            bail!("truncated message");
+        }
+        message = &message[skip..];
+    }
+    Ok(rv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_uint;
+    use super::encode_status;
+    use super::get_varint;
+    use super::put_varint;
+    use crate::control;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, 127, 128, 150, 300, u64::from(u32::MAX), u64::MAX] {
+            let mut encoded = Vec::new();
+            put_varint(&mut encoded, value);
+            encoded.push(0xff);
+            let mut input = encoded.as_slice();
+            assert_eq!(get_varint(&mut input).unwrap(), value);
+            // Only the varint itself is consumed.
+            assert_eq!(input, [0xff]);
+        }
+
+        let mut encoded = Vec::new();
+        put_varint(&mut encoded, 300);
+        assert_eq!(encoded, [0xac, 0x02]);
+
+        for (input, expected) in [
+            (&[][..], "truncated message"),
+            (&[0x80][..], "truncated message"),
+            (&[0xff; 10][..], "varint too long"),
+        ] {
+            let error = get_varint(&mut &input[..]).unwrap_err();
+            assert_eq!(format!("{:#}", error), expected, "input {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_decode_uint() {
+        // field 1 = 150, after fields of each other wire type that we skip
+        let message = [
+            0x11, 1, 2, 3, 4, 5, 6, 7, 8, // field 2, fixed64
+            0x1a, 3, b'a', b'b', b'c', // field 3, length-delimited
+            0x25, 1, 2, 3, 4, // field 4, fixed32
+            0x08, 0x96, 0x01, // field 1, varint
+            0x28, 0x05, // field 5, varint
+        ];
+        assert_eq!(decode_uint(&message, 1).unwrap(), 150);
+        assert_eq!(decode_uint(&message, 5).unwrap(), 5);
+        // Missing fields have the default value, as do fields of other types.
+        assert_eq!(decode_uint(&message, 6).unwrap(), 0);
+        assert_eq!(decode_uint(&message, 3).unwrap(), 0);
+        assert_eq!(decode_uint(&[], 1).unwrap(), 0);
+
+        for (message, expected) in [
+            (&[0x0b][..], "unsupported wire type 3"),
+            (&[0x08][..], "truncated message"),
+            (&[0x11, 1, 2, 3][..], "truncated message"),
+            (&[0x1a, 5, b'a'][..], "truncated message"),
+            (&[0x25, 1][..], "truncated message"),
+        ] {
+            let error = decode_uint(message, 1).unwrap_err();
+            assert_eq!(format!("{:#}", error), expected, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn test_encode_status() {
+        let status = control::Status {
+            session: String::from("s"),
+            working_dataset: String::new(),
+            elapsed_secs: 300,
+            paused: true,
+            concurrency: 2,
+            max_concurrency: 2,
+            results: BTreeMap::from([(String::from("ok"), 3)]),
+            failures: BTreeMap::new(),
+            threads: vec![control::ThreadStatus {
+                thread: 1,
+                phase: String::from("idle"),
+                run_id: Some(0),
+                attempt: 0,
+                phase_secs: 0,
+            }],
+            stopping: None,
+        };
+        let encoded = encode_status(&status);
+        #[rustfmt::skip]
+        let expected = [
+            0x0a, 1, b's', // session
+            0x18, 0xac, 0x02, // elapsed_secs
+            0x20, 1, // paused
+            0x28, 2, // concurrency
+            0x30, 2, // max_concurrency
+            // results entry: key "ok", count 3
+            0x3a, 6, 0x0a, 2, b'o', b'k', 0x10, 3,
+            // threads entry: thread 1, phase "idle", run_id 0 (which is sent
+            // even though it's zero)
+            0x4a, 10, 0x08, 1, 0x12, 4, b'i', b'd', b'l', b'e', 0x18, 0,
+        ];
+        assert_eq!(encoded, expected);
+
+        for (field, value) in [(3, 300), (4, 1), (5, 2), (6, 2), (10, 0)] {
+            assert_eq!(decode_uint(&encoded, field).unwrap(), value);
+        }
+    }
+}
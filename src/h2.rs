@@ -0,0 +1,1674 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Minimal HTTP/2 server, for gRPC
+//!
+//! gRPC (see `grpc.rs`) runs over HTTP/2, and as with `http.rs`, pulling in a
+//! whole HTTP stack for a handful of calls isn't worth it.  This handles what
+//! gRPC clients do on cleartext connections (HTTP/2 "with prior knowledge",
+//! as with `grpcurl -plaintext`): each request arrives as HEADERS (and maybe
+//! CONTINUATION) frames followed by DATA frames, and is handled on its own
+//! thread, which sends back headers, data, and trailers for as long as it
+//! likes, subject to the client's flow control.  We don't support upgrading
+//! from HTTP/1.1, TLS, server push, or priorities.  Requests are small, so we
+//! hand flow-control credit back to the client as soon as its data arrives.
+//!
+//! Header blocks are compressed with HPACK (RFC 7541), so we have a decoder
+//! for that, including the dynamic table and Huffman coding.  We send our own
+//! headers as plain literals that don't touch the client's dynamic table.
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// What clients send first (RFC 9113 section 3.4)
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Frame types (RFC 9113 section 6)
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+/// Frame flags (which ones apply depends on the type of frame)
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+/// Settings that we look at or send
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+/// Error codes for RST_STREAM and GOAWAY
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+
+/// Largest frame that we accept (the default, which we don't change) and
+/// that we send until the client says otherwise
+const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+
+/// Flow-control window that each stream and the connection start with
+const DEFAULT_WINDOW: i64 = 65535;
+
+/// Largest flow-control window allowed
+const MAX_WINDOW: i64 = 0x7fff_ffff;
+
+/// Most streams that a client may have open at once
+const MAX_STREAMS: u32 = 100;
+
+/// Most connections that we handle at once (each has its own thread, plus
+/// one for each of its streams)
+const MAX_CONNECTIONS: usize = 32;
+
+/// Reject requests whose headers or body are bigger than this
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// Size of the HPACK dynamic table for headers from the client (the default,
+/// which we don't change)
+const HEADER_TABLE_SIZE: usize = 4096;
+
+/// Close connections that have had no open streams for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often a handler waiting for flow-control credit checks whether the
+/// stream is still open
+const WINDOW_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A request from a client
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// other request headers (with lowercase names, as HTTP/2 requires)
+    pub headers: BTreeMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// Sends the response to one request (see `serve()`)
+pub struct Response<'a> {
+    conn: &'a Shared,
+    stream_id: u32,
+    /// whether we've ended the stream
+    ended: Cell<bool>,
+}
+
+impl Response<'_> {
+    /// Sends `headers` (or trailers, if data has already been sent), ending
+    /// the response if `end_stream` is set
+    pub fn headers(
+        &self,
+        headers: &[(&str, &str)],
+        end_stream: bool,
+    ) -> Result<(), anyhow::Error> {
+        let max_frame_size = self.check_open()?;
+        let block = encode_headers(headers);
+        let mut frames = Vec::new();
+        let mut chunks = block.chunks(max_frame_size).peekable();
+        let mut kind = FRAME_HEADERS;
+        // An empty block still needs a HEADERS frame.
+        let first = chunks.next().unwrap_or(&[]);
+        let mut chunk = first;
+        loop {
+            let last = chunks.peek().is_none();
+            let mut flags = if last { FLAG_END_HEADERS } else { 0 };
+            if kind == FRAME_HEADERS && end_stream {
+                flags |= FLAG_END_STREAM;
+            }
+            frames.extend(frame(kind, flags, self.stream_id, chunk));
+            match chunks.next() {
+                Some(next) => chunk = next,
+                None => break,
+            }
+            kind = FRAME_CONTINUATION;
+        }
+        self.conn.write(&frames)?;
+        if end_stream {
+            self.ended.set(true);
+        }
+        Ok(())
+    }
+
+    /// Sends `data` as part of the response body, waiting for the client to
+    /// allow it (with flow control) as needed
+    pub fn data(&self, mut data: &[u8]) -> Result<(), anyhow::Error> {
+        while !data.is_empty() {
+            let n = {
+                let mut state = self.conn.state.lock().unwrap();
+                loop {
+                    if state.closed {
+                        bail!("connection closed");
+                    }
+                    let Some(stream_window) =
+                        state.streams.get(&self.stream_id).copied()
+                    else {
+                        bail!("stream reset by client");
+                    };
+                    let n = [
+                        data.len() as i64,
+                        state.max_frame_size as i64,
+                        state.window,
+                        stream_window,
+                    ]
+                    .into_iter()
+                    .min()
+                    .unwrap();
+                    if n > 0 {
+                        state.window -= n;
+                        state.streams.insert(self.stream_id, stream_window - n);
+                        break usize::try_from(n).unwrap();
+                    }
+                    state = self
+                        .conn
+                        .changed
+                        .wait_timeout(state, WINDOW_POLL_INTERVAL)
+                        .unwrap()
+                        .0;
+                }
+            };
+            self.conn.write(&frame(
+                FRAME_DATA,
+                0,
+                self.stream_id,
+                &data[..n],
+            ))?;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    /// Returns whether the client can still get what we send (i.e., neither
+    /// the connection nor the stream has been closed)
+    pub fn is_open(&self) -> bool {
+        self.check_open().is_ok()
+    }
+
+    /// Returns an error if the client can't get what we send, and otherwise
+    /// the largest frame that we can send
+    fn check_open(&self) -> Result<usize, anyhow::Error> {
+        let state = self.conn.state.lock().unwrap();
+        if state.closed {
+            bail!("connection closed");
+        }
+        if !state.streams.contains_key(&self.stream_id) {
+            bail!("stream reset by client");
+        }
+        Ok(state.max_frame_size)
+    }
+}
+
+/// State shared by everything using one connection
+struct Shared {
+    writer: Mutex<TcpStream>,
+    state: Mutex<ConnState>,
+    /// signaled when the flow-control windows change or streams close
+    changed: Condvar,
+}
+
+struct ConnState {
+    /// how much data we may send on the connection
+    window: i64,
+    /// window that each new stream starts with
+    initial_window: i64,
+    /// largest frame that we may send
+    max_frame_size: usize,
+    /// how much data we may send on each stream that's being handled
+    streams: BTreeMap<u32, i64>,
+    /// number of handler threads still running (including ones whose
+    /// streams have been reset, which are no longer in `streams`)
+    nhandlers: usize,
+    /// whether the connection is closed
+    closed: bool,
+}
+
+impl Shared {
+    fn new(stream: TcpStream) -> Shared {
+        Shared {
+            writer: Mutex::new(stream),
+            state: Mutex::new(ConnState {
+                window: DEFAULT_WINDOW,
+                initial_window: DEFAULT_WINDOW,
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                streams: BTreeMap::new(),
+                nhandlers: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Writes `bytes` (one or more whole frames)
+    fn write(&self, bytes: &[u8]) -> Result<(), anyhow::Error> {
+        self.writer.lock().unwrap().write_all(bytes).context("write to client")
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+        let _ = self.writer.lock().unwrap().shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Returns a frame with the given type, flags, stream, and payload
+fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let length = u32::try_from(payload.len()).unwrap();
+    let mut rv = Vec::with_capacity(9 + payload.len());
+    rv.extend_from_slice(&length.to_be_bytes()[1..]);
+    rv.push(kind);
+    rv.push(flags);
+    rv.extend_from_slice(&(stream_id & 0x7fff_ffff).to_be_bytes());
+    rv.extend_from_slice(payload);
+    rv
+}
+
+/// Accepts connections forever, handling each request with `handler` (on its
+/// own thread)
+///
+/// `handler` must end each response (see `Response::headers()`), or the
+/// client gets RST_STREAM instead.  Connections beyond the first
+/// `MAX_CONNECTIONS` are closed right away.
+pub fn serve<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(Request, &Response) + Sync,
+{
+    let handler = &handler;
+    let nconnections = &AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    eprintln!("warning: accepting connection: {:#}", error);
+                    continue;
+                }
+            };
+            let peer = stream
+                .peer_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_else(|_| String::from("unknown client"));
+            if nconnections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                nconnections.fetch_sub(1, Ordering::SeqCst);
+                eprintln!(
+                    "warning: HTTP/2 client {}: too many connections",
+                    peer
+                );
+                continue;
+            }
+            scope.spawn(move || {
+                if let Err(error) = serve_connection(stream, handler) {
+                    eprintln!("warning: HTTP/2 client {}: {:#}", peer, error);
+                }
+                nconnections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+}
+
+/// One frame from the client
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+/// Handles the connection `stream` until the client closes it
+fn serve_connection<F>(
+    stream: TcpStream,
+    handler: &F,
+) -> Result<(), anyhow::Error>
+where
+    F: Fn(Request, &Response) + Sync,
+{
+    let mut reader =
+        BufReader::new(stream.try_clone().context("clone connection")?);
+    let conn = Shared::new(stream);
+    reader.get_ref().set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut preface = [0u8; PREFACE.len()];
+    reader.read_exact(&mut preface).context("read connection preface")?;
+    if preface != PREFACE {
+        bail!("expected HTTP/2 (with prior knowledge)");
+    }
+    let mut settings = SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes().to_vec();
+    settings.extend_from_slice(&MAX_STREAMS.to_be_bytes());
+    conn.write(&frame(FRAME_SETTINGS, 0, 0, &settings))?;
+
+    let conn = &conn;
+    std::thread::scope(|scope| {
+        let result = read_frames(&mut reader, conn, |request, stream_id| {
+            scope.spawn(move || {
+                let response =
+                    Response { conn, stream_id, ended: Cell::new(false) };
+                handler(request, &response);
+                if !response.ended.get() && response.is_open() {
+                    let _ = conn.write(&frame(
+                        FRAME_RST_STREAM,
+                        0,
+                        stream_id,
+                        &INTERNAL_ERROR.to_be_bytes(),
+                    ));
+                }
+                {
+                    let mut state = conn.state.lock().unwrap();
+                    state.streams.remove(&stream_id);
+                    state.nhandlers -= 1;
+                }
+                conn.changed.notify_all();
+            });
+        });
+        // Let handlers that are still sending know that nobody's listening.
+        conn.close();
+        result
+    })
+}
+
+/// Reads frames from the client until it closes the connection, passing each
+/// complete request to `dispatch`
+fn read_frames(
+    reader: &mut BufReader<TcpStream>,
+    conn: &Shared,
+    mut dispatch: impl FnMut(Request, u32),
+) -> Result<(), anyhow::Error> {
+    let mut decoder = Decoder::new();
+    // requests whose bodies are still arriving
+    let mut pending: BTreeMap<u32, Request> = BTreeMap::new();
+    let mut last_stream_id = 0;
+    let goaway = |last_stream_id: u32, code: u32, message: &str| {
+        let mut payload = last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(message.as_bytes());
+        let _ = conn.write(&frame(FRAME_GOAWAY, 0, 0, &payload));
+        Err(anyhow!("{}", message))
+    };
+
+    loop {
+        // Don't hold on to connections that aren't being used, but don't give
+        // up on clients that are just waiting for a long response.
+        let idle =
+            pending.is_empty() && conn.state.lock().unwrap().streams.is_empty();
+        reader.get_ref().set_read_timeout(if idle {
+            Some(IDLE_TIMEOUT)
+        } else {
+            None
+        })?;
+        let Some(frame_in) = read_frame(reader)? else {
+            return Ok(());
+        };
+        if frame_in.payload.len() > DEFAULT_MAX_FRAME_SIZE {
+            return goaway(last_stream_id, FRAME_SIZE_ERROR, "frame too big");
+        }
+        let Frame { kind, flags, stream_id, payload } = frame_in;
+        match kind {
+            FRAME_SETTINGS => {
+                if flags & FLAG_ACK != 0 {
+                    continue;
+                }
+                if payload.len() % 6 != 0 {
+                    return goaway(
+                        last_stream_id,
+                        FRAME_SIZE_ERROR,
+                        "bad SETTINGS frame",
+                    );
+                }
+                {
+                    let mut state = conn.state.lock().unwrap();
+                    for setting in payload.chunks(6) {
+                        let id = u16::from_be_bytes([setting[0], setting[1]]);
+                        let value = u32::from_be_bytes([
+                            setting[2], setting[3], setting[4], setting[5],
+                        ]);
+                        match id {
+                            SETTINGS_INITIAL_WINDOW_SIZE => {
+                                let value = i64::from(value);
+                                if value > MAX_WINDOW {
+                                    return goaway(
+                                        last_stream_id,
+                                        FLOW_CONTROL_ERROR,
+                                        "initial window too big",
+                                    );
+                                }
+                                let delta = value - state.initial_window;
+                                state.initial_window = value;
+                                for window in state.streams.values_mut() {
+                                    *window += delta;
+                                }
+                            }
+                            SETTINGS_MAX_FRAME_SIZE => {
+                                let value = usize::try_from(value).unwrap();
+                                if !(DEFAULT_MAX_FRAME_SIZE..=0xff_ffff)
+                                    .contains(&value)
+                                {
+                                    return goaway(
+                                        last_stream_id,
+                                        PROTOCOL_ERROR,
+                                        "bad maximum frame size",
+                                    );
+                                }
+                                state.max_frame_size = value;
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                conn.changed.notify_all();
+                conn.write(&frame(FRAME_SETTINGS, FLAG_ACK, 0, &[]))?;
+            }
+            FRAME_PING => {
+                if payload.len() != 8 {
+                    return goaway(
+                        last_stream_id,
+                        FRAME_SIZE_ERROR,
+                        "bad PING frame",
+                    );
+                }
+                if flags & FLAG_ACK == 0 {
+                    conn.write(&frame(FRAME_PING, FLAG_ACK, 0, &payload))?;
+                }
+            }
+            FRAME_WINDOW_UPDATE => {
+                let Ok(increment) = <[u8; 4]>::try_from(payload.as_slice())
+                else {
+                    return goaway(
+                        last_stream_id,
+                        FRAME_SIZE_ERROR,
+                        "bad WINDOW_UPDATE frame",
+                    );
+                };
+                let increment =
+                    i64::from(u32::from_be_bytes(increment) & 0x7fff_ffff);
+                if stream_id == 0 {
+                    if increment == 0 {
+                        return goaway(
+                            last_stream_id,
+                            PROTOCOL_ERROR,
+                            "zero WINDOW_UPDATE",
+                        );
+                    }
+                    let mut state = conn.state.lock().unwrap();
+                    state.window += increment;
+                    if state.window > MAX_WINDOW {
+                        drop(state);
+                        return goaway(
+                            last_stream_id,
+                            FLOW_CONTROL_ERROR,
+                            "window too big",
+                        );
+                    }
+                } else {
+                    // Errors here only affect the one stream: the handler
+                    // finds it reset, as though the client had reset it.
+                    let mut state = conn.state.lock().unwrap();
+                    let error = if increment == 0 {
+                        Some(PROTOCOL_ERROR)
+                    } else if let Some(window) =
+                        state.streams.get_mut(&stream_id)
+                    {
+                        *window += increment;
+                        (*window > MAX_WINDOW).then_some(FLOW_CONTROL_ERROR)
+                    } else {
+                        None
+                    };
+                    if let Some(code) = error {
+                        state.streams.remove(&stream_id);
+                        drop(state);
+                        pending.remove(&stream_id);
+                        conn.write(&frame(
+                            FRAME_RST_STREAM,
+                            0,
+                            stream_id,
+                            &code.to_be_bytes(),
+                        ))?;
+                    }
+                }
+                conn.changed.notify_all();
+            }
+            FRAME_RST_STREAM => {
+                pending.remove(&stream_id);
+                conn.state.lock().unwrap().streams.remove(&stream_id);
+                conn.changed.notify_all();
+            }
+            FRAME_GOAWAY => return Ok(()),
+            FRAME_HEADERS => {
+                let Some(mut fragment) = strip_padding(flags, &payload) else {
+                    return goaway(
+                        last_stream_id,
+                        PROTOCOL_ERROR,
+                        "bad padding",
+                    );
+                };
+                if flags & FLAG_PRIORITY != 0 {
+                    fragment = fragment.get(5..).unwrap_or(&[]);
+                }
+                let mut block = fragment.to_vec();
+                let mut end_headers = flags & FLAG_END_HEADERS != 0;
+                while !end_headers {
+                    let Some(next) = read_frame(reader)? else {
+                        bail!("connection closed in the middle of headers");
+                    };
+                    if next.kind != FRAME_CONTINUATION
+                        || next.stream_id != stream_id
+                    {
+                        return goaway(
+                            last_stream_id,
+                            PROTOCOL_ERROR,
+                            "expected CONTINUATION",
+                        );
+                    }
+                    block.extend_from_slice(&next.payload);
+                    if block.len() > MAX_REQUEST_BYTES {
+                        return goaway(
+                            last_stream_id,
+                            PROTOCOL_ERROR,
+                            "headers too big",
+                        );
+                    }
+                    end_headers = next.flags & FLAG_END_HEADERS != 0;
+                }
+                // Decode the headers even if we're going to ignore them, so
+                // that our copy of the dynamic table stays in sync.
+                let headers = match decoder.decode(&block) {
+                    Ok(headers) => headers,
+                    Err(error) => {
+                        return goaway(
+                            last_stream_id,
+                            COMPRESSION_ERROR,
+                            &format!("{:#}", error),
+                        );
+                    }
+                };
+                let end_stream = flags & FLAG_END_STREAM != 0;
+
+                // Headers on a stream whose request we're still reading are
+                // trailers, which we don't need.
+                if pending.contains_key(&stream_id) {
+                    if end_stream {
+                        let request = pending.remove(&stream_id).unwrap();
+                        start(conn, &mut dispatch, request, stream_id);
+                    }
+                    continue;
+                }
+                if stream_id % 2 == 0 || stream_id <= last_stream_id {
+                    return goaway(
+                        last_stream_id,
+                        PROTOCOL_ERROR,
+                        "bad stream id",
+                    );
+                }
+                last_stream_id = stream_id;
+                // Streams that the client has reset still count until their
+                // handlers finish, since they still have threads.
+                let nopen =
+                    conn.state.lock().unwrap().nhandlers + pending.len();
+                if nopen >= usize::try_from(MAX_STREAMS).unwrap() {
+                    conn.write(&frame(
+                        FRAME_RST_STREAM,
+                        0,
+                        stream_id,
+                        &REFUSED_STREAM.to_be_bytes(),
+                    ))?;
+                    continue;
+                }
+
+                let mut request = Request {
+                    method: String::new(),
+                    path: String::new(),
+                    headers: BTreeMap::new(),
+                    body: Vec::new(),
+                };
+                for (name, value) in headers {
+                    match name.as_str() {
+                        ":method" => request.method = value,
+                        ":path" => request.path = value,
+                        _ if name.starts_with(':') => (),
+                        _ => match request.headers.get_mut(&name) {
+                            Some(existing) => {
+                                existing.push_str(", ");
+                                existing.push_str(&value);
+                            }
+                            None => {
+                                request.headers.insert(name, value);
+                            }
+                        },
+                    }
+                }
+                if end_stream {
+                    start(conn, &mut dispatch, request, stream_id);
+                } else {
+                    pending.insert(stream_id, request);
+                }
+            }
+            FRAME_DATA => {
+                if stream_id == 0 {
+                    return goaway(
+                        last_stream_id,
+                        PROTOCOL_ERROR,
+                        "DATA on stream 0",
+                    );
+                }
+                let Some(data) = strip_padding(flags, &payload) else {
+                    return goaway(
+                        last_stream_id,
+                        PROTOCOL_ERROR,
+                        "bad padding",
+                    );
+                };
+                let end_stream = flags & FLAG_END_STREAM != 0;
+
+                // Give back the flow-control credit right away (see above).
+                if !payload.is_empty() {
+                    let increment =
+                        u32::try_from(payload.len()).unwrap().to_be_bytes();
+                    let mut frames =
+                        frame(FRAME_WINDOW_UPDATE, 0, 0, &increment);
+                    if !end_stream {
+                        frames.extend(frame(
+                            FRAME_WINDOW_UPDATE,
+                            0,
+                            stream_id,
+                            &increment,
+                        ));
+                    }
+                    conn.write(&frames)?;
+                }
+
+                let Some(request) = pending.get_mut(&stream_id) else {
+                    continue;
+                };
+                if request.body.len() + data.len() > MAX_REQUEST_BYTES {
+                    pending.remove(&stream_id);
+                    conn.write(&frame(
+                        FRAME_RST_STREAM,
+                        0,
+                        stream_id,
+                        &REFUSED_STREAM.to_be_bytes(),
+                    ))?;
+                    continue;
+                }
+                request.body.extend_from_slice(data);
+                if end_stream {
+                    let request = pending.remove(&stream_id).unwrap();
+                    start(conn, &mut dispatch, request, stream_id);
+                }
+            }
+            // PRIORITY frames (and types of frames that we don't know about)
+            // can be ignored.
+            _ => (),
+        }
+    }
+}
+
+/// Starts handling `request` on stream `stream_id`
+fn start(
+    conn: &Shared,
+    dispatch: &mut impl FnMut(Request, u32),
+    request: Request,
+    stream_id: u32,
+) {
+    {
+        let mut state = conn.state.lock().unwrap();
+        let window = state.initial_window;
+        state.streams.insert(stream_id, window);
+        state.nhandlers += 1;
+    }
+    dispatch(request, stream_id);
+}
+
+/// Reads one frame, returning `None` if the client has closed the connection
+fn read_frame(reader: &mut impl Read) -> Result<Option<Frame>, anyhow::Error> {
+    let mut header = [0u8; 9];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Ok(None);
+        }
+        Err(error) => return Err(error).context("read frame"),
+    }
+    let length = usize::try_from(u32::from_be_bytes([
+        0, header[0], header[1], header[2],
+    ]))
+    .unwrap();
+    // Read no more than we'd accept, so that the caller can reject frames
+    // that are too big without our allocating space for them.
+    let mut payload = vec![0; length.min(DEFAULT_MAX_FRAME_SIZE + 1)];
+    reader.read_exact(&mut payload).context("read frame")?;
+    Ok(Some(Frame {
+        kind: header[3],
+        flags: header[4],
+        stream_id: u32::from_be_bytes([
+            header[5], header[6], header[7], header[8],
+        ]) & 0x7fff_ffff,
+        payload,
+    }))
+}
+
+/// Returns the contents of a DATA or HEADERS frame without its padding (or
+/// `None` if the padding is invalid)
+fn strip_padding(flags: u8, payload: &[u8]) -> Option<&[u8]> {
+    if flags & FLAG_PADDED == 0 {
+        return Some(payload);
+    }
+    let (padding, rest) = payload.split_first()?;
+    rest.len().checked_sub(usize::from(*padding)).map(|length| &rest[..length])
+}
+
+/// Encodes headers for sending (see the module comment)
+fn encode_headers(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut rv = Vec::new();
+    for (name, value) in headers {
+        if *name == ":status" && *value == "200" {
+            // indexed header field: entry 8 in the static table
+            rv.push(0x88);
+            continue;
+        }
+        // literal header field without indexing, with a literal name
+        rv.push(0);
+        for s in [name, value] {
+            encode_integer(&mut rv, s.len(), 7, 0);
+            rv.extend_from_slice(s.as_bytes());
+        }
+    }
+    rv
+}
+
+/// Appends `value` as an HPACK integer with a `prefix_bits`-bit prefix,
+/// setting `flags` in the first byte (RFC 7541 section 5.1)
+fn encode_integer(
+    out: &mut Vec<u8>,
+    mut value: usize,
+    prefix_bits: u32,
+    flags: u8,
+) {
+    let max = (1usize << prefix_bits) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Decodes HPACK header blocks from one client (RFC 7541)
+struct Decoder {
+    /// the dynamic table, newest entry first
+    table: VecDeque<(String, String)>,
+    /// size of the entries in `table` (as RFC 7541 section 4.1 counts it)
+    size: usize,
+    /// the most that `size` may be
+    max_size: usize,
+}
+
+impl Decoder {
+    fn new() -> Decoder {
+        Decoder { table: VecDeque::new(), size: 0, max_size: HEADER_TABLE_SIZE }
+    }
+
+    /// Returns the headers in `block`
+    fn decode(
+        &mut self,
+        mut block: &[u8],
+    ) -> Result<Vec<(String, String)>, anyhow::Error> {
+        let mut rv = Vec::new();
+        let mut total = 0;
+        while let Some(first) = block.first() {
+            let header = if first & 0x80 != 0 {
+                // indexed header field
+                let index = decode_integer(&mut block, 7)?;
+                self.entry(index)?
+            } else if first & 0xc0 == 0x40 {
+                // literal header field with incremental indexing
+                let header = self.literal(&mut block, 6)?;
+                self.insert(header.clone());
+                header
+            } else if first & 0xe0 == 0x20 {
+                // dynamic table size update
+                let size = decode_integer(&mut block, 5)?;
+                if size > HEADER_TABLE_SIZE {
+                    bail!("dynamic table size {} is too big", size);
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // literal header field without indexing or never indexed
+                self.literal(&mut block, 4)?
+            };
+            total += entry_size(&header);
+            if total > MAX_REQUEST_BYTES {
+                bail!("headers too big");
+            }
+            rv.push(header);
+        }
+        Ok(rv)
+    }
+
+    /// Decodes a literal header field whose name index has a
+    /// `prefix_bits`-bit prefix
+    fn literal(
+        &self,
+        block: &mut &[u8],
+        prefix_bits: u32,
+    ) -> Result<(String, String), anyhow::Error> {
+        let index = decode_integer(block, prefix_bits)?;
+        let name = if index == 0 {
+            decode_string(block)?
+        } else {
+            self.entry(index)?.0
+        };
+        let value = decode_string(block)?;
+        Ok((name, value))
+    }
+
+    /// Returns the entry at `index` in the static and dynamic tables
+    fn entry(&self, index: usize) -> Result<(String, String), anyhow::Error> {
+        let entry = match index {
+            0 => None,
+            1..=61 => STATIC_TABLE
+                .get(index - 1)
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+            _ => self.table.get(index - 62).cloned(),
+        };
+        entry.ok_or_else(|| anyhow!("no header table entry {}", index))
+    }
+
+    fn insert(&mut self, entry: (String, String)) {
+        let size = entry_size(&entry);
+        self.evict(size);
+        // An entry bigger than the whole table just empties it.
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(entry);
+        }
+    }
+
+    /// Evicts the oldest entries until there's `room` to spare
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            let Some(entry) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= entry_size(&entry);
+        }
+    }
+}
+
+/// Returns the size of a header table entry (RFC 7541 section 4.1)
+fn entry_size((name, value): &(String, String)) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Decodes an HPACK integer with a `prefix_bits`-bit prefix from the front of
+/// `block` (RFC 7541 section 5.1)
+fn decode_integer(
+    block: &mut &[u8],
+    prefix_bits: u32,
+) -> Result<usize, anyhow::Error> {
+    let truncated = || anyhow!("truncated header block");
+    let (first, rest) = block.split_first().ok_or_else(truncated)?;
+    *block = rest;
+    let max = (1usize << prefix_bits) - 1;
+    let mut value = usize::from(*first) & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (byte, rest) = block.split_first().ok_or_else(truncated)?;
+        *block = rest;
+        if shift > 28 {
+            bail!("integer too big in header block");
+        }
+        value += usize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Decodes an HPACK string from the front of `block` (RFC 7541 section 5.2)
+fn decode_string(block: &mut &[u8]) -> Result<String, anyhow::Error> {
+    let huffman =
+        block.first().ok_or_else(|| anyhow!("truncated header block"))? & 0x80
+            != 0;
+    let length = decode_integer(block, 7)?;
+    if length > block.len() {
+        bail!("truncated header block");
+    }
+    let (bytes, rest) = block.split_at(length);
+    *block = rest;
+    let bytes = if huffman { huffman_decode(bytes)? } else { bytes.to_vec() };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Decodes a Huffman-coded string (RFC 7541 section 5.2 and appendix B)
+fn huffman_decode(input: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    // Codes sorted by length and then value, with their symbols, so that we
+    // can look up each code as we accumulate its bits.
+    static CODES: OnceLock<Vec<(u8, u32, u16)>> = OnceLock::new();
+    let codes = CODES.get_or_init(|| {
+        let mut codes = HUFFMAN_CODES
+            .iter()
+            .zip(0u16..)
+            .map(|((code, nbits), symbol)| (*nbits, *code, symbol))
+            .collect::<Vec<_>>();
+        codes.sort_unstable();
+        codes
+    });
+
+    let mut rv = Vec::with_capacity(input.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut nbits = 0u8;
+    for byte in input {
+        for shift in (0..8).rev() {
+            code = (code << 1) | u32::from((byte >> shift) & 1);
+            nbits += 1;
+            if let Ok(i) =
+                codes.binary_search_by_key(&(nbits, code), |(n, c, _)| (*n, *c))
+            {
+                let symbol = codes[i].2;
+                let Ok(symbol) = u8::try_from(symbol) else {
+                    bail!("EOS in Huffman-coded string");
+                };
+                rv.push(symbol);
+                code = 0;
+                nbits = 0;
+            } else if nbits >= 30 {
+                bail!("invalid Huffman code");
+            }
+        }
+    }
+    // What's left must be padding: fewer than 8 bits, all ones (a prefix of
+    // EOS).
+    if nbits > 7 || code != (1u32 << nbits) - 1 {
+        bail!("invalid Huffman padding");
+    }
+    Ok(rv)
+}
+
+/// The static table (RFC 7541 appendix A)
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// The Huffman code for each symbol (0-255, then EOS), with its length in bits
+/// (RFC 7541 appendix B)
+#[rustfmt::skip]
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28),
+    (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28),
+    (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12), (0x1ff9, 13), (0x15, 6),
+    (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11),
+    (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6), (0x0, 5), (0x1, 5), (0x2, 5),
+    (0x19, 6), (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6),
+    (0x5c, 7), (0xfb, 8), (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7),
+    (0x61, 7), (0x62, 7), (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7), (0x67, 7),
+    (0x68, 7), (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7), (0xfd, 8),
+    (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5), (0x25, 6),
+    (0x26, 6), (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7), (0x28, 6), (0x29, 6),
+    (0x2a, 6), (0x7, 5), (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5), (0x9, 5),
+    (0x2d, 6), (0x77, 7), (0x78, 7), (0x79, 7), (0x7a, 7), (0x7b, 7),
+    (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20), (0x3fffd3, 22),
+    (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23), (0x3fffd6, 22),
+    (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23),
+    (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23), (0xffffec, 24),
+    (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23), (0xffffee, 24),
+    (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23), (0x7fffe4, 23),
+    (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23), (0x3fffd9, 22),
+    (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24), (0x3fffda, 22),
+    (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22),
+    (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21), (0x7fffea, 23),
+    (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24), (0x1fffdf, 21),
+    (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21),
+    (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21), (0x7fffed, 23),
+    (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23), (0xfffea, 20),
+    (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23),
+    (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23), (0x3ffffe0, 26),
+    (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22),
+    (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26),
+    (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27), (0x7ffffdf, 27),
+    (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25), (0x7fff2, 19),
+    (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27),
+    (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24), (0x1fffe4, 21),
+    (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28),
+    (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20),
+    (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21), (0x3fffe9, 22),
+    (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23), (0x3fffea, 22),
+    (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24),
+    (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23), (0x3ffffeb, 26),
+    (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27),
+    (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27), (0x7ffffeb, 27),
+    (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27), (0x7ffffee, 27),
+    (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26), (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::frame;
+    use super::huffman_decode;
+    use super::read_frame;
+    use super::read_frames;
+    use super::Decoder;
+    use super::Shared;
+    use super::COMPRESSION_ERROR;
+    use super::DEFAULT_MAX_FRAME_SIZE;
+    use super::DEFAULT_WINDOW;
+    use super::FLAG_ACK;
+    use super::FLAG_END_HEADERS;
+    use super::FLAG_END_STREAM;
+    use super::FLAG_PADDED;
+    use super::FLOW_CONTROL_ERROR;
+    use super::FRAME_CONTINUATION;
+    use super::FRAME_DATA;
+    use super::FRAME_GOAWAY;
+    use super::FRAME_HEADERS;
+    use super::FRAME_PING;
+    use super::FRAME_RST_STREAM;
+    use super::FRAME_SETTINGS;
+    use super::FRAME_SIZE_ERROR;
+    use super::FRAME_WINDOW_UPDATE;
+    use super::MAX_STREAMS;
+    use super::PROTOCOL_ERROR;
+    use super::REFUSED_STREAM;
+    use std::collections::BTreeMap;
+    use std::io::BufReader;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Header names and values, as listed in RFC 7541 appendix C
+    type HeaderList = [(&'static str, &'static str)];
+
+    fn headers(list: &HeaderList) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Checks that `decoder` decodes each block in `blocks` (as hex) to the
+    /// corresponding headers, leaving the dynamic table as given
+    fn check_decode(
+        decoder: &mut Decoder,
+        blocks: &[(&str, &HeaderList, &HeaderList, usize)],
+    ) {
+        for (block, expected, table, size) in blocks {
+            let decoded = decoder.decode(&hex(block)).unwrap_or_else(|error| {
+                panic!("decode {}: {:#}", block, error)
+            });
+            assert_eq!(decoded, headers(expected), "block {}", block);
+            assert_eq!(
+                decoder.table.iter().cloned().collect::<Vec<_>>(),
+                headers(table),
+                "table after block {}",
+                block
+            );
+            assert_eq!(decoder.size, *size, "table size after block {}", block);
+        }
+    }
+
+    #[test]
+    fn test_decode_requests() {
+        // RFC 7541 appendix C.3: requests without Huffman coding
+        let mut decoder = Decoder::new();
+        check_decode(
+            &mut decoder,
+            &[
+                (
+                    "828684410f7777772e6578616d706c652e636f6d",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "http"),
+                        (":path", "/"),
+                        (":authority", "www.example.com"),
+                    ],
+                    &[(":authority", "www.example.com")],
+                    57,
+                ),
+                (
+                    "828684be58086e6f2d6361636865",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "http"),
+                        (":path", "/"),
+                        (":authority", "www.example.com"),
+                        ("cache-control", "no-cache"),
+                    ],
+                    &[
+                        ("cache-control", "no-cache"),
+                        (":authority", "www.example.com"),
+                    ],
+                    110,
+                ),
+                (
+                    "828785bf400a637573746f6d2d6b65790c637573746f6d2d76616c\
+                     7565",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "https"),
+                        (":path", "/index.html"),
+                        (":authority", "www.example.com"),
+                        ("custom-key", "custom-value"),
+                    ],
+                    &[
+                        ("custom-key", "custom-value"),
+                        ("cache-control", "no-cache"),
+                        (":authority", "www.example.com"),
+                    ],
+                    164,
+                ),
+            ],
+        );
+
+        // RFC 7541 appendix C.4: the same requests with Huffman coding
+        let mut decoder = Decoder::new();
+        check_decode(
+            &mut decoder,
+            &[
+                (
+                    "828684418cf1e3c2e5f23a6ba0ab90f4ff",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "http"),
+                        (":path", "/"),
+                        (":authority", "www.example.com"),
+                    ],
+                    &[(":authority", "www.example.com")],
+                    57,
+                ),
+                (
+                    "828684be5886a8eb10649cbf",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "http"),
+                        (":path", "/"),
+                        (":authority", "www.example.com"),
+                        ("cache-control", "no-cache"),
+                    ],
+                    &[
+                        ("cache-control", "no-cache"),
+                        (":authority", "www.example.com"),
+                    ],
+                    110,
+                ),
+                (
+                    "828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "https"),
+                        (":path", "/index.html"),
+                        (":authority", "www.example.com"),
+                        ("custom-key", "custom-value"),
+                    ],
+                    &[
+                        ("custom-key", "custom-value"),
+                        ("cache-control", "no-cache"),
+                        (":authority", "www.example.com"),
+                    ],
+                    164,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_decode_eviction() {
+        // RFC 7541 appendix C.5: responses with a 256-byte dynamic table, so
+        // that older entries get evicted
+        let mut decoder = Decoder::new();
+        decoder.max_size = 256;
+        let date1 = "Mon, 21 Oct 2013 20:13:21 GMT";
+        let date2 = "Mon, 21 Oct 2013 20:13:22 GMT";
+        let location = "https://www.example.com";
+        let cookie = "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1";
+        check_decode(
+            &mut decoder,
+            &[
+                (
+                    "4803333032580770726976617465611d4d6f6e2c203231204f637420\
+                     323031332032303a31333a323120474d546e1768747470733a2f2f77\
+                     77772e6578616d706c652e636f6d",
+                    &[
+                        (":status", "302"),
+                        ("cache-control", "private"),
+                        ("date", date1),
+                        ("location", location),
+                    ],
+                    &[
+                        ("location", location),
+                        ("date", date1),
+                        ("cache-control", "private"),
+                        (":status", "302"),
+                    ],
+                    222,
+                ),
+                (
+                    "4803333037c1c0bf",
+                    &[
+                        (":status", "307"),
+                        ("cache-control", "private"),
+                        ("date", date1),
+                        ("location", location),
+                    ],
+                    &[
+                        (":status", "307"),
+                        ("location", location),
+                        ("date", date1),
+                        ("cache-control", "private"),
+                    ],
+                    222,
+                ),
+                (
+                    "88c1611d4d6f6e2c203231204f637420323031332032303a31333a32\
+                     3220474d54c05a04677a69707738666f6f3d4153444a4b48514b42\
+                     5a584f5157454f50495541585157454f49553b206d61782d6167653d\
+                     333630303b2076657273696f6e3d31",
+                    &[
+                        (":status", "200"),
+                        ("cache-control", "private"),
+                        ("date", date2),
+                        ("location", location),
+                        ("content-encoding", "gzip"),
+                        ("set-cookie", cookie),
+                    ],
+                    &[
+                        ("set-cookie", cookie),
+                        ("content-encoding", "gzip"),
+                        ("date", date2),
+                    ],
+                    215,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        for (block, expected) in [
+            ("80", "no header table entry 0"),
+            ("c6", "no header table entry 70"),
+            ("7f", "truncated header block"),
+            ("400561", "truncated header block"),
+            ("3fe21f", "dynamic table size 4097 is too big"),
+            ("ffffffffffff01", "integer too big in header block"),
+            ("418100", "invalid Huffman padding"),
+        ] {
+            let error = Decoder::new().decode(&hex(block)).unwrap_err();
+            assert_eq!(format!("{:#}", error), expected, "block {}", block);
+        }
+    }
+
+    #[test]
+    fn test_huffman_decode() {
+        // examples from RFC 7541 appendix C.4 and C.6
+        for (input, expected) in [
+            ("", ""),
+            ("f1e3c2e5f23a6ba0ab90f4ff", "www.example.com"),
+            ("a8eb10649cbf", "no-cache"),
+            ("25a849e95ba97d7f", "custom-key"),
+            ("25a849e95bb8e8b4bf", "custom-value"),
+            ("6402", "302"),
+            ("aec3771a4b", "private"),
+            (
+                "d07abe941054d444a8200595040b8166e082a62d1bff",
+                "Mon, 21 Oct 2013 20:13:21 GMT",
+            ),
+            ("9d29ad171863c78f0b97c8e9ae82ae43d3", "https://www.example.com"),
+        ] {
+            let decoded = huffman_decode(&hex(input)).unwrap_or_else(|error| {
+                panic!("decode {}: {:#}", input, error)
+            });
+            assert_eq!(String::from_utf8(decoded).unwrap(), expected);
+        }
+
+        for (input, expected) in [
+            // padding that isn't all ones
+            ("00", "invalid Huffman padding"),
+            // a whole byte of padding
+            ("f1e3c2e5f23a6ba0ab90f4ffff", "invalid Huffman padding"),
+            // EOS (30 ones)
+            ("fffffffc", "EOS in Huffman-coded string"),
+        ] {
+            let error = huffman_decode(&hex(input)).unwrap_err();
+            assert_eq!(format!("{:#}", error), expected, "input {}", input);
+        }
+    }
+
+    /// What `read_frames()` did with some input (see `exchange()`)
+    struct Exchange {
+        result: Result<(), anyhow::Error>,
+        /// stream, method, path, and body of each request dispatched
+        requests: Vec<(u32, String, String, Vec<u8>)>,
+        /// type, flags, stream, and payload of each frame sent back
+        frames: Vec<(u8, u8, u32, Vec<u8>)>,
+        /// flow-control window of each stream still open afterwards
+        streams: BTreeMap<u32, i64>,
+    }
+
+    /// Feeds `input` (frames from a client) to `read_frames()`
+    fn exchange(input: &[u8]) -> Exchange {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.write_all(input).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let conn = Shared::new(server.try_clone().unwrap());
+        let mut requests = Vec::new();
+        let result =
+            read_frames(&mut BufReader::new(server), &conn, |request, id| {
+                requests.push((id, request.method, request.path, request.body))
+            });
+        let streams = conn.state.lock().unwrap().streams.clone();
+        conn.close();
+
+        let mut frames = Vec::new();
+        while let Some(f) = read_frame(&mut client).unwrap() {
+            frames.push((f.kind, f.flags, f.stream_id, f.payload));
+        }
+        Exchange { result, requests, frames, streams }
+    }
+
+    /// Returns a HEADERS frame for a GET of "/" on stream `stream_id`
+    fn get(stream_id: u32) -> Vec<u8> {
+        // :method GET, :path /, :scheme http (all from the static table)
+        let flags = FLAG_END_HEADERS | FLAG_END_STREAM;
+        frame(FRAME_HEADERS, flags, stream_id, &[0x82, 0x84, 0x86])
+    }
+
+    fn window_update(stream_id: u32, increment: u32) -> Vec<u8> {
+        frame(FRAME_WINDOW_UPDATE, 0, stream_id, &increment.to_be_bytes())
+    }
+
+    #[test]
+    fn test_read_frames() {
+        let ping = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut input = frame(FRAME_SETTINGS, 0, 0, &[0, 4, 0, 1, 0, 0]);
+        input.extend(frame(FRAME_PING, 0, 0, &ping));
+        input.extend(get(1));
+        // a POST with its headers split into a CONTINUATION frame and its body
+        // in two DATA frames
+        input.extend(frame(FRAME_HEADERS, 0, 3, &[0x83]));
+        input.extend(frame(FRAME_CONTINUATION, FLAG_END_HEADERS, 3, &[0x84]));
+        input.extend(frame(FRAME_DATA, 0, 3, b"hello, "));
+        input.extend(frame(FRAME_DATA, FLAG_END_STREAM, 3, b"world"));
+        let exchange = exchange(&input);
+        exchange.result.unwrap();
+        assert_eq!(
+            exchange.requests,
+            [
+                (1, String::from("GET"), String::from("/"), Vec::new()),
+                (
+                    3,
+                    String::from("POST"),
+                    String::from("/"),
+                    b"hello, world".to_vec()
+                ),
+            ]
+        );
+        assert_eq!(
+            exchange.frames,
+            [
+                (FRAME_SETTINGS, FLAG_ACK, 0, Vec::new()),
+                (FRAME_PING, FLAG_ACK, 0, ping.to_vec()),
+                (FRAME_WINDOW_UPDATE, 0, 0, 7u32.to_be_bytes().to_vec()),
+                (FRAME_WINDOW_UPDATE, 0, 3, 7u32.to_be_bytes().to_vec()),
+                (FRAME_WINDOW_UPDATE, 0, 0, 5u32.to_be_bytes().to_vec()),
+            ]
+        );
+        assert_eq!(
+            exchange.streams,
+            BTreeMap::from([(1, 0x10000), (3, 0x10000)])
+        );
+    }
+
+    #[test]
+    fn test_read_frames_errors() {
+        let mut bad_continuation = frame(FRAME_HEADERS, 0, 1, &[0x82]);
+        bad_continuation.extend(frame(FRAME_PING, 0, 0, &[0; 8]));
+        let mut stream_reused = get(3);
+        stream_reused.extend(get(1));
+        let mut window_overflow = window_update(0, 0x7fff_0000);
+        window_overflow.extend(window_update(0, 0x1_0000));
+        for (input, code, expected) in [
+            (
+                frame(FRAME_DATA, 0, 1, &[0; DEFAULT_MAX_FRAME_SIZE + 1]),
+                FRAME_SIZE_ERROR,
+                "frame too big",
+            ),
+            (
+                frame(FRAME_SETTINGS, 0, 0, &[0; 5]),
+                FRAME_SIZE_ERROR,
+                "bad SETTINGS frame",
+            ),
+            (
+                frame(FRAME_SETTINGS, 0, 0, &[0, 4, 0x80, 0, 0, 0]),
+                FLOW_CONTROL_ERROR,
+                "initial window too big",
+            ),
+            (
+                frame(FRAME_SETTINGS, 0, 0, &[0, 5, 0, 0, 0x3f, 0xff]),
+                PROTOCOL_ERROR,
+                "bad maximum frame size",
+            ),
+            (
+                frame(FRAME_PING, 0, 0, &[0; 4]),
+                FRAME_SIZE_ERROR,
+                "bad PING frame",
+            ),
+            (
+                frame(FRAME_WINDOW_UPDATE, 0, 0, &[0; 3]),
+                FRAME_SIZE_ERROR,
+                "bad WINDOW_UPDATE frame",
+            ),
+            (window_update(0, 0), PROTOCOL_ERROR, "zero WINDOW_UPDATE"),
+            (window_overflow, FLOW_CONTROL_ERROR, "window too big"),
+            (
+                frame(
+                    FRAME_HEADERS,
+                    FLAG_PADDED | FLAG_END_HEADERS,
+                    1,
+                    &[5, 0x82],
+                ),
+                PROTOCOL_ERROR,
+                "bad padding",
+            ),
+            (bad_continuation, PROTOCOL_ERROR, "expected CONTINUATION"),
+            (
+                frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &[0x80]),
+                COMPRESSION_ERROR,
+                "no header table entry 0",
+            ),
+            (get(2), PROTOCOL_ERROR, "bad stream id"),
+            (stream_reused, PROTOCOL_ERROR, "bad stream id"),
+            (
+                frame(FRAME_DATA, 0, 0, b"data"),
+                PROTOCOL_ERROR,
+                "DATA on stream 0",
+            ),
+        ] {
+            let exchange = exchange(&input);
+            let error = exchange.result.unwrap_err();
+            assert_eq!(format!("{:#}", error), expected);
+            let Some((FRAME_GOAWAY, 0, 0, payload)) = exchange.frames.last()
+            else {
+                panic!("{}: expected GOAWAY", expected);
+            };
+            assert_eq!(payload[4..8], code.to_be_bytes(), "{}", expected);
+            assert_eq!(&payload[8..], expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_read_frames_window_update() {
+        let mut input = get(1);
+        input.extend(window_update(1, 1000));
+        input.extend(get(3));
+        input.extend(window_update(3, 0x7fff_ffff));
+        input.extend(get(5));
+        input.extend(window_update(5, 0));
+        // a request whose body hasn't arrived yet
+        input.extend(frame(FRAME_HEADERS, FLAG_END_HEADERS, 7, &[0x83]));
+        input.extend(window_update(7, 0));
+        input.extend(frame(FRAME_DATA, FLAG_END_STREAM, 7, &[]));
+        let exchange = exchange(&input);
+        exchange.result.unwrap();
+        assert_eq!(
+            exchange.requests.iter().map(|r| r.0).collect::<Vec<_>>(),
+            [1, 3, 5]
+        );
+        assert_eq!(
+            exchange.frames,
+            [
+                (
+                    FRAME_RST_STREAM,
+                    0,
+                    3,
+                    FLOW_CONTROL_ERROR.to_be_bytes().to_vec()
+                ),
+                (FRAME_RST_STREAM, 0, 5, PROTOCOL_ERROR.to_be_bytes().to_vec()),
+                (FRAME_RST_STREAM, 0, 7, PROTOCOL_ERROR.to_be_bytes().to_vec()),
+            ]
+        );
+        assert_eq!(
+            exchange.streams,
+            BTreeMap::from([(1, DEFAULT_WINDOW + 1000)])
+        );
+    }
+
+    #[test]
+    fn test_read_frames_max_streams() {
+        // Streams that the client resets still count against the limit until
+        // their handlers finish (which they don't here).
+        let mut input = Vec::new();
+        let stream_ids =
+            (0..MAX_STREAMS).map(|i| 2 * i + 1).collect::<Vec<_>>();
+        for stream_id in &stream_ids {
+            input.extend(get(*stream_id));
+        }
+        input.extend(frame(FRAME_RST_STREAM, 0, 1, &[0, 0, 0, 8]));
+        let extra = 2 * MAX_STREAMS + 1;
+        input.extend(get(extra));
+        let exchange = exchange(&input);
+        exchange.result.unwrap();
+        assert_eq!(
+            exchange.requests.iter().map(|r| r.0).collect::<Vec<_>>(),
+            stream_ids
+        );
+        assert_eq!(
+            exchange.frames,
+            [(
+                FRAME_RST_STREAM,
+                0,
+                extra,
+                REFUSED_STREAM.to_be_bytes().to_vec()
+            )]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Tracking storage health over the course of a session
+//!
+//! Test failures caused by a flaky disk look a lot like test failures caused by
+//! a Go runtime bug.  We record the pool's error counters and the number of
+//! FMA faults at various points so that we can tell when errors appeared
+//! during a session.
+
+use crate::run_command;
+use std::process::Command;
+
+/// Summary of the health of a ZFS pool (and the system's fault manager) at a
+/// point in time
+#[derive(Clone, Debug)]
+pub struct PoolHealth {
+    /// name of the pool
+    pub pool: String,
+    /// state of the pool (e.g., "ONLINE" or "DEGRADED")
+    pub state: String,
+    /// read errors reported for the pool as a whole
+    pub read_errors: u64,
+    /// write errors reported for the pool as a whole
+    pub write_errors: u64,
+    /// checksum errors reported for the pool as a whole
+    pub cksum_errors: u64,
+    /// number of faults reported by `fmadm faulty` (None if not available)
+    pub nfaults: Option<usize>,
+    /// raw `zpool status` output
+    pub raw_status: String,
+}
+
+impl PoolHealth {
+    /// Describes whatever got worse between `self` and `later`, if anything
+    pub fn new_problems(&self, later: &PoolHealth) -> Vec<String> {
+        let mut problems = Vec::new();
+        if later.state != self.state {
+            problems.push(format!(
+                "pool state changed from {} to {}",
+                self.state, later.state
+            ));
+        }
+        let counters = [
+            ("read", self.read_errors, later.read_errors),
+            ("write", self.write_errors, later.write_errors),
+            ("checksum", self.cksum_errors, later.cksum_errors),
+        ];
+        for (label, before, after) in counters {
+            if after > before {
+                problems.push(format!(
+                    "{} new {} error{}",
+                    after - before,
+                    label,
+                    if after - before == 1 { "" } else { "s" }
+                ));
+            }
+        }
+        if let (Some(before), Some(after)) = (self.nfaults, later.nfaults) {
+            if after > before {
+                problems.push(format!(
+                    "{} new FMA fault{}",
+                    after - before,
+                    if after - before == 1 { "" } else { "s" }
+                ));
+            }
+        }
+        problems
+    }
+}
+
+impl std::fmt::Display for PoolHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pool {:?} {}, errors: {} read, {} write, {} checksum, ",
+            self.pool,
+            self.state,
+            self.read_errors,
+            self.write_errors,
+            self.cksum_errors
+        )?;
+        match self.nfaults {
+            Some(n) => write!(f, "FMA faults: {}", n),
+            None => write!(f, "FMA faults: unknown"),
+        }
+    }
+}
+
+/// Returns the name of the pool containing the given dataset
+pub fn pool_for_dataset(dataset: &str) -> &str {
+    dataset.split('/').next().unwrap()
+}
+
+/// Collects the current health of the given pool
+pub fn pool_health(pool: &str) -> Result<PoolHealth, anyhow::Error> {
+    let raw_status =
+        run_command(Command::new("zpool").arg("status").arg("-p").arg(pool))?;
+
+    let mut state = String::from("UNKNOWN");
+    let mut read_errors = 0;
+    let mut write_errors = 0;
+    let mut cksum_errors = 0;
+    let mut in_config = false;
+    for line in raw_status.lines() {
+        let line = line.trim();
+        if let Some(pool_state) = line.strip_prefix("state:") {
+            state = pool_state.trim().to_string();
+        } else if line.starts_with("NAME") {
+            in_config = true;
+        } else if in_config {
+            // Each line of the config looks like: NAME STATE READ WRITE CKSUM
+            // [...].  The first is the pool itself, whose counts already
+            // account for errors in its vdevs, so adding up the rest would
+            // count the same errors more than once.
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() >= 5 && fields[0] == pool {
+                let parse = |s: &str| s.parse::<u64>().unwrap_or(0);
+                read_errors = parse(fields[2]);
+                write_errors = parse(fields[3]);
+                cksum_errors = parse(fields[4]);
+                break;
+            }
+        }
+    }
+
+    Ok(PoolHealth {
+        pool: pool.to_string(),
+        state,
+        read_errors,
+        write_errors,
+        cksum_errors,
+        nfaults: fma_fault_count(),
+        raw_status,
+    })
+}
+
+/// Returns the number of faults reported by `fmadm faulty`, or None if that's
+/// not available (e.g., on systems without FMA)
+fn fma_fault_count() -> Option<usize> {
+    // The summary output is a header line, a line of dashes, and then one line
+    // per fault.
+    let output = run_command(
+        Command::new("pfexec").arg("fmadm").arg("faulty").arg("-s"),
+    )
+    .ok()?;
+    Some(
+        output
+            .lines()
+            .skip_while(|l| !l.starts_with("---"))
+            .skip(1)
+            .filter(|l| !l.trim().is_empty())
+            .count(),
+    )
+}
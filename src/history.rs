@@ -0,0 +1,710 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! History of sessions and runs, stored in a SQLite database
+//!
+//! With `--history-db`, gocrash records each session and each run in a
+//! database that outlives the datasets (which get destroyed or archived).
+//! Every row is keyed by the host that produced it, so that databases from
+//! different machines can be merged with `gocrash history merge`.
+
+use crate::session::SessionRecord;
+use anyhow::Context;
+use rusqlite::params;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Version of the database schema (stored in SQLite's `user_version`)
+///
+/// * version 1: initial version
+/// * version 2: runs that gocrash failed to carry out have result
+///   "infra-error" rather than "error" (and there are new results "hung",
+///   "interrupted", and "suspect")
+/// * version 3: new table "phase_timings"
+/// * version 4: new table "run_metadata"
+/// * version 5: new table "test_results"
+const SCHEMA_VERSION: i32 = 5;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS sessions (
+        host            TEXT NOT NULL,
+        session         TEXT NOT NULL,
+        snapshot        TEXT NOT NULL,
+        working_dataset TEXT,
+        start_time      TEXT NOT NULL,
+        end_time        TEXT,
+        outcome         TEXT,
+        parameters      TEXT NOT NULL,
+        PRIMARY KEY (host, session)
+    );
+
+    CREATE TABLE IF NOT EXISTS runs (
+        host            TEXT NOT NULL,
+        session         TEXT NOT NULL,
+        run_id          INTEGER NOT NULL,
+        thread          INTEGER NOT NULL,
+        attempt         INTEGER NOT NULL,
+        dataset         TEXT NOT NULL,
+        start_time      TEXT NOT NULL,
+        end_time        TEXT NOT NULL,
+        result          TEXT NOT NULL,
+        signature       TEXT,
+        PRIMARY KEY (host, session, run_id)
+    );
+
+    CREATE TABLE IF NOT EXISTS phase_timings (
+        host            TEXT NOT NULL,
+        session         TEXT NOT NULL,
+        run_id          INTEGER NOT NULL,
+        seq             INTEGER NOT NULL,
+        phase           TEXT NOT NULL,
+        duration_secs   REAL NOT NULL,
+        PRIMARY KEY (host, session, run_id, seq)
+    );
+
+    CREATE TABLE IF NOT EXISTS run_metadata (
+        host            TEXT NOT NULL,
+        session         TEXT NOT NULL,
+        run_id          INTEGER NOT NULL,
+        name            TEXT NOT NULL,
+        value           TEXT NOT NULL,
+        PRIMARY KEY (host, session, run_id, name)
+    );
+
+    CREATE TABLE IF NOT EXISTS test_results (
+        host            TEXT NOT NULL,
+        session         TEXT NOT NULL,
+        run_id          INTEGER NOT NULL,
+        seq             INTEGER NOT NULL,
+        test            TEXT NOT NULL,
+        result          TEXT NOT NULL,
+        duration_secs   REAL,
+        PRIMARY KEY (host, session, run_id, seq)
+    );
+";
+
+/// Tables in the database, with their columns (as in `SCHEMA`)
+///
+/// Copying rows between databases names the columns rather than relying on
+/// their order, which can differ between databases created by different
+/// versions.
+pub const TABLES: &[(&str, &[&str])] = &[
+    (
+        "sessions",
+        &[
+            "host",
+            "session",
+            "snapshot",
+            "working_dataset",
+            "start_time",
+            "end_time",
+            "outcome",
+            "parameters",
+        ],
+    ),
+    (
+        "runs",
+        &[
+            "host",
+            "session",
+            "run_id",
+            "thread",
+            "attempt",
+            "dataset",
+            "start_time",
+            "end_time",
+            "result",
+            "signature",
+        ],
+    ),
+    (
+        "phase_timings",
+        &["host", "session", "run_id", "seq", "phase", "duration_secs"],
+    ),
+    ("run_metadata", &["host", "session", "run_id", "name", "value"]),
+    (
+        "test_results",
+        &[
+            "host",
+            "session",
+            "run_id",
+            "seq",
+            "test",
+            "result",
+            "duration_secs",
+        ],
+    ),
+];
+
+/// Schema version in which each table first appeared (tables not listed here
+/// have always been there)
+const TABLE_VERSIONS: &[(&str, i32)] =
+    &[("phase_timings", 3), ("run_metadata", 4), ("test_results", 5)];
+
+/// Handle to an open history database
+pub struct History {
+    conn: rusqlite::Connection,
+    /// name of this host, recorded with every row we write
+    host: String,
+}
+
+/// Describes one run for the history database
+pub struct RunRecord<'a> {
+    pub session: &'a str,
+    pub run_id: usize,
+    pub thread: u8,
+    /// which attempt this was for its thread
+    pub attempt: usize,
+    pub dataset: &'a str,
+    pub start_time: chrono::DateTime<chrono::Utc>,
+    pub end_time: chrono::DateTime<chrono::Utc>,
+    /// "passed", "failed", "hung", "infra-error", "interrupted", or
+    /// "suspect"
+    pub result: &'a str,
+    pub signature: Option<&'a str>,
+}
+
+impl History {
+    /// Opens the history database at `path`, creating it if needed
+    pub fn open(path: &Path) -> Result<History, anyhow::Error> {
+        Ok(History {
+            conn: open_db(path)?,
+            host: crate::fingerprint::hostname()?,
+        })
+    }
+
+    /// Records (or updates) the summary of a session
+    pub fn record_session(
+        &self,
+        record: &SessionRecord,
+    ) -> Result<(), anyhow::Error> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO sessions VALUES
+                    (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    self.host,
+                    record.session,
+                    record.snapshot,
+                    record.working_dataset,
+                    record.start_time,
+                    record.end_time,
+                    record.outcome.map(|o| o.as_str()),
+                    record.parameters.to_string(),
+                ],
+            )
+            .context("record session in history database")?;
+        Ok(())
+    }
+
+    /// Records the result of one run
+    pub fn record_run(&self, run: &RunRecord) -> Result<(), anyhow::Error> {
+        self.conn
+            .execute(
+                "INSERT INTO runs VALUES
+                    (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    self.host,
+                    run.session,
+                    run.run_id,
+                    run.thread,
+                    run.attempt,
+                    run.dataset,
+                    run.start_time.to_rfc3339(),
+                    run.end_time.to_rfc3339(),
+                    run.result,
+                    run.signature,
+                ],
+            )
+            .context("record run in history database")?;
+        Ok(())
+    }
+
+    /// Records how long each phase of the workload took in one run
+    pub fn record_phases(
+        &self,
+        session: &str,
+        run_id: usize,
+        phases: &[(String, Duration)],
+    ) -> Result<(), anyhow::Error> {
+        for (seq, (phase, duration)) in phases.iter().enumerate() {
+            self.conn
+                .execute(
+                    "INSERT INTO phase_timings VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        self.host,
+                        session,
+                        run_id,
+                        seq,
+                        phase,
+                        duration.as_secs_f64(),
+                    ],
+                )
+                .context("record phase timings in history database")?;
+        }
+        Ok(())
+    }
+
+    /// Records what `go test` reported about each test in one run
+    pub fn record_tests(
+        &self,
+        session: &str,
+        run_id: usize,
+        tests: &[crate::gotest::TestResult],
+    ) -> Result<(), anyhow::Error> {
+        for (seq, test) in tests.iter().enumerate() {
+            self.conn
+                .execute(
+                    "INSERT INTO test_results VALUES
+                        (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        self.host,
+                        session,
+                        run_id,
+                        seq,
+                        test.test,
+                        test.result,
+                        test.duration_secs,
+                    ],
+                )
+                .context("record test results in history database")?;
+        }
+        Ok(())
+    }
+
+    /// Records a fact about the conditions that one run ran under (e.g., its
+    /// CPU weight)
+    pub fn record_run_metadata(
+        &self,
+        session: &str,
+        run_id: usize,
+        name: &str,
+        value: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO run_metadata VALUES
+                    (?1, ?2, ?3, ?4, ?5)",
+                params![self.host, session, run_id, name, value],
+            )
+            .context("record run metadata in history database")?;
+        Ok(())
+    }
+
+    /// Returns how long each phase of the workload took in passing runs on
+    /// this host, oldest first
+    pub fn passing_phase_timings(
+        &self,
+    ) -> Result<Vec<(String, Duration)>, anyhow::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT p.phase, p.duration_secs
+                FROM phase_timings p
+                JOIN runs r USING (host, session, run_id)
+                WHERE p.host = ?1 AND r.result = 'passed'
+                ORDER BY r.start_time, p.seq",
+        )?;
+        let rows = stmt.query_map(params![self.host], |row| {
+            Ok((row.get(0)?, Duration::from_secs_f64(row.get(1)?)))
+        })?;
+        rows.collect::<Result<_, _>>()
+            .context("read phase timings from history database")
+    }
+}
+
+/// Describes one run, along with everything recorded about the conditions it
+/// ran under (see `load_runs()`)
+pub struct RunFactors {
+    pub session: String,
+    /// "passed", "failed", etc. (see `RunRecord`)
+    pub result: String,
+    pub signature: Option<String>,
+    /// run metadata (see `History::record_run_metadata()`), plus the run's
+    /// "host" and "thread" and the options its session used that affect the
+    /// conditions runs run under (see `CORRELATED_PARAMETERS`)
+    pub factors: BTreeMap<String, String>,
+}
+
+/// Loads every run in the database at `path`, along with what was recorded
+/// about the conditions each one ran under
+pub fn load_runs(path: &Path) -> Result<Vec<RunFactors>, anyhow::Error> {
+    let (conn, version) = open_input(path)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT r.host, r.session, r.run_id, r.thread, r.result, r.signature,
+                s.parameters
+            FROM runs r LEFT JOIN sessions s USING (host, session)",
+    )?;
+    let mut rows = stmt.query([])?;
+    let mut runs = Vec::new();
+    let mut which = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        let host: String = row.get(0)?;
+        let session: String = row.get(1)?;
+        let run_id: usize = row.get(2)?;
+        let thread: u8 = row.get(3)?;
+        let parameters: Option<String> = row.get(6)?;
+
+        let mut factors = BTreeMap::new();
+        factors.insert(String::from("host"), host.clone());
+        factors.insert(String::from("thread"), thread.to_string());
+        if let Some(serde_json::Value::Object(parameters)) =
+            parameters.and_then(|p| serde_json::from_str(&p).ok())
+        {
+            for (name, value) in parameters {
+                if !CORRELATED_PARAMETERS.contains(&name.as_str()) {
+                    continue;
+                }
+                if let Some(value) = parameter_value(&value) {
+                    factors.insert(format!("--{}", name), value);
+                }
+            }
+        }
+
+        which.insert((host, session.clone(), run_id), runs.len());
+        runs.push(RunFactors {
+            session,
+            result: upgrade_result(version, row.get(4)?),
+            signature: row.get(5)?,
+            factors,
+        });
+    }
+
+    if !has_table(version, "run_metadata") {
+        return Ok(runs);
+    }
+    let mut stmt = conn.prepare(
+        "SELECT host, session, run_id, name, value FROM run_metadata",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let key: (String, String, usize) =
+            (row.get(0)?, row.get(1)?, row.get(2)?);
+        if let Some(i) = which.get(&key) {
+            runs[*i].factors.insert(row.get(3)?, row.get(4)?);
+        }
+    }
+
+    Ok(runs)
+}
+
+/// Session options (from `SessionRecord::parameters`) that change the
+/// conditions that runs run under: how many run at once, the load around them,
+/// and their environment
+///
+/// Most options (output formats, where things are saved, when to stop, and so
+/// on) can't affect whether a run fails, and including them would only bury
+/// the factors that can.
+const CORRELATED_PARAMETERS: &[&str] = &[
+    "concurrency",
+    "cpu-weight",
+    "background-threads",
+    "background-test-command",
+    "background-cpu-weight",
+    "max-load",
+    "no-isolate-env",
+    "no-network",
+    "pty",
+    "pty-keepalive",
+    "reuse-clones",
+];
+
+/// Returns a session parameter as a string, if it has a value that can be
+/// compared across runs
+fn parameter_value(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::Bool(b) => Some(b.to_string()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Array(values) if !values.is_empty() => {
+            let values = values
+                .iter()
+                .map(parameter_value)
+                .collect::<Option<Vec<_>>>()?;
+            Some(values.join(","))
+        }
+        serde_json::Value::Array(_) | serde_json::Value::Object(_) => None,
+    }
+}
+
+/// Describes one column of a table in the database
+pub struct ColumnInfo {
+    pub name: String,
+    /// declared type ("TEXT", "INTEGER", or "REAL")
+    pub sql_type: String,
+    pub not_null: bool,
+}
+
+/// The columns of a table and all of its rows (see `dump_table()`)
+pub type TableDump = (Vec<ColumnInfo>, Vec<Vec<rusqlite::types::Value>>);
+
+/// Returns the columns of `table` and all of its rows
+///
+/// Databases that predate `table` have no such table, so this returns `None`
+/// for those.
+pub fn dump_table(
+    conn: &rusqlite::Connection,
+    version: i32,
+    table: &str,
+) -> Result<Option<TableDump>, anyhow::Error> {
+    if !has_table(version, table) {
+        return Ok(None);
+    }
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt
+        .query_map([], |row| {
+            Ok(ColumnInfo {
+                name: row.get("name")?,
+                sql_type: row.get("type")?,
+                not_null: row.get("notnull")?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("read columns of {:?}", table))?;
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM {}",
+        columns
+            .iter()
+            .map(|c| format!("\"{}\"", c.name))
+            .collect::<Vec<_>>()
+            .join(", "),
+        table
+    ))?;
+    let rows = stmt
+        .query_map([], |row| (0..columns.len()).map(|i| row.get(i)).collect())?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("read {:?}", table))?;
+    Ok(Some((columns, rows)))
+}
+
+/// Opens the existing database at `path` (upgrading it if needed)
+#[cfg(feature = "http")]
+pub fn open_existing(
+    path: &Path,
+) -> Result<rusqlite::Connection, anyhow::Error> {
+    if !path.exists() {
+        anyhow::bail!("{:?}: no such file", path);
+    }
+    open_db(path)
+}
+
+/// Opens the existing database at `path` to read from, returning it and its
+/// schema version
+///
+/// Unlike `open_existing()`, this doesn't upgrade the database, since that
+/// would mean writing to a database that the user only asked us to read
+/// (which may be someone else's, or on read-only storage).  Callers must cope
+/// with older schemas themselves (see `has_table()` and `upgrade_result()`).
+pub fn open_input(
+    path: &Path,
+) -> Result<(rusqlite::Connection, i32), anyhow::Error> {
+    if !path.exists() {
+        anyhow::bail!("{:?}: no such file", path);
+    }
+    let conn = open_read_only(path)?;
+    let version = schema_version(&conn, path)?;
+    Ok((conn, version))
+}
+
+/// Returns the schema version of `conn` (opened from `path`), checking that
+/// it's one we understand
+fn schema_version(
+    conn: &rusqlite::Connection,
+    path: &Path,
+) -> Result<i32, anyhow::Error> {
+    let version: i32 =
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        anyhow::bail!(
+            "history database {:?} has schema version {}, but this gocrash \
+             only understands up to version {}",
+            path,
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    Ok(version)
+}
+
+/// Returns whether a database with schema version `version` has `table`
+fn has_table(version: i32, table: &str) -> bool {
+    TABLE_VERSIONS
+        .iter()
+        .find(|(t, _)| *t == table)
+        .is_none_or(|(_, since)| version >= *since)
+}
+
+/// Returns a run's result as the current schema records it, given what a
+/// database with schema version `version` recorded
+fn upgrade_result(version: i32, result: String) -> String {
+    if version < 2 && result == "error" {
+        String::from("infra-error")
+    } else {
+        result
+    }
+}
+
+/// Opens the existing database at `path` for reading only
+///
+/// Unlike `open_existing()`, this doesn't upgrade the database, so callers
+/// should use that first to make sure it's up to date (or use
+/// `open_input()`).
+pub fn open_read_only(
+    path: &Path,
+) -> Result<rusqlite::Connection, anyhow::Error> {
+    let conn = rusqlite::Connection::open_with_flags(
+        path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+            | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .with_context(|| format!("open history database {:?}", path))?;
+    conn.busy_timeout(Duration::from_secs(30))?;
+    Ok(conn)
+}
+
+/// Opens the database at `path`, creating it and its tables if needed
+fn open_db(path: &Path) -> Result<rusqlite::Connection, anyhow::Error> {
+    let conn = rusqlite::Connection::open(path)
+        .with_context(|| format!("open history database {:?}", path))?;
+    let version = schema_version(&conn, path)?;
+    conn.execute_batch(SCHEMA)
+        .with_context(|| format!("initialize history database {:?}", path))?;
+    if version == 1 {
+        conn.execute(
+            "UPDATE runs SET result = 'infra-error' WHERE result = 'error'",
+            [],
+        )
+        .with_context(|| format!("upgrade history database {:?}", path))?;
+    }
+    conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    // Worker threads write to the database concurrently with other gocrash
+    // processes possibly doing the same.
+    conn.busy_timeout(std::time::Duration::from_secs(30))?;
+    Ok(conn)
+}
+
+/// Returns a URI for attaching the database at `path` for reading only
+fn read_only_uri(path: &Path) -> String {
+    let mut uri = String::from("file:");
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '%' => uri.push_str("%25"),
+            '?' => uri.push_str("%3f"),
+            '#' => uri.push_str("%23"),
+            c => uri.push(c),
+        }
+    }
+    uri.push_str("?mode=ro");
+    uri
+}
+
+/// Work with history databases
+#[derive(clap::Args)]
+pub struct HistoryArgs {
+    #[command(subcommand)]
+    command: HistoryCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum HistoryCommand {
+    /// Merge history databases (e.g., from several machines) into one
+    Merge(MergeArgs),
+}
+
+#[derive(clap::Args)]
+struct MergeArgs {
+    /// database to write (created if it doesn't exist, added to if it does)
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// databases to merge
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+}
+
+/// Runs the `gocrash history` command
+pub fn history(args: &HistoryArgs) -> Result<(), anyhow::Error> {
+    match &args.command {
+        HistoryCommand::Merge(merge_args) => merge(merge_args),
+    }
+}
+
+/// Merges the input databases into the output database
+///
+/// Sessions and runs are identified by the host they ran on plus their
+/// session name (and run id), so the same rows appearing in several inputs
+/// (e.g., because one is a copy of another) are only added once.  The inputs
+/// are only read: rows from older schemas are upgraded as they're copied (see
+/// `open_input()`).
+fn merge(args: &MergeArgs) -> Result<(), anyhow::Error> {
+    let conn = open_db(&args.output)?;
+    for input in &args.inputs {
+        // Make sure the input is something we understand (but don't create
+        // it if it doesn't exist).
+        let version = open_input(input)?.1;
+
+        conn.execute(
+            "ATTACH DATABASE ?1 AS input",
+            params![read_only_uri(input)],
+        )
+        .with_context(|| format!("attach {:?}", input))?;
+        let result = (|| -> Result<_, anyhow::Error> {
+            let mut counts = BTreeMap::new();
+            for (table, columns) in TABLES {
+                if !has_table(version, table) {
+                    continue;
+                }
+                let columns = columns.join(", ");
+                let select = if *table == "runs" && version < 2 {
+                    columns.replace(
+                        "result",
+                        "CASE result WHEN 'error' THEN 'infra-error' \
+                         ELSE result END",
+                    )
+                } else {
+                    columns.clone()
+                };
+                let total: usize = conn.query_row(
+                    &format!("SELECT COUNT(*) FROM input.{}", table),
+                    [],
+                    |row| row.get(0),
+                )?;
+                let new = conn.execute(
+                    &format!(
+                        "INSERT OR IGNORE INTO main.{table} ({columns})
+                            SELECT {select} FROM input.{table}",
+                        table = table,
+                        columns = columns,
+                        select = select,
+                    ),
+                    [],
+                )?;
+                counts.insert(*table, (total, new));
+            }
+            let (total_sessions, new_sessions) = counts["sessions"];
+            let (total_runs, new_runs) = counts["runs"];
+            Ok((total_sessions, new_sessions, total_runs, new_runs))
+        })();
+        conn.execute("DETACH DATABASE input", [])?;
+        let (total_sessions, new_sessions, total_runs, new_runs) =
+            result.with_context(|| format!("merge {:?}", input))?;
+        println!(
+            "{}: {} new session{} ({} already present), {} new run{} ({} \
+             already present)",
+            input.display(),
+            new_sessions,
+            if new_sessions == 1 { "" } else { "s" },
+            total_sessions - new_sessions,
+            new_runs,
+            if new_runs == 1 { "" } else { "s" },
+            total_runs - new_runs,
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,103 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Commands run after each run (`--post-run-hook` and `--failure-hook`)
+//!
+//! Hooks are shell commands run in the run's clone once the test suite has
+//! finished, with the same `GOCRASH_*` environment variables as the test suite
+//! plus `GOCRASH_RESULT` (e.g., "passed" or "failed") and, for failed runs,
+//! `GOCRASH_SIGNATURE`.  A hook can annotate the run by printing a JSON object
+//! on stdout: each of its fields is recorded in the run's metadata (see
+//! `Gocrash::record_run_metadata()`), as in:
+//!
+//! ```text
+//! {"allocs-per-op": 1234, "flaky-test": "TestFoo"}
+//! ```
+//!
+//! If a hook fails (or prints something other than a JSON object), what
+//! happens depends on `--on-hook-failure`: by default we just log a warning,
+//! but the run can instead be treated as an infrastructure error.
+
+use crate::run_command;
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+/// What to do when a hook fails
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookFailure {
+    /// log a warning and otherwise ignore the failure
+    #[default]
+    Log,
+    /// treat the run as an infrastructure error (which stops the session)
+    InfraError,
+}
+
+/// Runs hook `command` in `dir` with the given environment, returning the
+/// run metadata that it reported
+pub fn run_hook(
+    command: &str,
+    dir: &Path,
+    env: impl IntoIterator<Item = (&'static str, OsString)>,
+) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let stdout = run_command(
+        Command::new("bash").arg("-c").arg(command).current_dir(dir).envs(env),
+    )
+    .with_context(|| format!("hook {:?}", command))?;
+    parse_annotations(&stdout)
+        .with_context(|| format!("hook {:?}: parse output", command))
+}
+
+/// Parses the output of a hook into run metadata
+///
+/// Empty output means no metadata.  String values are recorded as is, and
+/// other values (numbers, booleans, etc.) are recorded as JSON.
+fn parse_annotations(
+    stdout: &str,
+) -> Result<Vec<(String, String)>, anyhow::Error> {
+    if stdout.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let value: serde_json::Value = serde_json::from_str(stdout)?;
+    let object =
+        value.as_object().ok_or_else(|| anyhow!("expected a JSON object"))?;
+    object
+        .iter()
+        .map(|(name, value)| {
+            check_name(name)?;
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            Ok((name.clone(), value))
+        })
+        .collect()
+}
+
+/// Returns an error unless `name` can be used in the name of a ZFS user
+/// property (which is where run metadata is recorded)
+fn check_name(name: &str) -> Result<(), anyhow::Error> {
+    let valid = |c: char| {
+        c.is_ascii_lowercase()
+            || c.is_ascii_digit()
+            || ['-', '_', '.', '+', ':'].contains(&c)
+    };
+    if name.is_empty() || !name.chars().all(valid) {
+        bail!(
+            "field {:?}: names may contain only lowercase letters, digits, \
+             and \"-\", \"_\", \".\", \"+\", or \":\"",
+            name
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,872 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Minimal HTTP/1.1 server
+//!
+//! gocrash serves a handful of small, read-only pages to people on the lab
+//! network (see `web.rs`), which doesn't call for a web framework.  This
+//! handles just enough of HTTP for that: `GET` and `HEAD` requests with a
+//! query string, one request per connection, each handled on its own thread.
+//! A response can also be streamed for as long as the handler likes (ending
+//! when the connection is closed), which is how live progress is served while
+//! a session runs (see `live.rs`), including over a WebSocket (RFC 6455) that
+//! only ever sends messages to the client.
+//!
+//! Soak machines sit on shared lab networks, and even read-only logs can
+//! contain things that shouldn't be shared widely, so a server can require a
+//! token (see `ListenArgs`) or listen only on a Unix domain socket that only
+//! its own user can connect to.
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Give up on clients that take longer than this to send their request
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reject requests whose request line and headers are bigger than this
+const MAX_REQUEST_BYTES: u64 = 64 * 1024;
+
+/// Most connections that we handle at once (each on its own thread)
+const MAX_CONNECTIONS: usize = 64;
+
+/// A request from a client
+pub struct Request {
+    pub method: String,
+    /// path part of the request target (percent-decoded)
+    pub path: String,
+    /// parameters from the query string (percent-decoded)
+    pub query: BTreeMap<String, String>,
+    /// request headers (with lowercase names)
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Contents of a response
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// an open file and its size
+    File(std::fs::File, u64),
+    /// written by this function until it returns (after which the connection
+    /// is closed)
+    Stream(StreamBody),
+}
+
+/// Writes a streamed response body (see `Body::Stream`)
+pub type StreamBody = Box<dyn FnOnce(&mut dyn Write) -> std::io::Result<()>>;
+
+/// A response to send back to the client
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    /// Returns a response with status `status` and the given body
+    pub fn new(status: u16, content_type: &str, body: Body) -> Response {
+        Response {
+            status,
+            headers: vec![(
+                String::from("Content-Type"),
+                content_type.to_string(),
+            )],
+            body,
+        }
+    }
+
+    // Only `gocrash serve` (which also needs the "sqlite" feature) uses this.
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    pub fn html(html: String) -> Response {
+        Response::new(
+            200,
+            "text/html; charset=utf-8",
+            Body::Bytes(html.into_bytes()),
+        )
+    }
+
+    /// Returns a plain-text response with status `status`
+    pub fn text(status: u16, text: String) -> Response {
+        Response::new(
+            status,
+            "text/plain; charset=utf-8",
+            Body::Bytes(text.into_bytes()),
+        )
+    }
+
+    /// Adds a header to the response
+    pub fn header(mut self, name: &str, value: String) -> Response {
+        self.headers.push((name.to_string(), value));
+        self
+    }
+}
+
+/// An error to report to the client instead of a response
+pub struct HttpError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl HttpError {
+    pub fn bad_request(message: String) -> HttpError {
+        HttpError { status: 400, message }
+    }
+
+    pub fn not_found(message: String) -> HttpError {
+        HttpError { status: 404, message }
+    }
+}
+
+impl From<anyhow::Error> for HttpError {
+    fn from(error: anyhow::Error) -> HttpError {
+        HttpError { status: 500, message: format!("{:#}", error) }
+    }
+}
+
+impl From<HttpError> for Response {
+    fn from(error: HttpError) -> Response {
+        Response::text(error.status, format!("{}\n", error.message))
+    }
+}
+
+/// How to listen for HTTP requests, and who may make them
+#[derive(clap::Args)]
+pub struct ListenArgs {
+    /// address to listen on (e.g., "127.0.0.1:8080", or "0.0.0.0:8080" for
+    /// every interface)
+    #[arg(
+        long,
+        value_name = "ADDR",
+        required_unless_present = "listen_unix",
+        conflicts_with = "listen_unix"
+    )]
+    listen: Option<SocketAddr>,
+
+    /// listen on a Unix domain socket at this path instead (only this user
+    /// can connect to it; others can use "ssh -L" to reach it)
+    #[arg(long, value_name = "PATH")]
+    listen_unix: Option<PathBuf>,
+
+    /// require clients to present the token in this file, either as
+    /// "Authorization: Bearer TOKEN" or as the password for HTTP basic
+    /// authentication (with any user name)
+    #[arg(long, value_name = "PATH")]
+    token_file: Option<PathBuf>,
+}
+
+impl ListenArgs {
+    /// Returns arguments for listening on TCP address `addr`
+    pub fn tcp(addr: SocketAddr, token_file: Option<PathBuf>) -> ListenArgs {
+        ListenArgs { listen: Some(addr), listen_unix: None, token_file }
+    }
+}
+
+/// Where we accept connections
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener, PathBuf),
+}
+
+/// A connection from a client
+trait Connection: Read + Write + Send {
+    /// Returns a description of the client for messages
+    fn peer(&self) -> String;
+    fn set_read_timeout(&self, timeout: Duration) -> std::io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn peer(&self) -> String {
+        self.peer_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_else(|_| String::from("unknown client"))
+    }
+
+    fn set_read_timeout(&self, timeout: Duration) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, Some(timeout))
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn peer(&self) -> String {
+        String::from("client on Unix socket")
+    }
+
+    fn set_read_timeout(&self, timeout: Duration) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, Some(timeout))
+    }
+}
+
+/// Listens for HTTP requests as described by `ListenArgs`
+pub struct Server {
+    listener: Listener,
+    /// token that clients must present, if any
+    token: Option<String>,
+}
+
+impl Server {
+    /// Starts listening as described by `args`
+    pub fn bind(args: &ListenArgs) -> Result<Server, anyhow::Error> {
+        let token = args.token_file.as_deref().map(read_token).transpose()?;
+
+        let listener = match (&args.listen, &args.listen_unix) {
+            (_, Some(path)) => bind_unix(path)?,
+            (Some(addr), None) => {
+                if token.is_none() && !addr.ip().is_loopback() {
+                    eprintln!(
+                        "warning: listening on {} without --token-file: \
+                         anyone who can reach this host can read what's \
+                         served",
+                        addr
+                    );
+                }
+                Listener::Tcp(
+                    TcpListener::bind(addr)
+                        .with_context(|| format!("listen on {}", addr))?,
+                )
+            }
+            (None, None) => bail!("one of --listen or --listen-unix is needed"),
+        };
+        Ok(Server { listener, token })
+    }
+
+    /// Describes where clients can reach us
+    pub fn describe(&self) -> String {
+        match &self.listener {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => format!("http://{}/", addr),
+                Err(_) => String::from("unknown address"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(_, path) => {
+                format!("Unix socket {}", path.display())
+            }
+        }
+    }
+
+    /// Accepts connections forever, answering each request with whatever
+    /// `handler` returns
+    pub fn serve<F>(self, handler: F) -> Result<(), anyhow::Error>
+    where
+        F: Fn(&Request) -> Response + Sync,
+    {
+        let token = self.token.as_deref();
+        match self.listener {
+            Listener::Tcp(listener) => {
+                accept(listener.incoming(), token, &handler)
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                accept(listener.incoming(), token, &handler)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates a Unix domain socket at `path` that only this user can connect to
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<Listener, anyhow::Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // Remove a socket left behind by an earlier server (but nothing else).
+    if std::fs::symlink_metadata(path)
+        .is_ok_and(|metadata| metadata.file_type().is_socket())
+    {
+        std::fs::remove_file(path)
+            .with_context(|| format!("remove old socket {:?}", path))?;
+    }
+    // Create the socket with mode 0600 from the start, rather than changing
+    // it afterwards, so there's no window where others can connect.
+    // Safety: umask(2) can't fail.
+    let old_umask = unsafe { libc::umask(0o177) };
+    let result = std::os::unix::net::UnixListener::bind(path);
+    unsafe { libc::umask(old_umask) };
+    let listener = result.with_context(|| format!("listen on {:?}", path))?;
+    Ok(Listener::Unix(listener, path.to_path_buf()))
+}
+
+/// Creates a Unix domain socket (not supported on this system)
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path) -> Result<Listener, anyhow::Error> {
+    bail!("--listen-unix is not supported on this system")
+}
+
+/// Handles each connection from `incoming` on its own thread
+///
+/// Connections beyond the first `MAX_CONNECTIONS` are closed right away.
+fn accept<S, F>(
+    incoming: impl Iterator<Item = std::io::Result<S>>,
+    token: Option<&str>,
+    handler: &F,
+) where
+    S: Connection,
+    F: Fn(&Request) -> Response + Sync,
+{
+    let nconnections = &AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for stream in incoming {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    eprintln!("warning: accepting connection: {:#}", error);
+                    continue;
+                }
+            };
+            if nconnections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                nconnections.fetch_sub(1, Ordering::SeqCst);
+                eprintln!(
+                    "warning: too many connections: dropping connection \
+                     from {}",
+                    stream.peer()
+                );
+                continue;
+            }
+            scope.spawn(move || {
+                if let Err(error) = handle_connection(stream, token, handler) {
+                    eprintln!("warning: {:#}", error);
+                }
+                nconnections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+}
+
+/// Reads one request from `stream` and writes the response
+fn handle_connection<S, F>(
+    mut stream: S,
+    token: Option<&str>,
+    handler: &F,
+) -> Result<(), anyhow::Error>
+where
+    S: Connection,
+    F: Fn(&Request) -> Response,
+{
+    let peer = stream.peer();
+    stream.set_read_timeout(READ_TIMEOUT)?;
+    let request = match read_request(&mut stream) {
+        Ok(request) => request,
+        Err(error) => {
+            let response = Response::text(400, format!("{:#}\n", error));
+            return write_response(&mut stream, response, true)
+                .with_context(|| format!("responding to {}", peer));
+        }
+    };
+    let response = if token.is_some_and(|t| !authorized(&request.headers, t)) {
+        Response::text(401, String::from("unauthorized\n")).header(
+            "WWW-Authenticate",
+            String::from("Basic realm=\"gocrash\", charset=\"UTF-8\""),
+        )
+    } else if request.method == "GET" || request.method == "HEAD" {
+        handler(&request)
+    } else {
+        Response::text(405, String::from("method not allowed\n"))
+            .header("Allow", String::from("GET, HEAD"))
+    };
+    write_response(&mut stream, response, request.method != "HEAD")
+        .with_context(|| format!("responding to {}", peer))
+}
+
+/// Reads the token that clients must present from the file at `path`
+pub fn read_token(path: &Path) -> Result<String, anyhow::Error> {
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("read {:?}", path))?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        bail!("token file {:?} is empty", path);
+    }
+    Ok(token)
+}
+
+/// Returns whether a request with `headers` presents `token` (see
+/// `ListenArgs`)
+pub fn authorized(headers: &BTreeMap<String, String>, token: &str) -> bool {
+    let Some(authorization) = headers.get("authorization") else {
+        return false;
+    };
+    let Some((scheme, credentials)) = authorization.split_once(' ') else {
+        return false;
+    };
+    let presented = if scheme.eq_ignore_ascii_case("bearer") {
+        credentials.trim().to_string()
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        let Some(decoded) = base64_decode(credentials.trim()) else {
+            return false;
+        };
+        let decoded = String::from_utf8_lossy(&decoded).into_owned();
+        match decoded.split_once(':') {
+            Some((_user, password)) => password.to_string(),
+            None => return false,
+        }
+    } else {
+        return false;
+    };
+    // Compare every byte regardless of where they differ, so that how long
+    // this takes doesn't reveal how much of the token was right.
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Accepts a request to open a WebSocket, returning a response that sends
+/// messages with `write` (see `write_websocket_message()`)
+///
+/// Anything the client sends after the handshake is ignored.
+pub fn websocket<W>(request: &Request, write: W) -> Result<Response, HttpError>
+where
+    W: FnOnce(&mut dyn Write) -> std::io::Result<()> + 'static,
+{
+    let upgrade = request.headers.get("upgrade");
+    let key = request.headers.get("sec-websocket-key");
+    let (Some(upgrade), Some(key)) = (upgrade, key) else {
+        return Err(HttpError::bad_request(String::from(
+            "expected a WebSocket handshake",
+        )));
+    };
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return Err(HttpError::bad_request(format!(
+            "unsupported upgrade: {:?}",
+            upgrade
+        )));
+    }
+    let accept =
+        base64_encode(&sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()));
+    let response = Response {
+        status: 101,
+        headers: Vec::new(),
+        body: Body::Stream(Box::new(write)),
+    };
+    Ok(response
+        .header("Upgrade", String::from("websocket"))
+        .header("Connection", String::from("Upgrade"))
+        .header("Sec-WebSocket-Accept", accept))
+}
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Kinds of WebSocket messages that we send
+pub const WEBSOCKET_TEXT: u8 = 0x1;
+pub const WEBSOCKET_CLOSE: u8 = 0x8;
+pub const WEBSOCKET_PING: u8 = 0x9;
+
+/// Writes one WebSocket message of kind `opcode` (e.g., `WEBSOCKET_TEXT`) as a
+/// single frame
+pub fn write_websocket_message(
+    stream: &mut dyn Write,
+    opcode: u8,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    // Frames from the server aren't masked.
+    let mut frame = vec![0x80 | opcode];
+    let length = payload.len();
+    if length < 126 {
+        frame.push(u8::try_from(length).unwrap());
+    } else if let Ok(length) = u16::try_from(length) {
+        frame.push(126);
+        frame.extend(length.to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend(u64::try_from(length).unwrap().to_be_bytes());
+    }
+    frame.extend(payload);
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+/// Computes the SHA-1 digest of `data` (which WebSockets use in their
+/// handshake, not for security)
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] =
+        [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((u64::try_from(data.len()).unwrap() * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+    let mut digest = [0; 20];
+    for (chunk, word) in digest.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+const BASE64_ALPHABET: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes `data` as standard base64 (with padding)
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, b)| acc | (u32::from(*b) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (bits >> (18 - 6 * i)) & 0x3f;
+                let index = usize::try_from(index).unwrap();
+                encoded.push(char::from(BASE64_ALPHABET[index]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes standard base64 (with padding), as used for HTTP basic
+/// authentication, returning `None` if `s` isn't exactly what
+/// `base64_encode()` would produce for some data
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let unpadded = s.trim_end_matches('=');
+    if s.len() - unpadded.len() > 2 {
+        return None;
+    }
+    let s = unpadded;
+    let mut decoded = Vec::with_capacity(s.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut nbits = 0;
+    for c in s.bytes() {
+        bits = (bits << 6) | u32::from(value(c)?);
+        nbits += 6;
+        if nbits >= 8 {
+            nbits -= 8;
+            decoded.push(u8::try_from((bits >> nbits) & 0xff).unwrap());
+        }
+    }
+    // The bits left over (before the padding) must be zero.
+    if bits & ((1 << nbits) - 1) != 0 {
+        return None;
+    }
+    Some(decoded)
+}
+
+/// Reads and parses the request line and headers of a request
+fn read_request(stream: &mut impl Read) -> Result<Request, anyhow::Error> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_BYTES));
+    let mut line = String::new();
+    reader.read_line(&mut line).context("reading request")?;
+    let mut words = line.split_whitespace();
+    let (Some(method), Some(target), Some(_version), None) =
+        (words.next(), words.next(), words.next(), words.next())
+    else {
+        bail!("malformed request line: {:?}", line.trim_end());
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = percent_decode(path);
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect();
+
+    let mut headers = BTreeMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).context("reading request")? == 0 {
+            bail!("request headers were cut off");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("malformed header: {:?}", line))?;
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().into());
+    }
+
+    Ok(Request { method: method.to_string(), path, query, headers })
+}
+
+/// Writes `response` to `stream` (leaving out the body if `with_body` is
+/// false, as for `HEAD` requests)
+fn write_response(
+    stream: &mut impl Write,
+    response: Response,
+    with_body: bool,
+) -> Result<(), anyhow::Error> {
+    let length = match &response.body {
+        Body::Bytes(bytes) => Some(u64::try_from(bytes.len()).unwrap()),
+        Body::File(_, length) => Some(*length),
+        Body::Stream(_) => None,
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason_phrase(response.status)
+    );
+    if let Some(length) = length {
+        head.push_str(&format!("Content-Length: {}\r\n", length));
+    }
+    // A WebSocket handshake says "Connection: Upgrade" instead.
+    if response.status != 101 {
+        head.push_str("Connection: close\r\n");
+    }
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    if with_body {
+        match response.body {
+            Body::Bytes(bytes) => stream.write_all(&bytes)?,
+            Body::File(file, length) => {
+                std::io::copy(&mut file.take(length), stream)?;
+            }
+            Body::Stream(write) => write(stream)?,
+        }
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Decodes `%XX` escapes (and `+` for space) in part of a URL
+pub fn percent_decode(s: &str) -> String {
+    let hex = |b: u8| char::from(b).to_digit(16);
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push(u8::try_from(high * 16 + low).unwrap());
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Encodes `s` for use as a value in a query string
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-_.~/".contains(&b) {
+                char::from(b).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::authorized;
+    use super::base64_decode;
+    use super::base64_encode;
+    use super::sha1;
+    use super::websocket;
+    use super::Request;
+    use std::collections::BTreeMap;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_sha1() {
+        // examples from FIPS 180 and RFC 3174, plus inputs on either side
+        // of where the padding needs a second block
+        for (input, expected) in [
+            ("", "da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+            ("abc", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            (
+                "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+            ),
+            (
+                "The quick brown fox jumps over the lazy dog",
+                "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12",
+            ),
+        ] {
+            assert_eq!(hex(&sha1(input.as_bytes())), expected, "{:?}", input);
+        }
+        assert_eq!(
+            hex(&sha1(&[b'a'; 55])),
+            "c1c8bbdc22796e28c0e15163d20899b65621d65a"
+        );
+        assert_eq!(
+            hex(&sha1(&[b'a'; 64])),
+            "0098ba824b5c16427bd7a1122a5a442a25ec644d"
+        );
+    }
+
+    #[test]
+    fn test_base64() {
+        // examples from RFC 4648 section 10
+        for (input, expected) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64_encode(input.as_bytes()), expected);
+            assert_eq!(base64_decode(expected).unwrap(), input.as_bytes());
+        }
+        let all = (0..=255).collect::<Vec<u8>>();
+        assert_eq!(base64_decode(&base64_encode(&all)).unwrap(), all);
+
+        for input in [
+            // missing padding
+            "Zg", "Zg=", "Zm9vYg", // too much padding
+            "Zg===", "Z===", "====", // padding in the middle
+            "Zg==Zg==", "Zm=v", // leftover bits that aren't zero
+            "Zh==", "Zm9=", // characters outside the alphabet
+            "Zm9v!A==", "Zm9 ",
+        ] {
+            assert_eq!(base64_decode(input), None, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_websocket_accept() {
+        // the example from RFC 6455 section 1.3
+        let request = Request {
+            method: String::from("GET"),
+            path: String::from("/chat"),
+            query: BTreeMap::new(),
+            headers: BTreeMap::from([
+                (String::from("upgrade"), String::from("websocket")),
+                (
+                    String::from("sec-websocket-key"),
+                    String::from("dGhlIHNhbXBsZSBub25jZQ=="),
+                ),
+            ]),
+        };
+        let Ok(response) = websocket(&request, |_| Ok(())) else {
+            panic!("handshake failed");
+        };
+        assert_eq!(response.status, 101);
+        assert!(response.headers.contains(&(
+            String::from("Sec-WebSocket-Accept"),
+            String::from("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+        )));
+    }
+
+    #[test]
+    fn test_authorized() {
+        for (authorization, expected) in [
+            ("Bearer s3cret", true),
+            ("bearer s3cret", true),
+            ("Bearer wrong", false),
+            ("Bearer s3cre", false),
+            // "user:s3cret"
+            ("Basic dXNlcjpzM2NyZXQ=", true),
+            ("basic dXNlcjpzM2NyZXQ=", true),
+            // the same without its padding
+            ("Basic dXNlcjpzM2NyZXQ", false),
+            // "user:wrong"
+            ("Basic dXNlcjp3cm9uZw==", false),
+            // "s3cret", with no user
+            ("Basic czNjcmV0", false),
+            ("Digest s3cret", false),
+            ("s3cret", false),
+        ] {
+            let headers = BTreeMap::from([(
+                String::from("authorization"),
+                String::from(authorization),
+            )]);
+            assert_eq!(
+                authorized(&headers, "s3cret"),
+                expected,
+                "{:?}",
+                authorization
+            );
+        }
+        assert!(!authorized(&BTreeMap::new(), "s3cret"));
+    }
+}
@@ -0,0 +1,130 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Foreground and background lanes (`--background-threads`)
+//!
+//! Sometimes one machine has two jobs: narrowing down a reproducer for a
+//! failure that someone is actively chasing, and soaking the test suite in
+//! the background in case anything new turns up.  Rather than running two
+//! sessions that fight over the machine, one session can put some of its
+//! threads (the last `--background-threads` of them) in the background lane.
+//! Background threads:
+//!
+//! * may run a different workload (`--background-test-command`), e.g., the
+//!   whole test suite while foreground threads loop one test with `--go-test`
+//! * may get a smaller share of the CPU (`--background-cpu-weight`)
+//! * are the first to be held, and the last to start again, under
+//!   `--max-load` (see `load.rs`), and the first to be idled when the
+//!   concurrency is lowered over the control socket (since that idles the
+//!   highest-numbered threads)
+//! * never stop the session when they fail: their failures are recorded,
+//!   kept, and reported as usual, but only foreground failures end the
+//!   session (as with `--keep-going` for just those threads)
+//!
+//! The lane is passed to the workload and hooks in `GOCRASH_LANE` (so that
+//! failure hooks can, say, page someone only for foreground failures) and
+//! recorded in each run's metadata.
+
+use anyhow::bail;
+
+/// Which lane a thread is in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Lane {
+    Foreground,
+    Background,
+}
+
+impl Lane {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Lane::Foreground => "foreground",
+            Lane::Background => "background",
+        }
+    }
+}
+
+/// How a session's threads are divided into lanes
+pub struct Lanes {
+    concurrency: u8,
+    /// threads numbered this or higher are in the background lane
+    first_background: u8,
+    /// what background threads run instead of the session's workload
+    background_test_command: Option<String>,
+}
+
+impl Lanes {
+    /// Puts the last `nbackground` of `concurrency` threads in the background
+    /// lane
+    pub fn new(
+        concurrency: u8,
+        nbackground: u8,
+        background_test_command: Option<String>,
+    ) -> Result<Lanes, anyhow::Error> {
+        if nbackground > concurrency {
+            bail!(
+                "--background-threads ({}) cannot be more than --concurrency \
+                 ({})",
+                nbackground,
+                concurrency
+            );
+        }
+        if nbackground == 0 && background_test_command.is_some() {
+            bail!("--background-test-command requires --background-threads");
+        }
+        Ok(Lanes {
+            concurrency,
+            first_background: concurrency - nbackground,
+            background_test_command,
+        })
+    }
+
+    /// Returns whether any threads are in the background lane
+    pub fn any_background(&self) -> bool {
+        self.first_background < self.concurrency
+    }
+
+    /// Returns the lane that thread `which` is in
+    pub fn lane(&self, which: u8) -> Lane {
+        if which >= self.first_background {
+            Lane::Background
+        } else {
+            Lane::Foreground
+        }
+    }
+
+    /// Returns the command that thread `which` runs in place of the session's
+    /// workload, if any
+    pub fn test_command_for(&self, which: u8) -> Option<&String> {
+        match self.lane(which) {
+            Lane::Background => self.background_test_command.as_ref(),
+            Lane::Foreground => None,
+        }
+    }
+
+    /// Describes which threads are in which lane (e.g., "foreground: threads
+    /// 0-1; background: threads 2-3 (running "make test")")
+    pub fn describe(&self) -> String {
+        let threads = |first: u8, end: u8| match end - first {
+            1 => format!("thread {}", first),
+            _ => format!("threads {}-{}", first, end - 1),
+        };
+        let mut rv = Vec::new();
+        if self.first_background > 0 {
+            rv.push(format!(
+                "foreground: {}",
+                threads(0, self.first_background)
+            ));
+        }
+        rv.push(format!(
+            "background: {}{}",
+            threads(self.first_background, self.concurrency),
+            self.background_test_command
+                .as_ref()
+                .map(|command| format!(" (running {:?})", command))
+                .unwrap_or_default()
+        ));
+        rv.join("; ")
+    }
+}
@@ -0,0 +1,5051 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Command to run the Go test suite in parallel in a loop, using ZFS snapshots
+//! and clones to quickly ensure a clean slate every time (or, where ZFS isn't
+//! available, plain copies; see `backend.rs`)
+//!
+//! This is built into two commands.  `gocrash` does everything.
+//! `gocrash-exec` only has the parts that need to run on the machine being
+//! tested: running sessions (directly, with `watch`, or in a pipeline) and
+//! looking after what they leave behind there.  The rest (merging, exporting,
+//! and analyzing history databases, checking archives, and serving the web
+//! UI) works from files that can be copied elsewhere, so it can be left to
+//! `gocrash` on another machine.
+
+use anyhow::anyhow;
+use anyhow::Context;
+use clap::CommandFactory;
+use clap::FromArgMatches;
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+#[cfg(feature = "tui")]
+use std::io::IsTerminal;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[macro_use]
+mod console;
+mod archive;
+mod backend;
+mod batch;
+pub mod callbacks;
+mod capture;
+mod catalog;
+mod clean;
+mod compare;
+mod config;
+mod control;
+mod coredump;
+#[cfg(feature = "sqlite")]
+mod correlate;
+mod cpu;
+mod destroyer;
+mod error;
+mod events;
+#[cfg(feature = "sqlite")]
+mod export;
+mod fingerprint;
+mod gotest;
+#[cfg(all(unix, feature = "http"))]
+mod grpc;
+#[cfg(all(unix, feature = "http"))]
+mod h2;
+mod health;
+#[cfg(feature = "sqlite")]
+mod history;
+mod hooks;
+#[cfg(feature = "http")]
+mod http;
+mod lane;
+mod list;
+#[cfg(feature = "http")]
+mod live;
+mod load;
+#[cfg(feature = "http")]
+mod metrics;
+mod netlog;
+mod pairing;
+mod pipeline;
+mod postmortem;
+mod preclone;
+mod privileges;
+mod provenance;
+mod psi;
+mod pty;
+mod retention;
+mod rng;
+mod session;
+mod severity;
+mod show;
+mod shutdown;
+mod signature;
+mod simulate;
+#[cfg(feature = "http")]
+mod status;
+mod stop;
+mod storage;
+mod store;
+mod timing;
+#[cfg(feature = "tui")]
+mod tui;
+mod verify;
+mod watch;
+#[cfg(all(feature = "http", feature = "sqlite"))]
+mod web;
+mod zcp;
+mod zfs;
+
+use backend::Capability;
+use session::RunState;
+use session::SESSION_PROPERTY;
+
+pub use error::GocrashError;
+pub use shutdown::CancellationToken;
+pub use shutdown::Shutdown;
+
+/// Runs the `gocrash` command
+pub fn main() {
+    let (mut args, matches) = parse_command_line::<Args>();
+    args.record_sources(&matches);
+    run(args)
+}
+
+/// Runs the `gocrash-exec` command
+pub fn exec_main() {
+    let (args, matches) = parse_command_line::<ExecArgs>();
+    let mut args = Args {
+        command: args.command.map(GocrashCommand::from),
+        run: args.run,
+        snapshot: args.snapshot,
+    };
+    args.record_sources(&matches);
+    run(args)
+}
+
+/// Parses the command line as `P::parse()` does, also returning the matches
+/// (so that we can tell where each option came from)
+fn parse_command_line<P: Parser>() -> (P, clap::ArgMatches) {
+    let matches = P::command().get_matches();
+    let args = P::from_arg_matches(&matches)
+        .unwrap_or_else(|error| error.format(&mut P::command()).exit());
+    (args, matches)
+}
+
+/// Runs gocrash with command-line arguments `argv` (starting with the
+/// program name, as for the `gocrash` command), returning any error (see
+/// `GocrashError`) rather than reporting it and exiting
+///
+/// This is for programs that use gocrash as a library, usually along with
+/// `callbacks::register()`.  Cancelling `cancel` (say, from another thread)
+/// stops the session the way SIGINT does for the `gocrash` command: runs in
+/// progress are killed and cleaned up, and this returns once that's done, with
+/// `GocrashError::Interrupted`.  We don't install handlers for SIGINT or
+/// SIGTERM here, so that's how the caller should handle them, too.
+pub fn run_with_args<I, T>(
+    argv: I,
+    cancel: &CancellationToken,
+) -> Result<(), GocrashError>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let matches = Args::command()
+        .try_get_matches_from(argv)
+        .map_err(GocrashError::Usage)?;
+    let mut args =
+        Args::from_arg_matches(&matches).map_err(GocrashError::Usage)?;
+    args.record_sources(&matches);
+    execute(&args, cancel).map_err(GocrashError::from_anyhow)
+}
+
+fn run(args: Args) {
+    // Commands that run sessions shut them down gracefully on SIGINT and
+    // SIGTERM (see `shutdown.rs`).  Others just die as usual.
+    if matches!(
+        &args.command,
+        None | Some(GocrashCommand::Watch(_))
+            | Some(GocrashCommand::Pipeline(_))
+    ) {
+        if let Err(error) = shutdown::install() {
+            eprintln!("warning: {:#}", error);
+        }
+    }
+    if let Err(error) = execute(&args, &CancellationToken::new()) {
+        let error = GocrashError::from_anyhow(error);
+        eprintln!("gocrash: {}", error);
+        std::process::exit(error.exit_code());
+    }
+}
+
+fn execute(
+    args: &Args,
+    cancel: &CancellationToken,
+) -> Result<(), anyhow::Error> {
+    match &args.command {
+        None => gocrash(&args.run, args.snapshot.as_deref().unwrap(), cancel),
+        Some(GocrashCommand::Postmortem(pm_args)) => {
+            postmortem::postmortem(pm_args)
+        }
+        Some(GocrashCommand::Watch(watch_args)) => {
+            watch::watch(watch_args, cancel)
+        }
+        Some(GocrashCommand::Pipeline(pipeline_args)) => {
+            pipeline::pipeline(pipeline_args, cancel)
+        }
+        #[cfg(feature = "sqlite")]
+        Some(GocrashCommand::History(history_args)) => {
+            history::history(history_args)
+        }
+        Some(GocrashCommand::AnalyzeCore(analyze_args)) => {
+            coredump::analyze_core(analyze_args)
+        }
+        #[cfg(feature = "sqlite")]
+        Some(GocrashCommand::Correlate(correlate_args)) => {
+            correlate::correlate(correlate_args)
+        }
+        #[cfg(feature = "sqlite")]
+        Some(GocrashCommand::Export(export_args)) => {
+            export::export(export_args)
+        }
+        Some(GocrashCommand::Clean(clean_args)) => clean::clean(clean_args),
+        Some(GocrashCommand::List(list_args)) => list::list(list_args),
+        #[cfg(unix)]
+        Some(GocrashCommand::Status(status_args)) => {
+            control::status(status_args)
+        }
+        Some(GocrashCommand::VerifyArtifacts(verify_args)) => {
+            verify::verify_artifacts(verify_args)
+        }
+        Some(GocrashCommand::Show(show_args)) => show::show(show_args),
+        Some(GocrashCommand::CompareSessions(compare_args)) => {
+            compare::compare_sessions(compare_args)
+        }
+        Some(GocrashCommand::Simulate(simulate_args)) => {
+            simulate::simulate(simulate_args)
+        }
+        #[cfg(all(feature = "http", feature = "sqlite"))]
+        Some(GocrashCommand::Serve(serve_args)) => web::serve(serve_args),
+    }
+}
+
+/// Run the Go test suite in a loop until it fails
+#[derive(Parser)]
+#[command(
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<GocrashCommand>,
+
+    #[command(flatten)]
+    run: RunArgs,
+
+    /// ZFS snapshot for dataset containing "goroot" (or, with "--backend
+    /// copy", a directory containing "goroot")
+    #[arg(required = true)]
+    snapshot: Option<String>,
+}
+
+impl Args {
+    /// Notes which session options were given in `matches` (from which these
+    /// arguments were parsed), including those for `gocrash watch`
+    fn record_sources(&mut self, matches: &clap::ArgMatches) {
+        self.run.sources = config::Sources::from_matches(matches);
+        if let (Some(GocrashCommand::Watch(watch_args)), Some(("watch", sub))) =
+            (&mut self.command, matches.subcommand())
+        {
+            watch_args.run.sources = config::Sources::from_matches(sub);
+        }
+    }
+}
+
+/// Run the Go test suite in a loop until it fails (with only the commands
+/// that need to run on the machine being tested)
+#[derive(Parser)]
+#[command(
+    name = "gocrash-exec",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct ExecArgs {
+    #[command(subcommand)]
+    command: Option<ExecCommand>,
+
+    #[command(flatten)]
+    run: RunArgs,
+
+    /// ZFS snapshot for dataset containing "goroot" (or, with "--backend
+    /// copy", a directory containing "goroot")
+    #[arg(required = true)]
+    snapshot: Option<String>,
+}
+
+/// Options controlling a session of test suite runs
+///
+/// These can also be specified for each campaign in a pipeline file (see
+/// `pipeline.rs`), using the same names as the command-line flags.
+#[derive(clap::Args, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct RunArgs {
+    /// how to make a fresh copy of the source tree for each run
+    #[arg(long, value_enum, default_value_t)]
+    backend: backend::Backend,
+
+    /// how many concurrent threads to run the test suite
+    #[arg(long, default_value_t = 2)]
+    concurrency: u8,
+
+    /// give each thread's runs this relative share of CPU time when the
+    /// system is busy (1 to 10000; with several comma-separated weights,
+    /// thread N gets the Nth weight, starting over if there are more threads
+    /// than weights)
+    #[arg(
+        long,
+        value_name = "WEIGHT",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u32).range(
+            i64::from(cpu::MIN_WEIGHT)..=i64::from(cpu::MAX_WEIGHT)
+        )
+    )]
+    cpu_weight: Vec<u32>,
+
+    /// put the last N threads in the background lane: their failures never
+    /// stop the session, and they give way to the other (foreground) threads
+    /// under --max-load
+    #[arg(long, default_value_t = 0, value_name = "N")]
+    background_threads: u8,
+
+    /// have threads in the background lane run this shell command instead
+    /// of the session's workload (with the same replacements as
+    /// --test-command)
+    #[arg(long, value_name = "COMMAND")]
+    background_test_command: Option<String>,
+
+    /// give threads in the background lane this relative share of CPU time
+    /// (see --cpu-weight; other threads get their --cpu-weight, or 100)
+    #[arg(
+        long,
+        value_name = "WEIGHT",
+        value_parser = clap::value_parser!(u32).range(
+            i64::from(cpu::MIN_WEIGHT)..=i64::from(cpu::MAX_WEIGHT)
+        )
+    )]
+    background_cpu_weight: Option<u32>,
+
+    /// hold off on starting new runs while the 1-minute load average is
+    /// above this (one run is always allowed), starting them again, a thread
+    /// at a time, once it comes back down
+    #[arg(long, value_name = "LOAD")]
+    max_load: Option<f64>,
+
+    /// instead of the whole test suite, have each run do one long `go test`
+    /// with these arguments (e.g., "-count=1000 -run TestFoo runtime"), using
+    /// the toolchain already built in "goroot/bin".  The run is stopped at
+    /// the first test failure, and hangs are detected with
+    /// --output-stall-timeout (10 minutes by default).
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true)]
+    go_test: Option<String>,
+
+    /// instead of the Go test suite, have each run do this shell command, in
+    /// which "{mountpoint}", "{thread}", and "{run}" are replaced with the
+    /// run's clone (quoted for the shell), thread, and run number
+    #[arg(long, value_name = "COMMAND", conflicts_with = "go_test")]
+    test_command: Option<String>,
+
+    /// run the workload in this directory (relative to the run's clone, with
+    /// the same replacements as --test-command; by default, "goroot/src", or
+    /// the top of the clone with --test-command)
+    #[arg(long, value_name = "DIR")]
+    workdir: Option<String>,
+
+    /// stop after each thread does this many runs
+    /// (leave unspecified to run until failure)
+    #[arg(long)]
+    stop_after: Option<usize>,
+
+    /// keep going after failures, stopping once the same failure (as
+    /// identified by its signature) has been captured this many times
+    #[arg(long, value_name = "N")]
+    stop_when_reproduced: Option<usize>,
+
+    /// keep going after every failure (keeping each failed run's dataset as
+    /// usual), and list every failed run in the final report
+    #[arg(long, default_value_t = false)]
+    keep_going: bool,
+
+    /// keep going after failures, stopping once this condition holds (e.g.,
+    /// "(failures >= 3 AND new_signatures >= 1) OR elapsed >= 12h"; see the
+    /// README for the counters available)
+    #[arg(long, value_name = "CONDITION")]
+    stop_when: Option<String>,
+
+    /// after each failure, immediately rerun the test suite this many times
+    /// (from fresh clones, in the same thread) and report whether the failure
+    /// recurred
+    #[arg(long, default_value_t = 0, value_name = "N")]
+    verify_failures: usize,
+
+    /// group runs into batches of this many (across all threads), waiting for
+    /// all of the runs in each batch to finish before starting the next
+    #[arg(long, value_name = "N")]
+    batch_size: Option<usize>,
+
+    /// between batches (see --batch-size), do this: "summary" (print a
+    /// summary of the session so far), "trim" (trim the pool and wait for
+    /// that to finish), or "run:COMMAND" (run a shell command) (may be
+    /// repeated)
+    #[arg(long, value_name = "ACTION")]
+    between_batches: Vec<String>,
+
+    /// save output from successful test runs
+    #[arg(long, default_value_t = false)]
+    keep_success: bool,
+
+    /// rather than destroying the clone of each run that isn't kept, roll it
+    /// back and use it for the thread's next run (ZFS only)
+    #[arg(long, default_value_t = false)]
+    reuse_clones: bool,
+
+    /// keep this many clones ready ahead of time (made by a separate thread),
+    /// so that each run can start without waiting to clone the source
+    #[arg(long, value_name = "N")]
+    preclone: Option<usize>,
+
+    /// if no runs fail, destroy the working dataset (including datasets kept
+    /// with --keep-success) before exiting
+    #[arg(long, default_value_t = false)]
+    destroy_all_on_success_exit: bool,
+
+    /// run the test suite with the caller's HOME, GOPATH, GOMODCACHE, and
+    /// GOENV rather than per-run directories inside the clone
+    #[arg(long, default_value_t = false)]
+    no_isolate_env: bool,
+
+    /// run the test suite without network access (other than loopback)
+    /// (currently Linux only)
+    #[arg(long, default_value_t = false)]
+    no_network: bool,
+
+    /// run the test suite even though gocrash is running as root (or, on
+    /// illumos, with all privileges), which it otherwise refuses to do
+    #[arg(long, default_value_t = false)]
+    allow_root: bool,
+
+    /// record outbound network connections (including DNS lookups) made by
+    /// each run, using strace(1) on Linux or truss(1) elsewhere
+    #[arg(long, default_value_t = false)]
+    log_network: bool,
+
+    /// run the test suite on a pseudo-terminal, using script(1), for tests
+    /// that behave differently (or hang) when their output isn't a terminal
+    /// (currently Linux only)
+    #[arg(long, default_value_t = false)]
+    pty: bool,
+
+    /// with --pty, type a newline on the terminal whenever the test suite has
+    /// printed nothing for this long (e.g., "5m"; in seconds if no unit is
+    /// given), for tests that wait for input
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = timing::parse_duration_secs
+    )]
+    pty_keepalive: Option<u64>,
+
+    /// print complete error details in the final per-thread summary (by
+    /// default, only the first line is printed and the rest is saved to a
+    /// file in the working dataset)
+    #[arg(long, default_value_t = false)]
+    verbose_summary: bool,
+
+    /// also write the machine-readable session summary ("session.json",
+    /// normally saved in the working dataset) to this path
+    #[arg(long, value_name = "PATH")]
+    session_json: Option<std::path::PathBuf>,
+
+    /// print every option's value, and whether it came from the command
+    /// line, a pipeline file, or the defaults, when the session starts (this
+    /// is always recorded in "session.json")
+    #[arg(long, default_value_t = false)]
+    print_config: bool,
+
+    /// number runs in this session starting from this value (useful to keep
+    /// run ids unique when a campaign is split across several sessions)
+    #[arg(long, default_value_t = 0, value_name = "ID")]
+    run_id_start: usize,
+
+    /// record sessions and runs in this SQLite database (created if needed)
+    #[arg(long, value_name = "PATH")]
+    history_db: Option<std::path::PathBuf>,
+
+    /// how to compute signatures for failed runs, based on what the workload
+    /// prints when it fails
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = signature::SignatureProfile::Go,
+        value_name = "PROFILE"
+    )]
+    signature_profile: signature::SignatureProfile,
+
+    /// use the first output line starting with this prefix as the signature
+    /// of a failed run, in preference to the signature profile (may be
+    /// repeated)
+    #[arg(long, value_name = "PREFIX")]
+    signature_prefix: Vec<String>,
+
+    /// label failed runs that match an entry in this catalog of known
+    /// failures: a TOML or YAML file, a URL, or "git+REPO#PATH" for a file
+    /// in a git repository (fetched when the session starts; may be
+    /// repeated)
+    #[arg(long, value_name = "SOURCE")]
+    signature_catalog: Vec<String>,
+
+    /// keep going after failures that match an entry in --signature-catalog,
+    /// stopping only for new failures
+    #[arg(long, default_value_t = false)]
+    stop_on_new_failure: bool,
+
+    /// keep datasets for at most N failed runs matching the known failure
+    /// NAME (from --signature-catalog) or having signature SIGNATURE,
+    /// destroying the datasets of later ones (may be repeated)
+    #[arg(long, value_name = "NAME=N|SIGNATURE=N")]
+    keep_at_most: Vec<String>,
+
+    /// keep datasets for at most N failed runs with each signature,
+    /// destroying the datasets of later ones (unless --keep-at-most or the
+    /// catalog sets a limit for the failure)
+    #[arg(long, value_name = "N")]
+    keep_per_signature: Option<usize>,
+
+    /// once a failure has as many runs kept as --keep-per-signature,
+    /// --keep-at-most, or the catalog allows, keep this fraction of later
+    /// ones anyway (chosen at random, e.g., "0.1" for 10%)
+    #[arg(
+        long,
+        value_name = "FRACTION",
+        value_parser = retention::parse_fraction
+    )]
+    sample_duplicates: Option<f64>,
+
+    /// classify failed runs whose output contains TEXT as SEVERITY, in
+    /// preference to the built-in rules (may be repeated)
+    #[arg(long, value_name = "SEVERITY=TEXT")]
+    severity_rule: Vec<String>,
+
+    /// run the test suite with TMPDIR inside each run's clone (and "-work"
+    /// in GOFLAGS) and, after a failure, gather the test binaries and
+    /// temporary directories left by the packages and tests that failed into
+    /// "gocrash_output/go-test"
+    #[arg(long, default_value_t = false)]
+    collect_go_test_artifacts: bool,
+
+    /// run this shell command in each run's clone after the test suite
+    /// finishes; if it prints a JSON object, its fields are recorded in the
+    /// run's metadata
+    #[arg(long, value_name = "COMMAND")]
+    post_run_hook: Option<String>,
+
+    /// like --post-run-hook, but only for failed runs
+    #[arg(long, value_name = "COMMAND")]
+    failure_hook: Option<String>,
+
+    /// what to do when a hook fails or prints something other than a JSON
+    /// object
+    #[arg(long, value_enum, default_value_t = hooks::HookFailure::Log)]
+    on_hook_failure: hooks::HookFailure,
+
+    /// only stop for failures at least this severe (less severe failures are
+    /// recorded, but the session keeps going)
+    #[arg(long, value_enum, value_name = "SEVERITY")]
+    stop_severity: Option<severity::Severity>,
+
+    /// copy artifacts from each failed run (and each passed run, with
+    /// --keep-success) into a subdirectory of this directory (see
+    /// --archive-include and --archive-exclude)
+    #[arg(long, value_name = "DIR")]
+    archive_dir: Option<std::path::PathBuf>,
+
+    /// also archive paths in the clone matching this pattern, where "*"
+    /// matches any sequence of characters (may be repeated; by default,
+    /// "goroot/bin", "gocrash_output", test binaries, and core files are
+    /// archived)
+    #[arg(long, value_name = "PATTERN")]
+    archive_include: Vec<String>,
+
+    /// also skip directories in the clone matching this pattern unless they
+    /// are included explicitly (may be repeated; by default, "goroot/pkg" and
+    /// "obj" directories are skipped)
+    #[arg(long, value_name = "PATTERN")]
+    archive_exclude: Vec<String>,
+
+    /// kill a run (and count it as "runaway") if its clone uses more than this
+    /// much space (e.g., "30G")
+    #[arg(long, value_name = "BYTES", value_parser = storage::parse_bytes)]
+    max_run_bytes: Option<u64>,
+
+    /// before starting each run, check that at least this much space is
+    /// available for the working dataset (e.g., "10G"), and if not, hold off
+    /// on new runs until there is ("0" to skip the check)
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "1G",
+        value_parser = storage::parse_bytes
+    )]
+    min_free: u64,
+
+    /// limit the working dataset (including the clones of runs in progress
+    /// and kept runs) to this much space (e.g., "200G"), so that a session
+    /// can't fill the pool
+    #[arg(long, value_name = "BYTES", value_parser = storage::parse_bytes)]
+    quota: Option<u64>,
+
+    /// retry ZFS commands that fail because a dataset is busy (e.g., `zfs
+    /// destroy` while a process from the run is still exiting) up to this
+    /// many times before giving up
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    zfs_retries: u32,
+
+    /// how long to wait before the first retry of a ZFS command (e.g., "2s";
+    /// in seconds if no unit is given), doubling for each retry after that
+    #[arg(
+        long,
+        value_name = "DURATION",
+        default_value = "1",
+        value_parser = timing::parse_duration_secs
+    )]
+    zfs_retry_delay: u64,
+
+    /// while each run is in progress, take a ZFS snapshot of its clone this
+    /// often (e.g., "10m"; in seconds if no unit is given), so that a failed
+    /// run also shows what its clone looked like before the failure
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = timing::parse_duration_secs
+    )]
+    snapshot_interval: Option<u64>,
+
+    /// treat a run as hung if the test suite writes no output for this long
+    /// (e.g., "20m"; in seconds if no unit is given).  Before killing it, the
+    /// process tree and each process's stack are saved in the clone, and Go
+    /// processes are sent SIGQUIT so that they dump their stacks.
+    #[arg(
+        long,
+        visible_alias = "stall-timeout",
+        value_name = "DURATION",
+        value_parser = timing::parse_duration_secs
+    )]
+    #[serde(alias = "stall-timeout")]
+    output_stall_timeout: Option<u64>,
+
+    /// treat a run as hung if the workload takes longer than this (e.g.,
+    /// "2h"; in seconds if no unit is given).  Go processes are sent SIGQUIT
+    /// so that they dump their stacks, and then everything the workload
+    /// started is killed.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = timing::parse_duration_secs
+    )]
+    run_timeout: Option<u64>,
+
+    /// treat a run as hung if the workload, or any phase of it, takes longer
+    /// than this multiple (default 1.5) of the 99th percentile of its
+    /// duration in passing runs.  Durations are learned from this session and
+    /// (with --history-db) from earlier sessions on this host.
+    #[arg(
+        long,
+        value_name = "FACTOR",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "1.5",
+        value_parser = timing::parse_timeout_factor
+    )]
+    adaptive_timeout: Option<f64>,
+
+    /// stop the session after this long (e.g., "3h"; in seconds if no unit is
+    /// given), killing any runs still in progress, and report the results as
+    /// usual
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = timing::parse_duration_secs
+    )]
+    session_timeout: Option<u64>,
+
+    /// stop starting new runs after this long (e.g., "8h"; in seconds if no
+    /// unit is given), let the runs in progress finish, and report the
+    /// results as usual
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = timing::parse_duration_secs
+    )]
+    max_duration: Option<u64>,
+
+    /// stop starting new runs at this time (e.g., "07:00" for the next 7am
+    /// local time, or an RFC 3339 timestamp), let the runs in progress
+    /// finish, and report the results as usual
+    #[arg(long, value_name = "TIME")]
+    stop_at: Option<String>,
+
+    /// also save each run's stdout and stderr interleaved in one file, with
+    /// each line tagged with which stream it came from and when
+    /// ("--combined-log=only" saves only the combined file)
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "also"
+    )]
+    combined_log: Option<capture::CombinedLog>,
+
+    /// prefix each line saved from the test suite's stdout and stderr with
+    /// the number of seconds since the test suite started
+    #[arg(long, default_value_t = false)]
+    timestamp_lines: bool,
+
+    /// how to report progress on stdout ("json" writes one JSON object per
+    /// event and sends the usual messages to stderr)
+    #[arg(long, value_enum, default_value_t)]
+    format: events::OutputFormat,
+
+    /// show a live dashboard with one row per thread instead of a message for
+    /// each step of each run (ignored if stdout isn't a terminal)
+    #[arg(long, default_value_t = false)]
+    tui: bool,
+
+    /// while the session runs, serve live progress over HTTP on this address
+    /// (e.g., "127.0.0.1:8080"): a WebSocket at "/events" sends the same
+    /// events as "--format json", and "/runs/ID/log?follow=1" streams a
+    /// run's output as it's written
+    #[arg(long, value_name = "ADDR")]
+    live_listen: Option<std::net::SocketAddr>,
+
+    /// with --live-listen, require clients to present the token in this file
+    /// (as with "gocrash serve --token-file")
+    #[arg(long, value_name = "PATH", requires = "live_listen")]
+    live_token_file: Option<std::path::PathBuf>,
+
+    /// while the session runs, serve the control operations (status, pause,
+    /// resume, stop, and set concurrency) and the events of "--format json"
+    /// over gRPC on this address (e.g., "127.0.0.1:50051"), as described in
+    /// "proto/gocrash.proto"
+    #[arg(long, value_name = "ADDR")]
+    grpc_listen: Option<std::net::SocketAddr>,
+
+    /// with --grpc-listen, require each call to present the token in this
+    /// file as "authorization: Bearer TOKEN" metadata
+    #[arg(long, value_name = "PATH", requires = "grpc_listen")]
+    grpc_token_file: Option<std::path::PathBuf>,
+
+    /// serve Prometheus metrics at "/metrics" on this address (e.g.,
+    /// "127.0.0.1:9090"): runs started, finished (by result), and in
+    /// progress, how long each phase took, and free space in the pool
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<std::net::SocketAddr>,
+
+    /// with --metrics-addr, require clients to present the token in this file
+    /// (as with "gocrash serve --token-file")
+    #[arg(long, value_name = "PATH", requires = "metrics_addr")]
+    metrics_token_file: Option<std::path::PathBuf>,
+
+    /// serve a JSON description of the running session at "/status" on this
+    /// address (e.g., "127.0.0.1:8090"): what each thread is running, counts
+    /// of runs by result, uptime, the last failure, and the options used
+    #[arg(long, value_name = "ADDR")]
+    status_addr: Option<std::net::SocketAddr>,
+
+    /// with --status-addr, require clients to present the token in this file
+    /// (as with "gocrash serve --token-file")
+    #[arg(long, value_name = "PATH", requires = "status_addr")]
+    status_token_file: Option<std::path::PathBuf>,
+
+    /// where each of these options came from (see `config.rs`)
+    #[arg(skip)]
+    #[serde(skip)]
+    sources: config::Sources,
+}
+
+impl Default for RunArgs {
+    fn default() -> Self {
+        // Use the same defaults as the command-line interface.
+        let command = <RunArgs as clap::Args>::augment_args(
+            clap::Command::new("gocrash"),
+        );
+        let matches = command.get_matches_from(["gocrash"]);
+        <RunArgs as clap::FromArgMatches>::from_arg_matches(&matches).unwrap()
+    }
+}
+
+/// Commands other than running the test suite
+#[derive(clap::Subcommand)]
+enum GocrashCommand {
+    /// Report on the runs that were in progress when the host went down
+    Postmortem(postmortem::PostmortemArgs),
+    /// Run a bounded session against each new snapshot that shows up
+    Watch(Box<watch::WatchArgs>),
+    /// Run a series of sessions and commands described in a file
+    Pipeline(pipeline::PipelineArgs),
+    /// Work with history databases
+    #[cfg(feature = "sqlite")]
+    History(history::HistoryArgs),
+    /// Produce stack listings for core files left by a failed run
+    AnalyzeCore(coredump::AnalyzeCoreArgs),
+    /// Rank recorded factors by how much they differ between failing and
+    /// passing runs
+    #[cfg(feature = "sqlite")]
+    Correlate(correlate::CorrelateArgs),
+    /// Export the history database for offline analysis
+    #[cfg(feature = "sqlite")]
+    Export(export::ExportArgs),
+    /// Destroy working datasets left behind by earlier sessions
+    Clean(clean::CleanArgs),
+    /// Show the sessions whose working datasets are still around
+    List(list::ListArgs),
+    /// Show what a running session is doing
+    #[cfg(unix)]
+    Status(control::StatusArgs),
+    /// Check archived artifacts for corruption or missing files
+    VerifyArtifacts(verify::VerifyArtifactsArgs),
+    /// Show an archived run without extracting it
+    Show(show::ShowArgs),
+    /// Show what's different between two sessions
+    CompareSessions(compare::CompareSessionsArgs),
+    /// Try out stop conditions and reporting against simulated failures
+    Simulate(simulate::SimulateArgs),
+    /// Browse the history database in a web browser
+    #[cfg(all(feature = "http", feature = "sqlite"))]
+    Serve(web::ServeArgs),
+}
+
+/// The commands in `gocrash-exec` (see the top of this file)
+#[derive(clap::Subcommand)]
+enum ExecCommand {
+    /// Report on the runs that were in progress when the host went down
+    Postmortem(postmortem::PostmortemArgs),
+    /// Run a bounded session against each new snapshot that shows up
+    Watch(Box<watch::WatchArgs>),
+    /// Run a series of sessions and commands described in a file
+    Pipeline(pipeline::PipelineArgs),
+    /// Produce stack listings for core files left by a failed run
+    AnalyzeCore(coredump::AnalyzeCoreArgs),
+    /// Destroy working datasets left behind by earlier sessions
+    Clean(clean::CleanArgs),
+    /// Show the sessions whose working datasets are still around
+    List(list::ListArgs),
+    /// Show what a running session is doing
+    #[cfg(unix)]
+    Status(control::StatusArgs),
+}
+
+impl From<ExecCommand> for GocrashCommand {
+    fn from(command: ExecCommand) -> GocrashCommand {
+        match command {
+            ExecCommand::Postmortem(args) => GocrashCommand::Postmortem(args),
+            ExecCommand::Watch(args) => GocrashCommand::Watch(args),
+            ExecCommand::Pipeline(args) => GocrashCommand::Pipeline(args),
+            ExecCommand::AnalyzeCore(args) => GocrashCommand::AnalyzeCore(args),
+            ExecCommand::Clean(args) => GocrashCommand::Clean(args),
+            ExecCommand::List(args) => GocrashCommand::List(args),
+            #[cfg(unix)]
+            ExecCommand::Status(args) => GocrashCommand::Status(args),
+        }
+    }
+}
+
+/// Runs the guts of the `gocrash` command, saving a summary of what happened
+/// (see `session::SessionRecord`) however it turns out
+///
+/// The session stops early (as for SIGINT) if `cancel` is cancelled.
+fn gocrash(
+    args: &RunArgs,
+    snapshot: &str,
+    cancel: &CancellationToken,
+) -> Result<(), anyhow::Error> {
+    console::set_events_on_stdout(args.format == events::OutputFormat::Json);
+    zfs::set_policy(zfs::RetryPolicy {
+        retries: args.zfs_retries,
+        delay: std::time::Duration::from_secs(args.zfs_retry_delay),
+    });
+
+    // Determine a unique name for this session (and our working dataset).
+    let timestamp_millis = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let gocrash_key = format!("gocrash-{}", timestamp_millis);
+
+    let parameters = serde_json::to_value(args)?;
+    let configuration = config::effective(&parameters, &args.sources);
+    if args.print_config {
+        config::print(&configuration);
+    }
+    let mut record =
+        session::SessionRecord::new(gocrash_key, snapshot, parameters);
+    record.configuration = configuration;
+    #[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+    let (history, result) = match open_history(args) {
+        Ok(history) => {
+            let result = gocrash_session(
+                args,
+                snapshot,
+                &mut record,
+                history.as_ref(),
+                cancel,
+            );
+            (history, result)
+        }
+        Err(error) => (None, Err(error)),
+    };
+    record.finish(&result);
+    #[cfg(feature = "sqlite")]
+    if let Some(history) = &history {
+        if let Err(error) = history.lock().unwrap().record_session(&record) {
+            eprintln!("warning: {:#}", error);
+        }
+    }
+    let paths = match record.save(args.session_json.as_deref()) {
+        Ok(paths) => {
+            for path in &paths {
+                text_println!("session summary: {}", path.display());
+            }
+            paths
+        }
+        Err(error) => {
+            eprintln!("warning: saving session summary: {:#}", error);
+            Vec::new()
+        }
+    };
+    events::emit(
+        &record.session,
+        &events::Event::SessionEnd {
+            outcome: record.outcome.map(|o| o.as_str()),
+            error: record.error.as_deref(),
+            stop_condition: record
+                .stop_reason
+                .as_ref()
+                .map(|r| r.condition.as_str()),
+            stop_detail: record.stop_reason.as_ref().map(|r| r.detail.as_str()),
+            results: &record.results,
+            session_json: &paths,
+        },
+    );
+    result
+}
+
+/// The history database that runs are recorded in (see `--history-db`)
+#[cfg(feature = "sqlite")]
+type HistoryDb = Mutex<history::History>;
+
+/// Without the "sqlite" feature, there's never a history database.
+#[cfg(not(feature = "sqlite"))]
+type HistoryDb = std::convert::Infallible;
+
+/// Opens the history database given with `--history-db`, if any
+#[cfg(feature = "sqlite")]
+fn open_history(args: &RunArgs) -> Result<Option<HistoryDb>, anyhow::Error> {
+    args.history_db
+        .as_deref()
+        .map(|path| Ok(Mutex::new(history::History::open(path)?)))
+        .transpose()
+}
+
+/// Opens the history database given with `--history-db`, if any
+#[cfg(not(feature = "sqlite"))]
+fn open_history(args: &RunArgs) -> Result<Option<HistoryDb>, anyhow::Error> {
+    match args.history_db {
+        Some(_) => Err(missing_feature("--history-db", "sqlite")),
+        None => Ok(None),
+    }
+}
+
+/// Returns an error for an option that needs a Cargo feature that this build
+/// of gocrash was built without
+fn missing_feature(option: &str, feature: &str) -> anyhow::Error {
+    anyhow!(
+        "{} is not available (gocrash was built without the {:?} feature)",
+        option,
+        feature
+    )
+}
+
+/// Runs one session of the test suite, updating `record` as we go
+fn gocrash_session(
+    args: &RunArgs,
+    snapshot: &str,
+    record: &mut session::SessionRecord,
+    history: Option<&HistoryDb>,
+    cancel: &CancellationToken,
+) -> Result<(), anyhow::Error> {
+    args.backend.check_supported()?;
+    if !args.cpu_weight.is_empty() || args.background_cpu_weight.is_some() {
+        cpu::check_supported()?;
+    }
+    let lanes = lane::Lanes::new(
+        args.concurrency,
+        args.background_threads,
+        args.background_test_command.clone(),
+    )?;
+    if args.background_cpu_weight.is_some() && !lanes.any_background() {
+        return Err(anyhow!(
+            "--background-cpu-weight requires --background-threads"
+        ));
+    }
+    if let Some(max_load) = args.max_load {
+        load::check_supported()?;
+        if max_load.is_nan() || max_load <= 0.0 {
+            return Err(anyhow!("--max-load must be greater than 0"));
+        }
+    }
+    if args.max_run_bytes.is_some() {
+        args.backend.require(Capability::Quotas, "--max-run-bytes")?;
+    }
+    if let Some(quota) = args.quota {
+        args.backend.require(Capability::Quotas, "--quota")?;
+        if quota == 0 {
+            return Err(anyhow!("--quota must be greater than 0"));
+        }
+    }
+    if args.snapshot_interval.is_some() {
+        args.backend.require(Capability::Snapshots, "--snapshot-interval")?;
+    }
+    if args.reuse_clones {
+        args.backend.require(Capability::Rollback, "--reuse-clones")?;
+    }
+    if let Some(n) = args.preclone {
+        args.backend.require(Capability::Rename, "--preclone")?;
+        if n == 0 {
+            return Err(anyhow!("--preclone must be greater than 0"));
+        }
+    }
+    if args.go_test.is_some() && args.test_command.is_some() {
+        return Err(anyhow!(
+            "--go-test and --test-command cannot be used together"
+        ));
+    }
+    if args.run_timeout == Some(0) {
+        return Err(anyhow!("--run-timeout must be greater than zero"));
+    }
+    if args.snapshot_interval == Some(0) {
+        return Err(anyhow!("--snapshot-interval must be greater than zero"));
+    }
+    if args.no_network && !cfg!(target_os = "linux") {
+        return Err(anyhow!(
+            "--no-network is currently only supported on Linux"
+        ));
+    }
+    privileges::check(args.allow_root)?;
+    if args.pty && !cfg!(target_os = "linux") {
+        return Err(anyhow!("--pty is currently only supported on Linux"));
+    }
+    if args.pty_keepalive.is_some() && !args.pty {
+        return Err(anyhow!("--pty-keepalive requires --pty"));
+    }
+    if args.pty_keepalive == Some(0) {
+        return Err(anyhow!("--pty-keepalive must be greater than zero"));
+    }
+    if args.tui && !cfg!(feature = "tui") {
+        return Err(missing_feature("--tui", "tui"));
+    }
+    if args.tui && args.format == events::OutputFormat::Json {
+        return Err(anyhow!("--tui cannot be used with --format json"));
+    }
+    if args.live_listen.is_some() && !cfg!(feature = "http") {
+        return Err(missing_feature("--live-listen", "http"));
+    }
+    if args.grpc_listen.is_some() && !cfg!(feature = "http") {
+        return Err(missing_feature("--grpc-listen", "http"));
+    }
+    if args.grpc_listen.is_some() && !cfg!(unix) {
+        return Err(anyhow!(
+            "--grpc-listen is currently only supported on Unix systems"
+        ));
+    }
+    if args.metrics_addr.is_some() && !cfg!(feature = "http") {
+        return Err(missing_feature("--metrics-addr", "http"));
+    }
+    if args.status_addr.is_some() && !cfg!(feature = "http") {
+        return Err(missing_feature("--status-addr", "http"));
+    }
+    let clones = args.backend.clones();
+    if clones.source_exists(snapshot) == Some(false) {
+        return Err(anyhow::Error::new(SourceGone(format!(
+            "source {:?} does not exist",
+            snapshot
+        ))));
+    }
+    match clones.source_identity(snapshot) {
+        Ok(identity) => record.source_identity = identity,
+        Err(error) => eprintln!("warning: identifying source: {:#}", error),
+    }
+    let gocrash_key = record.session.clone();
+    let (source_name, gocrash_dataset) =
+        clones.working_dataset(snapshot, &gocrash_key)?;
+    record.working_dataset = Some(gocrash_dataset.clone());
+
+    let severity_rules = args
+        .severity_rule
+        .iter()
+        .map(|rule| rule.parse())
+        .collect::<Result<Vec<severity::SeverityRule>, _>>()?;
+    if args.stop_on_new_failure && args.signature_catalog.is_empty() {
+        return Err(anyhow!(
+            "--stop-on-new-failure requires --signature-catalog"
+        ));
+    }
+    let stop_when = args
+        .stop_when
+        .as_deref()
+        .map(|condition| condition.parse::<stop::StopCondition>())
+        .transpose()?;
+    if stop_when.is_some()
+        && (args.stop_when_reproduced.is_some()
+            || args.stop_severity.is_some()
+            || args.stop_on_new_failure)
+    {
+        return Err(anyhow!(
+            "--stop-when cannot be used with --stop-when-reproduced, \
+             --stop-severity, or --stop-on-new-failure"
+        ));
+    }
+    let mut start_deadline = args.max_duration.map(|secs| {
+        let duration = std::time::Duration::from_secs(secs);
+        (
+            Instant::now() + duration,
+            "max-duration",
+            format!("--max-duration {}", timing::format_duration(duration)),
+        )
+    });
+    if let Some(stop_at) = &args.stop_at {
+        let now = chrono::Local::now();
+        let when = timing::parse_stop_at(stop_at, now)?;
+        let deadline =
+            Instant::now() + (when - now).to_std().unwrap_or_default();
+        if start_deadline.as_ref().is_none_or(|(d, ..)| deadline < *d) {
+            start_deadline =
+                Some((deadline, "stop-at", format!("--stop-at {}", stop_at)));
+        }
+    }
+    if args.keep_going
+        && (args.stop_when_reproduced.is_some()
+            || args.stop_severity.is_some()
+            || args.stop_on_new_failure)
+    {
+        return Err(anyhow!(
+            "--keep-going cannot be used with --stop-when-reproduced, \
+             --stop-severity, or --stop-on-new-failure"
+        ));
+    }
+    let catalog = catalog::Catalog::load(&args.signature_catalog)?;
+    let keep_rules = args
+        .keep_at_most
+        .iter()
+        .map(|rule| rule.parse())
+        .collect::<Result<Vec<retention::KeepRule>, _>>()?;
+    if args.sample_duplicates.is_some()
+        && args.keep_per_signature.is_none()
+        && keep_rules.is_empty()
+        && !catalog.has_keep_limits()
+    {
+        return Err(anyhow!(
+            "--sample-duplicates requires --keep-per-signature, \
+             --keep-at-most, or a catalog entry with \"keep\""
+        ));
+    }
+    let batch_actions = args
+        .between_batches
+        .iter()
+        .map(|action| action.parse())
+        .collect::<Result<Vec<batch::BatchAction>, _>>()?;
+    let batches = match args.batch_size {
+        Some(0) => {
+            return Err(anyhow!("--batch-size must be greater than zero"));
+        }
+        Some(size) => Some(batch::Batches::new(size, batch_actions)),
+        None if !batch_actions.is_empty() => {
+            return Err(anyhow!("--between-batches requires --batch-size"));
+        }
+        None => None,
+    };
+    if batches
+        .iter()
+        .flat_map(|b| &b.actions)
+        .any(|a| matches!(a, batch::BatchAction::Trim))
+    {
+        args.backend.require(Capability::Pool, "--between-batches trim")?;
+    }
+
+    // Start the servers only once all of the options have been checked, so
+    // that a bad option fails the session before anything is listening.
+    #[cfg(feature = "http")]
+    if let Some(addr) = args.live_listen {
+        live::start(addr, args.live_token_file.as_deref())?;
+    }
+    #[cfg(all(unix, feature = "http"))]
+    if let Some(addr) = args.grpc_listen {
+        grpc::start(addr, args.grpc_token_file.as_deref())?;
+    }
+    #[cfg(feature = "http")]
+    if let Some(addr) = args.metrics_addr {
+        metrics::start(addr, args.metrics_token_file.as_deref())?;
+    }
+    #[cfg(feature = "http")]
+    if let Some(addr) = args.status_addr {
+        status::start(addr, args.status_token_file.as_deref())?;
+    }
+
+    // Record the health of the pool so that we can tell if storage errors
+    // show up during the session.
+    let pool_health_start = if args.backend.supports(Capability::Pool) {
+        health::pool_health(health::pool_for_dataset(&source_name))
+    } else {
+        Err(anyhow!("not checked with the {} backend", args.backend.name()))
+    };
+
+    // Start with what earlier sessions learned about how long passing runs
+    // take.
+    let adaptive_timeouts = args.adaptive_timeout.map(|factor| {
+        #[cfg_attr(not(feature = "sqlite"), allow(unused_mut))]
+        let mut adaptive = timing::AdaptiveTimeouts::new(factor);
+        #[cfg(feature = "sqlite")]
+        if let Some(history) = history {
+            match history.lock().unwrap().passing_phase_timings() {
+                Ok(samples) => {
+                    for (phase, duration) in samples {
+                        adaptive.record(&phase, duration);
+                    }
+                }
+                Err(error) => eprintln!("warning: {:#}", error),
+            }
+        }
+        Mutex::new(adaptive)
+    });
+
+    let mut gocrash = Gocrash {
+        backend: args.backend,
+        clones,
+        cpu_weights: match args.background_cpu_weight {
+            None => args.cpu_weight.clone(),
+            Some(background) => (0..args.concurrency)
+                .map(|thread| match lanes.lane(thread) {
+                    lane::Lane::Background => background,
+                    lane::Lane::Foreground => {
+                        cpu::weight_for_thread(&args.cpu_weight, thread)
+                            .unwrap_or(cpu::DEFAULT_WEIGHT)
+                    }
+                })
+                .collect(),
+        },
+        lanes,
+        go_test: args
+            .go_test
+            .as_ref()
+            .map(|a| a.split_whitespace().map(String::from).collect()),
+        test_command: args.test_command.clone(),
+        workdir: args.workdir.clone(),
+        source_snapshot: snapshot,
+        session_key: gocrash_key,
+        stop_after: args.stop_after,
+        stop_when_reproduced: args.stop_when_reproduced,
+        stop_severity: args.stop_severity,
+        stop_on_new_failure: args.stop_on_new_failure,
+        stop_when,
+        keep_going: args.keep_going,
+        verify_failures: args.verify_failures,
+        batches,
+        collect_go_test_artifacts: args.collect_go_test_artifacts,
+        post_run_hook: args.post_run_hook.clone(),
+        failure_hook: args.failure_hook.clone(),
+        on_hook_failure: args.on_hook_failure,
+        severity_rules,
+        archive_dir: args.archive_dir.clone(),
+        archive_store: args.archive_dir.as_deref().map(store::Store::new),
+        archive_policy: archive::ArchivePolicy::new(
+            &args.archive_include,
+            &args.archive_exclude,
+        ),
+        keep_success: args.keep_success,
+        reuse_clones: args.reuse_clones,
+        spare_runs: Mutex::new(BTreeMap::new()),
+        clone_pool: args.preclone.map(preclone::ClonePool::new),
+        next_preclone: AtomicUsize::new(0),
+        isolate_env: !args.no_isolate_env,
+        no_network: args.no_network,
+        log_network: args.log_network,
+        pty: args.pty,
+        pty_keepalive: args.pty_keepalive.map(std::time::Duration::from_secs),
+        gocrash_dataset,
+        working_mountpoint: std::path::PathBuf::new(),
+        stopping: AtomicBool::new(false),
+        cancel,
+        control: control::Control::new(args.concurrency),
+        load_limit: args.max_load.map(load::LoadLimit::new),
+        space_low: Mutex::new(false),
+        session_start: Instant::now(),
+        session_deadline: args
+            .session_timeout
+            .map(|secs| Instant::now() + std::time::Duration::from_secs(secs)),
+        start_deadline,
+        stop_reason: Mutex::new(None),
+        next_run_id: AtomicUsize::new(args.run_id_start),
+        history,
+        signer: signature::Signer::new(
+            args.signature_profile,
+            &args.signature_prefix,
+        ),
+        catalog,
+        retention: retention::Retention::new(
+            keep_rules,
+            args.keep_per_signature,
+            args.sample_duplicates,
+        ),
+        pool_health_start: pool_health_start.as_ref().ok().cloned(),
+        results: Mutex::new(BTreeMap::new()),
+        thread_stats: Mutex::new(BTreeMap::new()),
+        timings: Mutex::new(timing::PhaseTimings::default()),
+        storage: Mutex::new(storage::StorageTracker::default()),
+        max_run_bytes: args.max_run_bytes,
+        min_free: args.min_free,
+        quota: args.quota,
+        snapshot_interval: args
+            .snapshot_interval
+            .map(std::time::Duration::from_secs),
+        run_timeout: args.run_timeout.map(std::time::Duration::from_secs),
+        output_stall_timeout: args
+            .output_stall_timeout
+            .map(std::time::Duration::from_secs)
+            .or_else(|| args.go_test.as_ref().map(|_| GO_TEST_STALL_TIMEOUT)),
+        adaptive_timeouts,
+        combined_log: args.combined_log,
+        timestamp_lines: args.timestamp_lines,
+        failures: Mutex::new(BTreeMap::new()),
+        failed_runs: Mutex::new(Vec::new()),
+        pairings: pairing::Pairings::new(),
+        destroyer: destroyer::Destroyer::new(usize::from(args.concurrency)),
+        toolchain: Mutex::new(None),
+    };
+
+    // Print a summary of parameters.
+    match args.backend {
+        backend::Backend::Zfs => text_println!("using snapshot:  {}", snapshot),
+        backend::Backend::Copy => {
+            text_println!("copying:         {}", snapshot)
+        }
+        backend::Backend::Overlay => {
+            text_println!("overlaying:      {}", snapshot)
+        }
+    }
+    text_println!("working dataset: {}", gocrash.gocrash_dataset);
+    let missing = Capability::ALL
+        .iter()
+        .filter(|c| !args.backend.supports(**c))
+        .map(|c| c.as_str())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        text_println!(
+            "not available:   {} (with the {} backend)",
+            missing.join(", "),
+            args.backend.name()
+        );
+    }
+    if let Some(quota) = args.quota {
+        text_println!("quota:           {}", storage::format_bytes(quota));
+    }
+    text_println!("concurrency:     {}", args.concurrency);
+    if gocrash.lanes.any_background() {
+        text_println!("lanes:           {}", gocrash.lanes.describe());
+    }
+    if !gocrash.cpu_weights.is_empty() {
+        text_println!(
+            "cpu weights:     {}",
+            (0..args.concurrency)
+                .filter_map(|thread| {
+                    let weight =
+                        cpu::weight_for_thread(&gocrash.cpu_weights, thread)?;
+                    Some(format!("thread {}: {}", thread, weight))
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if let Some(max_load) = args.max_load {
+        text_println!(
+            "max load:        {} (1-minute load average; now {})",
+            max_load,
+            load::load_average().map_or_else(
+                |_| String::from("unknown"),
+                |l| format!("{:.2}", l)
+            )
+        );
+    }
+    if let Some(go_test) = &gocrash.go_test {
+        text_println!(
+            "workload:        go test {} (until the first test failure)",
+            GO_TEST_DEFAULT_ARGS
+                .iter()
+                .copied()
+                .chain(go_test.iter().map(String::as_str))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+    if let Some(test_command) = &gocrash.test_command {
+        text_println!("workload:        {}", test_command);
+    }
+    if let Some(workdir) = &gocrash.workdir {
+        text_println!("workdir:         {}", workdir);
+    }
+    text_println!(
+        "save results:    {}",
+        if gocrash.keep_success {
+            "for all runs"
+        } else {
+            "for failed runs only"
+        }
+    );
+    let mut stop_on_failure = match args.stop_when_reproduced {
+        None => String::from("any run fails"),
+        Some(n) => format!(
+            "the same failure is seen {} time{}",
+            n,
+            if n == 1 { "" } else { "s" }
+        ),
+    };
+    if let Some(stop_severity) = args.stop_severity {
+        write!(stop_on_failure, " (with severity {} or worse)", stop_severity)
+            .unwrap();
+    }
+    if args.stop_on_new_failure {
+        stop_on_failure.push_str(" (not counting known failures)");
+    }
+    text_println!(
+        "stop:            {}",
+        match (args.stop_after, &gocrash.stop_when) {
+            (None, Some(stop_when)) => format!("when {}", stop_when),
+            (Some(stop_after), Some(stop_when)) => format!(
+                "after all threads do {} run{} or when {}",
+                stop_after,
+                if stop_after == 1 { "" } else { "s" },
+                stop_when,
+            ),
+            (None, None) => format!("after {}", stop_on_failure),
+            (Some(stop_after), None) if args.stop_when_reproduced.is_some() => {
+                format!(
+                    "after all threads do {} run{} or {}",
+                    stop_after,
+                    if stop_after == 1 { "" } else { "s" },
+                    stop_on_failure,
+                )
+            }
+            (Some(stop_after), None) => format!(
+                "after all threads do {} run{}",
+                stop_after,
+                if stop_after == 1 { "" } else { "s" }
+            ),
+        }
+    );
+    text_println!(
+        "environment:     {}",
+        if gocrash.isolate_env {
+            "HOME, GOPATH, GOMODCACHE, and GOENV are per-run"
+        } else {
+            "HOME, GOPATH, GOMODCACHE, and GOENV are inherited"
+        }
+    );
+    if args.verify_failures > 0 {
+        text_println!(
+            "verify failures: rerun {} time{} after each failure",
+            args.verify_failures,
+            if args.verify_failures == 1 { "" } else { "s" }
+        );
+    }
+    if let Some(batches) = &gocrash.batches {
+        text_println!(
+            "batches:         {} run{} each{}",
+            batches.size,
+            if batches.size == 1 { "" } else { "s" },
+            if batches.actions.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    ", then: {}",
+                    batches
+                        .actions
+                        .iter()
+                        .map(|a| a.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+        );
+    }
+    if !args.signature_catalog.is_empty() {
+        let n = gocrash.catalog.len();
+        text_println!(
+            "known failures:  {} (from {})",
+            n,
+            args.signature_catalog.join(", ")
+        );
+    }
+    if let Some(timeout) = gocrash.run_timeout {
+        text_println!("run timeout:     {}", timing::format_duration(timeout));
+    }
+    if let Some(timeout) = gocrash.output_stall_timeout {
+        text_println!("stall timeout:   {}", timing::format_duration(timeout));
+    }
+    if let Some(interval) = gocrash.snapshot_interval {
+        text_println!(
+            "live snapshots:  every {} during each run",
+            timing::format_duration(interval)
+        );
+    }
+    if gocrash.reuse_clones {
+        text_println!(
+            "reusing clones:  rolled back between runs on each thread"
+        );
+    }
+    if let Some(n) = args.preclone {
+        text_println!(
+            "cloning ahead:   keeping {} clone{} ready",
+            n,
+            if n == 1 { "" } else { "s" }
+        );
+    }
+    if let Some((deadline, _, label)) = &gocrash.start_deadline {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        text_println!(
+            "no new runs:     after {} ({})",
+            (chrono::Local::now()
+                + chrono::Duration::from_std(remaining).unwrap_or_default())
+            .format("%Y-%m-%d %H:%M:%S %Z"),
+            label
+        );
+    }
+    if let Some(secs) = args.session_timeout {
+        text_println!(
+            "session timeout: {}",
+            timing::format_duration(std::time::Duration::from_secs(secs))
+        );
+    }
+    if gocrash.no_network {
+        text_println!("network:         disabled (loopback only)");
+    } else if gocrash.log_network {
+        text_println!("network:         connections logged");
+    }
+    if gocrash.pty {
+        text_println!(
+            "terminal:        pseudo-terminal{}",
+            match gocrash.pty_keepalive {
+                Some(keepalive) => format!(
+                    " (newline typed after {} without output)",
+                    timing::format_duration(keepalive)
+                ),
+                None => String::new(),
+            }
+        );
+    }
+    if let (Some(factor), Some(adaptive)) =
+        (args.adaptive_timeout, &gocrash.adaptive_timeouts)
+    {
+        let nruns = adaptive.lock().unwrap().nruns();
+        text_println!(
+            "timeouts:        adaptive ({} x p99 of passing runs; learned \
+             from {} earlier run{})",
+            factor,
+            nruns,
+            if nruns == 1 { "" } else { "s" }
+        );
+    }
+    match &pool_health_start {
+        Ok(h) => text_println!("pool health:     {}", h),
+        Err(error) => text_println!("pool health:     unknown ({:#})", error),
+    }
+    print_go_env_vars();
+    text_println!();
+
+    record.stage = session::SessionStage::CreateWorkingDataset;
+    let working_mountpoint = clones.create_working_dataset(
+        &gocrash.gocrash_dataset,
+        &gocrash.session_key,
+        snapshot,
+        args.quota,
+    )?;
+    record.working_mountpoint = Some(working_mountpoint.clone());
+    gocrash.working_mountpoint = working_mountpoint.clone();
+    #[cfg(feature = "http")]
+    metrics::session_started(
+        args.backend,
+        &gocrash.gocrash_dataset,
+        working_mountpoint.clone(),
+    );
+    #[cfg(feature = "http")]
+    status::session_started(
+        &gocrash.session_key,
+        snapshot,
+        &gocrash.gocrash_dataset,
+        &record.parameters,
+        args.concurrency,
+    );
+    #[cfg(unix)]
+    let control_socket = match control::ControlSocket::bind(&working_mountpoint)
+    {
+        Ok(socket) => {
+            text_println!("control socket:  {}", socket.path().display());
+            Some(socket)
+        }
+        Err(error) => {
+            eprintln!("warning: control socket: {:#}", error);
+            None
+        }
+    };
+    let control_done = AtomicBool::new(false);
+
+    // Create threads to run the test suite.
+    record.stage = session::SessionStage::Run;
+    #[cfg(feature = "sqlite")]
+    if let Some(history) = history {
+        history.lock().unwrap().record_session(record)?;
+    }
+    events::emit(
+        &gocrash.session_key,
+        &events::Event::SessionStart {
+            source: snapshot,
+            working_dataset: &gocrash.gocrash_dataset,
+            backend: args.backend,
+            concurrency: args.concurrency,
+            parameters: &record.parameters,
+        },
+    );
+    #[cfg(feature = "tui")]
+    let dashboard = if !args.tui {
+        None
+    } else if std::io::stdout().is_terminal() {
+        Some(tui::start(
+            format!("gocrash {} testing {}", gocrash.session_key, snapshot),
+            args.concurrency,
+        ))
+    } else {
+        eprintln!("warning: --tui: stdout is not a terminal");
+        None
+    };
+    let result = std::thread::scope(|scope| {
+        let myref = &gocrash;
+        let handles = (0..args.concurrency)
+            .map(|i| {
+                scope.spawn(move || {
+                    let result = gocrash_worker(myref, i);
+                    myref.control.set_phase(i, control::Phase::Done);
+                    #[cfg(feature = "tui")]
+                    tui::set_phase(i, control::Phase::Done);
+                    result
+                })
+            })
+            .collect::<Vec<_>>();
+        let destroyer = scope.spawn(move || {
+            myref.destroyer.run(|dataset| myref.destroy_run(dataset))
+        });
+        let precloner = myref
+            .clone_pool
+            .as_ref()
+            .map(|pool| scope.spawn(move || pool.run(|| myref.preclone())));
+        #[cfg(unix)]
+        {
+            let socket = control_socket.as_ref();
+            let done = &control_done;
+            scope.spawn(move || {
+                control::serve(
+                    &myref.control,
+                    socket,
+                    || done.load(Ordering::SeqCst),
+                    |command| {
+                        control_command(myref, command, "the control socket")
+                    },
+                )
+            });
+        }
+        #[cfg(all(unix, feature = "http"))]
+        {
+            let done = &control_done;
+            scope.spawn(move || {
+                grpc::serve_session(
+                    || done.load(Ordering::SeqCst),
+                    |command| control_command(myref, command, "gRPC"),
+                )
+            });
+        }
+
+        // Wait for every thread to finish (with the dashboard, if any, still
+        // up) and then print the results.  Setup errors are likely to be hit
+        // identically by every thread, so we report those once at the end
+        // rather than for each thread.
+        let joined = handles.into_iter().map(|h| h.join()).collect::<Vec<_>>();
+        control_done.store(true, Ordering::SeqCst);
+        if let Some(pool) = &gocrash.clone_pool {
+            pool.close();
+        }
+        #[cfg(feature = "tui")]
+        drop(dashboard);
+
+        // Finish destroying the datasets that the threads left behind,
+        // including any that were waiting to be reused or were cloned ahead
+        // of time and never used.  Errors here are reported only after the
+        // destroyer has been closed and joined: returning before then would
+        // leave its thread waiting for more work, and the scope waiting for
+        // it, forever.
+        let mut leftovers =
+            std::mem::take(&mut *gocrash.spare_runs.lock().unwrap())
+                .into_values()
+                .collect::<Vec<_>>();
+        let mut cleanup_error = None;
+        if let (Some(pool), Some(precloner)) = (&gocrash.clone_pool, precloner)
+        {
+            match precloner.join() {
+                Ok(()) => leftovers.extend(pool.leftovers()),
+                Err(error) => {
+                    cleanup_error =
+                        Some(anyhow!("precloner panicked: {:?}", error));
+                }
+            }
+        }
+        for dataset in &leftovers {
+            if let Err(error) = gocrash.destroy_run_later(dataset) {
+                cleanup_error.get_or_insert(error);
+            }
+        }
+        gocrash.destroyer.close();
+        let npending = gocrash.destroyer.npending();
+        if npending > 0 {
+            text_println!(
+                "waiting for {} dataset{} to be destroyed",
+                npending,
+                if npending == 1 { "" } else { "s" }
+            );
+        }
+        destroyer
+            .join()
+            .map_err(|error| anyhow!("destroyer panicked: {:?}", error))?;
+        if let Some(error) = cleanup_error {
+            return Err(error);
+        }
+        record.destroy_failures = gocrash.destroyer.failures();
+        if !record.destroy_failures.is_empty() {
+            let n = record.destroy_failures.len();
+            text_println!(
+                "failed to destroy {} dataset{} (use `gocrash clean` to \
+                 remove {}):",
+                n,
+                if n == 1 { "" } else { "s" },
+                if n == 1 { "it" } else { "them" }
+            );
+            for failure in &record.destroy_failures {
+                text_println!("    {}: {}", failure.dataset, failure.error);
+            }
+        }
+        if let Some(pool) = &gocrash.clone_pool {
+            let (ntaken, nmissed) = pool.stats();
+            text_println!(
+                "cloned ahead: {} of {} run{} started with a clone made ahead \
+                 of time",
+                ntaken,
+                ntaken + nmissed,
+                if ntaken + nmissed == 1 { "" } else { "s" }
+            );
+        }
+        let mut nerrors = 0;
+        let mut setup_error = None;
+        let mut source_gone = None;
+        let mut storage_error = None;
+        let mut thread_error = None;
+        for (i, worker_result) in joined.into_iter().enumerate() {
+            let worker_result = worker_result.map_err(|error| {
+                anyhow!("thread {} panicked: {:?}", i, error)
+            })?;
+            let thread = u8::try_from(i).unwrap();
+            let stats = gocrash
+                .thread_stats
+                .lock()
+                .unwrap()
+                .get(&thread)
+                .copied()
+                .unwrap_or_default();
+            record.threads.push(session::ThreadRecord {
+                thread,
+                ntries: worker_result.ntries,
+                nruns: stats.nruns,
+                nfailures: stats.nfailures,
+                error: worker_result
+                    .result
+                    .as_ref()
+                    .err()
+                    .map(|e| format!("{:#}", e)),
+            });
+            text_println!(
+                "thread {}: {} tries, result = {}",
+                i,
+                worker_result.ntries,
+                match worker_result.result {
+                    Ok(_) => String::from("ok"),
+                    Err(error) if error.is::<SetupError>() => {
+                        setup_error = Some(error);
+                        String::from("setup error")
+                    }
+                    Err(error) if error.is::<SourceGone>() => {
+                        source_gone = Some(error);
+                        String::from("source snapshot disappeared")
+                    }
+                    Err(error) => {
+                        nerrors += 1;
+                        let summary = summarize_error(
+                            &error,
+                            &working_mountpoint
+                                .join(format!("thread-{}-error.txt", i)),
+                            args.verbose_summary,
+                        );
+                        if error::storage_exhausted(&error) {
+                            storage_error.get_or_insert(error);
+                        } else {
+                            thread_error.get_or_insert(error);
+                        }
+                        summary
+                    }
+                }
+            )
+        }
+
+        // If nothing else stopped the session, every thread must have done
+        // the runs it was asked to.
+        if let Some(shutdown) = gocrash.cancel.requested() {
+            gocrash.note_stop(shutdown.condition(), shutdown.reason());
+        }
+        if let Some(n) = gocrash.stop_after {
+            gocrash.note_stop(
+                "stop-after",
+                format!(
+                    "each thread finished {} run{} (--stop-after {})",
+                    n,
+                    if n == 1 { "" } else { "s" },
+                    n
+                ),
+            );
+        }
+        record.stop_reason = gocrash.stop_reason.lock().unwrap().clone();
+        if let Some(reason) = &record.stop_reason {
+            text_println!(
+                "stopped after {}: {}",
+                timing::format_duration(std::time::Duration::from_secs_f64(
+                    reason.elapsed_secs
+                )),
+                reason.detail
+            );
+        }
+        let paused = gocrash.control.paused_time();
+        record.paused_secs = paused.as_secs_f64();
+        if !paused.is_zero() {
+            text_println!(
+                "paused for {} in total",
+                timing::format_duration(paused)
+            );
+        }
+        gocrash.record_pairings(gocrash.pairings.finish());
+        print_results(&gocrash);
+        print_failures(&gocrash);
+        if gocrash.keep_going {
+            print_failed_runs(&gocrash);
+        }
+        print_thread_heatmap(&record.threads);
+        record.timings = gocrash.timings.lock().unwrap().summarize();
+        timing::print_report(&record.timings);
+        record.storage = gocrash.storage.lock().unwrap().summarize();
+        record.toolchain = gocrash.toolchain.lock().unwrap().clone();
+        if record.storage.nruns > 0 {
+            text_println!("storage: {}", record.storage);
+        }
+        record.results = gocrash
+            .results
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(result, count)| (result.to_string(), *count))
+            .collect();
+        record.failed_runs = gocrash.failed_runs.lock().unwrap().clone();
+        record.failures = gocrash
+            .failures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(signature, group)| session::FailureRecord {
+                signature: signature.clone(),
+                severity: group.severity,
+                known_failure: group.known.as_ref().map(|k| k.name.clone()),
+                links: group
+                    .known
+                    .as_ref()
+                    .map(|k| k.links.clone())
+                    .unwrap_or_default(),
+                results: group
+                    .results
+                    .iter()
+                    .map(|(result, count)| (result.to_string(), *count))
+                    .collect(),
+                datasets: group.datasets.clone(),
+                ndiscarded: group.ndiscarded,
+                nreruns: group.nreruns,
+                nrecurred: group.nrecurred,
+            })
+            .collect();
+        if let Some(problems) = gocrash.check_pool_health() {
+            if problems.is_empty() {
+                text_println!("pool health: no new errors during this session");
+            } else {
+                text_println!(
+                    "WARNING: storage problems appeared during this session \
+                     (failures may not be the test suite's fault): {}",
+                    problems.join(", ")
+                );
+            }
+        }
+
+        if let Some(error) = setup_error {
+            Err(error)
+        } else if let Some(error) = source_gone {
+            Err(error)
+        } else if let Some(error) = storage_error {
+            Err(error)
+        } else if nerrors > 0 {
+            // Threads can also stop because of problems running the test
+            // suite.  If that's all that happened, report the problem.
+            let counts = gocrash.stop_counts();
+            match thread_error {
+                Some(error) if counts.failures == 0 => Err(error),
+                _ => Err(anyhow::Error::new(error::TestFailed {
+                    failures: counts.failures,
+                    hangs: counts.hangs,
+                    signatures: gocrash
+                        .failures
+                        .lock()
+                        .unwrap()
+                        .keys()
+                        .cloned()
+                        .collect(),
+                })),
+            }
+        } else if let Err(error) = gocrash.cancel.check() {
+            Err(anyhow::Error::new(error))
+        } else {
+            Ok(())
+        }
+    });
+
+    if result.is_ok() && args.destroy_all_on_success_exit {
+        record.stage = session::SessionStage::Cleanup;
+        record.teardown = Some(clones.destroy_working_dataset(
+            &gocrash.gocrash_dataset,
+            &gocrash.session_key,
+            args.concurrency,
+        )?);
+        record.working_mountpoint = None;
+    }
+
+    result
+}
+
+fn print_go_env_vars() {
+    let mut go_vars = std::env::vars_os()
+        .filter_map(|(key, v)| {
+            key.into_string()
+                .ok()
+                .filter(|s| s.starts_with("GO"))
+                .map(|k| (k, v))
+        })
+        .peekable();
+    if go_vars.peek().is_some() {
+        text_println!("Environment variables starting with GO:");
+        for (k, v) in go_vars {
+            text_println!("    {:>20}={}", k, v.to_string_lossy());
+        }
+    } else {
+        text_println!("Environment variables starting with GO: none");
+    }
+}
+
+/// Maximum length of the one-line error summaries in the final per-thread
+/// summary
+const ERROR_SUMMARY_MAX_LEN: usize = 120;
+
+/// Returns a description of `error` for the final per-thread summary
+///
+/// Errors can include many kilobytes of command output.  Unless `verbose` is
+/// set, we save the complete error to `details_path` and return only its first
+/// line and a pointer to the file.
+fn summarize_error(
+    error: &anyhow::Error,
+    details_path: &std::path::Path,
+    verbose: bool,
+) -> String {
+    let full = format!("{:#}", error);
+    if verbose {
+        return full;
+    }
+
+    let mut summary = first_line_of(&full);
+    match std::fs::write(details_path, &full) {
+        Ok(()) => {
+            write!(&mut summary, " (details: {})", details_path.display())
+                .unwrap();
+        }
+        Err(write_error) => {
+            eprintln!(
+                "warning: write {:?}: {:#}",
+                details_path.display(),
+                write_error
+            );
+        }
+    }
+    summary
+}
+
+/// Returns the first line of `message`, truncated to `ERROR_SUMMARY_MAX_LEN`
+/// characters
+fn first_line_of(message: &str) -> String {
+    let first_line = message.lines().next().unwrap_or("");
+    if first_line.chars().count() > ERROR_SUMMARY_MAX_LEN {
+        let truncated =
+            first_line.chars().take(ERROR_SUMMARY_MAX_LEN).collect::<String>();
+        format!("{}...", truncated)
+    } else {
+        first_line.to_string()
+    }
+}
+
+/// Don't bother judging whether failures are skewed towards particular threads
+/// until there have been at least this many
+const SKEW_MIN_FAILURES: usize = 4;
+
+/// Report skew when the chance of failures landing only in the threads where
+/// they did (if failures were independent of the thread) is below this
+const SKEW_THRESHOLD: f64 = 0.05;
+
+/// Width of the bars in the per-thread heatmap
+const HEATMAP_WIDTH: usize = 40;
+
+/// Prints how failures were distributed across worker threads, warning if
+/// they're concentrated in a way that's unlikely to be chance
+///
+/// If only some threads ever fail (e.g., only thread 0), the problem is
+/// probably with the harness or how resources are divided between threads,
+/// not with the test suite.
+fn print_thread_heatmap(threads: &[session::ThreadRecord]) {
+    let total_runs: usize = threads.iter().map(|t| t.nruns).sum();
+    let total_failures: usize = threads.iter().map(|t| t.nfailures).sum();
+    if threads.len() < 2 || total_failures == 0 {
+        return;
+    }
+
+    text_println!("failures by thread:");
+    let max_failures = threads.iter().map(|t| t.nfailures).max().unwrap();
+    for t in threads {
+        text_println!(
+            "    thread {:>3}: {:>5} / {:>5} runs failed  {}",
+            t.thread,
+            t.nfailures,
+            t.nruns,
+            "#".repeat(t.nfailures * HEATMAP_WIDTH / max_failures),
+        );
+    }
+
+    // If failures were equally likely in every run, the chance that all of
+    // them would land in the threads that saw any is the fraction of runs
+    // done by those threads, raised to the number of failures.
+    let failing = threads.iter().filter(|t| t.nfailures > 0);
+    let failing_runs: usize = failing.clone().map(|t| t.nruns).sum();
+    let nfailing = failing.clone().count();
+    if total_failures < SKEW_MIN_FAILURES || nfailing == threads.len() {
+        return;
+    }
+    let p = (failing_runs as f64 / total_runs as f64)
+        .powi(i32::try_from(total_failures).unwrap_or(i32::MAX));
+    if p < SKEW_THRESHOLD {
+        text_println!(
+            "WARNING: all {} failures happened in thread{} {} (p = {:.3} if \
+             failures were independent of the thread).  This usually \
+             indicates a problem with the harness or how resources are \
+             divided between threads rather than a bug in the test suite.",
+            total_failures,
+            if nfailing == 1 { "" } else { "s" },
+            failing
+                .map(|t| t.thread.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            p
+        );
+    }
+}
+
+/// Prints how many runs had each result
+fn print_results(gocrash: &Gocrash) {
+    let results = gocrash.results.lock().unwrap();
+    if results.is_empty() {
+        return;
+    }
+
+    text_println!(
+        "runs: {}",
+        results
+            .iter()
+            .map(|(result, count)| format!("{} {}", count, result))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+}
+
+/// Prints the failed runs seen during this session, grouped by signature
+fn print_failures(gocrash: &Gocrash) {
+    let failures = gocrash.failures.lock().unwrap();
+    if failures.is_empty() {
+        return;
+    }
+
+    // Show the most severe (and then most common) failures first.
+    let mut failures = failures.iter().collect::<Vec<_>>();
+    failures.sort_by(|(_, a), (_, b)| {
+        b.severity.cmp(&a.severity).then_with(|| b.count().cmp(&a.count()))
+    });
+
+    text_println!("failures by signature:");
+    for (signature, group) in failures {
+        text_println!(
+            "    {} x [{}] {:?}{}{}",
+            group.count(),
+            group.severity,
+            signature,
+            describe_known(group.known.as_ref()),
+            if group.results.keys().all(|r| *r == "failed") {
+                String::new()
+            } else {
+                format!(
+                    " ({})",
+                    group
+                        .results
+                        .iter()
+                        .map(|(result, count)| format!("{} {}", count, result))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+        );
+        if !group.details.is_empty() {
+            text_println!("        go test reported: {}", group.details);
+        }
+        if group.nreruns > 0 {
+            text_println!(
+                "        recurred in {} of {} immediate rerun{}",
+                group.nrecurred,
+                group.nreruns,
+                if group.nreruns == 1 { "" } else { "s" }
+            );
+        }
+        for link in group.known.iter().flat_map(|k| &k.links) {
+            text_println!("        see {}", link);
+        }
+        for dataset in &group.datasets {
+            text_println!("        {}", dataset);
+        }
+        if group.ndiscarded > 0 {
+            text_println!(
+                "        ({} {}not kept: limited by --keep-at-most, \
+                 --keep-per-signature, or the catalog)",
+                group.ndiscarded,
+                if group.datasets.is_empty() { "" } else { "more " }
+            );
+        }
+    }
+}
+
+/// Prints every failed run, in the order they finished (see `--keep-going`)
+fn print_failed_runs(gocrash: &Gocrash) {
+    let failed_runs = gocrash.failed_runs.lock().unwrap();
+    if failed_runs.is_empty() {
+        return;
+    }
+
+    text_println!("failed runs:");
+    for run in failed_runs.iter() {
+        text_println!(
+            "    run {} (thread {}): {}: {}{}",
+            run.run_id,
+            run.thread,
+            run.result,
+            run.dataset,
+            if run.kept { "" } else { " (not kept)" }
+        );
+        text_println!("        {}", run.error);
+        if let Some(control_run) = run.control_run {
+            text_println!(
+                "        control: run {}: {}{}",
+                control_run,
+                gocrash.run_dataset(run.thread, control_run),
+                if gocrash.keep_success { "" } else { " (not kept)" }
+            );
+        }
+    }
+}
+
+/// Returns a label for failures that match a known failure (or an empty
+/// string for those that don't)
+fn describe_known(known: Option<&catalog::KnownFailure>) -> String {
+    known.map_or_else(String::new, |k| format!(" (known: {})", k.name))
+}
+
+/// Describes the state of this "gocrash" run
+struct Gocrash<'a> {
+    /// how we make a copy of the source tree for each run
+    backend: backend::Backend,
+    /// the implementation of `backend`
+    clones: &'static dyn backend::CloneBackend,
+    /// CPU weight for each thread's runs (see `cpu.rs`)
+    cpu_weights: Vec<u32>,
+    /// which threads are in the foreground and background lanes (see
+    /// `lane.rs`)
+    lanes: lane::Lanes,
+    /// arguments for `go test` to run in place of the whole test suite (see
+    /// `--go-test`)
+    go_test: Option<Vec<String>>,
+    /// shell command to run in place of the whole test suite (see
+    /// `expand_template()`)
+    test_command: Option<String>,
+    /// directory (relative to the clone) in which to run the workload (see
+    /// `expand_template()`)
+    workdir: Option<String>,
+    // Immutable parameters
+    /// user-provided snapshot that we'll clone for each test run
+    source_snapshot: &'a str,
+    /// unique name for this session (see `SESSION_PROPERTY`)
+    session_key: String,
+    /// each thread will do this number of attempts (None: infinite)
+    stop_after: Option<usize>,
+    /// stop once any one failure signature has been seen this many times
+    /// (None: stop on the first failure)
+    stop_when_reproduced: Option<usize>,
+    /// only stop for failures at least this severe
+    stop_severity: Option<severity::Severity>,
+    /// keep going after failures that match a known failure
+    stop_on_new_failure: bool,
+    /// keep going after failures until this holds (see `stop.rs`)
+    stop_when: Option<stop::StopCondition>,
+    /// keep going after every failure
+    keep_going: bool,
+    /// number of times to rerun the test suite after each failure
+    verify_failures: usize,
+    /// how runs are grouped into batches, if they are (see `batch.rs`)
+    batches: Option<batch::Batches>,
+    /// whether to collect what `go test` leaves behind (see `gotest.rs`)
+    collect_go_test_artifacts: bool,
+    /// command to run after each run (see `hooks.rs`)
+    post_run_hook: Option<String>,
+    /// command to run after each failed run
+    failure_hook: Option<String>,
+    /// what to do when a hook fails
+    on_hook_failure: hooks::HookFailure,
+    /// user-supplied rules for classifying failures
+    severity_rules: Vec<severity::SeverityRule>,
+    /// where to archive artifacts from failed runs
+    archive_dir: Option<std::path::PathBuf>,
+    /// where archived files are stored (with `archive_dir`)
+    archive_store: Option<store::Store>,
+    /// which artifacts to archive
+    archive_policy: archive::ArchivePolicy,
+    /// whether to keep datasets for successful test runs
+    keep_success: bool,
+    /// whether to reuse runs' datasets rather than destroying them (see
+    /// `release_run()`)
+    reuse_clones: bool,
+    /// each thread's last run's dataset, if it's waiting to be reused for the
+    /// thread's next run
+    spare_runs: Mutex<BTreeMap<u8, String>>,
+    /// clones made ahead of time (see `preclone.rs`)
+    clone_pool: Option<preclone::ClonePool>,
+    /// number to use in the name of the next clone made ahead of time
+    next_preclone: AtomicUsize,
+    /// whether to give each run its own HOME, GOPATH, etc. (see
+    /// `isolated_env()`)
+    isolate_env: bool,
+    /// whether to run the test suite without network access
+    no_network: bool,
+    /// whether to record network connections made by the test suite
+    log_network: bool,
+    /// whether to run the test suite on a pseudo-terminal (see `pty.rs`)
+    pty: bool,
+    /// how long the test suite can be quiet before we type `pty::KEEPALIVE`
+    pty_keepalive: Option<std::time::Duration>,
+    /// health of the pool when we started (None if we couldn't determine it)
+    pool_health_start: Option<health::PoolHealth>,
+    /// name of our working ZFS dataset (containing per-run datasets)
+    gocrash_dataset: String,
+    /// where the working dataset is mounted (once it's been created), under
+    /// which each run's clone is mounted (see `run_mountpoint()`)
+    working_mountpoint: std::path::PathBuf,
+
+    // Runtime state
+    /// whether we're stopping
+    stopping: AtomicBool,
+    /// tells us to stop early (as SIGINT does)
+    cancel: &'a CancellationToken,
+    /// whether threads may start runs (see `control.rs`)
+    control: control::Control,
+    /// holds off on new runs while the system is busy (see `load.rs`)
+    load_limit: Option<load::LoadLimit>,
+    /// whether runs are being held until there's more free space
+    space_low: Mutex<bool>,
+    /// when the session started
+    session_start: Instant,
+    /// when to stop the session (see `--session-timeout`)
+    session_deadline: Option<Instant>,
+    /// when to stop starting new runs, the stop condition's name, and the
+    /// option that said so (see `--max-duration` and `--stop-at`)
+    start_deadline: Option<(Instant, &'static str, String)>,
+    /// why we're stopping (the first reason we found)
+    stop_reason: Mutex<Option<session::StopReason>>,
+    /// id to assign to the next run (unique within the session)
+    next_run_id: AtomicUsize,
+    /// where to record the result of each run
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    history: Option<&'a HistoryDb>,
+    /// computes signatures for failed runs
+    signer: signature::Signer,
+    /// known failures to label failed runs with
+    catalog: catalog::Catalog,
+    /// decides which failed runs' datasets to keep
+    retention: retention::Retention,
+    /// datasets for failed runs seen so far, grouped by failure signature
+    failures: Mutex<BTreeMap<String, FailureGroup>>,
+    /// every failed run so far, in the order they finished
+    failed_runs: Mutex<Vec<session::FailedRunRecord>>,
+    /// pairs each failed run with a passing run to compare it with (see
+    /// `pairing.rs`)
+    pairings: pairing::Pairings,
+    /// destroys datasets for runs that weren't kept (see `destroyer.rs`)
+    destroyer: destroyer::Destroyer,
+    /// the toolchain used by the first run to finish (see `provenance.rs`)
+    toolchain: Mutex<Option<provenance::Toolchain>>,
+    /// number of runs with each result so far (see `RunResult::as_str()`)
+    results: Mutex<BTreeMap<&'static str, usize>>,
+    /// number of runs and failures so far in each worker thread
+    thread_stats: Mutex<BTreeMap<u8, ThreadStats>>,
+    /// how long each phase of each run has taken so far
+    timings: Mutex<timing::PhaseTimings>,
+    /// how much space runs have used so far
+    storage: Mutex<storage::StorageTracker>,
+    /// kill runs whose clones use more than this many bytes
+    max_run_bytes: Option<u64>,
+    /// space to leave free for the working dataset (see `wait_for_space()`)
+    min_free: u64,
+    /// limit on the working dataset's space (see `--quota`)
+    quota: Option<u64>,
+    /// how often to snapshot each run's clone while the run is in progress
+    snapshot_interval: Option<std::time::Duration>,
+    /// treat runs as hung if the workload takes longer than this
+    run_timeout: Option<std::time::Duration>,
+    /// treat runs as hung if they produce no output for this long
+    output_stall_timeout: Option<std::time::Duration>,
+    /// durations of passing runs, for `--adaptive-timeout`
+    adaptive_timeouts: Option<Mutex<timing::AdaptiveTimeouts>>,
+    /// whether to save a combined log of stdout and stderr
+    combined_log: Option<capture::CombinedLog>,
+    /// whether to timestamp each line of stdout and stderr
+    timestamp_lines: bool,
+}
+
+/// How often to check whether space has been freed up while runs are being
+/// held for `--min-free`
+const SPACE_POLL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(10);
+
+impl<'a> Gocrash<'a> {
+    /// Returns the name of the dataset for the given run
+    fn run_dataset(&self, which_thread: u8, run_id: usize) -> String {
+        format!(
+            "{}/thread-{}-run-{}",
+            self.gocrash_dataset, which_thread, run_id
+        )
+    }
+
+    /// Records the result of a run in the history database (if any)
+    #[cfg(feature = "sqlite")]
+    fn record_run_history(
+        &self,
+        which_thread: u8,
+        which_run: usize,
+        run_id: usize,
+        start_time: chrono::DateTime<chrono::Utc>,
+        result: &RunResult,
+    ) {
+        let Some(history) = self.history else {
+            return;
+        };
+        let signature = result.failure().map(|f| f.signature.as_str());
+        let dataset = self.run_dataset(which_thread, run_id);
+        let run = history::RunRecord {
+            session: &self.session_key,
+            run_id,
+            thread: which_thread,
+            attempt: which_run,
+            dataset: &dataset,
+            start_time,
+            end_time: chrono::Utc::now(),
+            result: result.as_str(),
+            signature,
+        };
+        if let Err(error) = history.lock().unwrap().record_run(&run) {
+            run_println!(which_thread, which_run, "warning: {:#}", error);
+        }
+    }
+
+    /// Records the result of a run in the history database (if any)
+    #[cfg(not(feature = "sqlite"))]
+    fn record_run_history(
+        &self,
+        _which_thread: u8,
+        _which_run: usize,
+        _run_id: usize,
+        _start_time: chrono::DateTime<chrono::Utc>,
+        _result: &RunResult,
+    ) {
+    }
+
+    /// Reports how a run ended (see `events.rs` and `callbacks.rs`)
+    fn emit_run_end(
+        &self,
+        which_thread: u8,
+        which_run: usize,
+        run_id: usize,
+        start_time: chrono::DateTime<chrono::Utc>,
+        result: &RunResult,
+    ) {
+        let dataset = self.run_dataset(which_thread, run_id);
+        let duration_secs = (chrono::Utc::now() - start_time)
+            .to_std()
+            .unwrap_or_default()
+            .as_secs_f64();
+        let event = match result {
+            RunResult::Pass => events::Event::RunSuccess {
+                thread: which_thread,
+                attempt: which_run,
+                run_id,
+                dataset: &dataset,
+                duration_secs,
+                kept: self.keep_success,
+            },
+            _ => {
+                let failure = result.failure();
+                let error = match result {
+                    RunResult::InfraError(error) => Some(error),
+                    _ => failure.map(|f| &f.error),
+                };
+                events::Event::RunFailure {
+                    thread: which_thread,
+                    attempt: which_run,
+                    run_id,
+                    dataset: &dataset,
+                    duration_secs,
+                    result: result.as_str(),
+                    signature: failure.map(|f| f.signature.as_str()),
+                    severity: failure.map(|f| f.severity),
+                    known_failure: failure
+                        .and_then(|f| f.known.as_ref())
+                        .map(|k| k.name.as_str()),
+                    kept: failure.is_some_and(|f| f.kept),
+                    error: error.map(|e| first_line_of(&format!("{:#}", e))),
+                }
+            }
+        };
+        events::emit(&self.session_key, &event);
+
+        let failure = result.failure();
+        callbacks::run_ended(
+            &callbacks::Run {
+                session: &self.session_key,
+                thread: which_thread,
+                attempt: which_run,
+                run_id,
+                dataset: &dataset,
+            },
+            &callbacks::RunEnd {
+                result: result.as_str(),
+                duration: std::time::Duration::from_secs_f64(duration_secs),
+                kept: match result {
+                    RunResult::Pass => self.keep_success,
+                    _ => failure.is_some_and(|f| f.kept),
+                },
+                error: match result {
+                    RunResult::InfraError(error) => Some(error),
+                    _ => failure.map(|f| &f.error),
+                },
+            },
+            failure
+                .map(|f| callbacks::Failure {
+                    signature: &f.signature,
+                    severity: f.severity.as_str(),
+                    known_failure: f.known.as_ref().map(|k| k.name.as_str()),
+                })
+                .as_ref(),
+        );
+    }
+
+    /// Records how much space a run used (and how much the working dataset now
+    /// uses), warning if it's unusually large
+    fn record_storage(
+        &self,
+        which_thread: u8,
+        which_run: usize,
+        dataset: &str,
+    ) {
+        // We'd have to walk the whole tree to find this out otherwise.
+        if !self.backend.supports(Capability::SpaceAccounting) {
+            return;
+        }
+
+        let result = storage::dataset_usage(dataset).and_then(|usage| {
+            Ok((usage, storage::dataset_usage(&self.gocrash_dataset)?))
+        });
+        match result {
+            Ok((usage, working)) => {
+                let mut storage = self.storage.lock().unwrap();
+                storage.record_working(working.used);
+                if let Some(warning) = storage.record_run(usage) {
+                    run_println!(
+                        which_thread,
+                        which_run,
+                        "WARNING: {}",
+                        warning
+                    );
+                }
+            }
+            Err(error) => run_println!(
+                which_thread,
+                which_run,
+                "warning: checking storage usage: {:#}",
+                error
+            ),
+        }
+    }
+
+    /// Returns whether the session has reached its deadline (if any), noting
+    /// that we're stopping because of it
+    fn session_deadline_passed(&self) -> bool {
+        let passed = self.session_deadline.is_some_and(|d| Instant::now() >= d);
+        if passed {
+            self.note_stop(
+                "session-timeout",
+                String::from("reached --session-timeout"),
+            );
+        }
+        passed
+    }
+
+    /// Waits until at least `min_free` bytes are available for the working
+    /// dataset, returning false if `stop()` says to give up first
+    ///
+    /// If the pool fills up, the test suite fails in all kinds of confusing
+    /// ways (and the rest of the system may be in trouble, too), so rather
+    /// than starting a run, we say what's wrong and wait for someone to free
+    /// up space (or stop the session).  If we can't tell how much space is
+    /// available, we go ahead.
+    fn wait_for_space(&self, stop: impl Fn() -> bool) -> bool {
+        if self.min_free == 0 {
+            return true;
+        }
+        loop {
+            if stop() {
+                return false;
+            }
+            // Without ZFS, the working dataset is a directory.
+            let available = match storage::available(
+                self.backend,
+                &self.gocrash_dataset,
+                std::path::Path::new(&self.gocrash_dataset),
+            ) {
+                Ok(available) => available,
+                Err(error) => {
+                    eprintln!("warning: checking free space: {:#}", error);
+                    return true;
+                }
+            };
+            let mut low = self.space_low.lock().unwrap();
+            if available >= self.min_free {
+                if *low {
+                    ts_println!(
+                        "{} available for {}: starting runs again",
+                        storage::format_bytes(available),
+                        self.gocrash_dataset
+                    );
+                    *low = false;
+                }
+                return true;
+            }
+            if !*low {
+                eprintln!(
+                    "warning: only {} available for {} (--min-free {}): \
+                     holding off on new runs until more space is free (e.g., \
+                     by destroying the datasets of kept runs)",
+                    storage::format_bytes(available),
+                    self.gocrash_dataset,
+                    storage::format_bytes(self.min_free)
+                );
+                *low = true;
+            }
+            drop(low);
+
+            let start = Instant::now();
+            while start.elapsed() < SPACE_POLL_INTERVAL && !stop() {
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            }
+        }
+    }
+
+    /// Returns the arguments for `go test` that thread `which` runs in place
+    /// of the whole test suite, if any (see `--go-test`)
+    fn go_test_for(&self, which: u8) -> Option<&Vec<String>> {
+        match self.lanes.test_command_for(which) {
+            Some(_) => None,
+            None => self.go_test.as_ref(),
+        }
+    }
+
+    /// Returns the shell command that thread `which` runs in place of the
+    /// whole test suite, if any (see `--test-command` and
+    /// `--background-test-command`)
+    fn test_command_for(&self, which: u8) -> Option<&String> {
+        self.lanes.test_command_for(which).or(self.test_command.as_ref())
+    }
+
+    /// Returns whether it's too late to start another run (see
+    /// `start_deadline`), noting that we're stopping because of it
+    fn start_deadline_passed(&self) -> bool {
+        let Some((deadline, condition, label)) = &self.start_deadline else {
+            return false;
+        };
+        let passed = Instant::now() >= *deadline;
+        if passed {
+            self.note_stop(
+                condition,
+                format!("reached {} (no more runs were started)", label),
+            );
+        }
+        passed
+    }
+
+    /// Records why the session is stopping, unless we already know
+    fn note_stop(&self, condition: &str, detail: String) {
+        let mut stop_reason = self.stop_reason.lock().unwrap();
+        if stop_reason.is_none() {
+            *stop_reason = Some(session::StopReason {
+                condition: condition.to_string(),
+                detail,
+                time: chrono::Utc::now().to_rfc3339(),
+                elapsed_secs: self.session_start.elapsed().as_secs_f64(),
+            });
+        }
+    }
+
+    /// Tells every thread to stop once it's finished its current run, noting
+    /// why
+    fn stop(&self, condition: &str, detail: String) {
+        self.note_stop(condition, detail);
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns the current values of the counters used in `--stop-when`
+    fn stop_counts(&self) -> stop::Counts {
+        let results = self.results.lock().unwrap();
+        let count = |result: &str| results.get(result).copied().unwrap_or(0);
+        let failures = self.failures.lock().unwrap();
+        let new = failures.values().filter(|g| g.known.is_none());
+        stop::Counts {
+            runs: results.values().sum(),
+            passes: count("passed"),
+            failures: failures.values().map(|g| g.count()).sum(),
+            new_failures: new.clone().map(|g| g.count()).sum(),
+            hangs: count("hung"),
+            signatures: failures.len(),
+            new_signatures: new.count(),
+            max_repeats: failures
+                .values()
+                .map(|g| g.count())
+                .max()
+                .unwrap_or(0),
+            elapsed: self.session_start.elapsed(),
+        }
+    }
+
+    /// Returns whether runs are being cut short because the session is ending
+    /// (because it reached its deadline or we were asked to shut down)
+    fn cutting_short(&self) -> bool {
+        self.session_deadline_passed() || self.cancel.requested().is_some()
+    }
+
+    /// Sets a gocrash property on a per-run dataset
+    ///
+    /// Only ZFS has somewhere to keep these, so they're dropped with other
+    /// backends.
+    fn set_property(
+        &self,
+        dataset: &str,
+        property: &str,
+        value: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.clones.set_properties(dataset, &[(property, value)])
+    }
+
+    /// Records a fact about the conditions that a run ran under, both on its
+    /// dataset and in the history database (if any)
+    fn record_run_metadata(
+        &self,
+        which_thread: u8,
+        which_run: usize,
+        run_id: usize,
+        name: &str,
+        value: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.record_run_metadata_all(
+            which_thread,
+            which_run,
+            run_id,
+            &[(name.to_owned(), value.to_owned())],
+        )
+    }
+
+    /// Like `record_run_metadata()`, for several facts at once (which only
+    /// takes one "zfs set")
+    #[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+    fn record_run_metadata_all(
+        &self,
+        which_thread: u8,
+        which_run: usize,
+        run_id: usize,
+        metadata: &[(String, String)],
+    ) -> Result<(), anyhow::Error> {
+        let properties = metadata
+            .iter()
+            .map(|(name, value)| {
+                (
+                    format!(
+                        "{}{}",
+                        session::RUN_METADATA_PROPERTY_PREFIX,
+                        name
+                    ),
+                    value.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        self.clones.set_properties(
+            &self.run_dataset(which_thread, run_id),
+            &properties
+                .iter()
+                .map(|(property, value)| (property.as_str(), *value))
+                .collect::<Vec<_>>(),
+        )?;
+        #[cfg(feature = "sqlite")]
+        if let Some(history) = self.history {
+            let history = history.lock().unwrap();
+            for (name, value) in metadata {
+                if let Err(error) = history.record_run_metadata(
+                    &self.session_key,
+                    run_id,
+                    name,
+                    value,
+                ) {
+                    run_println!(
+                        which_thread,
+                        which_run,
+                        "warning: {:#}",
+                        error
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records which toolchain a run used (see `provenance.rs`), both for the
+    /// session (if this is the first run to get here) and, if the run is being
+    /// kept, in its clone and its metadata
+    fn record_toolchain(
+        &self,
+        which_thread: u8,
+        which_run: usize,
+        run_id: usize,
+        mountpoint: &std::path::Path,
+        kept: bool,
+    ) -> Result<(), anyhow::Error> {
+        if !kept && self.toolchain.lock().unwrap().is_some() {
+            return Ok(());
+        }
+        let go_binary = mountpoint.join("goroot/bin").join(GO_BINARY);
+        let toolchain = match provenance::capture(mountpoint, &go_binary) {
+            Ok(Some(toolchain)) => toolchain,
+            Ok(None) => return Ok(()),
+            Err(error) => {
+                run_println!(
+                    which_thread,
+                    which_run,
+                    "warning: recording toolchain: {:#}",
+                    error
+                );
+                return Ok(());
+            }
+        };
+        {
+            let mut session_toolchain = self.toolchain.lock().unwrap();
+            if session_toolchain.is_none() {
+                run_println!(
+                    which_thread,
+                    which_run,
+                    "toolchain: {}",
+                    toolchain.version
+                );
+                *session_toolchain = Some(toolchain.clone());
+            }
+        }
+        if !kept {
+            return Ok(());
+        }
+        if let Err(error) = provenance::write(mountpoint, &toolchain) {
+            run_println!(which_thread, which_run, "warning: {:#}", error);
+        }
+        let mut metadata = vec![(
+            String::from("toolchain-version"),
+            toolchain.version.clone(),
+        )];
+        for (path, hash) in &toolchain.binaries {
+            let name = std::path::Path::new(path)
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            metadata.push((format!("toolchain-{}", name), hash.clone()));
+        }
+        self.record_run_metadata_all(which_thread, which_run, run_id, &metadata)
+    }
+
+    /// Checks whether the source snapshot (or directory) still exists,
+    /// returning an error describing what happened if it doesn't
+    fn source_gone(&self) -> Option<SourceGone> {
+        let exists =
+            self.clones.source_exists(self.source_snapshot).unwrap_or(true);
+        (!exists).then(|| {
+            SourceGone(format!(
+                "source {:?} disappeared during the session (was it \
+                 destroyed or renamed?); stopped starting new runs",
+                self.source_snapshot
+            ))
+        })
+    }
+
+    /// Runs the hooks that apply to a run with the given result (and, for
+    /// failed runs, signature), recording what they report in the run's
+    /// metadata
+    ///
+    /// Returns an error if a hook failed and `--on-hook-failure=infra-error`.
+    fn run_hooks(
+        &self,
+        which_thread: u8,
+        which_run: usize,
+        run_id: usize,
+        mountpoint: &std::path::Path,
+        result: RunState,
+        signature: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let hooks = self
+            .post_run_hook
+            .iter()
+            .chain(signature.and(self.failure_hook.as_ref()));
+        for command in hooks {
+            let mut env = run_context_env(
+                &self.session_key,
+                which_thread,
+                self.lanes.lane(which_thread),
+                run_id,
+                &mountpoint.join(RUN_OUTPUT_DIR),
+            )
+            .to_vec();
+            env.push(("GOCRASH_RESULT", result.as_str().into()));
+            if let Some(signature) = signature {
+                env.push(("GOCRASH_SIGNATURE", signature.into()));
+            }
+            let annotations = match hooks::run_hook(command, mountpoint, env) {
+                Ok(annotations) => annotations,
+                Err(error) => match self.on_hook_failure {
+                    hooks::HookFailure::Log => {
+                        run_println!(
+                            which_thread,
+                            which_run,
+                            "warning: {:#}",
+                            error
+                        );
+                        continue;
+                    }
+                    hooks::HookFailure::InfraError => return Err(error),
+                },
+            };
+            for (name, value) in annotations {
+                self.record_run_metadata(
+                    which_thread,
+                    which_run,
+                    run_id,
+                    &name,
+                    &value,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes a snapshot of a per-run dataset while its run is in progress (see
+    /// `--snapshot-interval`)
+    fn snapshot_run(&self, snapshot: &str) -> Result<(), anyhow::Error> {
+        zfs::run(
+            Command::new("pfexec")
+                .arg("zfs")
+                .arg("snapshot")
+                .args(session::property_arg(
+                    SESSION_PROPERTY,
+                    &self.session_key,
+                ))
+                .arg(snapshot),
+        )?;
+        Ok(())
+    }
+
+    /// Records the state of a run (see `session::RUN_STATE_PROPERTY`)
+    fn set_run_state(
+        &self,
+        dataset: &str,
+        state: RunState,
+    ) -> Result<(), anyhow::Error> {
+        self.set_property(dataset, session::RUN_STATE_PROPERTY, state.as_str())
+    }
+
+    /// Destroys a per-run dataset (along with any live snapshots of it)
+    fn destroy_run(&self, dataset: &str) -> Result<(), anyhow::Error> {
+        self.clones.destroy_run(dataset)
+    }
+
+    /// Disposes of a per-run dataset that isn't being kept: with
+    /// `--reuse-clones`, it's set aside for the thread's next run (which
+    /// rolls it back and renames it rather than cloning a new one), and
+    /// otherwise it's destroyed in the background
+    fn release_run(
+        &self,
+        thread: u8,
+        dataset: &str,
+    ) -> Result<(), anyhow::Error> {
+        if !self.reuse_clones {
+            return self.destroy_run_later(dataset);
+        }
+        let previous =
+            self.spare_runs.lock().unwrap().insert(thread, dataset.to_owned());
+        match previous {
+            Some(previous) => self.destroy_run_later(&previous),
+            None => Ok(()),
+        }
+    }
+
+    /// Clones the source to a new dataset that's not yet assigned to a run
+    /// (see `preclone.rs`) and returns its name
+    fn preclone(&self) -> Result<String, anyhow::Error> {
+        let dataset = format!(
+            "{}/preclone-{}",
+            self.gocrash_dataset,
+            self.next_preclone.fetch_add(1, Ordering::SeqCst)
+        );
+        let mountpoint =
+            self.clones.run_mountpoint(&self.working_mountpoint, &dataset);
+        self.clones.clone_run(
+            self.source_snapshot,
+            &dataset,
+            &mountpoint,
+            &[(SESSION_PROPERTY, self.session_key.as_str())],
+        )?;
+        if self.reuse_clones {
+            self.clones.mark_pristine(
+                &dataset,
+                &[(SESSION_PROPERTY, self.session_key.as_str())],
+            )?;
+        }
+        Ok(dataset)
+    }
+
+    /// Hands a per-run dataset to the background destroyer (see
+    /// `destroyer.rs`), or destroys it right away if the session is already
+    /// finishing up
+    fn destroy_run_later(&self, dataset: &str) -> Result<(), anyhow::Error> {
+        match self.destroyer.enqueue(dataset.to_owned()) {
+            Ok(()) => Ok(()),
+            Err(dataset) => self.destroy_run(&dataset),
+        }
+    }
+
+    fn record_timing(&self, phase: &str, duration: std::time::Duration) {
+        self.timings.lock().unwrap().record(phase, duration);
+        #[cfg(feature = "http")]
+        metrics::record_phase(phase, duration);
+    }
+
+    /// Updates the counts of runs by result and by thread
+    fn record_result(&self, which_thread: u8, result: &RunResult) {
+        *self.results.lock().unwrap().entry(result.as_str()).or_insert(0) += 1;
+        #[cfg(feature = "http")]
+        metrics::run_finished(result.as_str());
+        let mut thread_stats = self.thread_stats.lock().unwrap();
+        let stats = thread_stats.entry(which_thread).or_default();
+        stats.nruns += 1;
+        if result.failure().is_some() {
+            stats.nfailures += 1;
+        }
+        self.control.set_phase(which_thread, control::Phase::Idle);
+        #[cfg(feature = "tui")]
+        tui::run_finished(
+            which_thread,
+            matches!(result, RunResult::Pass),
+            result.failure().is_some(),
+        );
+    }
+
+    /// Records a failed test run, returning how many times a failure with the
+    /// same signature has now been seen
+    fn record_failure(
+        &self,
+        which_thread: u8,
+        run_id: usize,
+        failure: &TestFailure,
+        result: &'static str,
+    ) -> usize {
+        self.failed_runs.lock().unwrap().push(session::FailedRunRecord {
+            run_id,
+            thread: which_thread,
+            result: result.to_string(),
+            signature: failure.signature.clone(),
+            dataset: failure.dataset.clone(),
+            kept: failure.kept,
+            error: first_line_of(&format!("{:#}", failure.error)),
+            details: failure.details.clone(),
+            control_run: None,
+        });
+        let mut failures = self.failures.lock().unwrap();
+        let group =
+            failures.entry(failure.signature.clone()).or_insert_with(|| {
+                FailureGroup {
+                    severity: failure.severity,
+                    known: failure.known.clone(),
+                    details: failure.details.clone(),
+                    results: BTreeMap::new(),
+                    datasets: Vec::new(),
+                    ndiscarded: 0,
+                    nreruns: 0,
+                    nrecurred: 0,
+                }
+            });
+        *group.results.entry(result).or_insert(0) += 1;
+        if failure.kept {
+            group.datasets.push(failure.dataset.clone());
+        } else {
+            group.ndiscarded += 1;
+        }
+        group.count()
+    }
+
+    /// Records which passing run each failed run should be compared with (see
+    /// `pairing.rs`)
+    fn record_pairings(&self, pairings: Vec<pairing::Pairing>) {
+        for pairing in pairings {
+            let kept = {
+                let mut failed_runs = self.failed_runs.lock().unwrap();
+                let Some(run) = failed_runs
+                    .iter_mut()
+                    .find(|run| run.run_id == pairing.failed_run)
+                else {
+                    continue;
+                };
+                run.control_run = Some(pairing.control_run);
+                run.kept
+            };
+            run_println!(
+                pairing.thread,
+                pairing.attempt,
+                "run {}: control run is run {}",
+                pairing.failed_run,
+                pairing.control_run
+            );
+            if !kept {
+                continue;
+            }
+            if let Err(error) = self.record_run_metadata(
+                pairing.thread,
+                pairing.attempt,
+                pairing.failed_run,
+                "control-run",
+                &pairing.control_run.to_string(),
+            ) {
+                run_println!(
+                    pairing.thread,
+                    pairing.attempt,
+                    "warning: recording control run for run {}: {:#}",
+                    pairing.failed_run,
+                    error
+                );
+            }
+        }
+    }
+
+    /// Records the results of rerunning the test suite after a failure with
+    /// the given signature (see `verify_failure()`)
+    fn record_verification(
+        &self,
+        signature: &str,
+        nreruns: usize,
+        nrecurred: usize,
+    ) {
+        if let Some(group) = self.failures.lock().unwrap().get_mut(signature) {
+            group.nreruns += nreruns;
+            group.nrecurred += nrecurred;
+        }
+    }
+
+    /// Compares the pool's current health against what it was when we
+    /// started, returning descriptions of any new problems (or None if we
+    /// can't tell)
+    fn check_pool_health(&self) -> Option<Vec<String>> {
+        let start = self.pool_health_start.as_ref()?;
+        match health::pool_health(&start.pool) {
+            Ok(now) => Some(start.new_problems(&now)),
+            Err(error) => {
+                eprintln!("warning: checking pool health: {:#}", error);
+                None
+            }
+        }
+    }
+
+    /// Returns the current health of the pool that runs are in (or None if
+    /// the backend doesn't use a pool or we can't tell)
+    fn run_pool_health(
+        &self,
+        which_thread: u8,
+        which_run: usize,
+    ) -> Option<health::PoolHealth> {
+        if !self.backend.supports(Capability::Pool) {
+            return None;
+        }
+        match health::pool_health(health::pool_for_dataset(
+            &self.gocrash_dataset,
+        )) {
+            Ok(health) => Some(health),
+            Err(error) => {
+                run_println!(
+                    which_thread,
+                    which_run,
+                    "warning: checking pool health: {:#}",
+                    error
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Counts of what happened in one worker thread
+#[derive(Clone, Copy, Default)]
+struct ThreadStats {
+    nruns: usize,
+    nfailures: usize,
+}
+
+/// Describes the failed runs seen so far that share a signature
+struct FailureGroup {
+    /// severity of the first such failure
+    severity: severity::Severity,
+    /// known failure matched by the first such failure, if any
+    known: Option<catalog::KnownFailure>,
+    /// what `go test` reported about the first such failure
+    details: gotest::FailureDetails,
+    /// number of these runs with each result ("failed", "hung", etc.)
+    results: BTreeMap<&'static str, usize>,
+    /// per-run datasets for the failed runs that were kept
+    datasets: Vec<String>,
+    /// number of failed runs whose datasets were destroyed (see
+    /// `retention.rs`)
+    ndiscarded: usize,
+    /// number of runs done right after these failures to check whether they
+    /// recur
+    nreruns: usize,
+    /// number of those reruns that failed with the same signature
+    nrecurred: usize,
+}
+
+impl FailureGroup {
+    /// Returns the number of failed runs in the group
+    fn count(&self) -> usize {
+        self.datasets.len() + self.ndiscarded
+    }
+}
+
+/// Describes the result of one worker thread
+struct WorkerResult {
+    /// number of times the test suite was run
+    ntries: usize,
+    /// result of the last test suite run
+    result: Result<(), anyhow::Error>,
+}
+
+/// Describes the outcome of one run of the test suite
+enum RunResult {
+    /// the test suite passed
+    Pass,
+    /// the test suite failed
+    Fail(TestFailure),
+    /// the test suite stopped making progress (e.g., `go test` timed out)
+    Hang(TestFailure),
+    /// gocrash failed to set up, carry out, or clean up after the run
+    InfraError(anyhow::Error),
+    /// the test suite was killed by SIGINT or SIGTERM
+    Interrupted,
+    /// the test suite was killed because its clone used too much space
+    Runaway(TestFailure),
+    /// the test suite failed, but storage problems appeared during the
+    /// session that might explain it
+    Suspect(TestFailure),
+}
+
+impl RunResult {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RunResult::Pass => "passed",
+            RunResult::Fail(_) => "failed",
+            RunResult::Hang(_) => "hung",
+            RunResult::InfraError(_) => "infra-error",
+            RunResult::Interrupted => "interrupted",
+            RunResult::Runaway(_) => "runaway",
+            RunResult::Suspect(_) => "suspect",
+        }
+    }
+
+    /// Returns details about the failure, if the test suite failed
+    fn failure(&self) -> Option<&TestFailure> {
+        match self {
+            RunResult::Fail(failure)
+            | RunResult::Hang(failure)
+            | RunResult::Runaway(failure)
+            | RunResult::Suspect(failure) => Some(failure),
+            RunResult::Pass
+            | RunResult::InfraError(_)
+            | RunResult::Interrupted => None,
+        }
+    }
+}
+
+/// Describes a failed run of the test suite (as opposed to a failure to set up
+/// or clean up after the run)
+struct TestFailure {
+    /// ZFS dataset containing the failed run
+    dataset: String,
+    /// signature identifying this kind of failure (see `signature.rs`)
+    signature: String,
+    /// how severe this failure is (see `severity.rs`)
+    severity: severity::Severity,
+    /// the known failure that this one matched, if any (see `catalog.rs`)
+    known: Option<catalog::KnownFailure>,
+    /// whether the run's dataset was kept (see `retention.rs`)
+    kept: bool,
+    /// what `go test` reported about the failure (see `gotest.rs`)
+    details: gotest::FailureDetails,
+    /// error describing the failure
+    error: anyhow::Error,
+}
+
+/// Describes a problem with how gocrash was set up (e.g., the wrong snapshot)
+/// that would cause every run to fail the same way
+#[derive(Debug)]
+struct SetupError(String);
+
+impl std::fmt::Display for SetupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SetupError {}
+
+/// Describes the source snapshot (or directory) not existing when the session
+/// starts or disappearing partway through (e.g., because a nightly rotation
+/// destroyed or renamed it)
+///
+/// No further runs can be started, so the session stops once the runs in
+/// progress finish.
+#[derive(Debug)]
+struct SourceGone(String);
+
+impl std::fmt::Display for SourceGone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SourceGone {}
+
+/// Describes a run's clone failing because the working dataset reached its
+/// `--quota`
+///
+/// Kept runs count against the quota, so this won't go away by itself, and
+/// the session stops once the runs in progress finish.
+#[derive(Debug)]
+struct QuotaExceeded(String);
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// Carries out a command received on the control socket (or over gRPC, as
+/// `via` says), returning the reply
+#[cfg(unix)]
+fn control_command(
+    gocrash: &Gocrash,
+    command: control::Command,
+    via: &str,
+) -> Result<String, anyhow::Error> {
+    match command {
+        control::Command::Status => {
+            let status = control::Status {
+                session: gocrash.session_key.clone(),
+                working_dataset: gocrash.gocrash_dataset.clone(),
+                elapsed_secs: gocrash.session_start.elapsed().as_secs(),
+                paused: gocrash.control.paused(),
+                concurrency: gocrash.control.concurrency(),
+                max_concurrency: gocrash.control.max_concurrency(),
+                results: gocrash
+                    .results
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(result, n)| (result.to_string(), *n))
+                    .collect(),
+                failures: gocrash
+                    .failures
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(signature, group)| {
+                        (signature.clone(), group.count())
+                    })
+                    .collect(),
+                threads: gocrash.control.threads(),
+                stopping: gocrash
+                    .stop_reason
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .map(|reason| reason.detail.clone()),
+            };
+            Ok(serde_json::to_string(&status)?)
+        }
+        control::Command::Pause => {
+            if gocrash.control.set_paused(true) {
+                ts_println!("paused (from {})", via);
+            }
+            Ok(String::from("ok: paused (runs in progress will finish)"))
+        }
+        control::Command::Resume => {
+            if gocrash.control.set_paused(false) {
+                ts_println!("resumed (from {})", via);
+            }
+            Ok(String::from("ok: resumed"))
+        }
+        control::Command::Stop => {
+            gocrash.stop("control", format!("stop requested from {}", via));
+            Ok(String::from("ok: stopping once runs in progress finish"))
+        }
+        control::Command::SetConcurrency(n) => {
+            gocrash.control.set_concurrency(n)?;
+            ts_println!("concurrency set to {} (from {})", n, via);
+            Ok(format!("ok: concurrency {}", n))
+        }
+    }
+}
+
+/// Body of one worker thread that runs the test suite
+fn gocrash_worker<'a>(gocrash: &'a Gocrash<'a>, which: u8) -> WorkerResult {
+    let mut ntries = 0;
+    // Reruns (see `verify_failure()`) get attempt numbers of their own, but
+    // they don't count as tries.
+    let mut nreruns = 0;
+    let mut last_failure = None;
+    while !gocrash.stopping.load(Ordering::SeqCst) {
+        if gocrash.cutting_short() || gocrash.start_deadline_passed() {
+            break;
+        }
+        let stop = || {
+            gocrash.stopping.load(Ordering::SeqCst)
+                || gocrash.cutting_short()
+                || gocrash.start_deadline_passed()
+        };
+        if !gocrash.control.wait_to_start(which, stop) {
+            break;
+        }
+        if !gocrash.wait_for_space(stop) {
+            break;
+        }
+        let _load_guard = match &gocrash.load_limit {
+            None => None,
+            Some(limit) => match limit.wait_to_start(
+                which,
+                gocrash.lanes.lane(which),
+                stop,
+            ) {
+                None => break,
+                guard => guard,
+            },
+        };
+        if let Some(batches) = &gocrash.batches {
+            if !batches.start_run(stop) {
+                break;
+            }
+        }
+
+        // Carry out one run of the test suite.
+        let attempt = ntries + nreruns;
+        let run_id = gocrash.next_run_id.fetch_add(1, Ordering::SeqCst);
+        let start_time = chrono::Utc::now();
+        let result = gocrash_worker_run_one(gocrash, which, attempt, run_id)
+            .unwrap_or_else(RunResult::InfraError);
+        let end_time = chrono::Utc::now();
+        gocrash.record_run_history(which, attempt, run_id, start_time, &result);
+        gocrash.emit_run_end(which, attempt, run_id, start_time, &result);
+        let kind = result.as_str();
+        gocrash.record_result(which, &result);
+        match result {
+            RunResult::InfraError(error) => {
+                let condition = if error.is::<SetupError>() {
+                    "setup-error"
+                } else if error.is::<SourceGone>() {
+                    "source-gone"
+                } else if error.is::<QuotaExceeded>() {
+                    "quota-exceeded"
+                } else {
+                    "infra-error"
+                };
+                gocrash.stop(
+                    condition,
+                    format!(
+                        "run {}: {}",
+                        run_id,
+                        first_line_of(&format!("{:#}", error))
+                    ),
+                );
+                return WorkerResult { ntries, result: Err(error) };
+            }
+            // Runs that we killed because the session timed out or we were
+            // asked to shut down don't count against the session.
+            RunResult::Interrupted if gocrash.cutting_short() => {
+                break;
+            }
+            RunResult::Interrupted => {
+                gocrash.stop(
+                    "interrupted",
+                    format!("run {}: test suite was interrupted", run_id),
+                );
+                return WorkerResult {
+                    ntries,
+                    result: Err(anyhow!("test suite was interrupted")),
+                };
+            }
+            RunResult::Pass => {
+                gocrash.record_pairings(
+                    gocrash
+                        .pairings
+                        .run_passed(which, run_id, start_time, end_time),
+                );
+            }
+            RunResult::Fail(failure)
+            | RunResult::Hang(failure)
+            | RunResult::Runaway(failure)
+            | RunResult::Suspect(failure) => {
+                let nseen =
+                    gocrash.record_failure(which, run_id, &failure, kind);
+                gocrash
+                    .pairings
+                    .run_failed(which, attempt, run_id, start_time, end_time);
+                run_println!(
+                    which,
+                    attempt,
+                    "{} (seen {} time{}): [{}] {}{}",
+                    kind,
+                    nseen,
+                    if nseen == 1 { "" } else { "s" },
+                    failure.severity,
+                    failure.signature,
+                    describe_known(failure.known.as_ref()),
+                );
+                if !failure.details.is_empty() {
+                    run_println!(
+                        which,
+                        attempt,
+                        "go test reported: {}",
+                        failure.details
+                    );
+                }
+                if gocrash.verify_failures > 0 {
+                    match verify_failure(gocrash, which, attempt, &failure) {
+                        Ok(n) => nreruns += n,
+                        Err(error) => {
+                            gocrash.stop(
+                                "infra-error",
+                                format!(
+                                    "verifying run {}: {}",
+                                    run_id,
+                                    first_line_of(&format!("{:#}", error))
+                                ),
+                            );
+                            return WorkerResult { ntries, result: Err(error) };
+                        }
+                    }
+                }
+
+                // Unless the failure is less severe than the user cares about,
+                // it's a known failure that the user wants to keep going past,
+                // it happened in the background lane, the user asked us to
+                // keep going until this failure has been reproduced enough
+                // times, or the user asked us to keep going regardless (or
+                // until their own stop condition, checked below, holds), we're
+                // done.
+                let minor = gocrash
+                    .stop_severity
+                    .is_some_and(|min| failure.severity < min)
+                    || (gocrash.stop_on_new_failure && failure.known.is_some())
+                    || gocrash.lanes.lane(which) == lane::Lane::Background;
+                match gocrash.stop_when_reproduced {
+                    _ if minor
+                        || gocrash.keep_going
+                        || gocrash.stop_when.is_some() =>
+                    {
+                        last_failure = Some(failure.error)
+                    }
+                    Some(n) if nseen < n => last_failure = Some(failure.error),
+                    Some(n) => {
+                        gocrash.stop(
+                            "stop-when-reproduced",
+                            format!(
+                                "run {} {} (seen {} times, \
+                                 --stop-when-reproduced {}): [{}] {}",
+                                run_id,
+                                kind,
+                                nseen,
+                                n,
+                                failure.severity,
+                                failure.signature
+                            ),
+                        );
+                        return WorkerResult {
+                            ntries,
+                            result: Err(failure.error),
+                        };
+                    }
+                    None => {
+                        gocrash.stop(
+                            "failure",
+                            format!(
+                                "run {} {}: [{}] {}",
+                                run_id,
+                                kind,
+                                failure.severity,
+                                failure.signature
+                            ),
+                        );
+                        return WorkerResult {
+                            ntries,
+                            result: Err(failure.error),
+                        };
+                    }
+                }
+            }
+        }
+
+        // If that was the last run in its batch, it's up to us to do what's
+        // needed before the next batch starts.
+        if let Some(batches) = &gocrash.batches {
+            if let Some(number) = batches.finish_run() {
+                between_batches(gocrash, batches, number);
+                batches.next_batch();
+            }
+        }
+
+        ntries += 1;
+
+        // If the user specified a limit, and we've reached it, we're done.
+        if let Some(stop_after) = gocrash.stop_after {
+            if ntries >= stop_after {
+                break;
+            }
+        }
+
+        // If the user's stop condition now holds, everybody's done.
+        if let Some(stop_when) = &gocrash.stop_when {
+            let counts = gocrash.stop_counts();
+            if stop_when.holds(&counts) {
+                gocrash.stop(
+                    "stop-when",
+                    format!(
+                        "--stop-when {} held ({})",
+                        stop_when,
+                        stop_when.describe(&counts)
+                    ),
+                );
+                break;
+            }
+        }
+    }
+
+    WorkerResult { ntries, result: last_failure.map_or(Ok(()), Err) }
+}
+
+/// Carries out the actions given with `--between-batches` once batch `number`
+/// has finished
+///
+/// Failed actions are reported, but they don't otherwise affect the session.
+fn between_batches(gocrash: &Gocrash, batches: &batch::Batches, number: usize) {
+    if gocrash.stopping.load(Ordering::SeqCst) || gocrash.cutting_short() {
+        return;
+    }
+
+    ts_println!("finished batch {}", number);
+    for action in &batches.actions {
+        let result = match action {
+            batch::BatchAction::Summary => {
+                print_results(gocrash);
+                print_failures(gocrash);
+                Ok(())
+            }
+            batch::BatchAction::Trim => {
+                let pool = health::pool_for_dataset(&gocrash.gocrash_dataset);
+                ts_println!("trimming pool {:?}", pool);
+                run_command(
+                    Command::new("pfexec")
+                        .arg("zpool")
+                        .arg("trim")
+                        .arg("-w")
+                        .arg(pool),
+                )
+                .map(|_| ())
+            }
+            batch::BatchAction::Run(command) => run_command(
+                Command::new("bash")
+                    .arg("-c")
+                    .arg(command)
+                    .env("GOCRASH_SESSION", &gocrash.session_key)
+                    .env("GOCRASH_BATCH", number.to_string()),
+            )
+            .map(|output| {
+                if !output.is_empty() {
+                    ts_println!("{}", output.trim_end());
+                }
+            }),
+        };
+        if let Err(error) = result {
+            ts_println!("warning: between batches: {}: {:#}", action, error);
+        }
+    }
+    ts_println!("starting batch {}", number + 1);
+}
+
+/// Reruns the test suite right after a failure (as many times as
+/// `--verify-failures` says) and reports whether the failure recurred
+///
+/// The reruns are recorded like any other run (with the attempt numbers right
+/// after `which_run`, the attempt that failed), but they don't count toward
+/// `--stop-after`, and their failures don't stop the session.  Returns the
+/// number of reruns, or an error if a rerun couldn't be carried out.
+fn verify_failure<'a>(
+    gocrash: &'a Gocrash<'a>,
+    which: u8,
+    which_run: usize,
+    failure: &TestFailure,
+) -> Result<usize, anyhow::Error> {
+    let mut nreruns = 0;
+    let mut nfailed = 0;
+    let mut nrecurred = 0;
+    while nreruns < gocrash.verify_failures {
+        if gocrash.stopping.load(Ordering::SeqCst)
+            || gocrash.cutting_short()
+            || gocrash.start_deadline_passed()
+        {
+            break;
+        }
+
+        let run_id = gocrash.next_run_id.fetch_add(1, Ordering::SeqCst);
+        run_println!(
+            which,
+            which_run,
+            "verifying failure: rerun {} of {}",
+            nreruns + 1,
+            gocrash.verify_failures
+        );
+        let attempt = which_run + 1 + nreruns;
+        let start_time = chrono::Utc::now();
+        let result = gocrash_worker_run_one(gocrash, which, attempt, run_id)
+            .unwrap_or_else(RunResult::InfraError);
+        gocrash.record_run_history(which, attempt, run_id, start_time, &result);
+        gocrash.emit_run_end(which, attempt, run_id, start_time, &result);
+        let kind = result.as_str();
+        gocrash.record_result(which, &result);
+        match result {
+            RunResult::InfraError(error) => return Err(error),
+            RunResult::Interrupted if gocrash.cutting_short() => {
+                break;
+            }
+            RunResult::Interrupted => {
+                return Err(anyhow!("test suite was interrupted"));
+            }
+            RunResult::Pass => (),
+            RunResult::Fail(rerun)
+            | RunResult::Hang(rerun)
+            | RunResult::Runaway(rerun)
+            | RunResult::Suspect(rerun) => {
+                gocrash.record_failure(which, run_id, &rerun, kind);
+                nfailed += 1;
+                if rerun.signature == failure.signature {
+                    nrecurred += 1;
+                }
+                run_println!(
+                    which,
+                    which_run,
+                    "rerun {}: [{}] {}",
+                    kind,
+                    rerun.severity,
+                    rerun.signature
+                );
+            }
+        }
+        nreruns += 1;
+    }
+
+    run_println!(
+        which,
+        which_run,
+        "verified failure: recurred in {} of {} rerun{} ({} failed in all)",
+        nrecurred,
+        nreruns,
+        if nreruns == 1 { "" } else { "s" },
+        nfailed
+    );
+    gocrash.record_verification(&failure.signature, nreruns, nrecurred);
+    Ok(nreruns)
+}
+
+/// Carries out one run of the test suite
+fn gocrash_worker_run_one<'a>(
+    gocrash: &'a Gocrash<'a>,
+    which_thread: u8,
+    which_run: usize,
+    run_id: usize,
+) -> Result<RunResult, anyhow::Error> {
+    // Clone the original snapshot to a new dataset.
+    let run_start = Instant::now();
+    let test_run_dataset = gocrash.run_dataset(which_thread, run_id);
+    gocrash.control.run_started(which_thread, which_run, run_id);
+    #[cfg(feature = "tui")]
+    tui::run_started(which_thread, which_run, run_id);
+    #[cfg(feature = "http")]
+    let _metrics_run = metrics::run_started();
+    callbacks::run_started(&callbacks::Run {
+        session: &gocrash.session_key,
+        thread: which_thread,
+        attempt: which_run,
+        run_id,
+        dataset: &test_run_dataset,
+    });
+    events::emit(
+        &gocrash.session_key,
+        &events::Event::RunStart {
+            thread: which_thread,
+            attempt: which_run,
+            run_id,
+            dataset: &test_run_dataset,
+        },
+    );
+
+    let mountpoint = gocrash
+        .clones
+        .run_mountpoint(&gocrash.working_mountpoint, &test_run_dataset);
+    let run_id_str = run_id.to_string();
+    let run_start_str = chrono::Utc::now().to_rfc3339();
+    let properties = [
+        (SESSION_PROPERTY, gocrash.session_key.as_str()),
+        (session::RUN_STATE_PROPERTY, RunState::Running.as_str()),
+        (session::RUN_ID_PROPERTY, &run_id_str),
+        (session::RUN_START_PROPERTY, &run_start_str),
+    ];
+    let spare = gocrash.spare_runs.lock().unwrap().remove(&which_thread);
+    let precloned = match spare {
+        Some(_) => None,
+        None => gocrash.clone_pool.as_ref().and_then(|pool| pool.take()),
+    };
+    let cloned = match (spare, precloned) {
+        (Some(old), _) => gocrash.clones.reuse_run(
+            &old,
+            &test_run_dataset,
+            &mountpoint,
+            &properties,
+        ),
+        (None, Some(old)) => gocrash.clones.rename_run(
+            &old,
+            &test_run_dataset,
+            &mountpoint,
+            &properties,
+        ),
+        (None, None) => gocrash
+            .clones
+            .clone_run(
+                gocrash.source_snapshot,
+                &test_run_dataset,
+                &mountpoint,
+                &properties,
+            )
+            .and_then(|()| {
+                if gocrash.reuse_clones {
+                    gocrash.clones.mark_pristine(
+                        &test_run_dataset,
+                        &[(SESSION_PROPERTY, gocrash.session_key.as_str())],
+                    )
+                } else {
+                    Ok(())
+                }
+            }),
+    };
+    if let Err(error) = cloned {
+        // If the clone failed because the source is gone or the working
+        // dataset is full, say so, since every later run will fail the same
+        // way.
+        return Err(match (gocrash.source_gone(), gocrash.quota) {
+            (Some(gone), _) => error.context(gone),
+            (None, Some(quota)) if error::storage_exhausted(&error) => error
+                .context(QuotaExceeded(format!(
+                    "working dataset {:?} is full (--quota {})",
+                    gocrash.gocrash_dataset,
+                    storage::format_bytes(quota)
+                ))),
+            (None, _) => error,
+        });
+    }
+    let mountpoint = mountpoint.as_path();
+
+    // Make sure the clone contains the test suite (or, for --go-test, a
+    // built toolchain, or for --workdir, that directory).  If it doesn't, the
+    // user probably gave us the wrong snapshot and there's no sense in
+    // continuing.
+    let go_binary = mountpoint.join("goroot/bin").join(GO_BINARY);
+    let go_test = gocrash.go_test_for(which_thread);
+    let test_command = gocrash.test_command_for(which_thread);
+    if go_test.is_some() && !go_binary.is_file() {
+        let _ = gocrash.destroy_run(&test_run_dataset);
+        return Err(anyhow::Error::new(SetupError(format!(
+            "copy of {:?} has no \"goroot/bin/{}\" (--go-test expects the \
+             snapshot of a dataset, or a directory, containing a Go source \
+             tree in \"goroot\" where the toolchain has already been built)",
+            gocrash.source_snapshot, GO_BINARY
+        ))));
+    }
+    let workdir = match &gocrash.workdir {
+        Some(workdir) => mountpoint.join(expand_template(
+            workdir,
+            &mountpoint.display().to_string(),
+            which_thread,
+            run_id,
+        )),
+        None if test_command.is_some() => mountpoint.to_path_buf(),
+        None => mountpoint.join("goroot/src"),
+    };
+    if gocrash.workdir.is_some() && !workdir.is_dir() {
+        let _ = gocrash.destroy_run(&test_run_dataset);
+        return Err(anyhow::Error::new(SetupError(format!(
+            "copy of {:?} has no directory {:?} (see --workdir)",
+            gocrash.source_snapshot,
+            workdir.strip_prefix(mountpoint).unwrap_or(&workdir)
+        ))));
+    }
+    let test_script_path = workdir.join(TEST_SCRIPT);
+    if go_test.is_none()
+        && test_command.is_none()
+        && !test_script_path.is_file()
+    {
+        let _ = gocrash.destroy_run(&test_run_dataset);
+        return Err(anyhow::Error::new(SetupError(format!(
+            "copy of {:?} has no {:?} (expected the snapshot of a dataset, \
+             or a directory, containing a Go source tree in \"goroot\")",
+            gocrash.source_snapshot,
+            test_script_path
+                .strip_prefix(mountpoint)
+                .unwrap_or(&test_script_path)
+        ))));
+    }
+
+    // Run the Go build and test suite with stdout and stderr redirected to
+    // files in the new dataset.
+    let stdout_file_path = mountpoint.join("test_run_stdout");
+    let stderr_file_path = mountpoint.join("test_run_stderr");
+    let combined_file_path = mountpoint.join("test_run_combined");
+    let separate = gocrash.combined_log != Some(capture::CombinedLog::Only);
+    run_println!(
+        which_thread,
+        which_run,
+        "start run {} (see {})",
+        run_id,
+        if separate { &stdout_file_path } else { &combined_file_path }
+            .display()
+    );
+
+    let create = |path: &std::path::Path| {
+        std::fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("create {:?}", path))
+    };
+    let outputs = capture::Outputs {
+        stdout: separate.then(|| create(&stdout_file_path)).transpose()?,
+        stderr: separate.then(|| create(&stderr_file_path)).transpose()?,
+        combined: gocrash
+            .combined_log
+            .is_some()
+            .then(|| create(&combined_file_path))
+            .transpose()?,
+        timestamps: gocrash.timestamp_lines,
+        diagnostics: Some(mountpoint.to_path_buf()),
+    };
+    #[cfg(feature = "http")]
+    let _live_run = live::run_started(
+        run_id,
+        if separate { &stdout_file_path } else { &combined_file_path },
+    );
+
+    let mut argv = if let Some(go_test) = go_test {
+        [go_binary.display().to_string(), String::from("test")]
+            .into_iter()
+            .chain(GO_TEST_DEFAULT_ARGS.iter().map(|a| String::from(*a)))
+            .chain(go_test.iter().cloned())
+            .collect::<Vec<_>>()
+    } else if let Some(test_command) = test_command {
+        // The path goes into a shell command, so it has to be quoted (for
+        // bash, or on Windows, for cmd, where paths can't contain quotes).
+        let mountpoint = if cfg!(windows) {
+            format!("\"{}\"", mountpoint.display())
+        } else {
+            pty::shell_quote(&mountpoint.display().to_string())
+        };
+        let command =
+            expand_template(test_command, &mountpoint, which_thread, run_id);
+        if cfg!(windows) {
+            vec![String::from("cmd"), String::from("/C"), command]
+        } else {
+            vec![String::from("bash"), String::from("-c"), command]
+        }
+    } else if cfg!(windows) {
+        ["cmd", "/C", TEST_SCRIPT].into_iter().map(String::from).collect()
+    } else {
+        vec![String::from("bash"), String::from("./all.bash")]
+    };
+    if gocrash.pty {
+        argv = pty::wrap(argv);
+    }
+    if gocrash.no_network {
+        // Run the test suite in its own network namespace, which has only a
+        // loopback interface (that we have to bring up ourselves).  The user
+        // namespace lets us do this without privileges, but only as root in
+        // that namespace, so once the interface is up we enter a nested user
+        // namespace that maps our uid and gid back to what they were, which
+        // keeps the tests from thinking that they're running as root.
+        // Safety: getuid() and getgid() are always safe to call.
+        #[cfg(unix)]
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        #[cfg(not(unix))]
+        let (uid, gid) = (0, 0);
+        argv.splice(
+            0..0,
+            ["unshare", "--user", "--map-root-user", "--net", "--"]
+                .into_iter()
+                .map(String::from)
+                .chain([
+                    String::from("bash"),
+                    String::from("-c"),
+                    format!(
+                        "ip link set lo up && exec unshare --user \
+                         --map-user={} --map-group={} -- \"$@\"",
+                        uid, gid
+                    ),
+                    String::from("bash"),
+                ]),
+        );
+    }
+    let network_log_path = mountpoint.join("network_log");
+    if gocrash.log_network {
+        argv.splice(0..0, netlog::tracer_prefix(&network_log_path));
+    }
+    if let Some(weight) =
+        cpu::weight_for_thread(&gocrash.cpu_weights, which_thread)
+    {
+        argv.splice(0..0, cpu::command_prefix(weight, which_thread));
+        gocrash.record_run_metadata(
+            which_thread,
+            which_run,
+            run_id,
+            "cpu-weight",
+            &weight.to_string(),
+        )?;
+    }
+    if gocrash.lanes.any_background() {
+        gocrash.record_run_metadata(
+            which_thread,
+            which_run,
+            run_id,
+            "lane",
+            gocrash.lanes.lane(which_thread).as_str(),
+        )?;
+    }
+
+    let mut test_cmd = Command::new(&argv[0]);
+    test_cmd.args(&argv[1..]).current_dir(&workdir);
+    // script(1) copies its stdin to the terminal (see `pty.rs`).  This pipe
+    // stays open until the run is over.
+    let pty_input = if gocrash.pty {
+        let (reader, writer) =
+            std::io::pipe().context("create pipe for --pty")?;
+        test_cmd.stdin(reader);
+        Some(writer)
+    } else {
+        None
+    };
+    let output_dir = mountpoint.join(RUN_OUTPUT_DIR);
+    std::fs::create_dir(&output_dir)
+        .with_context(|| format!("mkdir {:?}", output_dir))?;
+    test_cmd.envs(run_context_env(
+        &gocrash.session_key,
+        which_thread,
+        gocrash.lanes.lane(which_thread),
+        run_id,
+        &output_dir,
+    ));
+    if gocrash.collect_go_test_artifacts {
+        let tmp_dir = mountpoint.join(gotest::TMP_DIR);
+        std::fs::create_dir(&tmp_dir)
+            .with_context(|| format!("mkdir {:?}", tmp_dir))?;
+        test_cmd.env("TMPDIR", &tmp_dir);
+        test_cmd.env("GOFLAGS", gotest::goflags());
+    }
+    if gocrash.isolate_env {
+        for (name, path) in isolated_env(mountpoint) {
+            test_cmd.env(name, path);
+        }
+        for dir in ["gocrash_home", "gocrash_gopath"] {
+            let path = mountpoint.join(dir);
+            std::fs::create_dir(&path)
+                .with_context(|| format!("mkdir {:?}", path))?;
+        }
+    }
+
+    gocrash.record_timing(timing::PHASE_PROVISION, run_start.elapsed());
+    gocrash.control.set_phase(which_thread, control::Phase::Test);
+    #[cfg(feature = "tui")]
+    tui::set_phase(which_thread, control::Phase::Test);
+    // Problems that were already there when this run started can't explain
+    // its failure (and were already reported when they appeared).
+    let pool_health_before = gocrash.run_pool_health(which_thread, which_run);
+    let workload_start = Instant::now();
+    let pressure = Mutex::new(psi::PressureTracker::start());
+    let mut watchdogs = Vec::new();
+    if pressure.lock().unwrap().is_some() {
+        // This never kills the run; it just samples pressure while it runs.
+        let pressure = &pressure;
+        watchdogs.push(capture::Watchdog {
+            name: WATCHDOG_PRESSURE,
+            interval: psi::SAMPLE_INTERVAL,
+            check: Box::new(move |_| {
+                if let Some(tracker) = &mut *pressure.lock().unwrap() {
+                    tracker.sample();
+                }
+                None
+            }),
+            diagnose: false,
+        });
+    }
+    if let Some(deadline) = gocrash.session_deadline {
+        watchdogs.push(capture::Watchdog {
+            name: WATCHDOG_SESSION_TIMEOUT,
+            interval: SESSION_TIMEOUT_POLL_INTERVAL,
+            check: Box::new(move |_| {
+                (Instant::now() >= deadline)
+                    .then(|| String::from("reached --session-timeout"))
+            }),
+            diagnose: false,
+        });
+    }
+    watchdogs.push(capture::Watchdog {
+        name: WATCHDOG_SHUTDOWN,
+        interval: SHUTDOWN_POLL_INTERVAL,
+        check: Box::new(move |_| {
+            gocrash.cancel.requested().map(|shutdown| shutdown.reason())
+        }),
+        diagnose: false,
+    });
+    if let Some(max_run_bytes) = gocrash.max_run_bytes {
+        let dataset = &test_run_dataset;
+        watchdogs.push(capture::Watchdog {
+            name: WATCHDOG_MAX_RUN_BYTES,
+            interval: MAX_RUN_BYTES_POLL_INTERVAL,
+            check: Box::new(move |_| {
+                let used = storage::dataset_usage(dataset).ok()?.used;
+                (used > max_run_bytes).then(|| {
+                    format!(
+                        "clone used {} (more than --max-run-bytes {})",
+                        storage::format_bytes(used),
+                        storage::format_bytes(max_run_bytes)
+                    )
+                })
+            }),
+            diagnose: false,
+        });
+    }
+    if let Some(interval) = gocrash.snapshot_interval {
+        // Like the pressure watchdog, this never kills the run.  Snapshots are
+        // named for how far into the workload they were taken.
+        let dataset = &test_run_dataset;
+        let last = Mutex::new(workload_start);
+        watchdogs.push(capture::Watchdog {
+            name: WATCHDOG_SNAPSHOT,
+            interval,
+            check: Box::new(move |_| {
+                // Watchdogs can be woken up early, so make sure it's time.
+                let mut last = last.lock().unwrap();
+                if last.elapsed() < interval {
+                    return None;
+                }
+                *last = Instant::now();
+                let snapshot = format!(
+                    "{}@live-{}",
+                    dataset,
+                    timing::format_duration(workload_start.elapsed())
+                );
+                match gocrash.snapshot_run(&snapshot) {
+                    Ok(()) => run_println!(
+                        which_thread,
+                        which_run,
+                        "took snapshot {}",
+                        snapshot
+                    ),
+                    Err(error) => run_println!(
+                        which_thread,
+                        which_run,
+                        "warning: taking live snapshot: {:#}",
+                        error
+                    ),
+                }
+                None
+            }),
+            diagnose: false,
+        });
+    }
+    if let Some(timeout) = gocrash.output_stall_timeout {
+        watchdogs.push(capture::Watchdog {
+            name: WATCHDOG_OUTPUT_STALL,
+            interval: OUTPUT_STALL_POLL_INTERVAL.min(timeout),
+            check: Box::new(move |progress| {
+                (progress.last_output.elapsed() >= timeout).then(|| {
+                    format!(
+                        "no output for {} (--output-stall-timeout)",
+                        timing::format_duration(timeout)
+                    )
+                })
+            }),
+            diagnose: true,
+        });
+    }
+    if let (Some(keepalive), Some(input)) = (gocrash.pty_keepalive, &pty_input)
+    {
+        // This never kills the run; it just types on the terminal.
+        let last_typed = Mutex::new(workload_start);
+        watchdogs.push(capture::Watchdog {
+            name: WATCHDOG_PTY_KEEPALIVE,
+            interval: PTY_KEEPALIVE_POLL_INTERVAL.min(keepalive),
+            check: Box::new(move |progress| {
+                let mut last_typed = last_typed.lock().unwrap();
+                if progress.last_output.elapsed() >= keepalive
+                    && last_typed.elapsed() >= keepalive
+                {
+                    // If this fails, the test suite has already exited.
+                    let mut input = input;
+                    let _ =
+                        std::io::Write::write_all(&mut input, pty::KEEPALIVE);
+                    *last_typed = Instant::now();
+                }
+                None
+            }),
+            diagnose: false,
+        });
+    }
+    if gocrash.go_test_for(which_thread).is_some() {
+        // `go test -count=N` keeps going after a failure, but later iterations
+        // would only pile more output and state on top of the failure.  We
+        // give the failed test a moment to finish printing its logs first.
+        watchdogs.push(capture::Watchdog {
+            name: WATCHDOG_GO_TEST_FAILURE,
+            interval: GO_TEST_FAILURE_GRACE,
+            check: Box::new(move |progress| {
+                let (line, when) = progress.failure.as_ref()?;
+                (when.elapsed() >= GO_TEST_FAILURE_GRACE)
+                    .then(|| format!("stopped at first test failure: {}", line))
+            }),
+            diagnose: false,
+        });
+    }
+    if let Some(timeout) = gocrash.run_timeout {
+        watchdogs.push(capture::Watchdog {
+            name: WATCHDOG_RUN_TIMEOUT,
+            interval: RUN_TIMEOUT_POLL_INTERVAL.min(timeout),
+            check: Box::new(move |_| {
+                (workload_start.elapsed() >= timeout).then(|| {
+                    format!(
+                        "workload took longer than {} (--run-timeout)",
+                        timing::format_duration(timeout)
+                    )
+                })
+            }),
+            diagnose: true,
+        });
+    }
+    if let Some(adaptive) = &gocrash.adaptive_timeouts {
+        // Use the timeouts as they stand when the run starts.
+        let limits = adaptive.lock().unwrap().limits();
+        watchdogs.push(capture::Watchdog {
+            name: WATCHDOG_ADAPTIVE_TIMEOUT,
+            interval: ADAPTIVE_TIMEOUT_POLL_INTERVAL,
+            check: Box::new(move |progress| {
+                let mut phases = std::iter::once((
+                    String::from(timing::PHASE_WORKLOAD),
+                    workload_start,
+                ))
+                .chain(progress.phase.as_ref().map(|(name, start)| {
+                    (format!("workload: {}", name), *start)
+                }));
+                phases.find_map(|(phase, start)| {
+                    let limit = limits.get(&phase)?;
+                    let elapsed = start.elapsed();
+                    (elapsed > limit.timeout).then(|| {
+                        format!(
+                            "{} has taken {} (adaptive timeout {}, from \
+                             p99 {} over {} passing runs)",
+                            phase,
+                            timing::format_duration(elapsed),
+                            timing::format_duration(limit.timeout),
+                            timing::format_duration(limit.p99),
+                            limit.nsamples
+                        )
+                    })
+                })
+            }),
+            diagnose: true,
+        });
+    }
+    let captured = capture::run_captured(&mut test_cmd, outputs, &watchdogs);
+    drop(watchdogs);
+    let test_result = captured.result;
+    let phases = captured.phases;
+    let workload_done = Instant::now();
+    gocrash.control.set_phase(which_thread, control::Phase::Cleanup);
+    #[cfg(feature = "tui")]
+    tui::set_phase(which_thread, control::Phase::Cleanup);
+    let phase_timings = std::iter::once((
+        String::from(timing::PHASE_WORKLOAD),
+        workload_done - workload_start,
+    ))
+    .chain(
+        phases
+            .into_iter()
+            .map(|phase| (format!("workload: {}", phase.name), phase.duration)),
+    )
+    .collect::<Vec<_>>();
+    for (phase, duration) in &phase_timings {
+        gocrash.record_timing(phase, *duration);
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(history) = gocrash.history {
+        if let Err(error) = history.lock().unwrap().record_phases(
+            &gocrash.session_key,
+            run_id,
+            &phase_timings,
+        ) {
+            run_println!(which_thread, which_run, "warning: {:#}", error);
+        }
+    }
+    // The history database gets the result of each test, so we need the
+    // output of every run for that.  Otherwise, we only need it for failures
+    // (below).
+    #[cfg(feature = "sqlite")]
+    let output = gocrash.history.and_then(|history| {
+        let result = read_run_output(
+            gocrash,
+            separate,
+            &stdout_file_path,
+            &stderr_file_path,
+            &combined_file_path,
+        )
+        .and_then(|(stdout, stderr)| {
+            history.lock().unwrap().record_tests(
+                &gocrash.session_key,
+                run_id,
+                &gotest::test_results(&stdout, &stderr),
+            )?;
+            Ok((stdout, stderr))
+        });
+        match result {
+            Ok(output) => Some(output),
+            Err(error) => {
+                run_println!(which_thread, which_run, "warning: {:#}", error);
+                None
+            }
+        }
+    });
+    #[cfg(not(feature = "sqlite"))]
+    let output = None;
+    gocrash.record_storage(which_thread, which_run, &test_run_dataset);
+    if let Some(tracker) = pressure.into_inner().unwrap() {
+        gocrash.record_run_metadata_all(
+            which_thread,
+            which_run,
+            run_id,
+            &tracker.finish(),
+        )?;
+    }
+
+    if gocrash.log_network {
+        record_network_log(
+            gocrash,
+            &test_run_dataset,
+            mountpoint,
+            &network_log_path,
+            which_thread,
+            which_run,
+        )?;
+    }
+
+    if let Err(error) = test_result {
+        // Leave the dataset around for inspection.
+        let cut_short = captured.killed.as_ref().is_some_and(|k| {
+            k.watchdog == WATCHDOG_SESSION_TIMEOUT
+                || k.watchdog == WATCHDOG_SHUTDOWN
+        });
+        if cut_short || was_interrupted(&error) {
+            // If we cut the run short ourselves, it didn't fail, so (as with
+            // runs that pass) there's nothing worth keeping.
+            if gocrash.cutting_short() && !gocrash.keep_success {
+                gocrash.destroy_run_later(&test_run_dataset)?;
+            } else {
+                gocrash
+                    .set_run_state(&test_run_dataset, RunState::Interrupted)?;
+            }
+            gocrash
+                .record_timing(timing::PHASE_CLEANUP, workload_done.elapsed());
+            return Ok(RunResult::Interrupted);
+        }
+
+        // Note whether storage errors appeared while this run was going,
+        // since that might explain this.
+        let mut suspect = false;
+        if let Some(now) = gocrash.run_pool_health(which_thread, which_run) {
+            let problems = pool_health_before
+                .as_ref()
+                .map(|before| before.new_problems(&now))
+                .unwrap_or_default();
+            if !problems.is_empty() {
+                run_println!(
+                    which_thread,
+                    which_run,
+                    "WARNING: storage problems appeared during this run: {}",
+                    problems.join(", ")
+                );
+                suspect = true;
+            }
+            let _ = std::fs::write(
+                mountpoint.join("pool_status"),
+                format!("{}\n\n{}", now, now.raw_status),
+            );
+        }
+
+        let (stdout, stderr) = match output {
+            Some(output) => output,
+            None => read_run_output(
+                gocrash,
+                separate,
+                &stdout_file_path,
+                &stderr_file_path,
+                &combined_file_path,
+            )?,
+        };
+        let severity =
+            severity::classify(&gocrash.severity_rules, &stdout, &stderr);
+        gocrash.set_property(
+            &test_run_dataset,
+            session::SEVERITY_PROPERTY,
+            &severity.to_string(),
+        )?;
+        let stalled = captured
+            .killed
+            .as_ref()
+            .filter(|k| k.watchdog == WATCHDOG_OUTPUT_STALL);
+        let timed_out = captured.killed.as_ref().filter(|k| {
+            k.watchdog == WATCHDOG_ADAPTIVE_TIMEOUT
+                || k.watchdog == WATCHDOG_RUN_TIMEOUT
+        });
+        let hung = stalled.is_some()
+            || timed_out.is_some()
+            || [&stdout, &stderr]
+                .iter()
+                .any(|output| output.contains(GO_TEST_TIMEOUT_MESSAGE));
+        let runaway = captured
+            .killed
+            .as_ref()
+            .is_some_and(|k| k.watchdog == WATCHDOG_MAX_RUN_BYTES);
+        let (state, make_result): (_, fn(TestFailure) -> RunResult) = if runaway
+        {
+            (RunState::Runaway, RunResult::Runaway)
+        } else if suspect {
+            (RunState::Suspect, RunResult::Suspect)
+        } else if hung {
+            (RunState::Hung, RunResult::Hang)
+        } else {
+            (RunState::Failed, RunResult::Fail)
+        };
+        gocrash.set_run_state(&test_run_dataset, state)?;
+        let signature = if runaway {
+            // The output is cut off wherever we killed it, so it's not useful
+            // for telling runaway runs apart.
+            String::from("runaway: clone exceeded --max-run-bytes")
+        } else if let Some(stalled) = stalled {
+            // Go programs that got SIGQUIT dump every goroutine, which doesn't
+            // say much about where things got stuck, so go by the last thing
+            // that the test suite said before it went quiet.
+            format!(
+                "output stalled after: {}",
+                stalled
+                    .progress
+                    .last_line
+                    .as_deref()
+                    .map(signature::normalize)
+                    .unwrap_or_else(|| String::from("(no output)"))
+            )
+        } else if let Some(timed_out) = timed_out {
+            // As with stalls, group these by where the run got stuck.
+            format!(
+                "exceeded {} in: {}",
+                if timed_out.watchdog == WATCHDOG_RUN_TIMEOUT {
+                    "run timeout"
+                } else {
+                    "adaptive timeout"
+                },
+                timed_out
+                    .progress
+                    .phase
+                    .as_ref()
+                    .map_or(timing::PHASE_WORKLOAD, |(name, _)| name)
+            )
+        } else {
+            gocrash.signer.failure_signature(&stdout, &stderr)
+        };
+        let known = gocrash.catalog.lookup(&signature, &stdout, &stderr);
+        if let Some(known) = known {
+            gocrash.set_property(
+                &test_run_dataset,
+                session::KNOWN_FAILURE_PROPERTY,
+                &known.name,
+            )?;
+        }
+        gocrash.record_toolchain(
+            which_thread,
+            which_run,
+            run_id,
+            mountpoint,
+            true,
+        )?;
+        if gocrash.collect_go_test_artifacts {
+            collect_go_test_artifacts(
+                which_thread,
+                which_run,
+                mountpoint,
+                &stdout,
+                &stderr,
+            );
+        }
+        gocrash.run_hooks(
+            which_thread,
+            which_run,
+            run_id,
+            mountpoint,
+            state,
+            Some(&signature),
+        )?;
+
+        // Keep the run's dataset (and archive it) unless we've already kept
+        // as many runs with this failure as the user wants.
+        let decision = gocrash.retention.check(&signature, known);
+        if let retention::Decision::Sampled(limit) = decision {
+            run_println!(
+                which_thread,
+                which_run,
+                "keeping {} (sampled from failures beyond the first {})",
+                test_run_dataset,
+                limit
+            );
+        }
+        let kept = match decision {
+            retention::Decision::Keep | retention::Decision::Sampled(_) => {
+                if let Some(archive_dir) = &gocrash.archive_dir {
+                    archive_run(
+                        gocrash,
+                        which_thread,
+                        which_run,
+                        &test_run_dataset,
+                        mountpoint,
+                        archive_dir,
+                    );
+                }
+                true
+            }
+            retention::Decision::Destroy(limit) => {
+                run_println!(
+                    which_thread,
+                    which_run,
+                    "destroying {} (keeping at most {} run{} with this \
+                     failure{})",
+                    test_run_dataset,
+                    limit,
+                    if limit == 1 { "" } else { "s" },
+                    gocrash.retention.describe_sampling()
+                );
+                gocrash.release_run(which_thread, &test_run_dataset)?;
+                false
+            }
+        };
+        gocrash.record_timing(timing::PHASE_CLEANUP, workload_done.elapsed());
+        return Ok(make_result(TestFailure {
+            dataset: test_run_dataset,
+            signature,
+            severity,
+            known: known.cloned(),
+            kept,
+            details: gotest::failure_details(&stdout, &stderr),
+            error,
+        }));
+    }
+
+    gocrash.record_toolchain(
+        which_thread,
+        which_run,
+        run_id,
+        mountpoint,
+        gocrash.keep_success,
+    )?;
+    gocrash.run_hooks(
+        which_thread,
+        which_run,
+        run_id,
+        mountpoint,
+        RunState::Passed,
+        None,
+    )?;
+
+    // If that succeeded, we're done with the dataset (unless we're keeping
+    // it).
+    if gocrash.keep_success {
+        gocrash.set_run_state(&test_run_dataset, RunState::Passed)?;
+        if let Some(archive_dir) = &gocrash.archive_dir {
+            archive_run(
+                gocrash,
+                which_thread,
+                which_run,
+                &test_run_dataset,
+                mountpoint,
+                archive_dir,
+            );
+        }
+    } else {
+        gocrash.release_run(which_thread, &test_run_dataset)?;
+    }
+    gocrash.record_timing(timing::PHASE_CLEANUP, workload_done.elapsed());
+    if let Some(adaptive) = &gocrash.adaptive_timeouts {
+        let mut adaptive = adaptive.lock().unwrap();
+        for (phase, duration) in &phase_timings {
+            adaptive.record(phase, *duration);
+        }
+    }
+
+    Ok(RunResult::Pass)
+}
+
+/// Gathers what `go test` left behind for the tests that failed in a run into
+/// the run's output directory (see `gotest.rs`)
+fn collect_go_test_artifacts(
+    which_thread: u8,
+    which_run: usize,
+    mountpoint: &std::path::Path,
+    stdout: &str,
+    stderr: &str,
+) {
+    let result = gotest::collect(
+        &mountpoint.join(gotest::TMP_DIR),
+        &mountpoint.join(RUN_OUTPUT_DIR).join(gotest::OUTPUT_SUBDIR),
+        stdout,
+        stderr,
+    );
+    match result {
+        Ok(gotest::Collected { nbinaries: 0, ntempdirs: 0 }) => (),
+        Ok(collected) => run_println!(
+            which_thread,
+            which_run,
+            "collected {} test binar{} and {} temporary director{} from go \
+             test",
+            collected.nbinaries,
+            if collected.nbinaries == 1 { "y" } else { "ies" },
+            collected.ntempdirs,
+            if collected.ntempdirs == 1 { "y" } else { "ies" }
+        ),
+        Err(error) => run_println!(
+            which_thread,
+            which_run,
+            "warning: collecting go test artifacts: {:#}",
+            error
+        ),
+    }
+}
+
+/// Summarizes the network connections made during a run, saving the summary
+/// in the run's dataset
+fn record_network_log(
+    gocrash: &Gocrash,
+    test_run_dataset: &str,
+    mountpoint: &std::path::Path,
+    network_log_path: &std::path::Path,
+    which_thread: u8,
+    which_run: usize,
+) -> Result<(), anyhow::Error> {
+    let destinations = netlog::summarize(&read_lossy(network_log_path)?);
+    let mut summary = String::new();
+    for (dest, count) in &destinations {
+        writeln!(&mut summary, "{:>6}  {}", count, dest).unwrap();
+    }
+    let summary_path = mountpoint.join("network_summary");
+    std::fs::write(&summary_path, &summary)
+        .with_context(|| format!("write {:?}", summary_path))?;
+    gocrash.set_property(
+        test_run_dataset,
+        netlog::NETWORK_DESTINATIONS_PROPERTY,
+        &destinations.len().to_string(),
+    )?;
+
+    if !destinations.is_empty() {
+        run_println!(
+            which_thread,
+            which_run,
+            "connected to {} external destination{}: {}",
+            destinations.len(),
+            if destinations.len() == 1 { "" } else { "s" },
+            destinations
+                .keys()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Name of the watchdog that samples pressure during each run (see `psi.rs`)
+const WATCHDOG_PRESSURE: &str = "pressure";
+
+/// Name of the watchdog that enforces `--session-timeout`
+const WATCHDOG_SESSION_TIMEOUT: &str = "session-timeout";
+
+/// How often to check whether the session has timed out while a run is in
+/// progress
+const SESSION_TIMEOUT_POLL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(1);
+
+/// Name of the watchdog that kills runs when we're asked to shut down (see
+/// `shutdown.rs`)
+const WATCHDOG_SHUTDOWN: &str = "shutdown";
+
+/// How often to check whether we've been asked to shut down while a run is in
+/// progress
+const SHUTDOWN_POLL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(1);
+
+/// Name of the watchdog that enforces `--max-run-bytes`
+const WATCHDOG_MAX_RUN_BYTES: &str = "max-run-bytes";
+
+/// How often to check a clone's space usage when `--max-run-bytes` is used
+const MAX_RUN_BYTES_POLL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(10);
+
+/// Name of the watchdog that takes live snapshots (see `--snapshot-interval`)
+const WATCHDOG_SNAPSHOT: &str = "snapshot";
+
+/// Name of the watchdog that enforces `--output-stall-timeout`
+const WATCHDOG_OUTPUT_STALL: &str = "output-stall";
+
+/// Name of the watchdog that enforces `--run-timeout`
+const WATCHDOG_RUN_TIMEOUT: &str = "run-timeout";
+
+/// How often to check runs against `--run-timeout`
+const RUN_TIMEOUT_POLL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(5);
+
+/// Name of the watchdog that enforces `--adaptive-timeout`
+const WATCHDOG_ADAPTIVE_TIMEOUT: &str = "adaptive-timeout";
+
+/// How often to check runs against their adaptive timeouts
+const ADAPTIVE_TIMEOUT_POLL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(5);
+
+/// How often to check whether a run's output has stalled when
+/// `--output-stall-timeout` is used
+const OUTPUT_STALL_POLL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(5);
+
+/// Name of the watchdog that types on the terminal (see `--pty-keepalive`)
+const WATCHDOG_PTY_KEEPALIVE: &str = "pty-keepalive";
+
+/// How often to check whether it's time to type on the terminal with
+/// `--pty-keepalive`
+const PTY_KEEPALIVE_POLL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(5);
+
+/// Name of the watchdog that stops `--go-test` runs after a test fails
+const WATCHDOG_GO_TEST_FAILURE: &str = "go-test-failure";
+
+/// How long to let `--go-test` runs keep going after a test fails (so that
+/// the test's logs are complete)
+const GO_TEST_FAILURE_GRACE: std::time::Duration =
+    std::time::Duration::from_secs(2);
+
+/// Default for `--output-stall-timeout` with `--go-test` (the same as the
+/// default timeout for one `go test` binary)
+const GO_TEST_STALL_TIMEOUT: std::time::Duration =
+    std::time::Duration::from_secs(10 * 60);
+
+/// Arguments passed to `go test` ahead of those given with `--go-test`
+///
+/// "-v" makes every iteration print something, which is what lets us detect
+/// hangs (with `--output-stall-timeout`) and failures as they happen.  Go's
+/// own timeout applies to the whole process (i.e., all iterations together),
+/// so we turn it off and detect hangs ourselves.  Later flags take precedence,
+/// so the user can still override these.
+const GO_TEST_DEFAULT_ARGS: &[&str] = &["-v", "-timeout=0"];
+
+/// The `go` command (in "goroot/bin")
+const GO_BINARY: &str = if cfg!(windows) { "go.exe" } else { "go" };
+
+/// The Go test suite's entry point (in "goroot/src")
+const TEST_SCRIPT: &str = if cfg!(windows) { "all.bat" } else { "all.bash" };
+
+/// Printed by `go test` when a test binary exceeds its timeout (i.e., the test
+/// hung)
+const GO_TEST_TIMEOUT_MESSAGE: &str = "panic: test timed out after ";
+
+/// Returns whether a failed command was killed by SIGINT or SIGTERM (either
+/// directly or, for shell scripts, indirectly)
+fn was_interrupted(error: &anyhow::Error) -> bool {
+    let Some(error) = error.downcast_ref::<CommandError>() else {
+        return false;
+    };
+    match (error.status.code(), exit_signal(error.status)) {
+        (_, Some(signal)) => signal == libc::SIGINT || signal == libc::SIGTERM,
+        (Some(code), None) => {
+            code == 128 + libc::SIGINT || code == 128 + libc::SIGTERM
+        }
+        (None, None) => false,
+    }
+}
+
+/// Copies artifacts from a failed run (or a passed one, with
+/// `--keep-success`) into the archive directory
+///
+/// Failing to archive a run is reported, but doesn't otherwise affect the
+/// session, since the run's dataset is still around.
+fn archive_run(
+    gocrash: &Gocrash,
+    which_thread: u8,
+    which_run: usize,
+    dataset: &str,
+    mountpoint: &std::path::Path,
+    archive_dir: &std::path::Path,
+) {
+    let run_key = dataset.rsplit('/').next().unwrap();
+    let target = archive_dir.join(&gocrash.session_key).join(run_key);
+    let store = gocrash
+        .archive_store
+        .as_ref()
+        .expect("archive store exists with archive directory");
+    let result = gocrash
+        .archive_policy
+        .archive(mountpoint, &target, store)
+        .and_then(|summary| {
+            gocrash.set_property(
+                dataset,
+                archive::ARCHIVE_PROPERTY,
+                &target.display().to_string(),
+            )?;
+            Ok(summary)
+        });
+    match result {
+        Ok(summary) => run_println!(
+            which_thread,
+            which_run,
+            "archived {} file{} ({} new bytes, {} already stored) to {}",
+            summary.nfiles,
+            if summary.nfiles == 1 { "" } else { "s" },
+            summary.nbytes,
+            summary.nshared,
+            target.display()
+        ),
+        Err(error) => run_println!(
+            which_thread,
+            which_run,
+            "warning: archiving run: {:#}",
+            error
+        ),
+    }
+}
+
+/// Returns environment variables that give a run its own home directory, Go
+/// module cache, and so on inside the clone
+///
+/// Tests that use these would otherwise interfere with each other across runs
+/// and leave stuff in the caller's home directory.
+fn isolated_env(
+    mountpoint: &std::path::Path,
+) -> [(&'static str, std::path::PathBuf); 4] {
+    let home = mountpoint.join("gocrash_home");
+    let gopath = mountpoint.join("gocrash_gopath");
+    [
+        ("GOENV", home.join(".config/go/env")),
+        ("GOMODCACHE", gopath.join("pkg/mod")),
+        ("HOME", home),
+        ("GOPATH", gopath),
+    ]
+}
+
+/// Fills in the variables in a `--test-command` or `--workdir` template for
+/// one run: "{mountpoint}" (the run's clone, which the caller quotes as
+/// needed), "{thread}", and "{run}" (the run's number)
+///
+/// Anything else in braces is left alone, since shell commands use them, too.
+fn expand_template(
+    template: &str,
+    mountpoint: &str,
+    which_thread: u8,
+    run_id: usize,
+) -> String {
+    template
+        .replace("{mountpoint}", mountpoint)
+        .replace("{thread}", &which_thread.to_string())
+        .replace("{run}", &run_id.to_string())
+}
+
+/// Directory in each clone where the workload can save its own artifacts (see
+/// `GOCRASH_OUTPUT_DIR` in `run_context_env()`)
+const RUN_OUTPUT_DIR: &str = "gocrash_output";
+
+/// Returns environment variables that tell the workload which run it is, so
+/// that wrappers can label their own artifacts and logs to match our records
+fn run_context_env(
+    session: &str,
+    which_thread: u8,
+    lane: lane::Lane,
+    run_id: usize,
+    output_dir: &std::path::Path,
+) -> [(&'static str, std::ffi::OsString); 5] {
+    [
+        ("GOCRASH_SESSION", session.into()),
+        ("GOCRASH_THREAD", which_thread.to_string().into()),
+        ("GOCRASH_LANE", lane.as_str().into()),
+        ("GOCRASH_RUN_ID", run_id.to_string().into()),
+        ("GOCRASH_OUTPUT_DIR", output_dir.into()),
+    ]
+}
+
+/// Returns the mountpoint of the given ZFS dataset
+fn zfs_mountpoint(dataset: &str) -> Result<std::path::PathBuf, anyhow::Error> {
+    let output = zfs::run(
+        Command::new("zfs")
+            .arg("list")
+            .arg("-H")
+            .arg("-omountpoint")
+            .arg(dataset),
+    )?;
+    Ok(std::path::PathBuf::from(output.trim()))
+}
+
+/// Reads the contents of a file that's expected to be mostly text
+/// Returns a run's stdout and stderr as the workload wrote them (without the
+/// timestamps or notes that we add), from wherever they were saved
+fn read_run_output(
+    gocrash: &Gocrash,
+    separate: bool,
+    stdout_file_path: &std::path::Path,
+    stderr_file_path: &std::path::Path,
+    combined_file_path: &std::path::Path,
+) -> Result<(String, String), anyhow::Error> {
+    let (stdout, stderr) = if separate && gocrash.timestamp_lines {
+        (
+            capture::strip_timestamps(&read_lossy(stdout_file_path)?),
+            capture::strip_timestamps(&read_lossy(stderr_file_path)?),
+        )
+    } else if separate {
+        (read_lossy(stdout_file_path)?, read_lossy(stderr_file_path)?)
+    } else {
+        capture::split_combined(&read_lossy(combined_file_path)?)
+    };
+    Ok((capture::strip_notes(&stdout), capture::strip_notes(&stderr)))
+}
+
+fn read_lossy(path: &std::path::Path) -> Result<String, anyhow::Error> {
+    let contents = std::fs::read(path)
+        .with_context(|| format!("read {:?}", path.display()))?;
+    Ok(String::from_utf8_lossy(&contents).into_owned())
+}
+
+/// Construct a human-readable label for use in log and error messages.
+fn command_label(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program().to_string_lossy())
+        .chain(cmd.get_args().map(|s| s.to_string_lossy()))
+        .map(|s| format!("{:?}", s))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Runs the given command, buffering stdout and stderr and returning UTF-8
+/// decoded stdout.
+///
+/// On failure, a detailed error message is produced.
+fn run_command(cmd: &mut Command) -> Result<String, anyhow::Error> {
+    // Construct a human-readable label for use in error messages.
+    let label = command_label(cmd);
+
+    let result =
+        cmd.output().with_context(|| format!("failed to exec {}", label))?;
+    if result.status.success() {
+        Ok(String::from_utf8_lossy(&result.stdout).to_string())
+    } else {
+        Err(command_error(
+            &label,
+            result.status,
+            &result.stdout,
+            &result.stderr,
+        ))
+    }
+}
+
+/// Returns the signal that terminated a process, if any
+#[cfg(unix)]
+fn exit_signal(status: std::process::ExitStatus) -> Option<i32> {
+    status.signal()
+}
+
+/// Returns the signal that terminated a process, if any (processes aren't
+/// terminated by signals on this system)
+#[cfg(not(unix))]
+fn exit_signal(_status: std::process::ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Creates a symlink at `link` pointing to `original`
+#[cfg(unix)]
+fn symlink(
+    original: &std::path::Path,
+    link: &std::path::Path,
+) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+/// Creates a symlink at `link` pointing to `original` (not supported on this
+/// system)
+#[cfg(not(unix))]
+fn symlink(
+    _original: &std::path::Path,
+    _link: &std::path::Path,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "symlinks are not supported on this system",
+    ))
+}
+
+/// Constructs an error describing a command that failed, including whatever
+/// it wrote to stdout and stderr
+fn command_error(
+    label: &str,
+    status: std::process::ExitStatus,
+    stdout: &[u8],
+    stderr: &[u8],
+) -> anyhow::Error {
+    let result_summary = if let Some(code) = status.code() {
+        format!("exited with code {}", code)
+    } else {
+        let signal =
+            exit_signal(status).expect("process exited with no code or signal");
+        format!("terminated by signal {}", signal)
+    };
+
+    let mut output = String::new();
+    write!(&mut output, "command failed: {}: {}", label, result_summary)
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(stderr);
+    if !stderr.is_empty() {
+        write!(&mut output, "\nstderr:\n{}\n", stderr).unwrap();
+    }
+
+    let stdout = String::from_utf8_lossy(stdout);
+    if !stdout.is_empty() {
+        write!(&mut output, "\nstdout:\n{}\n", stdout).unwrap();
+    }
+
+    anyhow::Error::new(CommandError { status, message: output })
+}
+
+/// Describes a command that ran but failed
+#[derive(Debug)]
+struct CommandError {
+    status: std::process::ExitStatus,
+    message: String,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CommandError {}
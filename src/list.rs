@@ -0,0 +1,214 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! `gocrash list`: show the sessions whose working datasets are still around
+//!
+//! Before cleaning up (see `clean.rs`) or going back to a failure, it helps to
+//! see what's on the machine.  For each working dataset that a session created
+//! for the source, we show when the session started, how it ended and how
+//! many of its runs failed (from the `session.json` that it saved in its
+//! working dataset when it finished), how many runs' datasets are still
+//! there, and how much space it all uses.
+//!
+//! Sessions that are still running, and ones that died without saving
+//! `session.json`, are shown as "unfinished".  (As with `gocrash clean`, we
+//! can't tell which is which.)
+
+use crate::backend;
+use crate::clean;
+use crate::session;
+use crate::storage;
+use anyhow::Context;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Show the sessions whose working datasets are still around
+#[derive(clap::Args)]
+pub struct ListArgs {
+    /// how runs' copies of the source tree were made
+    #[arg(long, value_enum, default_value_t)]
+    backend: backend::Backend,
+
+    /// dataset (or snapshot of it) that the sessions used (with the copy or
+    /// overlay backend, the source directory)
+    source: String,
+}
+
+/// What we could find out about one session
+struct SessionSummary {
+    session_key: String,
+    started: SystemTime,
+    /// how the session ended ("passed", "failed", or "error"), or
+    /// "unfinished"
+    status: String,
+    /// number of runs that finished (if the session did)
+    nruns: Option<u64>,
+    /// number of runs that failed (if the session finished)
+    nfailed: Option<usize>,
+    /// number of per-run datasets that are still there
+    nkept: Option<usize>,
+    /// bytes used by the working dataset and everything in it
+    used: Option<u64>,
+}
+
+/// Runs the `gocrash list` command
+pub fn list(args: &ListArgs) -> Result<(), anyhow::Error> {
+    args.backend.check_supported()?;
+    let clones = args.backend.clones();
+    let mut sessions = clones
+        .find_working_datasets(&args.source)?
+        .into_iter()
+        .filter_map(|(name, session_key)| {
+            let started = clean::session_start(&session_key)?;
+            Some((name, session_key, started))
+        })
+        .collect::<Vec<_>>();
+    sessions.sort_by_key(|(_, _, started)| *started);
+
+    println!(
+        "found {} session{} for {:?}",
+        sessions.len(),
+        if sessions.len() == 1 { "" } else { "s" },
+        args.source
+    );
+    if sessions.is_empty() {
+        return Ok(());
+    }
+
+    let summaries = sessions
+        .into_iter()
+        .map(|(name, session_key, started)| {
+            summarize(args.backend, &name, session_key, started)
+        })
+        .collect::<Vec<_>>();
+    let unknown = || String::from("-");
+    let width = summaries.iter().map(|s| s.session_key.len()).max().unwrap();
+    println!(
+        "{:width$}  {:23}  {:10}  {:>5}  {:>6}  {:>5}  {:>10}",
+        "SESSION",
+        "STARTED",
+        "STATUS",
+        "RUNS",
+        "FAILED",
+        "KEPT",
+        "USED",
+        width = width
+    );
+    for summary in &summaries {
+        println!(
+            "{:width$}  {:23}  {:10}  {:>5}  {:>6}  {:>5}  {:>10}",
+            summary.session_key,
+            chrono::DateTime::<chrono::Utc>::from(summary.started)
+                .format("%Y-%m-%d %H:%M:%S UTC")
+                .to_string(),
+            summary.status,
+            summary.nruns.map_or_else(unknown, |n| n.to_string()),
+            summary.nfailed.map_or_else(unknown, |n| n.to_string()),
+            summary.nkept.map_or_else(unknown, |n| n.to_string()),
+            summary.used.map_or_else(unknown, storage::format_bytes),
+            width = width
+        );
+    }
+    let total: u64 = summaries.iter().filter_map(|s| s.used).sum();
+    println!("total used: {}", storage::format_bytes(total));
+    Ok(())
+}
+
+/// Gathers what we can about the session that created working dataset `name`,
+/// warning about anything we couldn't find out
+fn summarize(
+    backend: backend::Backend,
+    name: &str,
+    session_key: String,
+    started: SystemTime,
+) -> SessionSummary {
+    let mut summary = SessionSummary {
+        session_key,
+        started,
+        status: String::from("unfinished"),
+        nruns: None,
+        nfailed: None,
+        nkept: None,
+        used: None,
+    };
+    let warn = |what: &str, error: anyhow::Error| {
+        eprintln!("warning: {}: {}: {:#}", name, what, error);
+    };
+
+    let mountpoint = match backend.clones().mountpoint(name) {
+        Ok(mountpoint) => mountpoint,
+        Err(error) => {
+            warn("finding mountpoint", error);
+            return summary;
+        }
+    };
+    match read_record(&mountpoint) {
+        Ok(Some(record)) => {
+            if let Some(outcome) = record["outcome"].as_str() {
+                summary.status = outcome.to_string();
+            }
+            summary.nruns = record["results"]
+                .as_object()
+                .map(|results| results.values().filter_map(|n| n.as_u64()))
+                .map(|counts| counts.sum());
+            summary.nfailed = record["failed_runs"].as_array().map(Vec::len);
+        }
+        Ok(None) => (),
+        Err(error) => warn("reading session summary", error),
+    }
+    match count_runs(&mountpoint) {
+        Ok(nkept) => summary.nkept = Some(nkept),
+        Err(error) => warn("counting runs", error),
+    }
+    let used = match backend {
+        backend::Backend::Zfs => storage::dataset_usage(name).map(|u| u.used),
+        backend::Backend::Copy | backend::Backend::Overlay => {
+            storage::directory_usage(&mountpoint)
+        }
+    };
+    match used {
+        Ok(used) => summary.used = Some(used),
+        Err(error) => warn("measuring space used", error),
+    }
+    summary
+}
+
+/// Reads the session's summary from the working dataset mounted at
+/// `mountpoint`, if it's there
+fn read_record(
+    mountpoint: &Path,
+) -> Result<Option<serde_json::Value>, anyhow::Error> {
+    let path = mountpoint.join(session::SESSION_JSON);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        Err(error) => {
+            return Err(error).with_context(|| format!("read {:?}", path));
+        }
+    };
+    let record = serde_json::from_str(&contents)
+        .with_context(|| format!("parse {:?}", path))?;
+    Ok(Some(record))
+}
+
+/// Returns how many per-run datasets there are in the working dataset mounted
+/// at `mountpoint`
+fn count_runs(mountpoint: &Path) -> Result<usize, anyhow::Error> {
+    let mut nruns = 0;
+    let entries = std::fs::read_dir(mountpoint)
+        .with_context(|| format!("read directory {:?}", mountpoint))?;
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("read directory {:?}", mountpoint))?;
+        if entry.file_name().to_string_lossy().starts_with("thread-")
+            && entry.path().is_dir()
+        {
+            nruns += 1;
+        }
+    }
+    Ok(nruns)
+}
@@ -0,0 +1,280 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Live progress over HTTP for `--live-listen`
+//!
+//! `--format json` is fine for a program that started gocrash itself, but a
+//! dashboard elsewhere on the network wants to follow a session that's
+//! already running.  With `--live-listen`, we serve:
+//!
+//! * `/events`: a WebSocket that sends each event described in `events.rs`
+//!   (as a text message with the same JSON object) as it happens
+//! * `/runs/ID/log`: the output of run ID so far (its `test_run_stdout`, or
+//!   `test_run_combined` with `--combined-log=only`), while it's running.
+//!   With `?follow=1`, the connection stays open and new output is sent as
+//!   it's written, until the run is over.  (We forget about each run once
+//!   it's over and nobody's following its log, since a long session would
+//!   otherwise pile up an entry for every run it ever started.)
+//!
+//! There's one server per process, which carries on from one session to the
+//! next in a pipeline (see `pipeline.rs`).  Clients only see what happens
+//! after they connect.
+
+use crate::http;
+use crate::http::HttpError;
+use crate::http::Request;
+use crate::http::Response;
+use anyhow::bail;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::io::Seek;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of events that can be waiting to go to one client before we give up
+/// on it
+const MAX_QUEUED_EVENTS: usize = 1000;
+
+/// How often to ping WebSocket clients when there are no events (which lets
+/// us notice clients that have gone away)
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often to check for new output when following a run's log
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// State shared between the session and the server
+static HUB: Mutex<Hub> = Mutex::new(Hub {
+    listen: None,
+    subscribers: Vec::new(),
+    runs: BTreeMap::new(),
+});
+
+struct Hub {
+    /// where the server is listening, once it's started
+    listen: Option<SocketAddr>,
+    /// one for each WebSocket client
+    subscribers: Vec<mpsc::SyncSender<String>>,
+    /// runs that are in progress or whose logs are being followed, by run id
+    runs: BTreeMap<usize, RunLog>,
+}
+
+impl Hub {
+    /// Forgets run `run_id` if it's over and nobody's following its log
+    fn prune(&mut self, run_id: usize) {
+        if self
+            .runs
+            .get(&run_id)
+            .is_some_and(|run| run.done && run.nfollowers == 0)
+        {
+            self.runs.remove(&run_id);
+        }
+    }
+}
+
+/// Describes the log of one run
+struct RunLog {
+    path: PathBuf,
+    /// whether the run is over
+    done: bool,
+    /// number of clients following the log (see `Follower`)
+    nfollowers: usize,
+}
+
+/// Marks run `run_id` as over when dropped (see `run_started()`)
+pub struct LiveRun {
+    run_id: usize,
+}
+
+impl Drop for LiveRun {
+    fn drop(&mut self) {
+        let mut hub = HUB.lock().unwrap();
+        if let Some(run) = hub.runs.get_mut(&self.run_id) {
+            run.done = true;
+        }
+        hub.prune(self.run_id);
+    }
+}
+
+/// Counts one client following the log of run `run_id` until dropped
+struct Follower {
+    run_id: usize,
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        let mut hub = HUB.lock().unwrap();
+        if let Some(run) = hub.runs.get_mut(&self.run_id) {
+            run.nfollowers -= 1;
+        }
+        hub.prune(self.run_id);
+    }
+}
+
+/// Starts serving live progress on `addr` (unless we already are)
+pub fn start(
+    addr: SocketAddr,
+    token_file: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    let mut hub = HUB.lock().unwrap();
+    match hub.listen {
+        Some(listen) if listen == addr => return Ok(()),
+        Some(listen) => bail!("already serving live progress on {}", listen),
+        None => (),
+    }
+    let server = http::Server::bind(&http::ListenArgs::tcp(
+        addr,
+        token_file.map(Path::to_path_buf),
+    ))?;
+    text_println!("serving live progress at {}", server.describe());
+    std::thread::spawn(move || {
+        if let Err(error) = server.serve(handle) {
+            eprintln!("warning: live progress server: {:#}", error);
+        }
+    });
+    hub.listen = Some(addr);
+    Ok(())
+}
+
+/// Returns whether any clients are waiting for events
+pub fn has_subscribers() -> bool {
+    !HUB.lock().unwrap().subscribers.is_empty()
+}
+
+/// Sends `event` (already serialized) to every client waiting for events
+pub fn publish(event: &str) {
+    // Drop clients that have gone away or fallen too far behind.
+    HUB.lock()
+        .unwrap()
+        .subscribers
+        .retain(|subscriber| subscriber.try_send(event.to_string()).is_ok());
+}
+
+/// Makes the log at `path` available as that of run `run_id` until the
+/// returned value is dropped
+pub fn run_started(run_id: usize, path: &Path) -> LiveRun {
+    let mut hub = HUB.lock().unwrap();
+    if hub.listen.is_some() {
+        hub.runs.insert(
+            run_id,
+            RunLog { path: path.to_path_buf(), done: false, nfollowers: 0 },
+        );
+    }
+    LiveRun { run_id }
+}
+
+fn handle(request: &Request) -> Response {
+    let result = if request.path == "/events" {
+        events(request)
+    } else if let Some(run_id) = request
+        .path
+        .strip_prefix("/runs/")
+        .and_then(|rest| rest.strip_suffix("/log"))
+    {
+        run_log(request, run_id)
+    } else {
+        Err(HttpError::not_found(format!("no such page: {:?}", request.path)))
+    };
+    result.unwrap_or_else(Response::from)
+}
+
+/// Opens a WebSocket that streams events
+fn events(request: &Request) -> Result<Response, HttpError> {
+    http::websocket(request, |stream| {
+        let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_EVENTS);
+        HUB.lock().unwrap().subscribers.push(sender);
+        loop {
+            match receiver.recv_timeout(PING_INTERVAL) {
+                Ok(event) => http::write_websocket_message(
+                    stream,
+                    http::WEBSOCKET_TEXT,
+                    event.as_bytes(),
+                )?,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    http::write_websocket_message(
+                        stream,
+                        http::WEBSOCKET_PING,
+                        b"",
+                    )?
+                }
+                // We fell too far behind (see `publish()`).
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return http::write_websocket_message(
+                        stream,
+                        http::WEBSOCKET_CLOSE,
+                        b"",
+                    );
+                }
+            }
+        }
+    })
+}
+
+/// Returns (or, with `follow=1`, streams) the log of one run
+fn run_log(request: &Request, run_id: &str) -> Result<Response, HttpError> {
+    let run_id = run_id
+        .parse::<usize>()
+        .map_err(|_| HttpError::not_found(format!("no run {:?}", run_id)))?;
+    let follow = request.query.get("follow").map(String::as_str) == Some("1");
+    let (path, follower) = {
+        let mut hub = HUB.lock().unwrap();
+        let Some(run) = hub.runs.get_mut(&run_id) else {
+            return Err(HttpError::not_found(format!(
+                "run {} is not in progress",
+                run_id
+            )));
+        };
+        // Count the follower now, so that the run isn't forgotten before we
+        // start following it.
+        let follower = follow.then(|| {
+            run.nfollowers += 1;
+            Follower { run_id }
+        });
+        (run.path.clone(), follower)
+    };
+    let mut file = std::fs::File::open(&path).map_err(|error| {
+        HttpError::not_found(format!("open {:?}: {}", path, error))
+    })?;
+    let content_type = "text/plain; charset=utf-8";
+    if !follow {
+        let length = file
+            .seek(std::io::SeekFrom::End(0))
+            .and_then(|length| file.rewind().map(|_| length))
+            .with_context(|| format!("read {:?}", path))?;
+        return Ok(Response::new(
+            200,
+            content_type,
+            http::Body::File(file, length),
+        ));
+    }
+
+    Ok(Response::new(
+        200,
+        content_type,
+        http::Body::Stream(Box::new(move |stream| {
+            let _follower = follower;
+            loop {
+                // Check whether the run is over before catching up, so that
+                // we don't miss anything written at the very end.
+                let done = HUB
+                    .lock()
+                    .unwrap()
+                    .runs
+                    .get(&run_id)
+                    .is_none_or(|run| run.done);
+                std::io::copy(&mut file.by_ref(), stream)?;
+                stream.flush()?;
+                if done {
+                    return Ok(());
+                }
+                std::thread::sleep(FOLLOW_POLL_INTERVAL);
+            }
+        })),
+    ))
+}
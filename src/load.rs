@@ -0,0 +1,186 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Holding off on new runs while the system is busy (`--max-load`)
+//!
+//! Running the whole test suite on several threads at once can make a machine
+//! unusable for anything else.  With `--max-load`, each thread checks the
+//! 1-minute load average before starting a run, and while it's above the
+//! limit, the thread waits instead.  One run is always allowed, though, so
+//! that the session keeps making progress no matter what else is going on.
+//!
+//! Once the load comes back down, threads that were held start again one at a
+//! time, `RAMP_INTERVAL` apart.  The load average takes a while to reflect
+//! runs that just started, so letting them all go at once would just push it
+//! back over the limit.
+//!
+//! Threads in the background lane (see `lane.rs`) give way to the foreground:
+//! their runs don't count as the one that's always allowed, and held
+//! background threads only start again once no foreground threads are held.
+
+use crate::lane::Lane;
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How often a held thread checks the load average (and whether the session
+/// is stopping)
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait after one held thread starts again before letting another
+/// one go
+const RAMP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Returns an error if `--max-load` can't be used on this system
+pub fn check_supported() -> Result<(), anyhow::Error> {
+    if cfg!(unix) {
+        Ok(())
+    } else {
+        Err(anyhow!("--max-load is only supported on Unix-like systems"))
+    }
+}
+
+/// Returns the system's 1-minute load average
+#[cfg(unix)]
+pub fn load_average() -> Result<f64, anyhow::Error> {
+    let mut loadavg = [0.0f64; 1];
+    // Safety: we provide a buffer with room for the one value we ask for.
+    let n = unsafe { libc::getloadavg(loadavg.as_mut_ptr(), 1) };
+    if n < 1 {
+        return Err(anyhow!("getloadavg failed"));
+    }
+    Ok(loadavg[0])
+}
+
+#[cfg(not(unix))]
+pub fn load_average() -> Result<f64, anyhow::Error> {
+    Err(anyhow!("load average is not available on this system"))
+}
+
+/// Keeps track of which threads are running and which are held because the
+/// system is too busy
+pub struct LoadLimit {
+    /// highest 1-minute load average at which new runs start
+    max: f64,
+    state: Mutex<LoadState>,
+}
+
+struct LoadState {
+    /// number of foreground runs in progress
+    running: usize,
+    /// threads waiting for the load to come down, with their lanes
+    held: BTreeMap<u8, Lane>,
+    /// when a held thread last started again
+    last_resume: Option<Instant>,
+    /// whether we've warned about not being able to get the load average
+    warned: bool,
+}
+
+/// Counts a run as in progress (if it's in the foreground lane) until it's
+/// dropped
+pub struct LoadGuard<'a>(&'a LoadLimit, Lane);
+
+impl Drop for LoadGuard<'_> {
+    fn drop(&mut self) {
+        if self.1 == Lane::Foreground {
+            self.0.state.lock().unwrap().running -= 1;
+        }
+    }
+}
+
+impl LoadLimit {
+    pub fn new(max: f64) -> LoadLimit {
+        LoadLimit {
+            max,
+            state: Mutex::new(LoadState {
+                running: 0,
+                held: BTreeMap::new(),
+                last_resume: None,
+                warned: false,
+            }),
+        }
+    }
+
+    /// Waits until thread `which` (in lane `lane`) may start a run, returning
+    /// a guard that counts the run as in progress until it's dropped
+    ///
+    /// Returns `None` if `stop()` says to give up first.
+    pub fn wait_to_start(
+        &self,
+        which: u8,
+        lane: Lane,
+        stop: impl Fn() -> bool,
+    ) -> Option<LoadGuard<'_>> {
+        loop {
+            if stop() {
+                return None;
+            }
+
+            let load = load_average();
+            let mut state = self.state.lock().unwrap();
+            let load = match load {
+                Ok(load) => load,
+                Err(error) => {
+                    if !state.warned {
+                        eprintln!("warning: --max-load: {:#}", error);
+                        state.warned = true;
+                    }
+                    return Some(self.start(&mut state, lane));
+                }
+            };
+
+            // A thread that's being held keeps waiting while the load is high,
+            // unless it's in the foreground and no other foreground runs are
+            // in progress, in which case it does one run and is still held
+            // afterwards.
+            let held = state.held.contains_key(&which);
+            let may_resume = load <= self.max
+                && (!held
+                    || (state
+                        .last_resume
+                        .is_none_or(|t| t.elapsed() >= RAMP_INTERVAL)
+                        && (lane == Lane::Foreground
+                            || !state
+                                .held
+                                .values()
+                                .any(|l| *l == Lane::Foreground))));
+            if may_resume || (lane == Lane::Foreground && state.running == 0) {
+                if held && may_resume {
+                    ts_println!(
+                        "thread {}: load average is {:.2}: starting runs again",
+                        which,
+                        load
+                    );
+                    state.held.remove(&which);
+                    state.last_resume = Some(Instant::now());
+                }
+                return Some(self.start(&mut state, lane));
+            }
+
+            if !held {
+                ts_println!(
+                    "thread {}: load average is {:.2} (--max-load {}): \
+                     holding off on new runs",
+                    which,
+                    load,
+                    self.max
+                );
+                state.held.insert(which, lane);
+            }
+            drop(state);
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Notes that a run in lane `lane` is starting
+    fn start(&self, state: &mut LoadState, lane: Lane) -> LoadGuard<'_> {
+        if lane == Lane::Foreground {
+            state.running += 1;
+        }
+        LoadGuard(self, lane)
+    }
+}
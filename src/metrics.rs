@@ -0,0 +1,271 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Prometheus metrics for `--metrics-addr`
+//!
+//! Sessions that go on for days are easier to keep an eye on alongside
+//! everything else on a Grafana dashboard.  With `--metrics-addr`, we serve
+//! `/metrics` in the Prometheus text format:
+//!
+//! * `gocrash_runs_started_total`: runs started
+//! * `gocrash_runs_finished_total{result=...}`: runs finished, by result
+//!   ("passed", "failed", "hung", and so on)
+//! * `gocrash_runs_in_flight`: runs currently in progress
+//! * `gocrash_phase_duration_seconds{phase=...}`: a histogram of how long
+//!   each phase of each run took (as in the timing report)
+//! * `gocrash_pool_available_bytes`: space available where the working
+//!   dataset lives (measured when scraped)
+//!
+//! As with `--live-listen`, there's one server per process, and the counters
+//! carry on from one session to the next in a pipeline.
+
+use crate::backend::Backend;
+use crate::http;
+use crate::http::HttpError;
+use crate::http::Request;
+use crate::http::Response;
+use crate::storage;
+use anyhow::bail;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the phase duration histogram buckets
+const BUCKETS: &[f64] = &[
+    1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0,
+    7200.0, 14400.0,
+];
+
+/// Everything we report
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    listen: None,
+    nstarted: 0,
+    nin_flight: 0,
+    finished: BTreeMap::new(),
+    phases: BTreeMap::new(),
+    storage: None,
+});
+
+struct Metrics {
+    /// where the server is listening, once it's started
+    listen: Option<SocketAddr>,
+    nstarted: u64,
+    nin_flight: u64,
+    /// number of runs finished, by result
+    finished: BTreeMap<&'static str, u64>,
+    /// how long each phase took, by phase
+    phases: BTreeMap<String, Histogram>,
+    /// the backend, working dataset, and its mountpoint for the current
+    /// session
+    storage: Option<(Backend, String, PathBuf)>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// number of observations in each bucket of `BUCKETS` (not cumulative)
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; BUCKETS.len()];
+        }
+        if let Some(i) = BUCKETS.iter().position(|bound| value <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Counts a run as in progress until dropped (see `run_started()`)
+pub struct MetricsRun;
+
+impl Drop for MetricsRun {
+    fn drop(&mut self) {
+        let mut metrics = METRICS.lock().unwrap();
+        metrics.nin_flight = metrics.nin_flight.saturating_sub(1);
+    }
+}
+
+/// Starts serving metrics on `addr` (unless we already are)
+pub fn start(
+    addr: SocketAddr,
+    token_file: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    let mut metrics = METRICS.lock().unwrap();
+    match metrics.listen {
+        Some(listen) if listen == addr => return Ok(()),
+        Some(listen) => bail!("already serving metrics on {}", listen),
+        None => (),
+    }
+    let server = http::Server::bind(&http::ListenArgs::tcp(
+        addr,
+        token_file.map(Path::to_path_buf),
+    ))?;
+    text_println!("serving metrics at {}metrics", server.describe());
+    std::thread::spawn(move || {
+        if let Err(error) = server.serve(handle) {
+            eprintln!("warning: metrics server: {:#}", error);
+        }
+    });
+    metrics.listen = Some(addr);
+    Ok(())
+}
+
+/// Records where the current session's working dataset is, for reporting
+/// how much space is left
+pub fn session_started(backend: Backend, working: &str, mountpoint: PathBuf) {
+    METRICS.lock().unwrap().storage =
+        Some((backend, working.to_string(), mountpoint));
+}
+
+/// Counts a run as started, and as in progress until the returned value is
+/// dropped
+pub fn run_started() -> MetricsRun {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.nstarted += 1;
+    metrics.nin_flight += 1;
+    MetricsRun
+}
+
+/// Counts a run as finished with result `result`
+pub fn run_finished(result: &'static str) {
+    *METRICS.lock().unwrap().finished.entry(result).or_insert(0) += 1;
+}
+
+/// Records that `phase` of some run took `duration`
+pub fn record_phase(phase: &str, duration: Duration) {
+    METRICS
+        .lock()
+        .unwrap()
+        .phases
+        .entry(phase.to_string())
+        .or_default()
+        .observe(duration.as_secs_f64());
+}
+
+fn handle(request: &Request) -> Response {
+    if request.path != "/metrics" {
+        return Response::from(HttpError::not_found(format!(
+            "no such page: {:?}",
+            request.path
+        )));
+    }
+    Response::new(
+        200,
+        "text/plain; version=0.0.4; charset=utf-8",
+        http::Body::Bytes(render().into_bytes()),
+    )
+}
+
+/// Returns all of the metrics in the Prometheus text format
+fn render() -> String {
+    // Don't hold the lock while we check free space, which may take a while.
+    let storage = METRICS.lock().unwrap().storage.clone();
+    let available = storage.and_then(|(backend, working, mountpoint)| {
+        storage::available(backend, &working, &mountpoint)
+            .map_err(|error| {
+                eprintln!("warning: metrics: checking free space: {:#}", error)
+            })
+            .ok()
+    });
+
+    let metrics = METRICS.lock().unwrap();
+    let mut out = String::new();
+    header(&mut out, "gocrash_runs_started_total", "counter", "Runs started.");
+    writeln!(&mut out, "gocrash_runs_started_total {}", metrics.nstarted)
+        .unwrap();
+
+    header(
+        &mut out,
+        "gocrash_runs_finished_total",
+        "counter",
+        "Runs finished, by result.",
+    );
+    for (result, count) in &metrics.finished {
+        writeln!(
+            &mut out,
+            "gocrash_runs_finished_total{{result=\"{}\"}} {}",
+            escape(result),
+            count
+        )
+        .unwrap();
+    }
+
+    header(&mut out, "gocrash_runs_in_flight", "gauge", "Runs in progress.");
+    writeln!(&mut out, "gocrash_runs_in_flight {}", metrics.nin_flight)
+        .unwrap();
+
+    header(
+        &mut out,
+        "gocrash_phase_duration_seconds",
+        "histogram",
+        "How long each phase of each run took.",
+    );
+    for (phase, histogram) in &metrics.phases {
+        let phase = escape(phase);
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(&histogram.counts) {
+            cumulative += count;
+            writeln!(
+                &mut out,
+                "gocrash_phase_duration_seconds_bucket\
+                 {{phase=\"{}\",le=\"{}\"}} {}",
+                phase, bound, cumulative
+            )
+            .unwrap();
+        }
+        writeln!(
+            &mut out,
+            "gocrash_phase_duration_seconds_bucket\
+             {{phase=\"{}\",le=\"+Inf\"}} {}",
+            phase, histogram.count
+        )
+        .unwrap();
+        writeln!(
+            &mut out,
+            "gocrash_phase_duration_seconds_sum{{phase=\"{}\"}} {}",
+            phase, histogram.sum
+        )
+        .unwrap();
+        writeln!(
+            &mut out,
+            "gocrash_phase_duration_seconds_count{{phase=\"{}\"}} {}",
+            phase, histogram.count
+        )
+        .unwrap();
+    }
+
+    if let Some(available) = available {
+        header(
+            &mut out,
+            "gocrash_pool_available_bytes",
+            "gauge",
+            "Space available where the working dataset lives.",
+        );
+        writeln!(&mut out, "gocrash_pool_available_bytes {}", available)
+            .unwrap();
+    }
+    out
+}
+
+/// Writes the HELP and TYPE lines that precede a metric's samples
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Escapes `value` for use as a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
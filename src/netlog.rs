@@ -0,0 +1,109 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Recording outbound network connections made during a run
+//!
+//! Some tests' flakiness tracks the availability of external services.  To
+//! help spot these, we can trace connect(2) calls made by the test suite
+//! (using strace(1) on Linux or truss(1) on illumos) and summarize where they
+//! went.  DNS lookups show up as connections to port 53.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Set on each per-run dataset when network logging is enabled: the number of
+/// distinct non-loopback destinations the run connected to
+pub const NETWORK_DESTINATIONS_PROPERTY: &str = "gocrash:network-destinations";
+
+/// Returns the command-line prefix used to trace connections made by a
+/// command and all of its descendants, writing the trace to `log_path`
+pub fn tracer_prefix(log_path: &Path) -> Vec<String> {
+    let log_path = log_path.display().to_string();
+    if cfg!(target_os = "linux") {
+        ["strace", "-f", "-qq", "-e", "trace=connect", "-o", &log_path]
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    } else {
+        ["truss", "-f", "-t", "connect", "-v", "connect", "-o", &log_path]
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    }
+}
+
+/// Describes a destination that a run connected to
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Destination {
+    pub address: String,
+    pub port: u16,
+}
+
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.address.contains(':') {
+            write!(f, "[{}]:{}", self.address, self.port)?;
+        } else {
+            write!(f, "{}:{}", self.address, self.port)?;
+        }
+        if self.port == 53 {
+            write!(f, " (DNS)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Summarizes a trace written by the tracer from `tracer_prefix()`, returning
+/// the number of connection attempts made to each non-loopback destination
+pub fn summarize(trace: &str) -> BTreeMap<Destination, usize> {
+    let mut rv = BTreeMap::new();
+    for line in trace.lines() {
+        let Some(dest) = parse_strace(line).or_else(|| parse_truss(line))
+        else {
+            continue;
+        };
+        if dest.address.starts_with("127.") || dest.address == "::1" {
+            continue;
+        }
+        *rv.entry(dest).or_insert(0) += 1;
+    }
+    rv
+}
+
+/// Parses an IPv4 or IPv6 connect(2) call from strace(1) output, which looks
+/// like:
+///
+/// ```text
+/// 1234  connect(3, {sa_family=AF_INET, sin_port=htons(53),
+///     sin_addr=inet_addr("8.8.8.8")}, 16) = 0
+/// ```
+fn parse_strace(line: &str) -> Option<Destination> {
+    let port = between(line, "_port=htons(", ")")?.parse().ok()?;
+    let address = between(line, "inet_addr(\"", "\"")
+        .or_else(|| between(line, "inet_pton(AF_INET6, \"", "\""))?;
+    Some(Destination { address: address.to_string(), port })
+}
+
+/// Parses a connect(2) call from verbose truss(1) output, which includes a
+/// line like:
+///
+/// ```text
+/// 1234/1:         AF_INET  name = 8.8.8.8  port = 53
+/// ```
+fn parse_truss(line: &str) -> Option<Destination> {
+    if !line.contains("AF_INET") {
+        return None;
+    }
+    let address = line.split("name = ").nth(1)?.split_whitespace().next()?;
+    let port = line.split("port = ").nth(1)?.split_whitespace().next()?;
+    Some(Destination { address: address.to_string(), port: port.parse().ok()? })
+}
+
+/// Returns the part of `s` between the first occurrence of `start` and the
+/// next occurrence of `end`
+fn between<'a>(s: &'a str, start: &str, end: &str) -> Option<&'a str> {
+    let rest = &s[s.find(start)? + start.len()..];
+    Some(&rest[..rest.find(end)?])
+}
@@ -0,0 +1,144 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Pairing each failed run with a passing "control" run
+//!
+//! The first thing people usually do with a failed run is compare it with one
+//! that passed, and the most useful one to compare with is the one that ran
+//! under the most similar conditions.  For each failed run, we nominate as its
+//! control the passing run on the same thread (so with the same lane, CPU
+//! weight, and workload) that ran closest to it in time: either the last one
+//! before it or the first one after it, whichever is nearer.  Since the one
+//! after it hasn't happened yet when the run fails, failures wait until their
+//! thread's next passing run (or the end of the session) to be paired.
+//!
+//! The control is recorded in the failed run's metadata ("control-run"), in
+//! the session summary, and in the list of failed runs at the end of the
+//! session, and `gocrash serve` compares the two runs on the failed run's
+//! page.
+
+use chrono::DateTime;
+use chrono::Utc;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Keeps track of each thread's passing runs and the failed runs waiting to
+/// be paired with one
+pub struct Pairings {
+    threads: Mutex<BTreeMap<u8, ThreadRuns>>,
+}
+
+#[derive(Default)]
+struct ThreadRuns {
+    /// most recent passing run
+    last_pass: Option<RunTimes>,
+    /// failed runs since then
+    waiting: Vec<Waiting>,
+}
+
+#[derive(Clone, Copy)]
+struct RunTimes {
+    run_id: usize,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+}
+
+struct Waiting {
+    attempt: usize,
+    failed: RunTimes,
+    /// passing run before this one, if any
+    previous: Option<RunTimes>,
+}
+
+/// A failed run and the passing run nominated as its control
+pub struct Pairing {
+    pub thread: u8,
+    pub attempt: usize,
+    pub failed_run: usize,
+    pub control_run: usize,
+}
+
+impl Pairings {
+    pub fn new() -> Pairings {
+        Pairings { threads: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// Notes that run `run_id` (attempt `attempt` on thread `thread`) failed
+    pub fn run_failed(
+        &self,
+        thread: u8,
+        attempt: usize,
+        run_id: usize,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) {
+        let mut threads = self.threads.lock().unwrap();
+        let runs = threads.entry(thread).or_default();
+        let previous = runs.last_pass;
+        runs.waiting.push(Waiting {
+            attempt,
+            failed: RunTimes { run_id, start, end },
+            previous,
+        });
+    }
+
+    /// Notes that run `run_id` on thread `thread` passed, returning the
+    /// pairings for the failed runs that were waiting for it
+    pub fn run_passed(
+        &self,
+        thread: u8,
+        run_id: usize,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Vec<Pairing> {
+        let mut threads = self.threads.lock().unwrap();
+        let runs = threads.entry(thread).or_default();
+        let next = RunTimes { run_id, start, end };
+        runs.last_pass = Some(next);
+        std::mem::take(&mut runs.waiting)
+            .into_iter()
+            .map(|waiting| {
+                // Prefer the earlier run when they're equally close.
+                let control = match waiting.previous {
+                    Some(previous)
+                        if waiting.failed.start - previous.end
+                            <= next.start - waiting.failed.end =>
+                    {
+                        previous
+                    }
+                    _ => next,
+                };
+                Pairing {
+                    thread,
+                    attempt: waiting.attempt,
+                    failed_run: waiting.failed.run_id,
+                    control_run: control.run_id,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns pairings for the failed runs still waiting at the end of the
+    /// session, using the last passing run before each one (for those that
+    /// have one)
+    pub fn finish(&self) -> Vec<Pairing> {
+        let mut threads = self.threads.lock().unwrap();
+        threads
+            .iter_mut()
+            .flat_map(|(thread, runs)| {
+                std::mem::take(&mut runs.waiting).into_iter().filter_map(
+                    |waiting| {
+                        waiting.previous.map(|previous| Pairing {
+                            thread: *thread,
+                            attempt: waiting.attempt,
+                            failed_run: waiting.failed.run_id,
+                            control_run: previous.run_id,
+                        })
+                    },
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,221 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! `gocrash pipeline`: run a series of sessions and commands
+//!
+//! A pipeline is described in a TOML file as a sequence of steps.  Each step
+//! either runs a session (a "campaign") against a snapshot or runs an
+//! arbitrary shell command (e.g., to generate a report or send a
+//! notification).  Each step can be made conditional on how the previous step
+//! went.  For example:
+//!
+//! ```toml
+//! [[step]]
+//! name = "full-suite"
+//! snapshot = "rpool/gotest@initial"
+//! options = { concurrency = 4, stop-after = 50 }
+//!
+//! [[step]]
+//! name = "reproducer"
+//! when = "if-failed"
+//! snapshot = "rpool/gotest@reproducer"
+//! options = { stop-after = 500 }
+//!
+//! [[step]]
+//! name = "notify"
+//! command = "mail -s \"gocrash: $GOCRASH_PREVIOUS_RESULT\" dap </dev/null"
+//! ```
+
+use crate::config;
+use crate::gocrash;
+use crate::CancellationToken;
+use crate::RunArgs;
+use anyhow::anyhow;
+use anyhow::Context;
+use serde::Deserialize;
+use std::process::Command;
+
+/// Run a series of sessions and commands described in a file
+#[derive(clap::Args)]
+pub struct PipelineArgs {
+    /// TOML file describing the pipeline
+    file: std::path::PathBuf,
+}
+
+/// Describes a whole pipeline
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Pipeline {
+    step: Vec<Step>,
+}
+
+/// Describes one step in a pipeline
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Step {
+    /// name used in progress and summary output
+    name: String,
+    /// whether to run this step, based on how the previous step went
+    #[serde(default)]
+    when: Condition,
+    /// snapshot to run a session against (for campaign steps)
+    snapshot: Option<String>,
+    /// options for the session (for campaign steps)
+    #[serde(default)]
+    options: RunArgs,
+    /// shell command to run (for command steps)
+    command: Option<String>,
+}
+
+/// Describes when to run a step
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Condition {
+    /// run the step regardless of what happened before
+    #[default]
+    Always,
+    /// run the step only if the last step that ran failed
+    IfFailed,
+    /// run the step only if the last step that ran succeeded
+    IfPassed,
+}
+
+/// Runs the `gocrash pipeline` command, stopping after the current step if
+/// `cancel` is cancelled
+pub fn pipeline(
+    args: &PipelineArgs,
+    cancel: &CancellationToken,
+) -> Result<(), anyhow::Error> {
+    let contents = std::fs::read_to_string(&args.file)
+        .with_context(|| format!("read {:?}", args.file))?;
+    let mut pipeline: Pipeline = toml::from_str(&contents)
+        .with_context(|| format!("parse {:?}", args.file))?;
+
+    // Note which options each step set itself, as opposed to leaving them at
+    // their defaults (see `config.rs`).
+    let raw: toml::Value = toml::from_str(&contents)
+        .with_context(|| format!("parse {:?}", args.file))?;
+    let raw_steps = raw.get("step").and_then(toml::Value::as_array);
+    for (step, raw_step) in
+        pipeline.step.iter_mut().zip(raw_steps.into_iter().flatten())
+    {
+        let names = raw_step
+            .get("options")
+            .and_then(toml::Value::as_table)
+            .into_iter()
+            .flat_map(|options| options.keys());
+        step.options.sources = config::Sources::from_names(
+            names.map(String::as_str),
+            config::Source::PipelineFile,
+        );
+    }
+    for step in &pipeline.step {
+        if step.snapshot.is_some() == step.command.is_some() {
+            return Err(anyhow!(
+                "step {:?}: exactly one of \"snapshot\" or \"command\" must be \
+                 specified",
+                step.name
+            ));
+        }
+    }
+
+    let mut previous_passed: Option<bool> = None;
+    let mut results = Vec::with_capacity(pipeline.step.len());
+    let mut interrupted = None;
+    for step in &pipeline.step {
+        let should_run = match (step.when, previous_passed) {
+            (Condition::Always, _) => true,
+            (Condition::IfFailed, Some(passed)) => !passed,
+            (Condition::IfPassed, Some(passed)) => passed,
+            (_, None) => false,
+        };
+        if !should_run {
+            text_println!(
+                "{}: step {:?}: skipped",
+                chrono::Utc::now(),
+                step.name
+            );
+            results.push((step, None));
+            continue;
+        }
+
+        text_println!("{}: step {:?}: start", chrono::Utc::now(), step.name);
+        let result = if let Some(snapshot) = &step.snapshot {
+            gocrash(&step.options, snapshot, cancel)
+        } else {
+            run_shell(step.command.as_ref().unwrap(), previous_passed)
+        };
+        text_println!(
+            "{}: step {:?}: {}",
+            chrono::Utc::now(),
+            step.name,
+            match &result {
+                Ok(_) => String::from("passed"),
+                Err(error) => format!("failed: {:#}", error),
+            }
+        );
+        previous_passed = Some(result.is_ok());
+        results.push((step, Some(result.is_ok())));
+        if let Err(error) = cancel.check() {
+            text_println!(
+                "{}: stopping pipeline: {}",
+                chrono::Utc::now(),
+                error
+            );
+            interrupted = Some(error);
+            break;
+        }
+    }
+
+    text_println!("\npipeline summary:");
+    let mut nfailed = 0;
+    for (step, result) in results {
+        let label = match result {
+            None => "skipped",
+            Some(true) => "passed",
+            Some(false) => {
+                nfailed += 1;
+                "failed"
+            }
+        };
+        text_println!("    {:>8}  {}", label, step.name);
+    }
+
+    if let Some(error) = interrupted {
+        Err(error.into())
+    } else if nfailed == 0 {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} step{} failed",
+            nfailed,
+            if nfailed == 1 { "" } else { "s" }
+        ))
+    }
+}
+
+/// Runs a command step, making the result of the previous step available in
+/// the environment as `GOCRASH_PREVIOUS_RESULT`
+fn run_shell(
+    command: &str,
+    previous_passed: Option<bool>,
+) -> Result<(), anyhow::Error> {
+    let previous = match previous_passed {
+        None => "none",
+        Some(true) => "passed",
+        Some(false) => "failed",
+    };
+    let status = Command::new("bash")
+        .arg("-c")
+        .arg(command)
+        .env("GOCRASH_PREVIOUS_RESULT", previous)
+        .status()
+        .with_context(|| format!("failed to exec {:?}", command))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("command {:?} failed: {}", command, status))
+    }
+}
@@ -0,0 +1,319 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! `gocrash postmortem`: reconstruct what a session was doing when the host
+//! went down
+//!
+//! Some of the bugs we're chasing take down the whole machine.  When that
+//! happens, the gocrash process is gone, but the state it recorded in ZFS
+//! properties (see `session.rs`) tells us which runs were in progress.  If
+//! the system saved a crash dump, we associate it with those runs, since for
+//! bugs like this the dump is the most important artifact we'll get.
+
+use crate::run_command;
+use crate::session;
+use crate::session::RunState;
+use crate::zfs;
+use crate::zfs_mountpoint;
+use anyhow::anyhow;
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::TimeZone;
+use chrono::Utc;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Reconstruct what a gocrash session was doing when the host rebooted
+#[derive(clap::Args)]
+pub struct PostmortemArgs {
+    /// working dataset created by the gocrash session
+    working_dataset: String,
+}
+
+/// Runs the `gocrash postmortem` command
+pub fn postmortem(args: &PostmortemArgs) -> Result<(), anyhow::Error> {
+    let working_dataset = &args.working_dataset;
+    let all_props = session::dataset_properties(working_dataset)?;
+    let session_props = all_props
+        .get(working_dataset)
+        .filter(|p| p.contains_key(session::SESSION_PROPERTY))
+        .ok_or_else(|| {
+            anyhow!(
+                "{:?} does not look like a gocrash working dataset \
+                 (no {:?} property)",
+                working_dataset,
+                session::SESSION_PROPERTY
+            )
+        })?;
+
+    let session_start = session_props
+        .get(session::START_TIME_PROPERTY)
+        .map(|s| parse_time(s))
+        .transpose()?;
+    let boot_time_then = session_props
+        .get(session::BOOT_TIME_PROPERTY)
+        .map(|s| s.parse::<i64>())
+        .transpose()
+        .context("parse recorded boot time")?;
+    let boot_time_now = session::host_boot_time()?;
+
+    println!("working dataset: {}", working_dataset);
+    println!("session:         {}", session_props[session::SESSION_PROPERTY]);
+    if let Some(snapshot) = session_props.get(session::SNAPSHOT_PROPERTY) {
+        println!("using snapshot:  {}", snapshot);
+    }
+    if let Some(start) = session_start {
+        println!("session started: {}", start);
+    }
+    if let Some(then) = boot_time_then {
+        println!(
+            "host booted:     {} (as of session start)",
+            format_epoch(then)
+        );
+    }
+    println!("host booted:     {} (now)", format_epoch(boot_time_now));
+
+    // The boot time can wobble by a second on some systems.
+    let rebooted = match boot_time_then {
+        Some(then) => (then - boot_time_now).abs() > 1,
+        None => false,
+    };
+    if !rebooted {
+        println!(
+            "\nNOTE: host does not appear to have rebooted since this \
+             session started.  The session may still be running, so nothing \
+             will be modified."
+        );
+    }
+
+    let in_flight = all_props
+        .iter()
+        .filter(|(name, props)| {
+            *name != working_dataset
+                && props
+                    .get(session::RUN_STATE_PROPERTY)
+                    .and_then(|s| s.parse::<RunState>().ok())
+                    == Some(RunState::Running)
+        })
+        .collect::<Vec<_>>();
+
+    let dumps = match session_start {
+        Some(start) => crash_dumps_since(start),
+        None => Vec::new(),
+    };
+
+    println!();
+    if in_flight.is_empty() {
+        println!("no runs were in flight");
+        return Ok(());
+    }
+
+    let boot_now = Utc
+        .timestamp_opt(boot_time_now, 0)
+        .single()
+        .ok_or_else(|| anyhow!("bad boot time: {}", boot_time_now))?;
+
+    for (dataset, props) in in_flight {
+        let run_start = props
+            .get(session::RUN_START_PROPERTY)
+            .map(|s| parse_time(s))
+            .transpose()?;
+        let (run_dumps, other_dumps): (Vec<_>, Vec<_>) = dumps
+            .iter()
+            .partition(|d| run_start.is_some_and(|s| d.in_window(s, boot_now)));
+
+        let mut report = String::new();
+        writeln!(&mut report, "gocrash postmortem for run {}", dataset)?;
+        writeln!(
+            &mut report,
+            "    run started:  {}",
+            run_start
+                .map_or_else(|| String::from("unknown"), |t| t.to_string())
+        )?;
+        writeln!(
+            &mut report,
+            "    host rebooted since session started: {}",
+            if rebooted { "yes" } else { "no" }
+        )?;
+        write_dumps(&mut report, "crash dumps from this run", &run_dumps)?;
+        if !other_dumps.is_empty() {
+            write_dumps(
+                &mut report,
+                "other crash dumps since session start",
+                &other_dumps,
+            )?;
+        }
+
+        print!("{}", report);
+
+        // Preserve the dataset (by marking it as something other than an
+        // in-progress run), link any crash dumps into it, and leave the
+        // report alongside the test output.
+        if rebooted {
+            session::set_run_state(dataset, RunState::HostCrash)?;
+            let mountpoint = zfs_mountpoint(dataset)?;
+            if !run_dumps.is_empty() {
+                link_dumps(dataset, &mountpoint, &run_dumps)?;
+            }
+            let report_path = mountpoint.join("postmortem.txt");
+            std::fs::write(&report_path, &report)
+                .with_context(|| format!("write {:?}", report_path))?;
+            println!("    report saved: {}", report_path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Appends a list of crash dumps to a postmortem report
+fn write_dumps(
+    report: &mut String,
+    label: &str,
+    dumps: &[&CrashDump],
+) -> Result<(), std::fmt::Error> {
+    if dumps.is_empty() {
+        return writeln!(report, "    {}: none", label);
+    }
+
+    writeln!(report, "    {}:", label)?;
+    for dump in dumps {
+        writeln!(report, "        {} ({})", dump.path.display(), dump.time)?;
+    }
+    Ok(())
+}
+
+/// Records the given crash dumps in the run's dataset, both as a property and
+/// as symlinks next to the test output
+fn link_dumps(
+    dataset: &str,
+    mountpoint: &std::path::Path,
+    dumps: &[&CrashDump],
+) -> Result<(), anyhow::Error> {
+    let paths =
+        dumps.iter().map(|d| d.path.display().to_string()).collect::<Vec<_>>();
+    zfs::run(
+        Command::new("pfexec")
+            .arg("zfs")
+            .arg("set")
+            .arg(format!(
+                "{}={}",
+                session::CRASH_DUMP_PROPERTY,
+                paths.join(",")
+            ))
+            .arg(dataset),
+    )?;
+
+    for (i, dump) in dumps.iter().enumerate() {
+        let link_path = if dumps.len() == 1 {
+            mountpoint.join("crash_dump")
+        } else {
+            mountpoint.join(format!("crash_dump.{}", i))
+        };
+        crate::symlink(&dump.path, &link_path)
+            .with_context(|| format!("symlink {:?}", link_path))?;
+    }
+
+    Ok(())
+}
+
+/// How long after the host boots we'd expect a crash dump from the previous
+/// boot to be saved (on illumos, savecore(8) writes the dump after reboot)
+const DUMP_SAVE_GRACE_SECS: i64 = 3600;
+
+/// Describes a system crash dump
+pub struct CrashDump {
+    /// path to the dump file
+    pub path: PathBuf,
+    /// when the dump was written
+    pub time: DateTime<Utc>,
+}
+
+impl CrashDump {
+    /// Returns whether this dump could have come from a panic while a run
+    /// that started at `run_start` was in progress, given that the host
+    /// subsequently booted at `boot_time`
+    ///
+    /// Depending on the system, the dump is written either just before the
+    /// reboot (Linux kdump) or shortly after (illumos savecore).
+    pub fn in_window(
+        &self,
+        run_start: DateTime<Utc>,
+        boot_time: DateTime<Utc>,
+    ) -> bool {
+        self.time >= run_start
+            && self.time
+                <= boot_time + chrono::Duration::seconds(DUMP_SAVE_GRACE_SECS)
+    }
+}
+
+/// Returns system crash dumps written at or after `since`
+pub fn crash_dumps_since(since: DateTime<Utc>) -> Vec<CrashDump> {
+    // Dumps are either directly inside the dump directory (illumos savecore)
+    // or in per-dump subdirectories (Linux kdump).
+    let top = crash_dump_dir();
+    let mut candidates = Vec::new();
+    for entry in std::fs::read_dir(&top).into_iter().flatten().flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            candidates.extend(
+                std::fs::read_dir(&path)
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .map(|e| e.path()),
+            );
+        } else {
+            candidates.push(path);
+        }
+    }
+
+    let mut dumps = candidates
+        .into_iter()
+        .filter(|path| {
+            path.file_name().and_then(|f| f.to_str()).is_some_and(|f| {
+                f.starts_with("vmdump") || f.starts_with("vmcore")
+            })
+        })
+        .filter_map(|path| {
+            let mtime = std::fs::metadata(&path).ok()?.modified().ok()?;
+            let time = DateTime::<Utc>::from(mtime);
+            (time >= since).then_some(CrashDump { path, time })
+        })
+        .collect::<Vec<_>>();
+    dumps.sort_by_key(|d| d.time);
+    dumps
+}
+
+/// Returns the directory where the system saves crash dumps
+fn crash_dump_dir() -> PathBuf {
+    // On illumos, dumpadm(8) tells us where savecore puts dumps.
+    if let Ok(output) = run_command(&mut Command::new("dumpadm")) {
+        if let Some(dir) = output
+            .lines()
+            .find_map(|l| l.trim().strip_prefix("Savecore directory:"))
+        {
+            return PathBuf::from(dir.trim());
+        }
+    }
+
+    // Linux kdump (and most everything else) defaults to this.
+    PathBuf::from("/var/crash")
+}
+
+/// Parses a timestamp recorded by gocrash in a ZFS property
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, anyhow::Error> {
+    Ok(DateTime::parse_from_rfc3339(s)
+        .with_context(|| format!("parse timestamp {:?}", s))?
+        .with_timezone(&Utc))
+}
+
+/// Formats a time given in seconds since the epoch
+fn format_epoch(secs: i64) -> String {
+    match Utc.timestamp_opt(secs, 0).single() {
+        Some(t) => t.to_string(),
+        None => format!("{} (seconds since epoch)", secs),
+    }
+}
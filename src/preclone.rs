@@ -0,0 +1,134 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Cloning runs' datasets ahead of time
+//!
+//! Cloning the source for each run isn't free (a `pfexec zfs clone` can take a
+//! second or more on a busy pool, and the copy backend copies the whole tree),
+//! and without `--preclone` each thread does it between finishing one run and
+//! starting the next.  With `--preclone N`, a dedicated thread keeps up to N
+//! clones ready under neutral names ("preclone-K" in the working dataset).  A
+//! thread starting a run takes one of those and renames it to the run's name,
+//! which is much quicker.  If none is ready (say, because threads are finishing
+//! runs faster than clones can be made), the thread clones its own, just as it
+//! would have without `--preclone`.
+//!
+//! Clones made ahead of time have no run state (see `session.rs`), so nothing
+//! mistakes them for runs.  The ones left over when the session ends are
+//! destroyed like any other dataset that isn't kept.
+
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+pub struct ClonePool {
+    /// how many clones to keep ready
+    size: usize,
+    state: Mutex<PoolState>,
+    /// signaled when a clone is taken or the pool is closed
+    cv: Condvar,
+    /// number of runs that used a clone from the pool
+    ntaken: AtomicUsize,
+    /// number of runs that found the pool empty
+    nmissed: AtomicUsize,
+}
+
+struct PoolState {
+    /// clones ready to be used, oldest first
+    ready: VecDeque<String>,
+    /// whether we've stopped making clones
+    closed: bool,
+}
+
+impl ClonePool {
+    /// Returns a pool that keeps up to `size` clones ready
+    pub fn new(size: usize) -> ClonePool {
+        ClonePool {
+            size,
+            state: Mutex::new(PoolState {
+                ready: VecDeque::with_capacity(size),
+                closed: false,
+            }),
+            cv: Condvar::new(),
+            ntaken: AtomicUsize::new(0),
+            nmissed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Body of the thread that makes clones: calls `prepare` (which returns
+    /// the name of a new clone) whenever there's room in the pool, until
+    /// `close()` is called or `prepare` fails
+    pub fn run(
+        &self,
+        mut prepare: impl FnMut() -> Result<String, anyhow::Error>,
+    ) {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                while !state.closed && state.ready.len() >= self.size {
+                    state = self.cv.wait(state).unwrap();
+                }
+                if state.closed {
+                    return;
+                }
+            }
+
+            // Don't hold the lock while cloning, so that threads can take the
+            // clones that are already ready.
+            match prepare() {
+                Ok(dataset) => {
+                    self.state.lock().unwrap().ready.push_back(dataset);
+                }
+                Err(error) => {
+                    eprintln!(
+                        "warning: cloning ahead (runs will clone their own \
+                         datasets from now on): {:#}",
+                        error
+                    );
+                    self.state.lock().unwrap().closed = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Returns a clone that's ready to be used, if there is one
+    pub fn take(&self) -> Option<String> {
+        let dataset = self.state.lock().unwrap().ready.pop_front();
+        match dataset {
+            Some(_) => {
+                self.ntaken.fetch_add(1, Ordering::SeqCst);
+                self.cv.notify_all();
+            }
+            None => {
+                self.nmissed.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        dataset
+    }
+
+    /// Stops making clones, so that `run()` returns once it's finished the
+    /// one it's working on (if any)
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.cv.notify_all();
+    }
+
+    /// Returns the clones that were never used (once `run()` has returned)
+    pub fn leftovers(&self) -> Vec<String> {
+        self.state.lock().unwrap().ready.drain(..).collect()
+    }
+
+    /// Returns how many runs used a clone from the pool and how many found it
+    /// empty
+    pub fn stats(&self) -> (usize, usize) {
+        (
+            self.ntaken.load(Ordering::SeqCst),
+            self.nmissed.load(Ordering::SeqCst),
+        )
+    }
+}
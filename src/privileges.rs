@@ -0,0 +1,89 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Refusing to run the test suite with full privileges by accident
+//!
+//! gocrash only needs privileges for managing datasets and mounts, and it gets
+//! those by running the specific commands that need them with pfexec(1).  The
+//! test suite itself runs as whoever ran gocrash, and it shouldn't be root:
+//! the Go test suite skips some tests and runs others differently when it has
+//! privileges, so failures found that way may not reproduce anywhere else, and
+//! a misbehaving test with privileges can damage the host (e.g., by removing
+//! files outside its clone).  It's easy to end up there by accident (say,
+//! from a root shell on a lab machine, or by running all of gocrash under
+//! `sudo` or `pfexec`), so sessions refuse to start with full privileges
+//! unless `--allow-root` is given.
+//!
+//! On illumos, a process can have every privilege without being uid 0 (e.g.,
+//! with the "Primary Administrator" profile), so there we check the effective
+//! privilege set too.  We don't check on Windows.
+
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+use crate::run_command;
+use anyhow::anyhow;
+
+/// Returns a description of why this process has full privileges, if it does
+#[cfg(unix)]
+pub fn full_privileges() -> Result<Option<String>, anyhow::Error> {
+    // Safety: geteuid(2) has no preconditions.
+    if unsafe { libc::geteuid() } == 0 {
+        return Ok(Some(String::from("uid 0")));
+    }
+
+    #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+    {
+        let output = run_command(
+            std::process::Command::new("ppriv")
+                .arg(std::process::id().to_string()),
+        )?;
+        // ppriv(1) prints each privilege set on its own line, as in "E: all".
+        if output.lines().any(|line| {
+            line.trim()
+                .strip_prefix("E:")
+                .is_some_and(|privs| privs.trim() == "all")
+        }) {
+            return Ok(Some(String::from(
+                "all privileges in the effective set",
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Returns a description of why this process has full privileges, if it does
+#[cfg(not(unix))]
+pub fn full_privileges() -> Result<Option<String>, anyhow::Error> {
+    Ok(None)
+}
+
+/// Fails (unless `allow_root` is set, in which case it just warns) if this
+/// process has full privileges, since the test suite would too
+pub fn check(allow_root: bool) -> Result<(), anyhow::Error> {
+    let reason = match full_privileges() {
+        Ok(Some(reason)) => reason,
+        Ok(None) => return Ok(()),
+        Err(error) => {
+            eprintln!("warning: checking privileges: {:#}", error);
+            return Ok(());
+        }
+    };
+    if allow_root {
+        eprintln!(
+            "WARNING: running the test suite with full privileges ({}) \
+             because of --allow-root: this changes how some tests behave and \
+             lets a misbehaving test damage this host",
+            reason
+        );
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "refusing to run the test suite with full privileges ({}): run \
+             gocrash as an unprivileged user (it uses pfexec for the commands \
+             that need privileges) or use --allow-root",
+            reason
+        ))
+    }
+}
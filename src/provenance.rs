@@ -0,0 +1,163 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Recording which toolchain a run used
+//!
+//! A failed run (or its archive) is only useful evidence if we know exactly
+//! which toolchain produced it, and the snapshot's name doesn't say much about
+//! that, especially when the test suite builds the toolchain itself.  So after
+//! a run, we ask the clone's `go` command for its version and build
+//! information and hash the binaries that matter most (`go`, `compile`, and
+//! `link`).  For runs that are kept, this goes into `TOOLCHAIN_FILE` in the
+//! clone (where it gets archived with everything else) and the run's
+//! metadata.  The first run of each session to finish also records it in
+//! `session.json`.
+//!
+//! The `go` command we ask is whatever the run left in the clone, which might
+//! be broken in any number of ways (that's why we're testing it), so we don't
+//! wait for it forever (see `GO_VERSION_TIMEOUT`).
+
+use crate::command_error;
+use crate::command_label;
+use crate::store::hash_file;
+use anyhow::anyhow;
+use anyhow::Context;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::Instant;
+
+/// File written at the top of each kept run's clone describing its toolchain
+pub const TOOLCHAIN_FILE: &str = "toolchain.json";
+
+/// Tools (in `goroot/pkg/tool/GOOS_GOARCH`) whose hashes we record
+const TOOLS: &[&str] = &["compile", "link"];
+
+/// How long to wait for each `go version` command before killing it
+const GO_VERSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to check whether a `go version` command has finished
+const GO_VERSION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Describes the toolchain in a clone
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Toolchain {
+    /// output of `go version`
+    pub version: String,
+    /// output of `go version -m` on the `go` binary, which includes the
+    /// settings it was built with
+    pub buildinfo: String,
+    /// SHA3-256 hashes (in hex) of key binaries, by path relative to the clone
+    pub binaries: BTreeMap<String, String>,
+}
+
+/// Describes the toolchain in the clone mounted at `mountpoint`, if there is
+/// one
+pub fn capture(
+    mountpoint: &Path,
+    go_binary: &Path,
+) -> Result<Option<Toolchain>, anyhow::Error> {
+    if !go_binary.is_file() {
+        return Ok(None);
+    }
+    let version = run_go_version(Command::new(go_binary).arg("version"))?
+        .trim()
+        .to_string();
+    let buildinfo = run_go_version(
+        Command::new(go_binary).arg("version").arg("-m").arg(go_binary),
+    )?;
+
+    let mut paths = vec![go_binary.to_path_buf()];
+    let tool_dir = mountpoint.join("goroot/pkg/tool");
+    if tool_dir.is_dir() {
+        let entries = std::fs::read_dir(&tool_dir)
+            .with_context(|| format!("read directory {:?}", tool_dir))?;
+        for entry in entries {
+            let entry = entry
+                .with_context(|| format!("read directory {:?}", tool_dir))?;
+            for tool in TOOLS {
+                let exe = format!("{}{}", tool, std::env::consts::EXE_SUFFIX);
+                let path = entry.path().join(exe);
+                if path.is_file() {
+                    paths.push(path);
+                }
+            }
+        }
+    }
+    let binaries = paths
+        .iter()
+        .map(|path| {
+            let relative = path.strip_prefix(mountpoint).unwrap_or(path);
+            Ok((relative.display().to_string(), hash_file(path)?))
+        })
+        .collect::<Result<_, anyhow::Error>>()?;
+
+    Ok(Some(Toolchain { version, buildinfo, binaries }))
+}
+
+/// Like `run_command()`, but kills the command if it takes longer than
+/// `GO_VERSION_TIMEOUT`
+fn run_go_version(cmd: &mut Command) -> Result<String, anyhow::Error> {
+    let label = command_label(cmd);
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to exec {}", label))?;
+    // Read the output in the background so that the command can't block
+    // writing it while we wait.  If the command has to be killed, these
+    // threads finish on their own once whatever has the pipes open exits.
+    let read = |mut pipe: Box<dyn Read + Send>| {
+        std::thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = pipe.read_to_end(&mut output);
+            output
+        })
+    };
+    let stdout = read(Box::new(child.stdout.take().unwrap()));
+    let stderr = read(Box::new(child.stderr.take().unwrap()));
+
+    let deadline = Instant::now() + GO_VERSION_TIMEOUT;
+    let status = loop {
+        if let Some(status) =
+            child.try_wait().with_context(|| format!("wait for {}", label))?
+        {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!(
+                "{}: killed after {}s",
+                label,
+                GO_VERSION_TIMEOUT.as_secs()
+            ));
+        }
+        std::thread::sleep(GO_VERSION_POLL_INTERVAL);
+    };
+    let stdout = stdout.join().unwrap();
+    let stderr = stderr.join().unwrap();
+    if status.success() {
+        Ok(String::from_utf8_lossy(&stdout).to_string())
+    } else {
+        Err(command_error(&label, status, &stdout, &stderr))
+    }
+}
+
+/// Writes `toolchain` to `TOOLCHAIN_FILE` in the clone at `mountpoint`
+pub fn write(
+    mountpoint: &Path,
+    toolchain: &Toolchain,
+) -> Result<(), anyhow::Error> {
+    let path = mountpoint.join(TOOLCHAIN_FILE);
+    let contents = serde_json::to_string_pretty(toolchain)?;
+    std::fs::write(&path, contents).with_context(|| format!("write {:?}", path))
+}
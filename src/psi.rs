@@ -0,0 +1,116 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! CPU, memory, and I/O pressure during each run (Linux only)
+//!
+//! Linux reports how much of the time some tasks were stalled waiting for
+//! each resource ("pressure stall information", or PSI) in /proc/pressure.
+//! Timing-dependent test failures tend to show up when pressure is high, so we
+//! record the pressure that each run saw with the run's metadata: the peak
+//! 10-second average we sampled while it ran and the average over the whole
+//! run.  These cover the whole system, not just the run.
+
+use anyhow::anyhow;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// Resources whose pressure we track (file names in /proc/pressure)
+const RESOURCES: &[&str] = &["cpu", "memory", "io"];
+
+/// How often to sample pressure while a run is in progress
+pub const SAMPLE_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(5);
+
+/// The "some" line from one file in /proc/pressure
+#[derive(Clone, Copy)]
+struct Pressure {
+    /// percentage of the last 10 seconds that some task was stalled
+    avg10: f64,
+    /// total time that some task was stalled (in microseconds)
+    total_us: u64,
+}
+
+/// Reads the current pressure for `resource`
+fn read_pressure(resource: &str) -> Result<Pressure, anyhow::Error> {
+    let path = format!("/proc/pressure/{}", resource);
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("read {:?}", path))?;
+    let line = contents
+        .lines()
+        .find_map(|line| line.strip_prefix("some "))
+        .ok_or_else(|| anyhow!("{:?}: no \"some\" line", path))?;
+    let field = |name: &str| {
+        line.split_whitespace()
+            .find_map(|f| f.strip_prefix(name)?.strip_prefix('='))
+            .ok_or_else(|| anyhow!("{:?}: missing {:?}", path, name))
+    };
+    let avg10 = field("avg10")?;
+    let total = field("total")?;
+    Ok(Pressure {
+        avg10: avg10
+            .parse()
+            .with_context(|| format!("{:?}: parse avg10 {:?}", path, avg10))?,
+        total_us: total
+            .parse()
+            .with_context(|| format!("{:?}: parse total {:?}", path, total))?,
+    })
+}
+
+/// Tracks pressure over the course of one run
+pub struct PressureTracker {
+    start_time: Instant,
+    /// pressure for each resource when the run started
+    start: BTreeMap<&'static str, Pressure>,
+    /// highest 10-second average seen so far for each resource
+    peak_avg10: BTreeMap<&'static str, f64>,
+}
+
+impl PressureTracker {
+    /// Starts tracking pressure, returning `None` if this system doesn't
+    /// report it
+    pub fn start() -> Option<PressureTracker> {
+        let start = RESOURCES
+            .iter()
+            .map(|r| Ok((*r, read_pressure(r)?)))
+            .collect::<Result<BTreeMap<_, _>, anyhow::Error>>()
+            .ok()?;
+        let peak_avg10 = start.iter().map(|(r, p)| (*r, p.avg10)).collect();
+        Some(PressureTracker { start_time: Instant::now(), start, peak_avg10 })
+    }
+
+    /// Samples the current pressure, updating the peaks
+    pub fn sample(&mut self) {
+        for (resource, peak) in &mut self.peak_avg10 {
+            if let Ok(pressure) = read_pressure(resource) {
+                *peak = peak.max(pressure.avg10);
+            }
+        }
+    }
+
+    /// Finishes tracking, returning run metadata describing the pressure seen
+    /// (e.g., "psi-cpu-peak" and "psi-cpu-mean", as percentages)
+    pub fn finish(mut self) -> Vec<(String, String)> {
+        self.sample();
+        let elapsed_us =
+            (self.start_time.elapsed().as_micros() as f64).max(1.0);
+        let mut rv = Vec::new();
+        for (resource, start) in &self.start {
+            rv.push((
+                format!("psi-{}-peak", resource),
+                format!("{:.2}", self.peak_avg10[resource]),
+            ));
+            if let Ok(end) = read_pressure(resource) {
+                let stalled_us = end.total_us.saturating_sub(start.total_us);
+                let mean = (100.0 * stalled_us as f64 / elapsed_us).min(100.0);
+                rv.push((
+                    format!("psi-{}-mean", resource),
+                    format!("{:.2}", mean),
+                ));
+            }
+        }
+        rv
+    }
+}
@@ -0,0 +1,54 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Running the test suite on a pseudo-terminal
+//!
+//! Some tests behave differently when their output is a pipe rather than a
+//! terminal, and some hang waiting for a response to a query they send to the
+//! terminal.  With `--pty`, we run the test suite under script(1), which gives
+//! it a pseudo-terminal and copies everything written there to its own stdout
+//! (which we capture as usual).  Since the terminal is shared, stdout and
+//! stderr end up interleaved in `test_run_stdout`.
+//!
+//! script(1) copies its stdin to the terminal, so we give it a pipe that stays
+//! open (and empty) for the whole run.  With `--pty-keepalive`, whenever the
+//! test suite has been quiet for that long, we write `KEEPALIVE` to the pipe,
+//! which unsticks tests waiting for a line of input.  Input isn't echoed, so
+//! this doesn't count as output (e.g., for `--output-stall-timeout`).
+
+/// What we type on the terminal to keep the test suite going
+pub const KEEPALIVE: &[u8] = b"\n";
+
+/// Returns a command line that runs `argv` on a pseudo-terminal
+pub fn wrap(argv: Vec<String>) -> Vec<String> {
+    let command =
+        argv.iter().map(|arg| shell_quote(arg)).collect::<Vec<_>>().join(" ");
+    [
+        "script",
+        "--quiet",
+        "--return",
+        "--flush",
+        "--echo",
+        "never",
+        "--command",
+        &command,
+        "/dev/null",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// Quotes `arg` for use as one word in a shell command
+pub fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+    {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
@@ -0,0 +1,277 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Limiting how many failed runs are kept for each kind of failure
+//!
+//! By default, every failed run's dataset is kept.  For well-understood
+//! failures, the hundredth copy is rarely worth the space, so a limit can be
+//! set for a known failure (with `keep` in its catalog entry or with
+//! `--keep-at-most NAME=N`) or for a particular signature (with
+//! `--keep-at-most SIGNATURE=N`), or for every signature at once (with
+//! `--keep-per-signature N`, which is handy with `--keep-going`, where the
+//! same flaky failure would otherwise fill the pool).  Once that many runs
+//! with the failure have been kept, later ones are still counted and
+//! recorded, but their datasets are destroyed (and not archived).
+//!
+//! Destroying every later run gives up on learning anything more about a
+//! failure, though, and for very frequent ones, the later runs might differ
+//! in interesting ways.  With `--sample-duplicates FRACTION`, that fraction
+//! of the runs beyond the limit (chosen at random) are kept anyway.
+
+use crate::catalog::KnownFailure;
+use crate::rng::Rng;
+use anyhow::anyhow;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// A user-supplied limit on how many runs to keep for one kind of failure
+pub struct KeepRule {
+    /// name of a known failure or a failure signature
+    key: String,
+    limit: usize,
+}
+
+impl std::str::FromStr for KeepRule {
+    type Err = anyhow::Error;
+
+    /// Parses a rule of the form `KEY=N`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Signatures may contain "=", but the count won't.
+        let (key, limit) = s.rsplit_once('=').ok_or_else(|| {
+            anyhow!("keep rule {:?}: expected NAME=N or SIGNATURE=N", s)
+        })?;
+        let limit = limit.parse().map_err(|_| {
+            anyhow!("keep rule {:?}: expected a count, found {:?}", s, limit)
+        })?;
+        if key.is_empty() {
+            return Err(anyhow!("keep rule {:?}: empty name", s));
+        }
+        Ok(KeepRule { key: key.to_string(), limit })
+    }
+}
+
+/// Parses the fraction given with `--sample-duplicates`
+pub fn parse_fraction(s: &str) -> Result<f64, anyhow::Error> {
+    let fraction: f64 =
+        s.parse().with_context(|| format!("expected a fraction: {:?}", s))?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(anyhow!("fraction {:?} is not between 0 and 1", s));
+    }
+    Ok(fraction)
+}
+
+/// What to do with a failed run's dataset
+#[derive(Clone, Copy)]
+pub enum Decision {
+    /// keep it (no limit applies, or the limit hasn't been reached)
+    Keep,
+    /// keep it, even though this many runs have already been kept, because
+    /// it was picked by `--sample-duplicates`
+    Sampled(usize),
+    /// destroy it, since this many runs have already been kept
+    Destroy(usize),
+}
+
+/// Decides which failed runs to keep
+pub struct Retention {
+    /// limits from `--keep-at-most`, by known failure name or signature
+    rules: BTreeMap<String, usize>,
+    /// limit from `--keep-per-signature` for failures with no other limit
+    per_signature: Option<usize>,
+    /// number of runs kept so far for each limit (keyed as in `rules`, or by
+    /// known failure name for limits from the catalog)
+    kept: Mutex<BTreeMap<String, usize>>,
+    /// fraction of runs beyond the limit to keep anyway, from
+    /// `--sample-duplicates`
+    sample: Option<f64>,
+    rng: Mutex<Rng>,
+}
+
+impl Retention {
+    pub fn new(
+        rules: Vec<KeepRule>,
+        per_signature: Option<usize>,
+        sample: Option<f64>,
+    ) -> Retention {
+        Retention {
+            rules: rules.into_iter().map(|r| (r.key, r.limit)).collect(),
+            per_signature,
+            kept: Mutex::new(BTreeMap::new()),
+            sample,
+            rng: Mutex::new(Rng::from_time()),
+        }
+    }
+
+    /// Returns the limit that applies to a failure with the given signature
+    /// matching the given known failure (if any), along with the key under
+    /// which runs are counted against it
+    ///
+    /// Rules for the known failure take precedence over rules for the
+    /// signature, those take precedence over the catalog, and the catalog
+    /// takes precedence over `--keep-per-signature`.
+    fn limit<'a>(
+        &'a self,
+        signature: &'a str,
+        known: Option<&'a KnownFailure>,
+    ) -> Option<(&'a str, usize)> {
+        let rule = |key: &'a str| Some((key, *self.rules.get(key)?));
+        known
+            .and_then(|k| rule(&k.name))
+            .or_else(|| rule(signature))
+            .or_else(|| known.and_then(|k| Some((k.name.as_str(), k.keep?))))
+            .or_else(|| Some((signature, self.per_signature?)))
+    }
+
+    /// Decides whether to keep a failed run with the given signature matching
+    /// the given known failure (if any), counting it as kept if so
+    pub fn check(
+        &self,
+        signature: &str,
+        known: Option<&KnownFailure>,
+    ) -> Decision {
+        let Some((key, limit)) = self.limit(signature, known) else {
+            return Decision::Keep;
+        };
+        let mut kept = self.kept.lock().unwrap();
+        let nkept = kept.entry(key.to_string()).or_insert(0);
+        if *nkept < limit {
+            *nkept += 1;
+            Decision::Keep
+        } else if self
+            .sample
+            .is_some_and(|f| self.rng.lock().unwrap().next_f64() < f)
+        {
+            *nkept += 1;
+            Decision::Sampled(limit)
+        } else {
+            Decision::Destroy(limit)
+        }
+    }
+
+    /// Describes `--sample-duplicates` for messages about destroyed runs
+    /// (e.g., ", plus 10% of later ones"), or returns an empty string if it
+    /// wasn't given
+    pub fn describe_sampling(&self) -> String {
+        match self.sample {
+            Some(fraction) => {
+                format!(", plus {}% of later ones", fraction * 100.0)
+            }
+            None => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_fraction;
+    use super::Decision;
+    use super::KeepRule;
+    use super::Retention;
+    use crate::catalog::KnownFailure;
+
+    fn parse(s: &str) -> KeepRule {
+        s.parse().unwrap_or_else(|error| panic!("parse {:?}: {:#}", s, error))
+    }
+
+    #[test]
+    fn test_keep_rule() {
+        for (input, key, limit) in [
+            ("bug-123=5", "bug-123", 5),
+            ("bug-123=0", "bug-123", 0),
+            // Signatures may contain "=" (but the count can't).
+            ("panic: x=N=2", "panic: x=N", 2),
+            ("--- FAIL: TestFoo (N.Ns)=10", "--- FAIL: TestFoo (N.Ns)", 10),
+        ] {
+            let rule = parse(input);
+            assert_eq!((rule.key.as_str(), rule.limit), (key, limit));
+        }
+
+        for (input, expected) in [
+            (
+                "bug-123",
+                "keep rule \"bug-123\": expected NAME=N or SIGNATURE=N",
+            ),
+            (
+                "bug-123=",
+                "keep rule \"bug-123=\": expected a count, found \"\"",
+            ),
+            (
+                "bug-123=five",
+                "keep rule \"bug-123=five\": expected a count, found \"five\"",
+            ),
+            (
+                "bug=123=-1",
+                "keep rule \"bug=123=-1\": expected a count, found \"-1\"",
+            ),
+            ("=5", "keep rule \"=5\": empty name"),
+        ] {
+            let Err(error) = input.parse::<KeepRule>() else {
+                panic!("parsed {:?}", input);
+            };
+            assert_eq!(format!("{:#}", error), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_fraction() {
+        for (input, expected) in [("0", 0.0), ("0.25", 0.25), ("1", 1.0)] {
+            assert_eq!(parse_fraction(input).unwrap(), expected);
+        }
+        for input in ["-0.5", "1.5", "half", "", "NaN"] {
+            assert!(parse_fraction(input).is_err(), "input {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let known = KnownFailure {
+            name: String::from("bug-1"),
+            links: Vec::new(),
+            keep: Some(3),
+        };
+        let retention = Retention::new(
+            vec![parse("bug-1=1"), parse("sig-a=2")],
+            Some(4),
+            None,
+        );
+        let mut decisions = Vec::new();
+        for (signature, known) in [
+            // The rule for the known failure beats the one for its signature.
+            ("sig-a", Some(&known)),
+            ("sig-a", Some(&known)),
+            // The signature's own rule applies to runs that aren't known.
+            ("sig-a", None),
+            ("sig-a", None),
+            ("sig-a", None),
+            // --keep-per-signature applies to everything else.
+            ("sig-b", None),
+        ] {
+            decisions.push(match retention.check(signature, known) {
+                Decision::Keep => String::from("keep"),
+                Decision::Sampled(n) => format!("sampled {}", n),
+                Decision::Destroy(n) => format!("destroy {}", n),
+            });
+        }
+        assert_eq!(
+            decisions,
+            ["keep", "destroy 1", "keep", "keep", "destroy 2", "keep"]
+        );
+
+        // Without rules, the catalog's limit applies, and with no limit at
+        // all, everything is kept.
+        let retention = Retention::new(Vec::new(), None, None);
+        let destroyed = (0..5)
+            .filter(|_| {
+                matches!(
+                    retention.check("sig-a", Some(&known)),
+                    Decision::Destroy(3)
+                )
+            })
+            .count();
+        assert_eq!(destroyed, 2);
+        assert!(matches!(retention.check("sig-a", None), Decision::Keep));
+    }
+}
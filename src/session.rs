@@ -0,0 +1,461 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Session and run state persisted in ZFS user properties
+//!
+//! ZFS properties survive the gocrash process (and the host) going away, so we
+//! use them to record enough about each session and each run to reconstruct
+//! what was going on after the fact.
+
+use crate::run_command;
+use crate::zfs;
+use anyhow::anyhow;
+use anyhow::Context;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Set on every dataset created by gocrash, identifying the gocrash session
+/// that created it
+pub const SESSION_PROPERTY: &str = "gocrash:session";
+/// Set on the working dataset: the snapshot that the session is testing
+pub const SNAPSHOT_PROPERTY: &str = "gocrash:snapshot";
+/// Set on the working dataset: when the session started (RFC 3339)
+pub const START_TIME_PROPERTY: &str = "gocrash:start-time";
+/// Set on the working dataset: when the host booted (seconds since the epoch)
+pub const BOOT_TIME_PROPERTY: &str = "gocrash:boot-time";
+/// Set on each per-run dataset: what state the run is in (see `RunState`)
+pub const RUN_STATE_PROPERTY: &str = "gocrash:run-state";
+/// Set on each per-run dataset: the run's id (unique within the session, and
+/// across sessions if they use non-overlapping `--run-id-start` ranges)
+pub const RUN_ID_PROPERTY: &str = "gocrash:run-id";
+/// Set on each per-run dataset: when the run started (RFC 3339)
+pub const RUN_START_PROPERTY: &str = "gocrash:run-start";
+/// Set on per-run datasets by `gocrash postmortem`: paths of system crash dumps
+/// written while the run was in progress (comma-separated)
+pub const CRASH_DUMP_PROPERTY: &str = "gocrash:crash-dump";
+/// Set on failed per-run datasets: how severe the failure was (see
+/// `severity.rs`)
+pub const SEVERITY_PROPERTY: &str = "gocrash:severity";
+/// Set on failed per-run datasets that match an entry in a catalog of known
+/// failures: the name of that entry (see `catalog.rs`)
+pub const KNOWN_FAILURE_PROPERTY: &str = "gocrash:known-failure";
+/// Prefix for properties set on each per-run dataset describing the
+/// conditions the run ran under (e.g., "gocrash:meta.cpu-weight"; these are
+/// also recorded in the history database)
+pub const RUN_METADATA_PROPERTY_PREFIX: &str = "gocrash:meta.";
+
+/// Describes the state of one run, as recorded in `RUN_STATE_PROPERTY`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RunState {
+    /// the run was in progress (or gocrash went away while it was)
+    Running,
+    /// the test suite passed
+    Passed,
+    /// the test suite failed
+    Failed,
+    /// the test suite stopped making progress
+    Hung,
+    /// the test suite failed, but storage problems appeared during the
+    /// session that might explain it
+    Suspect,
+    /// the test suite was killed by SIGINT or SIGTERM (or because the
+    /// session reached its --session-timeout)
+    Interrupted,
+    /// the test suite was killed because its clone used too much space
+    Runaway,
+    /// the host went down while the run was in progress
+    HostCrash,
+}
+
+impl RunState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunState::Running => "running",
+            RunState::Passed => "passed",
+            RunState::Failed => "failed",
+            RunState::Hung => "hung",
+            RunState::Suspect => "suspect",
+            RunState::Interrupted => "interrupted",
+            RunState::Runaway => "runaway",
+            RunState::HostCrash => "host-crash",
+        }
+    }
+}
+
+impl std::str::FromStr for RunState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            RunState::Running,
+            RunState::Passed,
+            RunState::Failed,
+            RunState::Hung,
+            RunState::Suspect,
+            RunState::Interrupted,
+            RunState::Runaway,
+            RunState::HostCrash,
+        ]
+        .into_iter()
+        .find(|state| state.as_str() == s)
+        .ok_or_else(|| anyhow!("unknown run state: {:?}", s))
+    }
+}
+
+/// Returns a `-o property=value` argument pair for use with `zfs create` or
+/// `zfs clone`
+pub fn property_arg(property: &str, value: &str) -> [String; 2] {
+    [String::from("-o"), format!("{}={}", property, value)]
+}
+
+/// Records the state of the run in the given per-run dataset
+pub fn set_run_state(
+    dataset: &str,
+    state: RunState,
+) -> Result<(), anyhow::Error> {
+    zfs::run(
+        Command::new("pfexec")
+            .arg("zfs")
+            .arg("set")
+            .arg(format!("{}={}", RUN_STATE_PROPERTY, state.as_str()))
+            .arg(dataset),
+    )?;
+    Ok(())
+}
+
+/// Returns the locally-set gocrash properties for `dataset` and its immediate
+/// children, indexed by dataset name and then property name
+pub fn dataset_properties(
+    dataset: &str,
+) -> Result<BTreeMap<String, BTreeMap<String, String>>, anyhow::Error> {
+    let output = zfs::run(
+        Command::new("zfs")
+            .arg("get")
+            .arg("-H")
+            .arg("-d1")
+            .arg("-tfilesystem")
+            .arg("-slocal")
+            .arg("-oname,property,value")
+            .arg("all")
+            .arg(dataset),
+    )?;
+
+    let mut rv: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+    for line in output.lines() {
+        let mut parts = line.splitn(3, '\t');
+        let (Some(name), Some(property), Some(value)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        if property.starts_with("gocrash:") {
+            rv.entry(name.to_string())
+                .or_default()
+                .insert(property.to_string(), value.to_string());
+        }
+    }
+    Ok(rv)
+}
+
+/// Returns when the host last booted, in seconds since the epoch
+pub fn host_boot_time() -> Result<i64, anyhow::Error> {
+    // On Linux, this is in /proc/stat.
+    if let Ok(contents) = std::fs::read_to_string("/proc/stat") {
+        if let Some(btime) =
+            contents.lines().find_map(|l| l.strip_prefix("btime "))
+        {
+            return btime
+                .trim()
+                .parse()
+                .with_context(|| format!("parse btime {:?}", btime));
+        }
+    }
+
+    // On illumos, it's available from kstat.
+    let output = run_command(
+        Command::new("kstat").arg("-p").arg("unix:0:system_misc:boot_time"),
+    )?;
+    let value = output
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| anyhow!("unexpected kstat output: {:?}", output))?;
+    value.parse().with_context(|| format!("parse boot_time {:?}", value))
+}
+
+/// Version of the format of `SessionRecord` (bump this for incompatible
+/// changes)
+pub const SESSION_RECORD_VERSION: u32 = 1;
+
+/// Name of the file (at the top of the working dataset) where
+/// `SessionRecord` is saved
+pub const SESSION_JSON: &str = "session.json";
+
+/// Machine-readable summary of one invocation of gocrash, saved as
+/// "session.json"
+///
+/// This gets written however the session ends, including when it fails before
+/// running the test suite at all.
+#[derive(Serialize)]
+pub struct SessionRecord {
+    /// see `SESSION_RECORD_VERSION`
+    pub format_version: u32,
+    /// unique name for this session (see `SESSION_PROPERTY`)
+    pub session: String,
+    /// snapshot being tested
+    pub snapshot: String,
+    /// properties that identify the snapshot beyond its name, where the
+    /// backend has them (see `CloneBackend::source_identity()`)
+    pub source_identity: BTreeMap<String, String>,
+    /// the host the session ran on (see `fingerprint.rs`)
+    pub host: Option<crate::fingerprint::HostFingerprint>,
+    /// name of the working dataset (if we got far enough to pick one)
+    pub working_dataset: Option<String>,
+    /// mountpoint of the working dataset, if it currently exists
+    pub working_mountpoint: Option<PathBuf>,
+    /// when the session started (RFC 3339)
+    pub start_time: String,
+    /// when the session ended (RFC 3339)
+    pub end_time: Option<String>,
+    /// how far the session got
+    pub stage: SessionStage,
+    /// options that the session was run with
+    pub parameters: serde_json::Value,
+    /// the same options, each with where its value came from (see
+    /// `config.rs`)
+    pub configuration: BTreeMap<String, crate::config::Setting>,
+    /// what happened in each worker thread
+    pub threads: Vec<ThreadRecord>,
+    /// number of runs with each result ("passed", "failed", "hung", etc.)
+    pub results: BTreeMap<String, usize>,
+    /// failed runs, grouped by failure signature
+    pub failures: Vec<FailureRecord>,
+    /// every failed run, in the order they finished
+    pub failed_runs: Vec<FailedRunRecord>,
+    /// why the session stopped (if it got as far as starting runs)
+    pub stop_reason: Option<StopReason>,
+    /// how long new runs were held back (by SIGUSR1 or the control socket)
+    pub paused_secs: f64,
+    /// how long each phase of the runs took
+    pub timings: BTreeMap<String, crate::timing::PhaseSummary>,
+    /// how much space the runs and the working dataset used
+    pub storage: crate::storage::StorageSummary,
+    /// datasets that the background destroyer failed to destroy (see
+    /// `destroyer.rs`)
+    pub destroy_failures: Vec<crate::destroyer::DestroyFailure>,
+    /// the toolchain used by the first run to finish, if it had one (see
+    /// `provenance.rs`)
+    pub toolchain: Option<crate::provenance::Toolchain>,
+    /// how the working dataset was destroyed, if it was (see
+    /// `--destroy-all-on-success-exit`)
+    pub teardown: Option<crate::backend::Teardown>,
+    /// how the session turned out overall
+    pub outcome: Option<SessionOutcome>,
+    /// error that ended the session, if any
+    pub error: Option<String>,
+}
+
+/// Describes how far a session got
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionStage {
+    /// checking arguments and the environment
+    Preflight,
+    /// creating the working dataset
+    CreateWorkingDataset,
+    /// running the test suite
+    Run,
+    /// cleaning up after the session
+    Cleanup,
+    /// all finished
+    Done,
+}
+
+/// Describes how a session turned out overall
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionOutcome {
+    /// every run passed
+    Passed,
+    /// at least one run of the test suite failed
+    Failed,
+    /// gocrash itself ran into a problem
+    Error,
+}
+
+impl SessionOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionOutcome::Passed => "passed",
+            SessionOutcome::Failed => "failed",
+            SessionOutcome::Error => "error",
+        }
+    }
+}
+
+/// Describes what happened in one worker thread
+#[derive(Serialize)]
+pub struct ThreadRecord {
+    pub thread: u8,
+    /// number of times the test suite was run
+    pub ntries: usize,
+    /// number of runs in this thread that completed (with any result)
+    pub nruns: usize,
+    /// number of runs in this thread that failed (including hangs and
+    /// suspect failures)
+    pub nfailures: usize,
+    /// error that stopped the thread, if any
+    pub error: Option<String>,
+}
+
+/// Describes the failed runs having one particular failure signature
+#[derive(Serialize)]
+pub struct FailureRecord {
+    pub signature: String,
+    pub severity: crate::severity::Severity,
+    /// name of the known failure (from `--signature-catalog`) that the first
+    /// such failure matched, if any
+    pub known_failure: Option<String>,
+    /// links describing the known failure
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<String>,
+    /// number of these runs with each result ("failed", "hung", etc.)
+    pub results: BTreeMap<String, usize>,
+    /// per-run datasets for the failed runs
+    pub datasets: Vec<String>,
+    /// number of these runs whose datasets were destroyed (because of a
+    /// limit on how many to keep; see `retention.rs`)
+    pub ndiscarded: usize,
+    /// number of runs done right after these failures to check whether they
+    /// recur (see `--verify-failures`)
+    pub nreruns: usize,
+    /// number of those reruns that failed with the same signature
+    pub nrecurred: usize,
+}
+
+/// Describes one failed run
+#[derive(Clone, Serialize)]
+pub struct FailedRunRecord {
+    pub run_id: usize,
+    pub thread: u8,
+    /// how the run ended ("failed", "hung", etc.)
+    pub result: String,
+    pub signature: String,
+    /// per-run dataset (which may not have been kept)
+    pub dataset: String,
+    /// whether the dataset was kept (see `retention.rs`)
+    pub kept: bool,
+    /// first line of the error describing the failure
+    pub error: String,
+    /// what `go test` reported about the failure
+    #[serde(flatten)]
+    pub details: crate::gotest::FailureDetails,
+    /// passing run on the same thread to compare this one with (see
+    /// `pairing.rs`)
+    pub control_run: Option<usize>,
+}
+
+/// Describes why a session stopped
+///
+/// This is whatever happened first, even if other stop conditions were met
+/// while the session was winding down.
+#[derive(Clone, Serialize)]
+pub struct StopReason {
+    /// which condition fired ("failure", "stop-when", "session-timeout", etc.)
+    pub condition: String,
+    /// what happened, for people to read
+    pub detail: String,
+    /// when it happened (RFC 3339)
+    pub time: String,
+    /// how long the session had been going when it happened
+    pub elapsed_secs: f64,
+}
+
+impl SessionRecord {
+    pub fn new(
+        session: String,
+        snapshot: &str,
+        parameters: serde_json::Value,
+    ) -> SessionRecord {
+        SessionRecord {
+            format_version: SESSION_RECORD_VERSION,
+            session,
+            snapshot: snapshot.to_string(),
+            source_identity: BTreeMap::new(),
+            host: Some(crate::fingerprint::HostFingerprint::capture()),
+            working_dataset: None,
+            working_mountpoint: None,
+            start_time: chrono::Utc::now().to_rfc3339(),
+            end_time: None,
+            stage: SessionStage::Preflight,
+            parameters,
+            configuration: BTreeMap::new(),
+            threads: Vec::new(),
+            results: BTreeMap::new(),
+            failures: Vec::new(),
+            failed_runs: Vec::new(),
+            stop_reason: None,
+            paused_secs: 0.0,
+            timings: BTreeMap::new(),
+            storage: Default::default(),
+            destroy_failures: Vec::new(),
+            toolchain: None,
+            teardown: None,
+            outcome: None,
+            error: None,
+        }
+    }
+
+    /// Records the final result of the session
+    pub fn finish(&mut self, result: &Result<(), anyhow::Error>) {
+        self.end_time = Some(chrono::Utc::now().to_rfc3339());
+        match result {
+            Ok(()) => {
+                self.stage = SessionStage::Done;
+                self.outcome = Some(SessionOutcome::Passed);
+            }
+            Err(error) => {
+                self.outcome = if self.stage == SessionStage::Run
+                    && !self.failures.is_empty()
+                {
+                    Some(SessionOutcome::Failed)
+                } else {
+                    Some(SessionOutcome::Error)
+                };
+                self.error = Some(format!("{:#}", error));
+            }
+        }
+    }
+
+    /// Writes the record to "session.json" in the working dataset (if it
+    /// exists) and to `extra_path` (if given)
+    ///
+    /// If there's nowhere else to put it, it's written to the current
+    /// directory.  Returns the paths written.
+    pub fn save(
+        &self,
+        extra_path: Option<&Path>,
+    ) -> Result<Vec<PathBuf>, anyhow::Error> {
+        let mut paths = Vec::new();
+        if let Some(mountpoint) = &self.working_mountpoint {
+            paths.push(mountpoint.join(SESSION_JSON));
+        }
+        if let Some(path) = extra_path {
+            paths.push(path.to_path_buf());
+        }
+        if paths.is_empty() {
+            paths.push(PathBuf::from(format!("{}.session.json", self.session)));
+        }
+
+        let contents = serde_json::to_string_pretty(self)?;
+        for path in &paths {
+            std::fs::write(path, &contents)
+                .with_context(|| format!("write {:?}", path))?;
+        }
+        Ok(paths)
+    }
+}
@@ -0,0 +1,206 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Ranking failed runs by severity
+//!
+//! Not all failures are equally interesting.  A build breakage is usually
+//! somebody else's problem and shows up right away, while a SIGSEGV or a sign
+//! of memory corruption in the runtime may be the rare bug we're hunting.  We
+//! classify each failure by looking for patterns in its output, using
+//! built-in rules plus any supplied with `--severity-rule`.
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// How bad a failure is, from least to most severe
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    Eq,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Serialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    /// no rule matched the output
+    Unknown,
+    /// the code under test failed to build
+    Build,
+    /// a test reported a failure
+    Assertion,
+    /// a Go panic
+    Panic,
+    /// a fatal signal, runtime fatal error, or sign of memory corruption
+    Crash,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Unknown => "unknown",
+            Severity::Build => "build",
+            Severity::Assertion => "assertion",
+            Severity::Panic => "panic",
+            Severity::Crash => "crash",
+        }
+    }
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Output that indicates each kind of failure, if it appears anywhere in a
+/// failed run's stdout or stderr
+const BUILTIN_RULES: &[(&str, Severity)] = &[
+    ("[build failed]", Severity::Build),
+    ("cannot find package", Severity::Build),
+    ("syntax error: ", Severity::Build),
+    ("--- FAIL: ", Severity::Assertion),
+    ("panic: ", Severity::Panic),
+    ("fatal error: ", Severity::Crash),
+    ("unexpected signal", Severity::Crash),
+    ("SIGSEGV", Severity::Crash),
+    ("SIGBUS", Severity::Crash),
+    ("found bad pointer in Go heap", Severity::Crash),
+    ("unexpected return pc", Severity::Crash),
+    ("WARNING: DATA RACE", Severity::Crash),
+];
+
+/// A user-supplied rule: output containing `pattern` indicates `severity`
+pub struct SeverityRule {
+    pattern: String,
+    severity: Severity,
+}
+
+impl std::str::FromStr for SeverityRule {
+    type Err = anyhow::Error;
+
+    /// Parses a rule of the form `SEVERITY=TEXT`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (severity, pattern) = s.split_once('=').ok_or_else(|| {
+            anyhow!("severity rule {:?}: expected SEVERITY=TEXT", s)
+        })?;
+        let severity = <Severity as clap::ValueEnum>::from_str(severity, true)
+            .map_err(|_| {
+                anyhow!(
+                    "severity rule {:?}: unknown severity {:?}",
+                    s,
+                    severity
+                )
+            })?;
+        if pattern.is_empty() {
+            return Err(anyhow!("severity rule {:?}: empty pattern", s));
+        }
+        Ok(SeverityRule { pattern: pattern.to_string(), severity })
+    }
+}
+
+/// Classifies a failed run from its captured stdout and stderr
+///
+/// If any of the user's rules match, the most severe of those wins (so that
+/// users can demote failures that the built-in rules would rank highly).
+/// Otherwise, the most severe matching built-in rule wins.
+pub fn classify(
+    rules: &[SeverityRule],
+    stdout: &str,
+    stderr: &str,
+) -> Severity {
+    let matches =
+        |pattern: &str| stdout.contains(pattern) || stderr.contains(pattern);
+    rules
+        .iter()
+        .filter(|rule| matches(&rule.pattern))
+        .map(|rule| rule.severity)
+        .max()
+        .or_else(|| {
+            BUILTIN_RULES
+                .iter()
+                .filter(|(pattern, _)| matches(pattern))
+                .map(|(_, severity)| *severity)
+                .max()
+        })
+        .unwrap_or(Severity::Unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::classify;
+    use super::Severity;
+    use super::SeverityRule;
+
+    fn parse(s: &str) -> SeverityRule {
+        s.parse().unwrap_or_else(|error| panic!("parse {:?}: {:#}", s, error))
+    }
+
+    #[test]
+    fn test_severity_rule() {
+        for (input, pattern, severity) in [
+            ("crash=boom", "boom", Severity::Crash),
+            // Severities are case-insensitive, and patterns may contain "=".
+            ("Assertion=x = y", "x = y", Severity::Assertion),
+            ("unknown=flaky network", "flaky network", Severity::Unknown),
+        ] {
+            let rule = parse(input);
+            assert_eq!(
+                (rule.pattern.as_str(), rule.severity),
+                (pattern, severity)
+            );
+        }
+
+        for (input, expected) in [
+            ("crash", "severity rule \"crash\": expected SEVERITY=TEXT"),
+            ("crash=", "severity rule \"crash=\": empty pattern"),
+            (
+                "fatal=boom",
+                "severity rule \"fatal=boom\": unknown severity \"fatal\"",
+            ),
+            ("=boom", "severity rule \"=boom\": unknown severity \"\""),
+        ] {
+            let Err(error) = input.parse::<SeverityRule>() else {
+                panic!("parsed {:?}", input);
+            };
+            assert_eq!(format!("{:#}", error), expected);
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let panic = "panic: boom\n\ngoroutine 1 [running]:\n";
+        let race = "WARNING: DATA RACE\n--- FAIL: TestFoo (0.00s)\n";
+        for (rules, stdout, stderr, expected) in [
+            (&[][..], "", "", Severity::Unknown),
+            (&[], "--- FAIL: TestFoo (0.00s)\n", "", Severity::Assertion),
+            (&[], "", panic, Severity::Panic),
+            // The most severe built-in rule wins.
+            (&[], race, panic, Severity::Crash),
+            // The user's rules win over the built-in ones, even when they're
+            // less severe...
+            (&["build=DATA RACE"], race, "", Severity::Build),
+            // ...and the most severe of those that match wins.
+            (&["build=DATA RACE", "panic=TestFoo"], race, "", Severity::Panic),
+            // Rules that don't match don't count.
+            (&["crash=nope"], "", panic, Severity::Panic),
+        ] {
+            let rules = rules.iter().map(|r| parse(r)).collect::<Vec<_>>();
+            assert_eq!(
+                classify(&rules, stdout, stderr),
+                expected,
+                "rules {:?}, stdout {:?}, stderr {:?}",
+                rules.iter().map(|r| &r.pattern).collect::<Vec<_>>(),
+                stdout,
+                stderr
+            );
+        }
+    }
+}
@@ -0,0 +1,615 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! `gocrash show --from-archive`: look at an archived run without unpacking
+//! the archive
+//!
+//! Archive directories (see `archive.rs`) tend to get shipped around as
+//! tarballs, which can be many gigabytes once they include toolchains and core
+//! files.  To triage a failure, though, you usually only want to know what
+//! the run left behind and how its logs end.  This command streams through the
+//! tarball once (decompressing it with `zstd`, `gzip`, or `xz` as it goes)
+//! and keeps only that: the run's file list, its toolchain (`toolchain.json`),
+//! and the end of its stdout and stderr.
+//!
+//! `tar` stores a file that's hard-linked more than once (as every file in
+//! the archive directory's store is) only the first time it comes across it,
+//! and later names for it are just links.  If a log we want is one of those
+//! links, we stream through the tarball a second time to get the contents.
+
+use crate::provenance;
+use crate::storage;
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+/// Logs whose ends are shown
+const LOGS: &[&str] = &["test_run_stdout", "test_run_stderr"];
+
+/// Keep at most this much from the end of each log to find the lines to show
+const EXCERPT_MAX_BYTES: usize = 64 * 1024;
+
+/// Size of a tar header (and the unit in which file contents are padded)
+const BLOCK_SIZE: usize = 512;
+
+/// Show an archived run without extracting it
+#[derive(clap::Args)]
+pub struct ShowArgs {
+    /// tarball of an archive directory (see --archive-dir) to read the run
+    /// from, compressed with zstd, gzip, or xz, or not at all (e.g.,
+    /// "archive.tar.zst")
+    #[arg(long, value_name = "PATH")]
+    from_archive: PathBuf,
+
+    /// session the run belongs to (needed if the archive has runs from more
+    /// than one session with the same run id)
+    #[arg(long, value_name = "SESSION")]
+    session: Option<String>,
+
+    /// number of lines to show from the end of each log
+    #[arg(long, value_name = "N", default_value_t = 40)]
+    lines: usize,
+
+    /// id of the run to show
+    run_id: usize,
+}
+
+/// What we found out about one archived run
+struct ArchivedRun {
+    session: String,
+    thread: u8,
+    /// every file, symlink, and hard link in the run's directory, by path
+    /// relative to it
+    files: BTreeMap<String, ArchivedFile>,
+    /// contents of the files we show (for logs, just the end), by relative
+    /// path
+    contents: BTreeMap<String, Excerpt>,
+}
+
+enum ArchivedFile {
+    File { bytes: u64 },
+    HardLink { target: String, bytes: Option<u64> },
+    Symlink { target: String },
+}
+
+/// The end of a file's contents
+struct Excerpt {
+    contents: Vec<u8>,
+    /// whether the beginning of the file was left out
+    truncated: bool,
+}
+
+/// Runs the `gocrash show` command
+pub fn show(args: &ShowArgs) -> Result<(), anyhow::Error> {
+    let path = &args.from_archive;
+
+    // In the first pass, find the run, keeping the contents of the files we
+    // want that are stored under the run's own names.  Remember the sizes of
+    // everything else in case the run's files turn out to be hard links to
+    // them.
+    let mut runs: BTreeMap<String, ArchivedRun> = BTreeMap::new();
+    let mut sizes: BTreeMap<String, u64> = BTreeMap::new();
+    scan(path, |entry, data| {
+        if entry.kind == EntryKind::File {
+            sizes.insert(entry.path.clone(), entry.size);
+        }
+        let Some((prefix, session, thread, relative)) =
+            run_path(&entry.path, args.run_id)
+        else {
+            return Ok(());
+        };
+        if args.session.as_ref().is_some_and(|s| *s != session) {
+            return Ok(());
+        }
+        let run = runs.entry(prefix).or_insert_with(|| ArchivedRun {
+            session,
+            thread,
+            files: BTreeMap::new(),
+            contents: BTreeMap::new(),
+        });
+        let file = match entry.kind {
+            EntryKind::File => ArchivedFile::File { bytes: entry.size },
+            EntryKind::HardLink => ArchivedFile::HardLink {
+                bytes: sizes.get(&entry.link).copied(),
+                target: entry.link.clone(),
+            },
+            EntryKind::Symlink => {
+                ArchivedFile::Symlink { target: entry.link.clone() }
+            }
+            EntryKind::Other => return Ok(()),
+        };
+        if entry.kind == EntryKind::File && wanted(&relative) {
+            run.contents.insert(relative.clone(), read_end(data)?);
+        }
+        run.files.insert(relative, file);
+        Ok(())
+    })?;
+
+    let mut run = match runs.len() {
+        0 => bail!(
+            "run {} not found in {:?}{}",
+            args.run_id,
+            path,
+            args.session
+                .as_ref()
+                .map(|s| format!(" for session {}", s))
+                .unwrap_or_default()
+        ),
+        1 => runs.into_values().next().unwrap(),
+        _ => bail!(
+            "{:?} contains runs with id {} from more than one session \
+             (use --session to pick one): {}",
+            path,
+            args.run_id,
+            runs.values()
+                .map(|run| run.session.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    // If any of the files we want are hard links, go back for their contents.
+    let mut linked: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (relative, file) in &run.files {
+        if let ArchivedFile::HardLink { target, .. } = file {
+            if wanted(relative) {
+                linked
+                    .entry(target.clone())
+                    .or_default()
+                    .push(relative.clone());
+            }
+        }
+    }
+    if !linked.is_empty() {
+        scan(path, |entry, data| {
+            if entry.kind != EntryKind::File {
+                return Ok(());
+            }
+            if let Some(relatives) = linked.get(&entry.path) {
+                let excerpt = read_end(data)?;
+                for relative in relatives {
+                    run.contents.insert(
+                        relative.clone(),
+                        Excerpt {
+                            contents: excerpt.contents.clone(),
+                            truncated: excerpt.truncated,
+                        },
+                    );
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    print_run(args, &run);
+    Ok(())
+}
+
+/// Returns whether we show the contents of file `relative` in a run
+fn wanted(relative: &str) -> bool {
+    relative == provenance::TOOLCHAIN_FILE || LOGS.contains(&relative)
+}
+
+fn print_run(args: &ShowArgs, run: &ArchivedRun) {
+    println!(
+        "run {} (thread {}) of session {}, from {}",
+        args.run_id,
+        run.thread,
+        run.session,
+        args.from_archive.display()
+    );
+    if let Some(excerpt) = run.contents.get(provenance::TOOLCHAIN_FILE) {
+        match serde_json::from_slice::<serde_json::Value>(&excerpt.contents) {
+            Ok(toolchain) => println!(
+                "toolchain: {}",
+                toolchain["version"].as_str().unwrap_or("unknown")
+            ),
+            Err(error) => eprintln!(
+                "warning: parsing {}: {:#}",
+                provenance::TOOLCHAIN_FILE,
+                error
+            ),
+        }
+    }
+
+    let total = run
+        .files
+        .values()
+        .filter_map(|file| match file {
+            ArchivedFile::File { bytes } => Some(*bytes),
+            ArchivedFile::HardLink { bytes, .. } => *bytes,
+            ArchivedFile::Symlink { .. } => None,
+        })
+        .sum::<u64>();
+    println!(
+        "files ({}, {} in total):",
+        run.files.len(),
+        storage::format_bytes(total)
+    );
+    for (relative, file) in &run.files {
+        match file {
+            ArchivedFile::File { bytes }
+            | ArchivedFile::HardLink { bytes: Some(bytes), .. } => {
+                println!(
+                    "    {:>10}  {}",
+                    storage::format_bytes(*bytes),
+                    relative
+                )
+            }
+            ArchivedFile::HardLink { target, bytes: None } => {
+                println!("    {:>10}  {} (link to {})", "?", relative, target)
+            }
+            ArchivedFile::Symlink { target } => {
+                println!("    {:>10}  {} -> {}", "", relative, target)
+            }
+        }
+    }
+
+    for log in LOGS {
+        let Some(excerpt) = run.contents.get(*log) else {
+            continue;
+        };
+        let contents = String::from_utf8_lossy(&excerpt.contents);
+        let mut lines = contents.lines().collect::<Vec<_>>();
+        // If we left out the beginning, the first line is probably
+        // incomplete.
+        if excerpt.truncated && !lines.is_empty() {
+            lines.remove(0);
+        }
+        let first = lines.len().saturating_sub(args.lines);
+        println!();
+        println!("{} (last {} lines):", log, lines.len() - first);
+        for line in &lines[first..] {
+            println!("    {}", line);
+        }
+    }
+}
+
+/// If `path` (from the tarball) is in the archive directory of run `run_id`,
+/// returns the path of that directory, the session, the thread, and the path
+/// relative to that directory
+fn run_path(path: &str, run_id: usize) -> Option<(String, String, u8, String)> {
+    let components = path.split('/').collect::<Vec<_>>();
+    let i = components.iter().position(|c| {
+        c.strip_prefix("thread-")
+            .and_then(|rest| rest.split_once("-run-"))
+            .is_some_and(|(thread, run)| {
+                thread.parse::<u8>().is_ok() && run.parse() == Ok(run_id)
+            })
+    })?;
+    let relative = components[i + 1..].join("/");
+    if relative.is_empty() {
+        return None;
+    }
+    let thread = components[i]
+        .strip_prefix("thread-")?
+        .split_once("-run-")?
+        .0
+        .parse()
+        .ok()?;
+    let session = if i > 0 { components[i - 1] } else { "" };
+    Some((components[..=i].join("/"), session.to_string(), thread, relative))
+}
+
+/// Reads `data` to the end, returning the last `EXCERPT_MAX_BYTES` of it
+fn read_end(data: &mut dyn Read) -> Result<Excerpt, anyhow::Error> {
+    let mut contents = Vec::new();
+    let mut truncated = false;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = data.read(&mut buf).context("read archive")?;
+        if n == 0 {
+            break;
+        }
+        contents.extend_from_slice(&buf[..n]);
+        if contents.len() > 2 * EXCERPT_MAX_BYTES {
+            contents.drain(..contents.len() - EXCERPT_MAX_BYTES);
+            truncated = true;
+        }
+    }
+    if contents.len() > EXCERPT_MAX_BYTES {
+        contents.drain(..contents.len() - EXCERPT_MAX_BYTES);
+        truncated = true;
+    }
+    Ok(Excerpt { contents, truncated })
+}
+
+/// What kind of thing an entry in the tarball is
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum EntryKind {
+    File,
+    HardLink,
+    Symlink,
+    /// directories, devices, and so on
+    Other,
+}
+
+/// One entry in the tarball
+struct Entry {
+    /// path, without any leading "./"
+    path: String,
+    kind: EntryKind,
+    /// size of the contents that follow the header
+    size: u64,
+    /// for links, what they point to (without any leading "./" for hard
+    /// links, which name another entry)
+    link: String,
+}
+
+/// Streams through the tarball at `path`, calling `visit` for each entry with
+/// a reader for its contents (which it may read as much or as little of as it
+/// likes)
+fn scan(
+    path: &Path,
+    mut visit: impl FnMut(&Entry, &mut dyn Read) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    let decompressor = decompressor(path);
+    let mut child = None;
+    let mut input: Box<dyn Read> = match decompressor {
+        None => Box::new(
+            std::fs::File::open(path)
+                .with_context(|| format!("open {:?}", path))?,
+        ),
+        Some(program) => {
+            let mut spawned = Command::new(program)
+                .arg("-dc")
+                .arg(path)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("failed to exec {:?}", program))?;
+            let stdout = spawned.stdout.take().unwrap();
+            child = Some(spawned);
+            Box::new(stdout)
+        }
+    };
+
+    // tar pads archives out to a whole number of records after the end
+    // marker, so read the rest to let the decompressor finish normally.
+    let result = scan_tar(&mut input, &mut visit).and_then(|()| {
+        std::io::copy(&mut input, &mut std::io::sink())?;
+        Ok(())
+    });
+    drop(input);
+    if let Some(mut child) = child {
+        if result.is_err() {
+            let _ = child.kill();
+        }
+        let status = child.wait().context("wait for decompressor")?;
+        if result.is_ok() && !status.success() {
+            bail!(
+                "{:?} failed to decompress {:?}: {}",
+                decompressor.unwrap(),
+                path,
+                status
+            );
+        }
+    }
+    result.with_context(|| format!("read {:?}", path))
+}
+
+/// Returns the program that decompresses the tarball at `path`, if it's
+/// compressed
+fn decompressor(path: &Path) -> Option<&'static str> {
+    let name = path.file_name()?.to_string_lossy();
+    if name.ends_with(".zst") || name.ends_with(".tzst") {
+        Some("zstd")
+    } else if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Some("gzip")
+    } else if name.ends_with(".xz") || name.ends_with(".txz") {
+        Some("xz")
+    } else {
+        None
+    }
+}
+
+/// Reads tar entries from `input` (see `scan()`)
+fn scan_tar(
+    input: &mut dyn Read,
+    visit: &mut dyn FnMut(&Entry, &mut dyn Read) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    // Long names come in entries of their own ahead of the entry they're for,
+    // either as GNU "L"/"K" entries or as pax extended headers.
+    let mut long_path = None;
+    let mut long_link = None;
+    let mut offset = 0u64;
+    loop {
+        let mut header = [0u8; BLOCK_SIZE];
+        if !read_block(input, &mut header)? {
+            // Some writers leave out the zero blocks at the end.
+            return Ok(());
+        }
+        if header.iter().all(|b| *b == 0) {
+            return Ok(());
+        }
+        check_header(&header, offset)?;
+        let size = header_size(&header, offset)?;
+        let padded = size.div_ceil(BLOCK_SIZE as u64) * BLOCK_SIZE as u64;
+        offset += BLOCK_SIZE as u64;
+
+        let typeflag = header[156];
+        match typeflag {
+            b'L' | b'K' => {
+                let mut name = Vec::new();
+                input.take(size).read_to_end(&mut name)?;
+                skip(input, padded - size)?;
+                let name = field(&name);
+                if typeflag == b'L' {
+                    long_path = Some(name);
+                } else {
+                    long_link = Some(name);
+                }
+            }
+            b'x' => {
+                let mut records = Vec::new();
+                input.take(size).read_to_end(&mut records)?;
+                skip(input, padded - size)?;
+                for (key, value) in pax_records(&records) {
+                    match key.as_str() {
+                        "path" => long_path = Some(value),
+                        "linkpath" => long_link = Some(value),
+                        _ => (),
+                    }
+                }
+            }
+            b'g' => skip(input, padded)?,
+            _ => {
+                let path = long_path.take().unwrap_or_else(|| {
+                    let name = field(&header[0..100]);
+                    let prefix = if &header[257..263] == b"ustar\0" {
+                        field(&header[345..500])
+                    } else {
+                        String::new()
+                    };
+                    if prefix.is_empty() {
+                        name
+                    } else {
+                        format!("{}/{}", prefix, name)
+                    }
+                });
+                let link = long_link
+                    .take()
+                    .unwrap_or_else(|| field(&header[157..257]));
+                let kind = match typeflag {
+                    b'0' | b'\0' | b'7' => EntryKind::File,
+                    b'1' => EntryKind::HardLink,
+                    b'2' => EntryKind::Symlink,
+                    _ => EntryKind::Other,
+                };
+                let entry = Entry {
+                    path: strip_dot(&path),
+                    kind,
+                    size,
+                    link: if kind == EntryKind::HardLink {
+                        strip_dot(&link)
+                    } else {
+                        link
+                    },
+                };
+                let mut data = input.take(size);
+                visit(&entry, &mut data)?;
+                let left = data.limit();
+                skip(input, left + (padded - size))?;
+            }
+        }
+        offset += padded;
+    }
+}
+
+/// Reads one block into `block`, returning `false` at the end of the input
+fn read_block(
+    input: &mut dyn Read,
+    block: &mut [u8; BLOCK_SIZE],
+) -> Result<bool, anyhow::Error> {
+    let mut nread = 0;
+    while nread < BLOCK_SIZE {
+        let n = input.read(&mut block[nread..])?;
+        if n == 0 {
+            if nread == 0 {
+                return Ok(false);
+            }
+            bail!("archive ends partway through a header");
+        }
+        nread += n;
+    }
+    Ok(true)
+}
+
+/// Reads and discards `n` bytes
+fn skip(input: &mut dyn Read, n: u64) -> Result<(), anyhow::Error> {
+    let skipped = std::io::copy(&mut input.take(n), &mut std::io::sink())?;
+    if skipped != n {
+        bail!("archive ends partway through an entry");
+    }
+    Ok(())
+}
+
+/// Checks that `header` (at `offset` in the tarball) looks like a tar header
+fn check_header(
+    header: &[u8; BLOCK_SIZE],
+    offset: u64,
+) -> Result<(), anyhow::Error> {
+    let expected = parse_octal(&header[148..156]);
+    // The checksum is computed with the checksum field itself as spaces.
+    let actual = header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| if (148..156).contains(&i) { 32 } else { u64::from(*b) })
+        .sum::<u64>();
+    if expected != Some(actual) {
+        bail!("not a tar archive (bad header checksum at offset {})", offset);
+    }
+    Ok(())
+}
+
+/// Returns the size of the contents following `header`
+fn header_size(
+    header: &[u8; BLOCK_SIZE],
+    offset: u64,
+) -> Result<u64, anyhow::Error> {
+    let field = &header[124..136];
+    // GNU tar stores sizes too big for 11 octal digits in base 256, flagged
+    // by the high bit of the first byte.
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |acc, b| {
+                (acc << 8) | u64::from(*b)
+            }));
+    }
+    parse_octal(field)
+        .ok_or_else(|| anyhow!("bad entry size in header at offset {}", offset))
+}
+
+/// Parses a NUL- or space-terminated octal number from a header field
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = String::from_utf8_lossy(field);
+    let digits = digits.trim_matches(|c| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// Returns a NUL-terminated string from a header field
+fn field(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// Returns `path` without any leading "./"
+fn strip_dot(path: &str) -> String {
+    path.trim_start_matches("./").to_string()
+}
+
+/// Parses pax extended header records ("LENGTH KEY=VALUE\n")
+fn pax_records(records: &[u8]) -> Vec<(String, String)> {
+    let mut rv = Vec::new();
+    let mut rest = records;
+    while !rest.is_empty() {
+        let Some(space) = rest.iter().position(|b| *b == b' ') else {
+            break;
+        };
+        let Some(length) = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .filter(|n| *n > space + 1 && *n <= rest.len())
+        else {
+            break;
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..length - 1]);
+        if let Some((key, value)) = record.split_once('=') {
+            rv.push((key.to_string(), value.to_string()));
+        }
+        rest = &rest[length..];
+    }
+    rv
+}
@@ -0,0 +1,233 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Graceful shutdown on SIGINT and SIGTERM (or when asked by a program using
+//! gocrash as a library)
+//!
+//! Dying on the spot would leave clones mounted and the working dataset
+//! behind, with nothing reported about the runs that were done.  Instead, the
+//! first SIGINT or SIGTERM just records that we should stop: worker threads
+//! stop starting new runs, a watchdog kills the runs in progress (see
+//! `WATCHDOG_SHUTDOWN` in `lib.rs`), clones for runs that hadn't failed are
+//! destroyed, and the session finishes with the usual report.  A second signal
+//! exits immediately, in case cleanup itself gets stuck.
+//!
+//! SIGUSR1 and SIGUSR2 are handled here too, though they don't stop anything:
+//! they ask the session to pause (finish the runs in progress, but start no
+//! more) and resume.  The handler just records which one arrived, and the
+//! session's control thread acts on it (see `control.rs`).
+//!
+//! Signals apply to the whole process.  Programs that use gocrash as a
+//! library get the same orderly shutdown for one call to `run_with_args()` by
+//! cancelling the `CancellationToken` that they passed to it.  (They're
+//! expected to handle signals themselves, so we don't install handlers for
+//! them.)
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+/// The signal that asked us to shut down (0 if none has arrived)
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// SIGUSR1 or SIGUSR2, whichever arrived last and hasn't been acted on yet (0
+/// if neither)
+#[cfg(unix)]
+static PAUSE_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// How often `sleep()` checks whether we've been asked to shut down
+const SLEEP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Describes a session cut short by SIGINT or SIGTERM, or by cancelling its
+/// `CancellationToken`
+#[derive(Debug)]
+pub enum Shutdown {
+    /// this signal arrived
+    Signal(i32),
+    Cancelled,
+}
+
+impl Shutdown {
+    /// Returns the condition that stopped the session (as recorded in
+    /// "session.json")
+    pub fn condition(&self) -> &'static str {
+        match self {
+            Shutdown::Signal(_) => "signal",
+            Shutdown::Cancelled => "cancelled",
+        }
+    }
+
+    /// Describes what happened, like "received SIGINT"
+    pub fn reason(&self) -> String {
+        match self {
+            Shutdown::Signal(signal) => {
+                format!("received {}", signal_name(*signal))
+            }
+            Shutdown::Cancelled => String::from("cancelled"),
+        }
+    }
+}
+
+impl std::fmt::Display for Shutdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Shutdown::Signal(signal) => {
+                write!(f, "interrupted by {}", signal_name(*signal))
+            }
+            Shutdown::Cancelled => f.write_str("cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Shutdown {}
+
+/// Asks a session to shut down gracefully, as SIGINT does
+///
+/// Pass one of these to `run_with_args()` and call `cancel()` (say, from
+/// another thread) to stop whatever's running.  `run_with_args()` returns once
+/// the runs in progress have been stopped and cleaned up.  Clones of a token
+/// share the same state, so any of them can be used to cancel it.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Asks whatever's using this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns whether `cancel()` has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Returns why we've been asked to shut down, if we have been (by a
+    /// signal or by cancelling this token)
+    pub(crate) fn requested(&self) -> Option<Shutdown> {
+        match SIGNAL.load(Ordering::SeqCst) {
+            0 if self.is_cancelled() => Some(Shutdown::Cancelled),
+            0 => None,
+            signal => Some(Shutdown::Signal(signal)),
+        }
+    }
+
+    /// Returns an error if we've been asked to shut down
+    pub(crate) fn check(&self) -> Result<(), Shutdown> {
+        self.requested().map_or(Ok(()), Err)
+    }
+
+    /// Sleeps for `duration`, returning early with an error if we're asked to
+    /// shut down in the meantime
+    pub(crate) fn sleep(&self, duration: Duration) -> Result<(), Shutdown> {
+        let start = Instant::now();
+        loop {
+            self.check()?;
+            let elapsed = start.elapsed();
+            if elapsed >= duration {
+                return Ok(());
+            }
+            std::thread::sleep((duration - elapsed).min(SLEEP_POLL_INTERVAL));
+        }
+    }
+}
+
+/// Returns a name for one of the signals that we handle
+fn signal_name(signal: i32) -> &'static str {
+    match signal {
+        libc::SIGINT => "SIGINT",
+        libc::SIGTERM => "SIGTERM",
+        #[cfg(unix)]
+        libc::SIGUSR1 => "SIGUSR1",
+        #[cfg(unix)]
+        libc::SIGUSR2 => "SIGUSR2",
+        _ => "signal",
+    }
+}
+
+/// Returns whether SIGUSR1 (true: pause) or SIGUSR2 (false: resume) has
+/// arrived since the last time we checked, if either has (the later one, if
+/// both have)
+#[cfg(unix)]
+pub fn take_pause_signal() -> Option<bool> {
+    match PAUSE_SIGNAL.swap(0, Ordering::SeqCst) {
+        libc::SIGUSR1 => Some(true),
+        libc::SIGUSR2 => Some(false),
+        _ => None,
+    }
+}
+
+/// Arranges for SIGINT and SIGTERM to request a graceful shutdown and for
+/// SIGUSR1 and SIGUSR2 to request pausing and resuming
+#[cfg(unix)]
+pub fn install() -> Result<(), anyhow::Error> {
+    let handlers = [
+        (libc::SIGINT, handle_signal as extern "C" fn(libc::c_int)),
+        (libc::SIGTERM, handle_signal),
+        (libc::SIGUSR1, handle_pause_signal),
+        (libc::SIGUSR2, handle_pause_signal),
+    ];
+    for (signal, handler) in handlers {
+        // Safety: the handler only does async-signal-safe things (atomic
+        // operations and _exit(2)), and `action` is fully initialized before
+        // we pass it to sigaction(2).
+        let rv = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if rv != 0 {
+            return Err(anyhow::Error::new(std::io::Error::last_os_error())
+                .context(format!(
+                    "install handler for {}",
+                    signal_name(signal)
+                )));
+        }
+    }
+    Ok(())
+}
+
+/// Arranges for SIGINT and SIGTERM to request a graceful shutdown (not
+/// supported on this system, where these kill the process as usual, and
+/// there's no SIGUSR1 or SIGUSR2)
+#[cfg(not(unix))]
+pub fn install() -> Result<(), anyhow::Error> {
+    Ok(())
+}
+
+#[cfg(unix)]
+extern "C" fn handle_signal(signal: libc::c_int) {
+    if SIGNAL.swap(signal, Ordering::SeqCst) != 0 {
+        // Put the terminal back the way it was if the dashboard is up (see
+        // `tui.rs`).
+        // Safety: write(2) and _exit(2) are async-signal-safe, and we pass a
+        // valid buffer along with its length.
+        unsafe {
+            #[cfg(feature = "tui")]
+            if crate::tui::ACTIVE.load(Ordering::SeqCst) {
+                let restore = crate::tui::RESTORE;
+                libc::write(
+                    libc::STDOUT_FILENO,
+                    restore.as_ptr() as *const libc::c_void,
+                    restore.len(),
+                );
+            }
+            libc::_exit(128 + signal)
+        };
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn handle_pause_signal(signal: libc::c_int) {
+    PAUSE_SIGNAL.store(signal, Ordering::SeqCst);
+}
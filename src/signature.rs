@@ -0,0 +1,309 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Computing signatures for failed test runs
+//!
+//! A signature is a short string intended to be the same for repeated
+//! occurrences of the same failure and different for different failures.
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Something that can compute a signature from a failed run's output
+pub trait SignatureExtractor {
+    /// Returns a signature for a failed run from its captured stdout and
+    /// stderr, or `None` if this extractor doesn't recognize the failure
+    fn extract(&self, stdout: &str, stderr: &str) -> Option<String>;
+}
+
+/// Selects which extractors are used to compute signatures, based on what
+/// kind of workload is being run
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignatureProfile {
+    /// Go runtime panics, then Go test failures, then the last line of output
+    #[default]
+    Go,
+    /// Go test failures only, then the last line of output
+    GoTest,
+    /// the first failed Go test together with its panic message and the
+    /// function that panicked, then as for "go"
+    GoTestPanic,
+    /// the last line that looks like an error, then the last line of output
+    Generic,
+}
+
+/// Computes signatures for failed runs using a list of extractors, the first
+/// of which to recognize the failure wins
+pub struct Signer {
+    extractors: Vec<Box<dyn SignatureExtractor + Send + Sync>>,
+}
+
+impl Signer {
+    /// Returns a `Signer` for the given profile.  Lines starting with any of
+    /// `extra_prefixes` are preferred over anything the profile would pick.
+    pub fn new(profile: SignatureProfile, extra_prefixes: &[String]) -> Signer {
+        let mut extractors: Vec<Box<dyn SignatureExtractor + Send + Sync>> =
+            Vec::new();
+        if !extra_prefixes.is_empty() {
+            extractors.push(Box::new(PrefixExtractor(extra_prefixes.to_vec())));
+        }
+        match profile {
+            SignatureProfile::Go => {
+                extractors.push(Box::new(go_panic()));
+                extractors.push(Box::new(go_test()));
+            }
+            SignatureProfile::GoTest => extractors.push(Box::new(go_test())),
+            SignatureProfile::GoTestPanic => {
+                extractors.push(Box::new(GoTestPanic));
+                extractors.push(Box::new(go_panic()));
+                extractors.push(Box::new(go_test()));
+            }
+            SignatureProfile::Generic => {
+                extractors.push(Box::new(LastErrorLine))
+            }
+        }
+        extractors.push(Box::new(LastLine));
+        Signer { extractors }
+    }
+
+    /// Computes a signature for a failed run from its captured stdout and
+    /// stderr
+    pub fn failure_signature(&self, stdout: &str, stderr: &str) -> String {
+        self.extractors
+            .iter()
+            .find_map(|e| e.extract(stdout, stderr))
+            .unwrap_or_else(|| String::from("(no output)"))
+    }
+}
+
+/// Picks the first line (from stderr, then stdout) starting with any of the
+/// given prefixes, trying each prefix in order
+struct PrefixExtractor(Vec<String>);
+
+impl SignatureExtractor for PrefixExtractor {
+    fn extract(&self, stdout: &str, stderr: &str) -> Option<String> {
+        let lines = || stderr.lines().chain(stdout.lines()).map(str::trim);
+        self.0.iter().find_map(|prefix| {
+            lines().find(|l| l.starts_with(prefix.as_str())).map(normalize)
+        })
+    }
+}
+
+/// Recognizes Go runtime panics and fatal errors
+fn go_panic() -> PrefixExtractor {
+    PrefixExtractor(
+        ["fatal error: ", "panic: ", "unexpected signal"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+    )
+}
+
+/// Recognizes failures reported by `go test`
+fn go_test() -> PrefixExtractor {
+    PrefixExtractor(
+        ["--- FAIL: ", "FAIL\t"].iter().map(|s| s.to_string()).collect(),
+    )
+}
+
+/// Combines the first failed Go test with the panic (if any) and the function
+/// at the top of the panicking goroutine's stack, like "TestFoo: panic: boom
+/// [in runtime.foo]"
+///
+/// With `--keep-going`, the same flaky test tends to fail the same way over
+/// and over, while other tests may hit the same panic for unrelated reasons.
+/// Including both keeps those apart.
+struct GoTestPanic;
+
+/// Prefixes of stack frames for the functions that implement panicking (which
+/// are never the interesting part)
+const PANIC_FRAMES: &[&str] = &[
+    "panic(",
+    "runtime.gopanic(",
+    "runtime.panic",
+    "runtime.throw(",
+    "runtime.fatal",
+    "testing.tRunner.func",
+];
+
+impl SignatureExtractor for GoTestPanic {
+    fn extract(&self, stdout: &str, stderr: &str) -> Option<String> {
+        let details = crate::gotest::failure_details(stdout, stderr);
+        let test = details.tests.first();
+        let Some(panic) = &details.panic else {
+            return test.map(|test| normalize(test));
+        };
+        let frame =
+            [stderr, stdout].iter().find_map(|output| panic_frame(output));
+        let mut rv = String::new();
+        if let Some(test) = test {
+            rv.push_str(test);
+            rv.push_str(": ");
+        }
+        rv.push_str(panic);
+        if let Some(frame) = frame {
+            rv.push_str(&format!(" [in {}]", frame));
+        }
+        Some(normalize(&rv))
+    }
+}
+
+/// Returns the function at the top of the stack of the first goroutine listed
+/// after a panic in `output`, leaving out the functions that do the panicking
+/// and the arguments
+fn panic_frame(output: &str) -> Option<&str> {
+    let mut lines = output.lines().map(str::trim);
+    lines
+        .find(|l| l.starts_with("panic: ") || l.starts_with("fatal error: "))?;
+    lines.find(|l| l.starts_with("goroutine ") && l.ends_with(':'))?;
+    // Frames alternate between the function and its file and line.  A blank
+    // line ends the goroutine's stack.
+    lines
+        .take_while(|l| !l.is_empty())
+        .step_by(2)
+        .find(|l| !PANIC_FRAMES.iter().any(|prefix| l.starts_with(prefix)))
+        .map(|l| l.rsplit_once('(').map_or(l, |(function, _)| function))
+}
+
+/// Picks the last line (from stderr, then stdout) that mentions an error or
+/// failure
+struct LastErrorLine;
+
+impl SignatureExtractor for LastErrorLine {
+    fn extract(&self, stdout: &str, stderr: &str) -> Option<String> {
+        [stderr, stdout]
+            .iter()
+            .find_map(|output| {
+                output.lines().rev().map(str::trim).find(|l| {
+                    let l = l.to_lowercase();
+                    l.contains("error") || l.contains("fail")
+                })
+            })
+            .map(normalize)
+    }
+}
+
+/// Picks the last non-empty line from stderr, or else stdout
+struct LastLine;
+
+impl SignatureExtractor for LastLine {
+    fn extract(&self, stdout: &str, stderr: &str) -> Option<String> {
+        [stderr, stdout]
+            .iter()
+            .find_map(|output| {
+                output.lines().rev().map(str::trim).find(|l| !l.is_empty())
+            })
+            .map(normalize)
+    }
+}
+
+/// Strips the parts of a line that commonly vary between occurrences of the
+/// same failure (addresses, counts, durations, and the like)
+pub fn normalize(line: &str) -> String {
+    let mut rv = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '0' && chars.peek() == Some(&'x') {
+            chars.next();
+            while chars.next_if(|c| c.is_ascii_hexdigit()).is_some() {}
+            rv.push_str("0x?");
+        } else if c.is_ascii_digit() {
+            while chars.next_if(|c| c.is_ascii_digit()).is_some() {}
+            rv.push('N');
+        } else {
+            rv.push(c);
+        }
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+    use super::SignatureProfile;
+    use super::Signer;
+
+    #[test]
+    fn test_normalize() {
+        for (line, expected) in [
+            ("", ""),
+            ("no numbers here", "no numbers here"),
+            ("panic: boom", "panic: boom"),
+            ("--- FAIL: TestFoo (0.25s)", "--- FAIL: TestFoo (N.Ns)"),
+            (
+                "unexpected fault address 0xc000a1b2c3",
+                "unexpected fault address 0x?",
+            ),
+            // uppercase hex digits, and "0x" with no digits after it
+            ("pc=0xDEADbeef sp=0x", "pc=0x? sp=0x?"),
+            ("goroutine 123 [running]:", "goroutine N [running]:"),
+            // digits inside identifiers are normalized too
+            ("TestHTTP2Server", "TestHTTPNServer"),
+            (
+                "FAIL\tgithub.com/foo/bar\t12.345s",
+                "FAIL\tgithub.com/foo/bar\tN.Ns",
+            ),
+            // a zero that isn't the start of "0x"
+            ("exit status 0", "exit status N"),
+            ("100x faster", "Nx faster"),
+        ] {
+            assert_eq!(normalize(line), expected, "line {:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_failure_signature() {
+        let stdout = "=== RUN   TestFoo\n--- FAIL: TestFoo (1.50s)\n\
+                      FAIL\texample.com/pkg\t1.503s\n";
+        let stderr = "panic: runtime error: index out of range [3] with \
+                      length 3\n\ngoroutine 7 [running]:\n\
+                      panic({0x4a1b20, 0xc000012345})\n\
+                      \t/usr/lib/go/src/runtime/panic.go:884 +0x213\n\
+                      example.com/pkg.lookup(...)\n\
+                      \t/src/pkg/lookup.go:12\n";
+        for (profile, prefixes, expected) in [
+            (
+                SignatureProfile::Go,
+                &[][..],
+                "panic: runtime error: index out of range [N] with length N",
+            ),
+            (SignatureProfile::GoTest, &[], "--- FAIL: TestFoo (N.Ns)"),
+            (
+                SignatureProfile::GoTestPanic,
+                &[],
+                "TestFoo: panic: runtime error: index out of range [N] with \
+                 length N [in example.com/pkg.lookup]",
+            ),
+            (
+                SignatureProfile::Generic,
+                &[],
+                "panic: runtime error: index out of range [N] with length N",
+            ),
+            (SignatureProfile::Go, &["FAIL\t"], "FAIL\texample.com/pkg\tN.Ns"),
+        ] {
+            let prefixes =
+                prefixes.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            let signer = Signer::new(profile, &prefixes);
+            assert_eq!(
+                signer.failure_signature(stdout, stderr),
+                expected,
+                "profile {:?}",
+                profile
+            );
+        }
+
+        // With nothing recognizable, we fall back to the last line, and then
+        // to a placeholder.
+        let signer = Signer::new(SignatureProfile::Go, &[]);
+        assert_eq!(
+            signer.failure_signature("line 1\nline 2\n\n", ""),
+            "line N"
+        );
+        assert_eq!(signer.failure_signature("", " \n"), "(no output)");
+    }
+}
@@ -0,0 +1,378 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! `gocrash simulate`: try out a campaign's configuration without running it
+//!
+//! Before spending a week of machine time chasing a failure that happens once
+//! in a few thousand runs, it's worth knowing whether the stop conditions do
+//! what you meant and what the final report will look like.  `gocrash
+//! simulate` runs a session in which each run fails independently with a
+//! given probability and takes a made-up amount of time, with no datasets and
+//! no test suite.  Runs are scheduled across threads and checked against the
+//! stop options the same way a real session does, and the same statistics are
+//! reported at the end (including the check for failures concentrated in
+//! some threads, which `--failing-threads` can provoke).
+//!
+//! The simulation uses its own clock (so "elapsed" in `--stop-when` is
+//! simulated time) and a seeded random number generator, so the same options
+//! always produce the same result.
+
+use crate::rng::Rng;
+use crate::session;
+use crate::stop;
+use crate::timing;
+use anyhow::bail;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Two-sided 95% quantile of the standard normal distribution, for the
+/// confidence interval on the failure rate
+const Z_95: f64 = 1.96;
+
+/// Simulate a session to check its stop conditions and reporting
+#[derive(clap::Args)]
+pub struct SimulateArgs {
+    /// chance that each run fails, as a fraction ("1/3000") or a decimal
+    /// ("0.0003")
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    rate: f64,
+
+    /// simulate at most this many runs in all
+    #[arg(long, value_name = "N")]
+    runs: usize,
+
+    /// number of simulated threads
+    #[arg(long, default_value_t = 1)]
+    concurrency: u8,
+
+    /// average time each run takes, in seconds (runs take between half and
+    /// one and a half times this)
+    #[arg(long, default_value_t = 60, value_name = "SECS")]
+    run_time: u64,
+
+    /// number of distinct failure signatures that failures are spread across
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    signatures: usize,
+
+    /// only the first N threads ever fail (as though the harness, rather than
+    /// the test suite, were the problem)
+    #[arg(long, value_name = "N")]
+    failing_threads: Option<u8>,
+
+    /// seed for the random number generator
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+
+    /// as with a real session: stop after each thread does this many runs
+    #[arg(long)]
+    stop_after: Option<usize>,
+
+    /// as with a real session: keep going after failures, stopping once the
+    /// same failure has been seen this many times
+    #[arg(long, value_name = "N")]
+    stop_when_reproduced: Option<usize>,
+
+    /// as with a real session: keep going after every failure
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with = "stop_when_reproduced"
+    )]
+    keep_going: bool,
+
+    /// as with a real session: keep going after failures, stopping once this
+    /// condition holds
+    #[arg(
+        long,
+        value_name = "CONDITION",
+        conflicts_with = "stop_when_reproduced"
+    )]
+    stop_when: Option<String>,
+}
+
+/// Parses a failure rate like "1/3000" or "0.0003"
+fn parse_rate(s: &str) -> Result<f64, anyhow::Error> {
+    let rate = match s.split_once('/') {
+        Some((numerator, denominator)) => {
+            let numerator = numerator
+                .trim()
+                .parse::<f64>()
+                .with_context(|| format!("bad numerator in {:?}", s))?;
+            let denominator = denominator
+                .trim()
+                .parse::<f64>()
+                .with_context(|| format!("bad denominator in {:?}", s))?;
+            numerator / denominator
+        }
+        None => s.trim().parse::<f64>().with_context(|| format!("{:?}", s))?,
+    };
+    if !(0.0..=1.0).contains(&rate) {
+        bail!("rate {:?} is not between 0 and 1", s);
+    }
+    Ok(rate)
+}
+
+/// Formats a failure rate for people to read (e.g., "1 in 3000")
+fn format_rate(rate: f64) -> String {
+    if rate <= 0.0 {
+        String::from("never")
+    } else {
+        format!("1 in {:.0}", 1.0 / rate)
+    }
+}
+
+/// A simulated thread
+struct SimThread {
+    record: session::ThreadRecord,
+    /// when the run in progress ends (in simulated seconds), if there is one
+    busy_until: Option<f64>,
+}
+
+/// Runs the `gocrash simulate` command
+pub fn simulate(args: &SimulateArgs) -> Result<(), anyhow::Error> {
+    if args.concurrency == 0 {
+        bail!("--concurrency must be at least 1");
+    }
+    if args.signatures == 0 {
+        bail!("--signatures must be at least 1");
+    }
+    if args.failing_threads.is_some_and(|n| n == 0 || n > args.concurrency) {
+        bail!("--failing-threads must be between 1 and --concurrency");
+    }
+    let stop_when = args
+        .stop_when
+        .as_deref()
+        .map(|condition| condition.parse::<stop::StopCondition>())
+        .transpose()?;
+    let stop_on_failure = !args.keep_going
+        && stop_when.is_none()
+        && args.stop_when_reproduced.is_none();
+
+    let nfailing = args.failing_threads.unwrap_or(args.concurrency);
+    let expected = args.runs as f64 * args.rate * f64::from(nfailing)
+        / f64::from(args.concurrency);
+    text_println!(
+        "simulating:      up to {} run{} failing {} of the time (seed {})",
+        args.runs,
+        if args.runs == 1 { "" } else { "s" },
+        format_rate(args.rate),
+        args.seed
+    );
+    text_println!("concurrency:     {}", args.concurrency);
+    if let Some(n) = args.failing_threads {
+        text_println!(
+            "failing threads: only thread{} {}",
+            if n == 1 { "" } else { "s" },
+            (0..n).map(|t| t.to_string()).collect::<Vec<_>>().join(", ")
+        );
+    }
+    let stop_on = match (&stop_when, args.stop_when_reproduced) {
+        (Some(stop_when), _) => Some(format!("when {}", stop_when)),
+        (None, Some(n)) => Some(format!(
+            "when the same failure is seen {} time{}",
+            n,
+            if n == 1 { "" } else { "s" }
+        )),
+        _ if stop_on_failure => Some(String::from("when any run fails")),
+        _ => None,
+    };
+    text_println!(
+        "stop:            {}",
+        match (args.stop_after, stop_on) {
+            (Some(n), Some(stop_on)) => format!(
+                "after all threads do {} run{} or {}",
+                n,
+                if n == 1 { "" } else { "s" },
+                stop_on
+            ),
+            (Some(n), None) => format!(
+                "after all threads do {} run{}",
+                n,
+                if n == 1 { "" } else { "s" }
+            ),
+            (None, Some(stop_on)) => stop_on,
+            (None, None) => format!("after {} runs (--runs)", args.runs),
+        }
+    );
+    text_println!(
+        "expected:        {:.1} failure{} in {} runs (chance of none: {:.1}%)",
+        expected,
+        if expected == 1.0 { "" } else { "s" },
+        args.runs,
+        100.0
+            * (1.0 - args.rate).powf(
+                args.runs as f64 * f64::from(nfailing)
+                    / f64::from(args.concurrency)
+            )
+    );
+    text_println!("");
+
+    let mut rng = Rng::new(args.seed);
+    let mut threads = (0..args.concurrency)
+        .map(|thread| SimThread {
+            record: session::ThreadRecord {
+                thread,
+                ntries: 0,
+                nruns: 0,
+                nfailures: 0,
+                error: None,
+            },
+            busy_until: None,
+        })
+        .collect::<Vec<_>>();
+    let mut counts = stop::Counts::default();
+    let mut by_signature: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut nstarted = 0;
+    let mut now = 0.0;
+    let mut stop_reason: Option<String> = None;
+
+    let run_time =
+        |rng: &mut Rng| args.run_time as f64 * (0.5 + rng.next_f64());
+    for t in &mut threads {
+        if nstarted < args.runs {
+            t.busy_until = Some(run_time(&mut rng));
+            nstarted += 1;
+        }
+    }
+
+    // Each time around, the run that ends soonest finishes.  As in a real
+    // session, once something stops the session, runs in progress still
+    // finish (and count), but no more start.
+    while let Some(t) = threads
+        .iter_mut()
+        .filter(|t| t.busy_until.is_some())
+        .min_by(|a, b| a.busy_until.partial_cmp(&b.busy_until).unwrap())
+    {
+        now = t.busy_until.take().unwrap();
+        counts.elapsed = Duration::from_secs_f64(now);
+        counts.runs += 1;
+        t.record.ntries += 1;
+        t.record.nruns += 1;
+
+        let can_fail = t.record.thread < nfailing;
+        if can_fail && rng.next_f64() < args.rate {
+            let which = usize::try_from(
+                rng.next_u64() % u64::try_from(args.signatures).unwrap(),
+            )
+            .unwrap();
+            let nseen = by_signature.entry(which).or_insert(0);
+            *nseen += 1;
+            let nseen = *nseen;
+            t.record.nfailures += 1;
+            counts.failures += 1;
+            counts.new_failures += 1;
+            counts.signatures = by_signature.len();
+            counts.new_signatures = by_signature.len();
+            counts.max_repeats = counts.max_repeats.max(nseen);
+            if stop_reason.is_none() {
+                if stop_on_failure {
+                    stop_reason = Some(format!(
+                        "run {} failed: simulated failure {}",
+                        counts.runs - 1,
+                        which
+                    ));
+                } else if args.stop_when_reproduced.is_some_and(|n| nseen >= n)
+                {
+                    stop_reason = Some(format!(
+                        "run {} failed (seen {} times, \
+                         --stop-when-reproduced {}): simulated failure {}",
+                        counts.runs - 1,
+                        nseen,
+                        args.stop_when_reproduced.unwrap(),
+                        which
+                    ));
+                }
+            }
+        } else {
+            counts.passes += 1;
+        }
+
+        if let Some(stop_when) = &stop_when {
+            if stop_reason.is_none() && stop_when.holds(&counts) {
+                stop_reason = Some(format!(
+                    "--stop-when {} held ({})",
+                    stop_when,
+                    stop_when.describe(&counts)
+                ));
+            }
+        }
+
+        if stop_reason.is_none()
+            && args.stop_after.is_none_or(|n| t.record.ntries < n)
+            && nstarted < args.runs
+        {
+            t.busy_until = Some(now + run_time(&mut rng));
+            nstarted += 1;
+        }
+    }
+
+    let detail = match (stop_reason, args.stop_after) {
+        (Some(reason), _) => reason,
+        (None, Some(n)) if threads.iter().all(|t| t.record.ntries >= n) => {
+            format!(
+                "each thread finished {} run{} (--stop-after {})",
+                n,
+                if n == 1 { "" } else { "s" },
+                n
+            )
+        }
+        (None, _) => format!(
+            "simulated {} run{} (--runs {})",
+            args.runs,
+            if args.runs == 1 { "" } else { "s" },
+            args.runs
+        ),
+    };
+    text_println!(
+        "stopped after {} (simulated): {}",
+        timing::format_duration(Duration::from_secs_f64(now)),
+        detail
+    );
+
+    let mut results = Vec::new();
+    if counts.failures > 0 {
+        results.push(format!("{} failed", counts.failures));
+    }
+    if counts.passes > 0 {
+        results.push(format!("{} passed", counts.passes));
+    }
+    text_println!("runs: {}", results.join(", "));
+    if !by_signature.is_empty() {
+        text_println!("failures by signature:");
+        let mut by_count = by_signature.iter().collect::<Vec<_>>();
+        by_count.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (which, count) in by_count {
+            text_println!("    {} x simulated failure {}", count, which);
+        }
+    }
+    let records = threads.into_iter().map(|t| t.record).collect::<Vec<_>>();
+    crate::print_thread_heatmap(&records);
+    print_rate(counts.failures, counts.runs);
+    Ok(())
+}
+
+/// Prints the failure rate observed over `nruns` runs, with a 95% confidence
+/// interval (the Wilson score interval, which behaves sensibly even with few
+/// or no failures)
+fn print_rate(nfailures: usize, nruns: usize) {
+    if nruns == 0 {
+        return;
+    }
+    let n = nruns as f64;
+    let p = nfailures as f64 / n;
+    let z2 = Z_95 * Z_95;
+    let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let margin =
+        Z_95 / (1.0 + z2 / n) * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+    text_println!(
+        "observed rate: {} ({} / {} runs; 95% confidence interval: {} to {})",
+        format_rate(p),
+        nfailures,
+        nruns,
+        format_rate((center - margin).max(0.0)),
+        format_rate((center + margin).min(1.0)),
+    );
+}
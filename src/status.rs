@@ -0,0 +1,242 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Session status over HTTP for `--status-addr`
+//!
+//! To check on a session running on some other machine, it's easier to ask it
+//! than to dig through its output.  With `--status-addr`, we serve `/status`:
+//! a JSON object describing the current session (its name, source, working
+//! dataset, when it started, and the options it was run with), what each
+//! thread is running, how many runs have ended with each result, and the
+//! most recent failure.
+//!
+//! We keep track of runs with the same callbacks that library users get (see
+//! `callbacks.rs`).  As with `--live-listen`, there's one server per process,
+//! which describes whichever session is running (e.g., in a pipeline).
+
+use crate::callbacks;
+use crate::http;
+use crate::http::HttpError;
+use crate::http::Request;
+use crate::http::Response;
+use anyhow::bail;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Where the server is listening, once it's started
+static LISTEN: Mutex<Option<SocketAddr>> = Mutex::new(None);
+
+/// The current session, if any
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+
+/// What we know about the current session
+struct Session {
+    start: Instant,
+    summary: SessionSummary,
+}
+
+/// What's reported for the current session (the body of `/status`)
+#[derive(Clone, Serialize)]
+struct SessionSummary {
+    session: String,
+    /// snapshot (or, without ZFS, directory) being tested
+    source: String,
+    working_dataset: String,
+    /// when the session started (RFC 3339)
+    started: String,
+    /// seconds since then (filled in when reported)
+    uptime_secs: u64,
+    /// options that the session was run with (as in "session.json")
+    parameters: serde_json::Value,
+    /// number of runs with each result so far
+    results: BTreeMap<String, usize>,
+    /// what each thread is doing
+    threads: Vec<ThreadStatus>,
+    last_failure: Option<LastFailure>,
+}
+
+#[derive(Clone, Serialize)]
+struct ThreadStatus {
+    thread: u8,
+    /// the run in progress, if any
+    run: Option<CurrentRun>,
+}
+
+#[derive(Clone, Serialize)]
+struct CurrentRun {
+    run_id: usize,
+    attempt: usize,
+    dataset: String,
+    /// when the run started (RFC 3339)
+    started: String,
+}
+
+#[derive(Clone, Serialize)]
+struct LastFailure {
+    run_id: usize,
+    thread: u8,
+    dataset: String,
+    /// when the run ended (RFC 3339)
+    time: String,
+    result: String,
+    signature: String,
+    severity: String,
+    known_failure: Option<String>,
+    kept: bool,
+    /// first line of the error describing what happened
+    error: Option<String>,
+}
+
+/// Updates `SESSION` as runs start and end
+struct Tracker;
+
+impl callbacks::Callbacks for Tracker {
+    fn on_run_start(&self, run: &callbacks::Run) {
+        update(run.session, |summary| {
+            if let Some(status) =
+                summary.threads.get_mut(usize::from(run.thread))
+            {
+                status.run = Some(CurrentRun {
+                    run_id: run.run_id,
+                    attempt: run.attempt,
+                    dataset: run.dataset.to_string(),
+                    started: chrono::Utc::now().to_rfc3339(),
+                });
+            }
+        });
+    }
+
+    fn on_failure(&self, run: &callbacks::Run, failure: &callbacks::Failure) {
+        update(run.session, |summary| {
+            summary.last_failure = Some(LastFailure {
+                run_id: run.run_id,
+                thread: run.thread,
+                dataset: run.dataset.to_string(),
+                time: chrono::Utc::now().to_rfc3339(),
+                // This is filled in by `on_run_end()`, which comes next.
+                result: String::new(),
+                signature: failure.signature.to_string(),
+                severity: failure.severity.to_string(),
+                known_failure: failure.known_failure.map(str::to_string),
+                kept: false,
+                error: None,
+            });
+        });
+    }
+
+    fn on_run_end(&self, run: &callbacks::Run, end: &callbacks::RunEnd) {
+        update(run.session, |summary| {
+            *summary.results.entry(end.result.to_string()).or_insert(0) += 1;
+            if let Some(status) =
+                summary.threads.get_mut(usize::from(run.thread))
+            {
+                status.run = None;
+            }
+            if let Some(last) = summary
+                .last_failure
+                .as_mut()
+                .filter(|last| last.run_id == run.run_id)
+            {
+                last.result = end.result.to_string();
+                last.kept = end.kept;
+                last.error = end.error.map(|error| {
+                    format!("{:#}", error).lines().next().unwrap_or("").into()
+                });
+            }
+        });
+    }
+}
+
+/// Applies `f` to the summary of session `session`, if it's the current one
+fn update(session: &str, f: impl FnOnce(&mut SessionSummary)) {
+    if let Some(current) = SESSION
+        .lock()
+        .unwrap()
+        .as_mut()
+        .filter(|current| current.summary.session == session)
+    {
+        f(&mut current.summary);
+    }
+}
+
+/// Starts serving status on `addr` (unless we already are)
+pub fn start(
+    addr: SocketAddr,
+    token_file: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    let mut listen = LISTEN.lock().unwrap();
+    match *listen {
+        Some(listen) if listen == addr => return Ok(()),
+        Some(listen) => bail!("already serving status on {}", listen),
+        None => (),
+    }
+    let server = http::Server::bind(&http::ListenArgs::tcp(
+        addr,
+        token_file.map(Path::to_path_buf),
+    ))?;
+    text_println!("serving status at {}status", server.describe());
+    std::thread::spawn(move || {
+        if let Err(error) = server.serve(handle) {
+            eprintln!("warning: status server: {:#}", error);
+        }
+    });
+    callbacks::register(Arc::new(Tracker));
+    *listen = Some(addr);
+    Ok(())
+}
+
+/// Makes `session` (with `nthreads` threads) the one described by `/status`
+pub fn session_started(
+    session: &str,
+    source: &str,
+    working_dataset: &str,
+    parameters: &serde_json::Value,
+    nthreads: u8,
+) {
+    if LISTEN.lock().unwrap().is_none() {
+        return;
+    }
+    *SESSION.lock().unwrap() = Some(Session {
+        start: Instant::now(),
+        summary: SessionSummary {
+            session: session.to_string(),
+            source: source.to_string(),
+            working_dataset: working_dataset.to_string(),
+            started: chrono::Utc::now().to_rfc3339(),
+            uptime_secs: 0,
+            parameters: parameters.clone(),
+            results: BTreeMap::new(),
+            threads: (0..nthreads)
+                .map(|thread| ThreadStatus { thread, run: None })
+                .collect(),
+            last_failure: None,
+        },
+    });
+}
+
+fn handle(request: &Request) -> Response {
+    if request.path != "/status" {
+        return Response::from(HttpError::not_found(format!(
+            "no such page: {:?}",
+            request.path
+        )));
+    }
+    let summary = SESSION.lock().unwrap().as_ref().map(|current| {
+        let mut summary = current.summary.clone();
+        summary.uptime_secs = current.start.elapsed().as_secs();
+        summary
+    });
+    match serde_json::to_vec_pretty(&summary) {
+        Ok(body) => {
+            Response::new(200, "application/json", http::Body::Bytes(body))
+        }
+        Err(error) => Response::text(500, format!("{:#}\n", error)),
+    }
+}
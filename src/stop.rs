@@ -0,0 +1,547 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Stop conditions given with `--stop-when`
+//!
+//! Rather than adding a flag for each new way that someone wants a session to
+//! end, `--stop-when` takes an expression combining comparisons of the
+//! session's counters (see `Counter`) with `AND`, `OR`, `NOT`, and
+//! parentheses, like:
+//!
+//! ```text
+//! (failures >= 3 AND new_signatures >= 1) OR runs >= 500 OR elapsed >= 12h
+//! ```
+//!
+//! `AND` binds more tightly than `OR`, and keywords and counter names are
+//! case-insensitive.  The condition is checked each time a run finishes.
+//! Once it holds, every thread finishes the run it's doing and the session
+//! ends.
+
+use crate::timing;
+use anyhow::anyhow;
+use anyhow::bail;
+use std::time::Duration;
+
+/// Something about the session that a stop condition can test
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Counter {
+    /// runs that finished (with any result)
+    Runs,
+    /// runs that passed
+    Passes,
+    /// runs that failed (including hangs and suspect failures)
+    Failures,
+    /// failed runs that didn't match a known failure
+    NewFailures,
+    /// runs that hung
+    Hangs,
+    /// distinct failure signatures seen
+    Signatures,
+    /// distinct failure signatures seen that didn't match a known failure
+    NewSignatures,
+    /// most failed runs seen with any one signature
+    MaxRepeats,
+    /// time since the session started (in seconds)
+    Elapsed,
+}
+
+const COUNTERS: &[Counter] = &[
+    Counter::Runs,
+    Counter::Passes,
+    Counter::Failures,
+    Counter::NewFailures,
+    Counter::Hangs,
+    Counter::Signatures,
+    Counter::NewSignatures,
+    Counter::MaxRepeats,
+    Counter::Elapsed,
+];
+
+impl Counter {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Counter::Runs => "runs",
+            Counter::Passes => "passes",
+            Counter::Failures => "failures",
+            Counter::NewFailures => "new_failures",
+            Counter::Hangs => "hangs",
+            Counter::Signatures => "signatures",
+            Counter::NewSignatures => "new_signatures",
+            Counter::MaxRepeats => "max_repeats",
+            Counter::Elapsed => "elapsed",
+        }
+    }
+}
+
+/// Values of the counters at some point in a session
+#[derive(Default)]
+pub struct Counts {
+    pub runs: usize,
+    pub passes: usize,
+    pub failures: usize,
+    pub new_failures: usize,
+    pub hangs: usize,
+    pub signatures: usize,
+    pub new_signatures: usize,
+    pub max_repeats: usize,
+    pub elapsed: Duration,
+}
+
+impl Counts {
+    fn get(&self, counter: Counter) -> u64 {
+        let count = match counter {
+            Counter::Runs => self.runs,
+            Counter::Passes => self.passes,
+            Counter::Failures => self.failures,
+            Counter::NewFailures => self.new_failures,
+            Counter::Hangs => self.hangs,
+            Counter::Signatures => self.signatures,
+            Counter::NewSignatures => self.new_signatures,
+            Counter::MaxRepeats => self.max_repeats,
+            Counter::Elapsed => return self.elapsed.as_secs(),
+        };
+        u64::try_from(count).unwrap()
+    }
+}
+
+/// How a counter is compared with a value
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Comparison {
+    Lt,
+    Le,
+    Eq,
+    Ne,
+    Ge,
+    Gt,
+}
+
+impl Comparison {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Ge => ">=",
+            Comparison::Gt => ">",
+        }
+    }
+
+    fn holds(&self, left: u64, right: u64) -> bool {
+        match self {
+            Comparison::Lt => left < right,
+            Comparison::Le => left <= right,
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+            Comparison::Ge => left >= right,
+            Comparison::Gt => left > right,
+        }
+    }
+}
+
+/// A parsed `--stop-when` expression
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StopCondition {
+    Compare { counter: Counter, comparison: Comparison, value: u64 },
+    Not(Box<StopCondition>),
+    And(Box<StopCondition>, Box<StopCondition>),
+    Or(Box<StopCondition>, Box<StopCondition>),
+}
+
+impl StopCondition {
+    /// Returns whether the condition holds for `counts`
+    pub fn holds(&self, counts: &Counts) -> bool {
+        match self {
+            StopCondition::Compare { counter, comparison, value } => {
+                comparison.holds(counts.get(*counter), *value)
+            }
+            StopCondition::Not(c) => !c.holds(counts),
+            StopCondition::And(a, b) => a.holds(counts) && b.holds(counts),
+            StopCondition::Or(a, b) => a.holds(counts) || b.holds(counts),
+        }
+    }
+
+    /// Describes the values in `counts` of the counters that the condition
+    /// tests, like "failures = 3, runs = 120"
+    pub fn describe(&self, counts: &Counts) -> String {
+        let mut counters = Vec::new();
+        self.counters(&mut counters);
+        counters
+            .iter()
+            .map(|counter| {
+                let value = counts.get(*counter);
+                if *counter == Counter::Elapsed {
+                    let value = Duration::from_secs(value);
+                    format!("elapsed = {}", timing::format_duration(value))
+                } else {
+                    format!("{} = {}", counter.as_str(), value)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Appends the counters that the condition tests to `counters` (once
+    /// each, in the order they first appear)
+    fn counters(&self, counters: &mut Vec<Counter>) {
+        match self {
+            StopCondition::Compare { counter, .. } => {
+                if !counters.contains(counter) {
+                    counters.push(*counter);
+                }
+            }
+            StopCondition::Not(c) => c.counters(counters),
+            StopCondition::And(a, b) | StopCondition::Or(a, b) => {
+                a.counters(counters);
+                b.counters(counters);
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for StopCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopCondition::Compare { counter, comparison, value } => {
+                write!(f, "{} {} ", counter.as_str(), comparison.as_str())?;
+                if *counter == Counter::Elapsed {
+                    // This has to be something that we can parse again, so
+                    // it's a single number with the largest unit that fits.
+                    let (count, unit) = [(60 * 60, "h"), (60, "m")]
+                        .into_iter()
+                        .find(|(secs, _)| *value > 0 && *value % secs == 0)
+                        .map_or((*value, "s"), |(secs, unit)| {
+                            (*value / secs, unit)
+                        });
+                    write!(f, "{}{}", count, unit)
+                } else {
+                    write!(f, "{}", value)
+                }
+            }
+            StopCondition::Not(c) => write!(f, "NOT {}", Grouped(c)),
+            StopCondition::And(a, b) => {
+                write!(f, "{} AND {}", Grouped(a), Grouped(b))
+            }
+            StopCondition::Or(a, b) => write!(f, "{} OR {}", a, b),
+        }
+    }
+}
+
+/// Formats a condition that's an operand of `NOT` or `AND`, parenthesized if
+/// needed
+struct Grouped<'a>(&'a StopCondition);
+
+impl std::fmt::Display for Grouped<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            StopCondition::Compare { .. } | StopCondition::Not(_) => {
+                write!(f, "{}", self.0)
+            }
+            StopCondition::And(..) | StopCondition::Or(..) => {
+                write!(f, "({})", self.0)
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for StopCondition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens: &tokens, next: 0 };
+        let condition = parser
+            .parse_or()
+            .and_then(|condition| match parser.peek() {
+                None => Ok(condition),
+                Some(token) => bail!("unexpected {:?}", token),
+            })
+            .map_err(|error| anyhow!("stop condition {:?}: {}", s, error))?;
+        Ok(condition)
+    }
+}
+
+/// Splits a stop condition into words, parentheses, and comparison operators
+fn tokenize(s: &str) -> Result<Vec<&str>, anyhow::Error> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c == '(' || c == ')' {
+            1
+        } else if "<>=!".contains(c) {
+            if rest[1..].starts_with('=') {
+                2
+            } else {
+                1
+            }
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len())
+        } else {
+            bail!("stop condition {:?}: unexpected {:?}", s, c);
+        };
+        tokens.push(&rest[..len]);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Parses a list of tokens into a `StopCondition`
+struct Parser<'a> {
+    tokens: &'a [&'a str],
+    next: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.next).copied()
+    }
+
+    fn take(&mut self) -> Result<&'a str, anyhow::Error> {
+        let token =
+            self.peek().ok_or_else(|| anyhow!("unexpected end of input"))?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    /// Consumes the next token if it's the keyword `keyword`
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if self.peek().is_some_and(|t| t.eq_ignore_ascii_case(keyword)) {
+            self.next += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<StopCondition, anyhow::Error> {
+        let mut condition = self.parse_and()?;
+        while self.keyword("or") {
+            let right = self.parse_and()?;
+            condition = StopCondition::Or(Box::new(condition), Box::new(right));
+        }
+        Ok(condition)
+    }
+
+    fn parse_and(&mut self) -> Result<StopCondition, anyhow::Error> {
+        let mut condition = self.parse_not()?;
+        while self.keyword("and") {
+            let right = self.parse_not()?;
+            condition =
+                StopCondition::And(Box::new(condition), Box::new(right));
+        }
+        Ok(condition)
+    }
+
+    fn parse_not(&mut self) -> Result<StopCondition, anyhow::Error> {
+        if self.keyword("not") {
+            return Ok(StopCondition::Not(Box::new(self.parse_not()?)));
+        }
+        if self.peek() == Some("(") {
+            self.next += 1;
+            let condition = self.parse_or()?;
+            match self.take()? {
+                ")" => return Ok(condition),
+                token => bail!("expected \")\", found {:?}", token),
+            }
+        }
+
+        let name = self.take()?;
+        let counter = COUNTERS
+            .iter()
+            .copied()
+            .find(|c| c.as_str().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown counter {:?} (expected one of: {})",
+                    name,
+                    COUNTERS
+                        .iter()
+                        .map(|c| c.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?;
+        let comparison = match self.take()? {
+            "<" => Comparison::Lt,
+            "<=" => Comparison::Le,
+            "=" | "==" => Comparison::Eq,
+            "!=" => Comparison::Ne,
+            ">=" => Comparison::Ge,
+            ">" => Comparison::Gt,
+            token => bail!("expected a comparison, found {:?}", token),
+        };
+        let token = self.take()?;
+        let value = if counter == Counter::Elapsed {
+            timing::parse_duration_secs(token)?
+        } else {
+            token
+                .parse()
+                .map_err(|_| anyhow!("expected a count, found {:?}", token))?
+        };
+        Ok(StopCondition::Compare { counter, comparison, value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Comparison;
+    use super::Counter;
+    use super::Counts;
+    use super::StopCondition;
+    use std::time::Duration;
+
+    fn parse(s: &str) -> StopCondition {
+        s.parse().unwrap_or_else(|error| panic!("parse {:?}: {:#}", s, error))
+    }
+
+    fn compare(counter: Counter, value: u64) -> Box<StopCondition> {
+        Box::new(StopCondition::Compare {
+            counter,
+            comparison: Comparison::Ge,
+            value,
+        })
+    }
+
+    #[test]
+    fn test_precedence() {
+        // AND binds more tightly than OR, on either side.
+        let expected = StopCondition::Or(
+            compare(Counter::Runs, 1),
+            Box::new(StopCondition::And(
+                compare(Counter::Failures, 2),
+                compare(Counter::Hangs, 3),
+            )),
+        );
+        assert_eq!(
+            parse("runs >= 1 OR failures >= 2 AND hangs >= 3"),
+            expected
+        );
+        let expected = StopCondition::Or(
+            Box::new(StopCondition::And(
+                compare(Counter::Runs, 1),
+                compare(Counter::Failures, 2),
+            )),
+            compare(Counter::Hangs, 3),
+        );
+        assert_eq!(
+            parse("runs >= 1 AND failures >= 2 OR hangs >= 3"),
+            expected
+        );
+
+        // NOT binds more tightly than AND.
+        let expected = StopCondition::And(
+            Box::new(StopCondition::Not(compare(Counter::Runs, 1))),
+            compare(Counter::Failures, 2),
+        );
+        assert_eq!(parse("NOT runs >= 1 AND failures >= 2"), expected);
+
+        // OR and AND group to the left.
+        let expected = StopCondition::Or(
+            Box::new(StopCondition::Or(
+                compare(Counter::Runs, 1),
+                compare(Counter::Failures, 2),
+            )),
+            compare(Counter::Hangs, 3),
+        );
+        assert_eq!(parse("runs >= 1 OR failures >= 2 OR hangs >= 3"), expected);
+    }
+
+    #[test]
+    fn test_parentheses() {
+        let expected = StopCondition::And(
+            Box::new(StopCondition::Or(
+                compare(Counter::Runs, 1),
+                compare(Counter::Failures, 2),
+            )),
+            compare(Counter::Hangs, 3),
+        );
+        assert_eq!(
+            parse("(runs >= 1 OR failures >= 2) AND hangs >= 3"),
+            expected
+        );
+        assert_eq!(
+            parse("((runs>=1 or failures>=2)) and (hangs>=3)"),
+            expected
+        );
+        let expected = StopCondition::Not(Box::new(StopCondition::Or(
+            compare(Counter::Runs, 1),
+            compare(Counter::Failures, 2),
+        )));
+        assert_eq!(parse("NOT (runs >= 1 OR failures >= 2)"), expected);
+    }
+
+    #[test]
+    fn test_display() {
+        // Conditions print with only the parentheses they need, and what they
+        // print parses back to the same thing.
+        for (input, output) in [
+            ("runs >= 1 OR failures >= 2 AND hangs >= 3", None),
+            ("(runs >= 1 OR failures >= 2) AND hangs >= 3", None),
+            ("NOT (runs >= 1 AND failures > 2)", None),
+            ("((runs < 5))", Some("runs < 5")),
+            ("Elapsed >= 90m", Some("elapsed >= 90m")),
+            ("elapsed >= 120m", Some("elapsed >= 2h")),
+            ("elapsed >= 3601", Some("elapsed >= 3601s")),
+            ("failures = 3", Some("failures == 3")),
+        ] {
+            let condition = parse(input);
+            let printed = condition.to_string();
+            assert_eq!(printed, output.unwrap_or(input));
+            assert_eq!(parse(&printed), condition);
+        }
+    }
+
+    #[test]
+    fn test_holds() {
+        let counts = Counts {
+            runs: 10,
+            failures: 2,
+            elapsed: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        for (condition, holds) in [
+            ("runs >= 10", true),
+            ("runs > 10", false),
+            ("failures == 2 AND runs != 10", false),
+            ("failures == 2 AND NOT runs != 10", true),
+            ("hangs >= 1 OR elapsed >= 1h", true),
+            ("(hangs >= 1 OR failures < 2) AND runs <= 10", false),
+        ] {
+            assert_eq!(parse(condition).holds(&counts), holds, "{}", condition);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        for (input, message) in [
+            ("", "unexpected end of input"),
+            ("runs", "unexpected end of input"),
+            ("runs >=", "unexpected end of input"),
+            ("bogus >= 1", "unknown counter \"bogus\""),
+            ("runs ~ 1", "unexpected '~'"),
+            ("runs is 1", "expected a comparison, found \"is\""),
+            ("runs >= lots", "expected a count, found \"lots\""),
+            ("(runs >= 1", "unexpected end of input"),
+            ("(runs >= 1 failures >= 2)", "expected \")\", found \"failures\""),
+            ("runs >= 1)", "unexpected \")\""),
+            ("runs >= 1 failures >= 2", "unexpected \"failures\""),
+            ("runs >= 1 AND", "unexpected end of input"),
+        ] {
+            let error = input
+                .parse::<StopCondition>()
+                .expect_err(&format!("parse {:?} succeeded", input));
+            let error = format!("{:#}", error);
+            assert!(
+                error.starts_with(&format!("stop condition {:?}: ", input))
+                    && error.contains(message),
+                "parse {:?}: expected {:?}, got {:?}",
+                input,
+                message,
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,353 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Tracking how much storage runs use
+//!
+//! We record how much space each clone uses when its run finishes, plus how
+//! much the working dataset (which includes all of the clones that we keep)
+//! uses over time.  A run that suddenly uses far more space than the ones
+//! before it is worth knowing about in its own right.
+
+use crate::backend::Backend;
+use crate::backend::Capability;
+use crate::zfs;
+use anyhow::anyhow;
+use anyhow::Context;
+use serde::Serialize;
+use std::path::Path;
+use std::process::Command;
+
+/// Don't judge whether a run's usage is unusual until this many runs have
+/// finished
+const DEVIATION_MIN_RUNS: usize = 3;
+
+/// Warn about runs that use more than this many times the median usage...
+const DEVIATION_FACTOR: u64 = 4;
+
+/// ... and more than this many bytes above it
+const DEVIATION_MIN_BYTES: u64 = 1 << 30;
+
+/// Describes how much space a dataset uses
+#[derive(Clone, Copy, Serialize)]
+pub struct DatasetUsage {
+    /// bytes used by the dataset and its descendants (ZFS's `used`)
+    pub used: u64,
+    /// bytes written, before compression (ZFS's `logicalused`)
+    pub logicalused: u64,
+}
+
+/// Returns how much space `dataset` uses
+pub fn dataset_usage(dataset: &str) -> Result<DatasetUsage, anyhow::Error> {
+    let output = zfs::run(
+        Command::new("zfs")
+            .arg("get")
+            .arg("-Hp")
+            .arg("-ovalue")
+            .arg("used,logicalused")
+            .arg(dataset),
+    )?;
+    let values = output
+        .lines()
+        .map(|line| line.trim().parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow!("unexpected output from zfs get: {:?}", output))?;
+    match values[..] {
+        [used, logicalused] => Ok(DatasetUsage { used, logicalused }),
+        _ => Err(anyhow!("unexpected output from zfs get: {:?}", output)),
+    }
+}
+
+/// Returns the total size of the files under directory `path` (for the
+/// backends that don't use ZFS)
+///
+/// Like `du -x`, this doesn't descend into other filesystems mounted inside
+/// `path` (like the overlay mounts of the overlay backend), whose files are
+/// already counted where they really live.
+pub fn directory_usage(path: &Path) -> Result<u64, anyhow::Error> {
+    let top = std::fs::symlink_metadata(path)
+        .with_context(|| format!("stat {:?}", path))?;
+    let mut total = 0;
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("read directory {:?}", dir))?;
+        for entry in entries {
+            let entry =
+                entry.with_context(|| format!("read directory {:?}", dir))?;
+            let metadata = std::fs::symlink_metadata(entry.path())
+                .with_context(|| format!("stat {:?}", entry.path()))?;
+            if metadata.is_dir() {
+                if same_filesystem(&top, &metadata) {
+                    dirs.push(entry.path());
+                }
+            } else {
+                total += metadata.len();
+            }
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(unix)]
+fn same_filesystem(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev()
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(_a: &std::fs::Metadata, _b: &std::fs::Metadata) -> bool {
+    true
+}
+
+/// Returns how many bytes are available for working dataset `working` (with
+/// mountpoint `mountpoint`) of a session using `backend`
+pub fn available(
+    backend: Backend,
+    working: &str,
+    mountpoint: &Path,
+) -> Result<u64, anyhow::Error> {
+    if backend.supports(Capability::SpaceAccounting) {
+        dataset_available(working)
+    } else {
+        filesystem_available(mountpoint)
+    }
+}
+
+/// Returns how many bytes are available to `dataset` (ZFS's `available`)
+fn dataset_available(dataset: &str) -> Result<u64, anyhow::Error> {
+    let output = zfs::run(
+        Command::new("zfs")
+            .arg("get")
+            .arg("-Hp")
+            .arg("-ovalue")
+            .arg("available")
+            .arg(dataset),
+    )?;
+    output
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("unexpected output from zfs get: {:?}", output))
+}
+
+/// Returns how many bytes are available (to unprivileged users) on the
+/// filesystem containing `path`
+#[cfg(unix)]
+fn filesystem_available(path: &Path) -> Result<u64, anyhow::Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let cpath = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("statvfs {:?}", path))?;
+    // Safety: statvfs(3) fills in the struct that we pass, and `cpath` is a
+    // valid C string.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("statvfs {:?}", path));
+    }
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+/// Returns how many bytes are available on the filesystem containing `path`
+/// (not supported on this system)
+#[cfg(not(unix))]
+fn filesystem_available(path: &Path) -> Result<u64, anyhow::Error> {
+    Err(anyhow!("statvfs {:?}: not supported on this system", path))
+}
+
+/// Accumulates storage usage over a session
+#[derive(Default)]
+pub struct StorageTracker {
+    /// usage of each clone when its run finished
+    runs: Vec<u64>,
+    runs_logical: u64,
+    /// usage of the working dataset over time
+    working: Vec<WorkingSample>,
+}
+
+/// Describes the usage of the working dataset at some point in time
+#[derive(Clone, Serialize)]
+pub struct WorkingSample {
+    /// when this was measured (RFC 3339)
+    pub time: String,
+    pub used: u64,
+}
+
+/// Summarizes storage usage over a session
+#[derive(Default, Serialize)]
+pub struct StorageSummary {
+    /// number of runs whose usage was recorded
+    pub nruns: usize,
+    /// total bytes used by all runs' clones when they finished
+    pub runs_total_used: u64,
+    /// total bytes written by all runs, before compression
+    pub runs_total_logicalused: u64,
+    /// median bytes used by a run's clone
+    pub runs_median_used: u64,
+    /// most bytes used by any one run's clone
+    pub runs_max_used: u64,
+    /// usage of the working dataset over time
+    pub working_dataset: Vec<WorkingSample>,
+}
+
+impl StorageTracker {
+    /// Records the usage of a clone whose run just finished, returning a
+    /// description of the problem if it's unusually large compared to the
+    /// runs before it
+    pub fn record_run(&mut self, usage: DatasetUsage) -> Option<String> {
+        let warning = (self.runs.len() >= DEVIATION_MIN_RUNS)
+            .then(|| median(&self.runs))
+            .filter(|median| {
+                usage.used > median.saturating_mul(DEVIATION_FACTOR)
+                    && usage.used - median > DEVIATION_MIN_BYTES
+            })
+            .map(|median| {
+                format!(
+                    "run used {} of storage (the median so far is {})",
+                    format_bytes(usage.used),
+                    format_bytes(median)
+                )
+            });
+        self.runs.push(usage.used);
+        self.runs_logical += usage.logicalused;
+        warning
+    }
+
+    /// Records the current usage of the working dataset
+    pub fn record_working(&mut self, used: u64) {
+        self.working.push(WorkingSample {
+            time: chrono::Utc::now().to_rfc3339(),
+            used,
+        });
+    }
+
+    pub fn summarize(&self) -> StorageSummary {
+        StorageSummary {
+            nruns: self.runs.len(),
+            runs_total_used: self.runs.iter().sum(),
+            runs_total_logicalused: self.runs_logical,
+            runs_median_used: median(&self.runs),
+            runs_max_used: self.runs.iter().copied().max().unwrap_or(0),
+            working_dataset: self.working.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for StorageSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} run{} used {} in total ({} before compression), median {}, \
+             max {}",
+            self.nruns,
+            if self.nruns == 1 { "" } else { "s" },
+            format_bytes(self.runs_total_used),
+            format_bytes(self.runs_total_logicalused),
+            format_bytes(self.runs_median_used),
+            format_bytes(self.runs_max_used),
+        )?;
+        if let Some(last) = self.working_dataset.last() {
+            write!(f, "; working dataset uses {}", format_bytes(last.used))?;
+        }
+        Ok(())
+    }
+}
+
+fn median(values: &[u64]) -> u64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).copied().unwrap_or(0)
+}
+
+/// Parses a byte count with an optional binary suffix (e.g., "512", "100M",
+/// "30G")
+pub fn parse_bytes(s: &str) -> Result<u64, anyhow::Error> {
+    let number = s.strip_suffix(['B', 'b']).unwrap_or(s);
+    let (digits, shift) = match number.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(anyhow!("unknown suffix in {:?}", s)),
+            };
+            (&number[..i], shift)
+        }
+        _ => (number, 0),
+    };
+    let value: u64 =
+        digits.parse().map_err(|_| anyhow!("invalid byte count {:?}", s))?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("byte count too large: {:?}", s))
+}
+
+/// Formats a byte count for people to read (e.g., "1.5 GiB")
+pub fn format_bytes(nbytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = nbytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", nbytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_bytes;
+    use super::parse_bytes;
+
+    #[test]
+    fn test_parse_bytes() {
+        for (input, expected) in [
+            ("0", 0),
+            ("512", 512),
+            ("512B", 512),
+            ("4K", 4 << 10),
+            ("4k", 4 << 10),
+            ("4KB", 4 << 10),
+            ("100M", 100 << 20),
+            ("30G", 30 << 30),
+            ("30gb", 30 << 30),
+            ("2T", 2 << 40),
+        ] {
+            assert_eq!(parse_bytes(input).unwrap(), expected, "{}", input);
+        }
+
+        for (input, expected) in [
+            ("", "invalid byte count \"\""),
+            ("B", "invalid byte count \"B\""),
+            ("10BB", "unknown suffix in \"10BB\""),
+            ("1.5G", "invalid byte count \"1.5G\""),
+            ("-1", "invalid byte count \"-1\""),
+            ("10 M", "invalid byte count \"10 M\""),
+            ("10P", "unknown suffix in \"10P\""),
+            ("16777216T", "byte count too large: \"16777216T\""),
+        ] {
+            let error = parse_bytes(input).unwrap_err();
+            assert_eq!(format!("{:#}", error), expected);
+        }
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        for (nbytes, expected) in [
+            (0, "0 B"),
+            (1023, "1023 B"),
+            (1024, "1.0 KiB"),
+            (1536 << 20, "1.5 GiB"),
+            (3 << 50, "3072.0 TiB"),
+        ] {
+            assert_eq!(format_bytes(nbytes), expected);
+        }
+    }
+}
@@ -0,0 +1,279 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Content-addressed storage for archived files
+//!
+//! Archived runs have a lot in common: every run of a session has the same
+//! toolchain binaries, and most runs' logs are nearly the same, too.  So the
+//! archive directory keeps each distinct file once, in a store named for the
+//! SHA3-256 hash of its contents (`store/ab/abcdef...`).  Each run's archive
+//! directory still looks like a normal tree of files, but each file is a hard
+//! link to the blob in the store, and the run's manifest (`MANIFEST_NAME`)
+//! records which blob each path refers to.
+//!
+//! Blobs are shared, so archived files must not be modified in place.  `gocrash
+//! verify-artifacts` (see `verify.rs`) checks that blobs still match their
+//! hashes and that each run's files still match its manifest.
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use tiny_keccak::Hasher;
+
+/// Name of the store directory (under the archive directory)
+pub const STORE_DIR: &str = "store";
+
+/// Name of the manifest written at the top of each run's archive directory
+pub const MANIFEST_NAME: &str = "gocrash-manifest.json";
+
+/// Describes one file stored for a run
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ManifestEntry {
+    /// path of the file (relative to the run's archive directory)
+    pub path: String,
+    /// SHA3-256 hash of the file's contents (in hex)
+    pub sha3_256: String,
+    /// size of the file
+    pub bytes: u64,
+}
+
+/// Describes what happened when a file was added to the store
+pub struct Added {
+    pub hash: String,
+    pub bytes: u64,
+    /// whether the store already had this blob
+    pub existed: bool,
+}
+
+/// A directory of blobs named by their hashes
+pub struct Store {
+    dir: PathBuf,
+    /// used to generate unique names for files being written
+    ntemps: AtomicUsize,
+}
+
+impl Store {
+    /// Returns the store under archive directory `archive_dir` (which is
+    /// created when something is first added)
+    pub fn new(archive_dir: &Path) -> Store {
+        Store { dir: archive_dir.join(STORE_DIR), ntemps: AtomicUsize::new(0) }
+    }
+
+    /// Adds a copy of the file at `source` to the store and links `target`
+    /// to it
+    pub fn add_file(
+        &self,
+        source: &Path,
+        target: &Path,
+    ) -> Result<Added, anyhow::Error> {
+        let mut input = std::fs::File::open(source)
+            .with_context(|| format!("open {:?}", source))?;
+        let permissions = input
+            .metadata()
+            .with_context(|| format!("stat {:?}", source))?
+            .permissions();
+        self.add(target, |output, hasher| {
+            let mut buf = vec![0; 128 * 1024];
+            let mut nbytes = 0;
+            loop {
+                let n = input
+                    .read(&mut buf)
+                    .with_context(|| format!("read {:?}", source))?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                output.write_all(&buf[..n])?;
+                nbytes += n as u64;
+            }
+            output.set_permissions(permissions)?;
+            Ok(nbytes)
+        })
+    }
+
+    /// Adds `contents` to the store and links `target` to it
+    pub fn add_bytes(
+        &self,
+        contents: &[u8],
+        target: &Path,
+    ) -> Result<Added, anyhow::Error> {
+        self.add(target, |output, hasher| {
+            hasher.update(contents);
+            output.write_all(contents)?;
+            Ok(contents.len() as u64)
+        })
+    }
+
+    /// Adds a blob whose contents are written (and hashed) by `write`, and
+    /// links `target` to it
+    ///
+    /// The contents are written to a temporary file in the store and then
+    /// renamed into place, so that concurrent runs (or sessions) storing the
+    /// same blob never see a partial one.
+    fn add(
+        &self,
+        target: &Path,
+        write: impl FnOnce(
+            &mut std::fs::File,
+            &mut tiny_keccak::Sha3,
+        ) -> Result<u64, anyhow::Error>,
+    ) -> Result<Added, anyhow::Error> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("create {:?}", self.dir))?;
+        let temp = self.dir.join(format!(
+            "tmp-{}-{}",
+            std::process::id(),
+            self.ntemps.fetch_add(1, Ordering::SeqCst)
+        ));
+        let mut hasher = tiny_keccak::Sha3::v256();
+        let result = std::fs::File::create(&temp)
+            .with_context(|| format!("create {:?}", temp))
+            .and_then(|mut output| {
+                write(&mut output, &mut hasher)
+                    .with_context(|| format!("write {:?}", temp))
+            });
+        let bytes = match result {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                let _ = std::fs::remove_file(&temp);
+                return Err(error);
+            }
+        };
+        let hash = finish(hasher);
+
+        let blob = self.blob_path(&hash);
+        let existed = blob.exists();
+        if existed {
+            let _ = std::fs::remove_file(&temp);
+        } else {
+            let parent = blob.parent().expect("blobs have a parent");
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("create {:?}", parent))?;
+            std::fs::rename(&temp, &blob)
+                .with_context(|| format!("rename {:?} to {:?}", temp, blob))?;
+        }
+
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("create {:?}", parent))?;
+        }
+        // Linking can fail if the blob already has as many links as the
+        // filesystem allows, in which case the run just gets its own copy.
+        if std::fs::hard_link(&blob, target).is_err() {
+            std::fs::copy(&blob, target)
+                .with_context(|| format!("copy {:?} to {:?}", blob, target))?;
+        }
+        Ok(Added { hash, bytes, existed })
+    }
+
+    /// Returns the path of the blob with the given hash
+    ///
+    /// `hash` must be valid (see `is_valid_hash()`).
+    pub fn blob_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[..2]).join(hash)
+    }
+
+    /// Returns the hash and path of every blob in the store
+    pub fn blobs(&self) -> Result<Vec<(String, PathBuf)>, anyhow::Error> {
+        let mut blobs = Vec::new();
+        if !self.dir.exists() {
+            return Ok(blobs);
+        }
+        let entries = std::fs::read_dir(&self.dir)
+            .with_context(|| format!("read directory {:?}", self.dir))?;
+        for entry in entries {
+            let entry = entry
+                .with_context(|| format!("read directory {:?}", self.dir))?;
+            // Skip files being written (or left behind by a crash).
+            if !entry.path().is_dir() {
+                continue;
+            }
+            let subdir = entry.path();
+            let entries = std::fs::read_dir(&subdir)
+                .with_context(|| format!("read directory {:?}", subdir))?;
+            for entry in entries {
+                let entry = entry
+                    .with_context(|| format!("read directory {:?}", subdir))?;
+                let hash = entry.file_name().to_string_lossy().into_owned();
+                blobs.push((hash, entry.path()));
+            }
+        }
+        blobs.sort();
+        Ok(blobs)
+    }
+}
+
+/// Returns the SHA3-256 hash of the contents of the file at `path` (in hex)
+pub fn hash_file(path: &Path) -> Result<String, anyhow::Error> {
+    let mut input = std::fs::File::open(path)
+        .with_context(|| format!("open {:?}", path))?;
+    let mut hasher = tiny_keccak::Sha3::v256();
+    let mut buf = vec![0; 128 * 1024];
+    loop {
+        let n =
+            input.read(&mut buf).with_context(|| format!("read {:?}", path))?;
+        if n == 0 {
+            return Ok(finish(hasher));
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Returns the SHA3-256 hash of `bytes` (in hex)
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    let mut hasher = tiny_keccak::Sha3::v256();
+    hasher.update(bytes);
+    finish(hasher)
+}
+
+/// Returns the hash computed by `hasher` (in hex)
+fn finish(hasher: tiny_keccak::Sha3) -> String {
+    let mut digest = [0u8; 32];
+    hasher.finalize(&mut digest);
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns whether `hash` looks like one that `finish()` returns (which a
+/// manifest that's been damaged or tampered with may not have)
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Writes the manifest for a run's archive directory `target`
+pub fn write_manifest(
+    target: &Path,
+    entries: &[ManifestEntry],
+) -> Result<(), anyhow::Error> {
+    let path = target.join(MANIFEST_NAME);
+    let contents = serde_json::to_string_pretty(entries)?;
+    std::fs::write(&path, contents).with_context(|| format!("write {:?}", path))
+}
+
+/// Reads the manifest for a run's archive directory `target`, if it has one
+pub fn read_manifest(
+    target: &Path,
+) -> Result<Option<Vec<ManifestEntry>>, anyhow::Error> {
+    let path = target.join(MANIFEST_NAME);
+    match std::fs::read(&path) {
+        Ok(contents) => Ok(Some(
+            serde_json::from_slice(&contents)
+                .with_context(|| format!("parse {:?}", path))?,
+        )),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => {
+            Err(anyhow::Error::new(error).context(format!("read {:?}", path)))
+        }
+    }
+}
@@ -0,0 +1,417 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Latency histograms for each phase of a run
+//!
+//! We time each phase of each run: provisioning (cloning the snapshot and
+//! setting up the clone), the workload itself (and each phase of it that we
+//! can identify from its output; see `capture.rs`), and cleanup.  A slowdown
+//! in provisioning or cleanup points at the harness or the pool rather than
+//! the test suite.
+
+use anyhow::anyhow;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Phase covering cloning the snapshot and setting up the clone
+pub const PHASE_PROVISION: &str = "provision";
+/// Phase covering the whole workload
+pub const PHASE_WORKLOAD: &str = "workload";
+/// Phase covering saving or destroying the clone after the workload
+pub const PHASE_CLEANUP: &str = "cleanup";
+
+/// Upper bounds of histogram buckets, in seconds (there's an implicit last
+/// bucket for anything bigger)
+const BUCKET_BOUNDS_SECS: &[f64] = &[
+    0.1, 0.2, 0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0,
+    1000.0, 2000.0, 5000.0,
+];
+
+/// Don't enforce adaptive timeouts for a phase until we've seen it complete
+/// in this many passing runs
+const ADAPTIVE_MIN_SAMPLES: usize = 10;
+
+/// Only use this many of the most recent passing runs to compute adaptive
+/// timeouts, so that they follow changes in how fast the machine is
+const ADAPTIVE_MAX_SAMPLES: usize = 200;
+
+/// Never use an adaptive timeout shorter than this (short phases vary a lot
+/// relative to their duration, and a hang is rarely that short anyway)
+const ADAPTIVE_MIN_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Don't show more than this many workload phases in the printed report
+/// (they're all in the summary)
+const MAX_REPORTED_WORKLOAD_PHASES: usize = 10;
+
+/// Durations observed for each phase
+#[derive(Default)]
+pub struct PhaseTimings {
+    samples: BTreeMap<String, Vec<Duration>>,
+}
+
+/// Summarizes the durations observed for one phase
+#[derive(Serialize)]
+pub struct PhaseSummary {
+    pub count: usize,
+    pub min_secs: f64,
+    pub p50_secs: f64,
+    pub p90_secs: f64,
+    pub p99_secs: f64,
+    pub max_secs: f64,
+    pub histogram: Vec<HistogramBucket>,
+}
+
+/// Describes one bucket of a latency histogram
+#[derive(Serialize)]
+pub struct HistogramBucket {
+    /// upper bound of this bucket, in seconds (None for the last bucket)
+    pub le_secs: Option<f64>,
+    pub count: usize,
+}
+
+impl PhaseTimings {
+    pub fn record(&mut self, phase: &str, duration: Duration) {
+        self.samples.entry(phase.to_string()).or_default().push(duration);
+    }
+
+    pub fn summarize(&self) -> BTreeMap<String, PhaseSummary> {
+        self.samples
+            .iter()
+            .map(|(phase, samples)| (phase.clone(), summarize(samples)))
+            .collect()
+    }
+}
+
+/// Returns the `p`th percentile of `sorted`, which must be sorted and
+/// non-empty
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let i = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[i.saturating_sub(1).min(sorted.len() - 1)]
+}
+
+fn summarize(samples: &[Duration]) -> PhaseSummary {
+    let mut secs = samples.iter().map(|d| d.as_secs_f64()).collect::<Vec<_>>();
+    secs.sort_by(f64::total_cmp);
+    let percentile = |p: f64| percentile(&secs, p);
+
+    let mut histogram = BUCKET_BOUNDS_SECS
+        .iter()
+        .map(|bound| HistogramBucket { le_secs: Some(*bound), count: 0 })
+        .chain(std::iter::once(HistogramBucket { le_secs: None, count: 0 }))
+        .collect::<Vec<_>>();
+    for s in &secs {
+        let bucket = histogram
+            .iter_mut()
+            .find(|b| b.le_secs.is_none_or(|le| *s <= le))
+            .unwrap();
+        bucket.count += 1;
+    }
+
+    PhaseSummary {
+        count: secs.len(),
+        min_secs: secs[0],
+        p50_secs: percentile(50.0),
+        p90_secs: percentile(90.0),
+        p99_secs: percentile(99.0),
+        max_secs: secs[secs.len() - 1],
+        histogram,
+    }
+}
+
+/// Prints percentiles and a histogram for each phase
+pub fn print_report(summaries: &BTreeMap<String, PhaseSummary>) {
+    if summaries.is_empty() {
+        return;
+    }
+
+    // Show the harness's own phases first, then the workload, then the
+    // slowest of the workload's phases.
+    let mut workload_phases = summaries
+        .iter()
+        .filter(|(phase, _)| phase.starts_with("workload: "))
+        .collect::<Vec<_>>();
+    workload_phases.sort_by(|(_, a), (_, b)| b.p50_secs.total_cmp(&a.p50_secs));
+    let nomitted =
+        workload_phases.len().saturating_sub(MAX_REPORTED_WORKLOAD_PHASES);
+    workload_phases.truncate(MAX_REPORTED_WORKLOAD_PHASES);
+
+    text_println!("timing by phase (seconds):");
+    let phases = [PHASE_PROVISION, PHASE_WORKLOAD, PHASE_CLEANUP]
+        .iter()
+        .filter_map(|phase| summaries.get_key_value(*phase))
+        .chain(workload_phases);
+    for (phase, summary) in phases {
+        text_println!(
+            "    {}: n = {}, min {:.1}, p50 {:.1}, p90 {:.1}, p99 {:.1}, \
+             max {:.1}",
+            phase,
+            summary.count,
+            summary.min_secs,
+            summary.p50_secs,
+            summary.p90_secs,
+            summary.p99_secs,
+            summary.max_secs,
+        );
+        for bucket in summary.histogram.iter().filter(|b| b.count > 0) {
+            text_println!(
+                "        {:>8} {:>5}  {}",
+                match bucket.le_secs {
+                    Some(le) => format!("<= {}", le),
+                    None => String::from("more"),
+                },
+                bucket.count,
+                "#".repeat(bucket.count * 40 / summary.count),
+            );
+        }
+    }
+    if nomitted > 0 {
+        text_println!(
+            "    ({} more workload phase{} in session.json)",
+            nomitted,
+            if nomitted == 1 { "" } else { "s" }
+        );
+    }
+}
+
+/// Learns how long each phase of the workload takes in passing runs in order
+/// to pick timeouts for it (`--adaptive-timeout`)
+pub struct AdaptiveTimeouts {
+    /// timeouts are this multiple of the 99th percentile
+    factor: f64,
+    /// most recent durations observed for each phase, oldest first
+    samples: BTreeMap<String, Vec<Duration>>,
+}
+
+/// Describes the adaptive timeout for one phase
+#[derive(Clone)]
+pub struct AdaptiveLimit {
+    pub timeout: Duration,
+    pub p99: Duration,
+    /// number of passing runs that this is based on
+    pub nsamples: usize,
+}
+
+impl AdaptiveTimeouts {
+    pub fn new(factor: f64) -> AdaptiveTimeouts {
+        AdaptiveTimeouts { factor, samples: BTreeMap::new() }
+    }
+
+    /// Records how long `phase` took in a passing run
+    pub fn record(&mut self, phase: &str, duration: Duration) {
+        let samples = self.samples.entry(phase.to_string()).or_default();
+        samples.push(duration);
+        if samples.len() > ADAPTIVE_MAX_SAMPLES {
+            samples.drain(..samples.len() - ADAPTIVE_MAX_SAMPLES);
+        }
+    }
+
+    /// Returns the number of passing runs we've learned from
+    pub fn nruns(&self) -> usize {
+        self.samples.get(PHASE_WORKLOAD).map_or(0, |s| s.len())
+    }
+
+    /// Returns the current timeout for each phase that we've seen often
+    /// enough to pick one
+    pub fn limits(&self) -> BTreeMap<String, AdaptiveLimit> {
+        self.samples
+            .iter()
+            .filter(|(_, samples)| samples.len() >= ADAPTIVE_MIN_SAMPLES)
+            .map(|(phase, samples)| {
+                let mut secs =
+                    samples.iter().map(|d| d.as_secs_f64()).collect::<Vec<_>>();
+                secs.sort_by(f64::total_cmp);
+                let p99 = percentile(&secs, 99.0);
+                let timeout = Duration::from_secs_f64(p99 * self.factor)
+                    .max(ADAPTIVE_MIN_TIMEOUT);
+                let limit = AdaptiveLimit {
+                    timeout,
+                    p99: Duration::from_secs_f64(p99),
+                    nsamples: samples.len(),
+                };
+                (phase.clone(), limit)
+            })
+            .collect()
+    }
+}
+
+/// Parses the multiple of the 99th percentile used for `--adaptive-timeout`
+pub fn parse_timeout_factor(s: &str) -> Result<f64, anyhow::Error> {
+    let factor: f64 =
+        s.parse().map_err(|_| anyhow!("invalid timeout factor {:?}", s))?;
+    if !(factor >= 1.0 && factor.is_finite()) {
+        return Err(anyhow!("timeout factor must be at least 1"));
+    }
+    Ok(factor)
+}
+
+/// Parses a duration in seconds with an optional unit suffix (e.g., "90",
+/// "90s", "20m", "2h", "1d")
+pub fn parse_duration_secs(s: &str) -> Result<u64, anyhow::Error> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier = match c {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                _ => return Err(anyhow!("unknown suffix in {:?}", s)),
+            };
+            (&s[..i], multiplier)
+        }
+        _ => (s, 1),
+    };
+    let value: u64 =
+        digits.parse().map_err(|_| anyhow!("invalid duration {:?}", s))?;
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("duration too large: {:?}", s))
+}
+
+/// Parses a time given with `--stop-at`: either a time of day (e.g., "07:00"
+/// or "07:00:30"), meaning the next time after `now` that it's that time
+/// locally, or a complete RFC 3339 timestamp (which must be after `now`)
+pub fn parse_stop_at(
+    s: &str,
+    now: chrono::DateTime<chrono::Local>,
+) -> Result<chrono::DateTime<chrono::Local>, anyhow::Error> {
+    if let Ok(when) = chrono::DateTime::parse_from_rfc3339(s) {
+        let when = when.with_timezone(&chrono::Local);
+        if when <= now {
+            return Err(anyhow!("time {:?} is in the past", s));
+        }
+        return Ok(when);
+    }
+    let time = chrono::NaiveTime::parse_from_str(s, "%H:%M")
+        .or_else(|_| chrono::NaiveTime::parse_from_str(s, "%H:%M:%S"))
+        .map_err(|_| {
+            anyhow!("invalid time {:?} (expected HH:MM or RFC 3339)", s)
+        })?;
+    let mut date = now.date_naive();
+    loop {
+        // Around DST changes, a time of day may happen twice or not at all.
+        // We take the first, or skip to the next day.
+        if let Some(when) =
+            date.and_time(time).and_local_timezone(chrono::Local).earliest()
+        {
+            if when > now {
+                return Ok(when);
+            }
+        }
+        date = date
+            .succ_opt()
+            .ok_or_else(|| anyhow!("time too far in the future: {:?}", s))?;
+    }
+}
+
+/// Formats a duration for people to read (e.g., "20m", "1h30m", "45s")
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let rv = [(secs / 3600, "h"), (secs / 60 % 60, "m"), (secs % 60, "s")]
+        .iter()
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect::<String>();
+    if rv.is_empty() {
+        String::from("0s")
+    } else {
+        rv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_duration;
+    use super::parse_duration_secs;
+    use super::parse_stop_at;
+    use chrono::TimeZone;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_duration_secs() {
+        for (input, expected) in [
+            ("0", 0),
+            ("90", 90),
+            ("90s", 90),
+            ("20m", 20 * 60),
+            ("2h", 2 * 60 * 60),
+            ("1d", 24 * 60 * 60),
+        ] {
+            assert_eq!(parse_duration_secs(input).unwrap(), expected);
+        }
+
+        for (input, expected) in [
+            ("", "invalid duration \"\""),
+            ("s", "invalid duration \"s\""),
+            ("-5", "invalid duration \"-5\""),
+            ("1.5h", "invalid duration \"1.5h\""),
+            ("1h30m", "invalid duration \"1h30m\""),
+            ("5 m", "invalid duration \"5 m\""),
+            ("5x", "unknown suffix in \"5x\""),
+            ("5M", "unknown suffix in \"5M\""),
+            (
+                "18446744073709551615m",
+                "duration too large: \"18446744073709551615m\"",
+            ),
+        ] {
+            let error = parse_duration_secs(input).unwrap_err();
+            assert_eq!(format!("{:#}", error), expected);
+        }
+
+        // What we print can be parsed back.
+        for secs in [0, 59, 60, 3600, 7200] {
+            let formatted = format_duration(Duration::from_secs(secs));
+            assert_eq!(parse_duration_secs(&formatted).unwrap(), secs);
+        }
+    }
+
+    #[test]
+    fn test_parse_stop_at() {
+        let now =
+            chrono::Local.with_ymd_and_hms(2026, 1, 15, 12, 0, 0).unwrap();
+        let at = |day, hour, min, sec| {
+            chrono::Local
+                .with_ymd_and_hms(2026, 1, day, hour, min, sec)
+                .unwrap()
+        };
+        for (input, expected) in [
+            // later today
+            ("13:00", at(15, 13, 0, 0)),
+            ("12:00:30", at(15, 12, 0, 30)),
+            // earlier today (or right now), so tomorrow
+            ("07:00", at(16, 7, 0, 0)),
+            ("12:00", at(16, 12, 0, 0)),
+            ("00:00:00", at(16, 0, 0, 0)),
+        ] {
+            assert_eq!(
+                parse_stop_at(input, now).unwrap(),
+                expected,
+                "{}",
+                input
+            );
+        }
+
+        let future = now + chrono::Duration::hours(36);
+        assert_eq!(parse_stop_at(&future.to_rfc3339(), now).unwrap(), future);
+        let past = now - chrono::Duration::seconds(1);
+        let error = parse_stop_at(&past.to_rfc3339(), now).unwrap_err();
+        assert!(format!("{:#}", error).ends_with("is in the past"));
+        let error = parse_stop_at(&now.to_rfc3339(), now).unwrap_err();
+        assert!(format!("{:#}", error).ends_with("is in the past"));
+
+        for input in
+            ["", "7", "7am", "25:00", "12:60", "2026-01-16", "tomorrow"]
+        {
+            let error = parse_stop_at(input, now).unwrap_err();
+            assert_eq!(
+                format!("{:#}", error),
+                format!(
+                    "invalid time {:?} (expected HH:MM or RFC 3339)",
+                    input
+                )
+            );
+        }
+    }
+}
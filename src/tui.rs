@@ -0,0 +1,255 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Live dashboard for `--tui`
+//!
+//! With many threads, the interleaved messages about each run are hard to
+//! follow.  With `--tui` (when stdout is a terminal), we instead take over the
+//! terminal (using its alternate screen, like a pager does) and redraw a table
+//! with one row per thread twice a second: which run it's on, what phase that
+//! run is in, how long it's been in it, and how many of the thread's runs have
+//! passed and failed.  The most recent messages that would have been printed
+//! (see `console.rs`) are shown below the table.  When the threads finish, the
+//! terminal is restored and the usual final report is printed.
+//!
+//! There's one dashboard per process, so the functions that update it can be
+//! called from anywhere.  They do nothing when there's no dashboard.
+
+use crate::control::Phase;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How often the dashboard is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Number of recent messages that we keep around to show
+const MAX_MESSAGES: usize = 200;
+
+/// Switches to the alternate screen and hides the cursor
+const ENTER: &str = "\x1b[?1049h\x1b[?25l";
+
+/// Shows the cursor and switches back to the normal screen
+pub const RESTORE: &str = "\x1b[?25h\x1b[?1049l";
+
+/// Whether the terminal is currently showing the dashboard
+///
+/// This is separate from `DASHBOARD` so that it can be checked from a signal
+/// handler (see `shutdown.rs`).
+pub static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// State shown on the dashboard, if there is one
+static DASHBOARD: Mutex<Option<State>> = Mutex::new(None);
+
+struct ThreadStatus {
+    run_id: Option<usize>,
+    attempt: usize,
+    phase: Phase,
+    phase_start: Instant,
+    npassed: usize,
+    nfailed: usize,
+}
+
+struct State {
+    /// first line of the dashboard
+    title: String,
+    session_start: Instant,
+    threads: Vec<ThreadStatus>,
+    messages: VecDeque<String>,
+}
+
+/// Shows the dashboard until this is dropped
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    redraw: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(redraw) = self.redraw.take() {
+            let _ = redraw.join();
+        }
+        *DASHBOARD.lock().unwrap() = None;
+        let mut stdout = std::io::stdout().lock();
+        ACTIVE.store(false, Ordering::SeqCst);
+        let _ = stdout.write_all(RESTORE.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+/// Takes over the terminal to show the dashboard for a session with
+/// `nthreads` threads
+pub fn start(title: String, nthreads: u8) -> Dashboard {
+    let now = Instant::now();
+    *DASHBOARD.lock().unwrap() = Some(State {
+        title,
+        session_start: now,
+        threads: (0..nthreads)
+            .map(|_| ThreadStatus {
+                run_id: None,
+                attempt: 0,
+                phase: Phase::Idle,
+                phase_start: now,
+                npassed: 0,
+                nfailed: 0,
+            })
+            .collect(),
+        messages: VecDeque::new(),
+    });
+    {
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(ENTER.as_bytes());
+        ACTIVE.store(true, Ordering::SeqCst);
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let redraw = {
+        let stop = Arc::clone(&stop);
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                draw();
+                std::thread::sleep(REDRAW_INTERVAL);
+            }
+        })
+    };
+    Dashboard { stop, redraw: Some(redraw) }
+}
+
+/// Applies `update` to the status of thread `thread`, if there's a dashboard
+fn update_thread(thread: u8, update: impl FnOnce(&mut ThreadStatus)) {
+    if let Some(state) = DASHBOARD.lock().unwrap().as_mut() {
+        if let Some(status) = state.threads.get_mut(usize::from(thread)) {
+            update(status);
+        }
+    }
+}
+
+/// Records that thread `thread` started attempt `attempt` (run `run_id`)
+pub fn run_started(thread: u8, attempt: usize, run_id: usize) {
+    update_thread(thread, |status| {
+        status.run_id = Some(run_id);
+        status.attempt = attempt;
+        status.phase = Phase::Clone;
+        status.phase_start = Instant::now();
+    });
+}
+
+/// Records that thread `thread`'s current run has moved on to `phase`
+pub fn set_phase(thread: u8, phase: Phase) {
+    update_thread(thread, |status| {
+        status.phase = phase;
+        status.phase_start = Instant::now();
+    });
+}
+
+/// Records that thread `thread`'s current run finished (and whether it passed
+/// or failed; runs that did neither, like interrupted ones, aren't counted)
+pub fn run_finished(thread: u8, passed: bool, failed: bool) {
+    update_thread(thread, |status| {
+        status.npassed += usize::from(passed);
+        status.nfailed += usize::from(failed);
+        status.phase = Phase::Idle;
+        status.phase_start = Instant::now();
+    });
+}
+
+/// Keeps `text` (lines that would otherwise have been printed) to show on the
+/// dashboard, returning whether there is one
+pub fn capture(text: &str) -> bool {
+    let mut dashboard = DASHBOARD.lock().unwrap();
+    let Some(state) = dashboard.as_mut() else {
+        return false;
+    };
+    for line in text.lines() {
+        if state.messages.len() == MAX_MESSAGES {
+            state.messages.pop_front();
+        }
+        state.messages.push_back(line.to_string());
+    }
+    true
+}
+
+/// Redraws the whole dashboard
+fn draw() {
+    let (width, height) = terminal_size();
+    let mut lines = Vec::new();
+    {
+        let dashboard = DASHBOARD.lock().unwrap();
+        let Some(state) = dashboard.as_ref() else {
+            return;
+        };
+        let npassed: usize = state.threads.iter().map(|t| t.npassed).sum();
+        let nfailed: usize = state.threads.iter().map(|t| t.nfailed).sum();
+        lines.push(state.title.clone());
+        lines.push(format!(
+            "elapsed {}, {} passed, {} failed",
+            crate::timing::format_duration(state.session_start.elapsed()),
+            npassed,
+            nfailed
+        ));
+        lines.push(String::new());
+        lines.push(format!(
+            "{:>6}  {:>7}  {:>7}  {:<7}  {:>8}  {:>6}  {:>6}",
+            "THREAD", "RUN", "ATTEMPT", "PHASE", "ELAPSED", "PASSED", "FAILED"
+        ));
+        for (i, status) in state.threads.iter().enumerate() {
+            lines.push(format!(
+                "{:>6}  {:>7}  {:>7}  {:<7}  {:>8}  {:>6}  {:>6}",
+                i,
+                status.run_id.map(|r| r.to_string()).unwrap_or_default(),
+                status.attempt,
+                status.phase.as_str(),
+                crate::timing::format_duration(status.phase_start.elapsed()),
+                status.npassed,
+                status.nfailed,
+            ));
+        }
+        lines.push(String::new());
+        let room = height.saturating_sub(lines.len() + 1);
+        if room > 0 {
+            lines.push(String::from("recent messages:"));
+            let skip = state.messages.len().saturating_sub(room - 1);
+            lines.extend(state.messages.iter().skip(skip).cloned());
+        }
+    }
+
+    // Clear the screen and draw everything in one write to avoid flicker.
+    let mut screen = String::from("\x1b[H\x1b[2J");
+    for line in lines.iter().take(height) {
+        screen.extend(line.chars().take(width));
+        screen.push('\n');
+    }
+    let mut stdout = std::io::stdout().lock();
+    if ACTIVE.load(Ordering::SeqCst) {
+        let _ = stdout.write_all(screen.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+/// Returns the width and height of the terminal on stdout
+#[cfg(unix)]
+fn terminal_size() -> (usize, usize) {
+    // Safety: TIOCGWINSZ fills in the winsize struct that we pass.
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let rv = unsafe {
+        libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size)
+    };
+    if rv != 0 || size.ws_col == 0 || size.ws_row == 0 {
+        return (80, 24);
+    }
+    (usize::from(size.ws_col), usize::from(size.ws_row))
+}
+
+/// Returns the width and height of the terminal on stdout
+#[cfg(not(unix))]
+fn terminal_size() -> (usize, usize) {
+    (80, 24)
+}
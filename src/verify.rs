@@ -0,0 +1,206 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! `gocrash verify-artifacts`: check an archive directory for damage
+//!
+//! Archived runs may sit untouched for months before someone needs them for a
+//! bug report, and by then a disk error or an overeager cleanup script may
+//! have damaged them.  This command checks the whole archive directory (see
+//! `archive.rs` and `store.rs`):
+//!
+//! * every blob in the store must still hash to its name
+//! * every file listed in a run's manifest must still exist, its blob must
+//!   exist, and its contents must match the recorded hash
+//!
+//! Runs archived before manifests were recorded can't be checked, so they're
+//! counted and reported, but not treated as problems.
+
+use crate::store;
+use anyhow::bail;
+use anyhow::Context;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Check archived artifacts for corruption or missing files
+#[derive(clap::Args)]
+pub struct VerifyArtifactsArgs {
+    /// archive directory (see --archive-dir)
+    archive_dir: PathBuf,
+}
+
+/// Counts what was checked
+#[derive(Default)]
+struct Verified {
+    nblobs: usize,
+    nruns: usize,
+    nfiles: usize,
+    /// runs without a manifest
+    nunchecked: usize,
+    nproblems: usize,
+}
+
+impl Verified {
+    fn problem(&mut self, message: String) {
+        println!("PROBLEM: {}", message);
+        self.nproblems += 1;
+    }
+}
+
+/// Runs the `gocrash verify-artifacts` command
+pub fn verify_artifacts(
+    args: &VerifyArtifactsArgs,
+) -> Result<(), anyhow::Error> {
+    let archive_dir = &args.archive_dir;
+    if !archive_dir.is_dir() {
+        bail!("{:?} is not a directory", archive_dir);
+    }
+    let store = store::Store::new(archive_dir);
+    let mut verified = Verified::default();
+
+    for (hash, path) in store.blobs()? {
+        verified.nblobs += 1;
+        match store::hash_file(&path) {
+            Ok(actual) if actual == hash => (),
+            Ok(actual) => verified.problem(format!(
+                "blob {} is corrupt (its contents hash to {})",
+                hash, actual
+            )),
+            Err(error) => verified.problem(format!("{:#}", error)),
+        }
+    }
+
+    // The archive directory has one directory per session, each with one
+    // directory per run.
+    for session_dir in subdirectories(archive_dir)? {
+        if session_dir.file_name() == Some(store::STORE_DIR.as_ref()) {
+            continue;
+        }
+        for run_dir in subdirectories(&session_dir)? {
+            verified.nruns += 1;
+            verify_run(&store, &run_dir, &mut verified)?;
+        }
+    }
+
+    println!(
+        "checked {} blob{} and {} file{} in {} run{}{}",
+        verified.nblobs,
+        if verified.nblobs == 1 { "" } else { "s" },
+        verified.nfiles,
+        if verified.nfiles == 1 { "" } else { "s" },
+        verified.nruns,
+        if verified.nruns == 1 { "" } else { "s" },
+        if verified.nunchecked == 0 {
+            String::new()
+        } else {
+            format!(
+                " ({} run{} without a manifest could not be checked)",
+                verified.nunchecked,
+                if verified.nunchecked == 1 { "" } else { "s" }
+            )
+        }
+    );
+    if verified.nproblems > 0 {
+        bail!(
+            "found {} problem{}",
+            verified.nproblems,
+            if verified.nproblems == 1 { "" } else { "s" }
+        );
+    }
+    println!("no problems found");
+    Ok(())
+}
+
+/// Checks the files in one run's archive directory against its manifest
+fn verify_run(
+    store: &store::Store,
+    run_dir: &Path,
+    verified: &mut Verified,
+) -> Result<(), anyhow::Error> {
+    let manifest = match store::read_manifest(run_dir) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            verified.nunchecked += 1;
+            return Ok(());
+        }
+        Err(error) => {
+            verified.problem(format!("{:#}", error));
+            return Ok(());
+        }
+    };
+
+    for entry in manifest {
+        verified.nfiles += 1;
+        let path = run_dir.join(&entry.path);
+        if !store::is_valid_hash(&entry.sha3_256) {
+            verified.problem(format!(
+                "{}: manifest has invalid hash {:?}",
+                path.display(),
+                entry.sha3_256
+            ));
+            continue;
+        }
+        let blob = store.blob_path(&entry.sha3_256);
+        if !blob.exists() {
+            verified.problem(format!(
+                "{}: blob {} is missing",
+                path.display(),
+                entry.sha3_256
+            ));
+        }
+        if !path.exists() {
+            verified.problem(format!("{}: file is missing", path.display()));
+            continue;
+        }
+        // Files that are links to their blob were checked with the blob.
+        if same_file(&path, &blob) {
+            continue;
+        }
+        match store::hash_file(&path) {
+            Ok(actual) if actual == entry.sha3_256 => (),
+            Ok(actual) => verified.problem(format!(
+                "{}: contents hash to {}, but {} was recorded",
+                path.display(),
+                actual,
+                entry.sha3_256
+            )),
+            Err(error) => verified.problem(format!("{:#}", error)),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the subdirectories of `dir`, sorted by name
+fn subdirectories(dir: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut subdirs = Vec::new();
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("read directory {:?}", dir))?;
+    for entry in entries {
+        let entry =
+            entry.with_context(|| format!("read directory {:?}", dir))?;
+        if entry.path().is_dir() {
+            subdirs.push(entry.path());
+        }
+    }
+    subdirs.sort();
+    Ok(subdirs)
+}
+
+/// Returns whether `a` and `b` are the same file (e.g., hard links to each
+/// other)
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Returns whether `a` and `b` are the same file (which we can't tell on this
+/// system, so we always check both)
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
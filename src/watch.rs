@@ -0,0 +1,147 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! `gocrash watch`: run a bounded session against each new snapshot
+//!
+//! This is intended for snapshots that get created automatically (e.g., of
+//! nightly builds), so that new flakiness shows up without anybody having to
+//! go looking for it.
+
+use crate::backend::Capability;
+use crate::events;
+use crate::gocrash;
+use crate::zfs;
+use crate::CancellationToken;
+use crate::RunArgs;
+use anyhow::anyhow;
+use std::collections::BTreeSet;
+use std::process::Command;
+
+/// Set on each snapshot tested by `gocrash watch`: whether the session passed
+pub const WATCH_RESULT_PROPERTY: &str = "gocrash:watch-result";
+
+/// Run a bounded session against each new snapshot matching a pattern
+#[derive(clap::Args)]
+pub struct WatchArgs {
+    /// how often to check for new snapshots, in seconds
+    #[arg(long, default_value_t = 60)]
+    poll_interval: u64,
+
+    #[command(flatten)]
+    pub(crate) run: RunArgs,
+
+    /// snapshots to watch for, where "*" matches any sequence of characters
+    /// (e.g., "rpool/gotest@nightly-*")
+    pattern: String,
+}
+
+/// Runs the `gocrash watch` command, until `cancel` is cancelled
+pub fn watch(
+    args: &WatchArgs,
+    cancel: &CancellationToken,
+) -> Result<(), anyhow::Error> {
+    args.run.backend.require(Capability::Snapshots, "gocrash watch")?;
+    if args.run.stop_after.is_none() {
+        return Err(anyhow!(
+            "watch mode requires --stop-after so that each session ends"
+        ));
+    }
+
+    crate::console::set_events_on_stdout(
+        args.run.format == events::OutputFormat::Json,
+    );
+
+    // Snapshots that exist when we start are not new.
+    let mut seen =
+        matching_snapshots(&args.pattern)?.into_iter().collect::<BTreeSet<_>>();
+    text_println!(
+        "watching for new snapshots matching {:?} (ignoring {} existing)",
+        args.pattern,
+        seen.len()
+    );
+
+    loop {
+        for snapshot in matching_snapshots(&args.pattern)? {
+            if !seen.insert(snapshot.clone()) {
+                continue;
+            }
+
+            text_println!(
+                "\n{}: new snapshot {}",
+                chrono::Utc::now(),
+                snapshot
+            );
+            let result = gocrash(&args.run, &snapshot, cancel);
+            let summary = match &result {
+                Ok(_) => "passed",
+                Err(_) => "failed",
+            };
+            text_println!(
+                "{}: snapshot {}: {}{}",
+                chrono::Utc::now(),
+                snapshot,
+                summary,
+                match &result {
+                    Ok(_) => String::new(),
+                    Err(error) => format!(" ({:#})", error),
+                }
+            );
+
+            if let Err(error) = zfs::run(
+                Command::new("pfexec")
+                    .arg("zfs")
+                    .arg("set")
+                    .arg(format!("{}={}", WATCH_RESULT_PROPERTY, summary))
+                    .arg(&snapshot),
+            ) {
+                eprintln!(
+                    "warning: recording result on {:?}: {:#}",
+                    snapshot, error
+                );
+            }
+            cancel.check()?;
+        }
+
+        cancel.sleep(std::time::Duration::from_secs(args.poll_interval))?;
+    }
+}
+
+/// Returns the names of snapshots matching `pattern`, oldest first
+fn matching_snapshots(pattern: &str) -> Result<Vec<String>, anyhow::Error> {
+    let mut cmd = Command::new("zfs");
+    cmd.arg("list").arg("-H").arg("-tsnapshot").arg("-oname").arg("-screation");
+
+    // If the dataset part of the pattern is literal, only look at that
+    // dataset's snapshots.
+    if let Some((dataset, _)) = pattern.split_once('@') {
+        if !dataset.contains('*') {
+            cmd.arg("-d1").arg(dataset);
+        }
+    }
+
+    let output = zfs::run(&mut cmd)?;
+    Ok(output
+        .lines()
+        .filter(|name| glob_match(pattern, name))
+        .map(String::from)
+        .collect())
+}
+
+/// Returns whether `s` matches `pattern`, in which "*" matches any sequence of
+/// characters
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == s,
+        Some((prefix, rest)) => {
+            let Some(s) = s.strip_prefix(prefix) else {
+                return false;
+            };
+            s.char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(s.len()))
+                .any(|i| glob_match(rest, &s[i..]))
+        }
+    }
+}
@@ -0,0 +1,1098 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! `gocrash serve`: browse the history database in a web browser
+//!
+//! This lets people without a shell on the soak machine see what it's found.
+//! The pages are plain HTML (no JavaScript) built from the history database
+//! (see `history.rs`) on each request:
+//!
+//! * `/`: every session, newest first, with how many of its runs failed
+//! * `/session`: one session's parameters, its failures grouped by signature,
+//!   and its runs, which can be filtered by result, thread, and signature
+//! * `/run`: one run's details, metadata, and phase timings, the end of its
+//!   output, and links to download its files
+//! * `/file`: one of a run's files
+//!
+//! A run's files come from its archive directory (see `archive.rs`), if its
+//! session used `--archive-dir`, and from its dataset, if that's still around
+//! on this host.  Only the files listed on the run's page can be downloaded.
+
+use crate::backend;
+use crate::fingerprint;
+use crate::history;
+use crate::http;
+use crate::http::HttpError;
+use crate::http::Request;
+use crate::http::Response;
+use crate::store;
+use crate::timing;
+use anyhow::Context;
+use rusqlite::params;
+use rusqlite::types::Value;
+use std::io::Read;
+use std::io::Seek;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Browse the history database in a web browser
+#[derive(clap::Args)]
+pub struct ServeArgs {
+    #[command(flatten)]
+    listen: http::ListenArgs,
+
+    /// history database (see --history-db)
+    history_db: PathBuf,
+}
+
+/// Runs with these results count as failing
+const FAILING_RESULTS: &[&str] = &["failed", "hung", "runaway", "suspect"];
+
+/// Logs (at the top of a run's clone) whose ends are shown on the run's page
+const LOGS: &[&str] = &["test_run_stdout", "test_run_stderr"];
+
+/// Number of lines shown from the end of each log
+const EXCERPT_LINES: usize = 40;
+
+/// Read at most this much from the end of each log to find those lines
+const EXCERPT_MAX_BYTES: u64 = 64 * 1024;
+
+const STYLE: &str = "
+    body { font-family: sans-serif; margin: 1em 2em; }
+    table { border-collapse: collapse; margin-bottom: 1em; }
+    th, td { border: 1px solid #ccc; padding: 0.2em 0.5em; text-align: left;
+             vertical-align: top; }
+    th { background: #eee; }
+    pre { background: #f6f6f6; padding: 0.5em; overflow-x: auto; }
+    .failed, .hung, .runaway, .suspect, .error { color: #b00; }
+    .passed { color: #070; }
+";
+
+/// Runs the `gocrash serve` command
+pub fn serve(args: &ServeArgs) -> Result<(), anyhow::Error> {
+    // Make sure the database exists and is up to date, since we'll only be
+    // reading it from here on.
+    drop(history::open_existing(&args.history_db)?);
+    let site =
+        Site { history_db: &args.history_db, host: fingerprint::hostname()? };
+    let server = http::Server::bind(&args.listen)?;
+    println!("serving {} at {}", args.history_db.display(), server.describe());
+    server.serve(|request| site.handle(request))
+}
+
+/// The pages of the web UI
+struct Site<'a> {
+    history_db: &'a Path,
+    /// name of this host (only runs from this host can have datasets here)
+    host: String,
+}
+
+/// Identifies one run in the database
+struct RunKey<'a> {
+    host: &'a str,
+    session: &'a str,
+    run_id: usize,
+}
+
+impl Site<'_> {
+    fn handle(&self, request: &Request) -> Response {
+        let result = match request.path.as_str() {
+            "/" => self.sessions_page(),
+            "/session" => self.session_page(request),
+            "/run" => self.run_page(request),
+            "/file" => self.file(request),
+            _ => Err(HttpError::not_found(format!(
+                "no such page: {:?}",
+                request.path
+            ))),
+        };
+        result.unwrap_or_else(Response::from)
+    }
+
+    /// Lists every session
+    fn sessions_page(&self) -> Result<Response, HttpError> {
+        let conn = history::open_read_only(self.history_db)?;
+        let sessions = load_sessions(&conn)?;
+        let rows = sessions
+            .iter()
+            .map(|s| {
+                vec![
+                    link(
+                        &url(
+                            "/session",
+                            &[("host", &s.host), ("session", &s.session)],
+                        ),
+                        &s.session,
+                    ),
+                    escape(&s.host),
+                    escape(&s.snapshot),
+                    format_time(&s.start_time),
+                    s.end_time.as_deref().map(format_time).unwrap_or_default(),
+                    result_cell(s.outcome.as_deref().unwrap_or("")),
+                    s.nruns.to_string(),
+                    s.nfailed.to_string(),
+                ]
+            })
+            .collect();
+        Ok(page(
+            "gocrash sessions",
+            &table(
+                &[
+                    "session", "host", "snapshot", "started", "ended",
+                    "outcome", "runs", "failed",
+                ],
+                rows,
+            ),
+        ))
+    }
+
+    /// Describes one session and lists its runs (see `RunFilter`)
+    fn session_page(&self, request: &Request) -> Result<Response, HttpError> {
+        let host = param(request, "host")?;
+        let session = param(request, "session")?;
+        let filter = RunFilter::from_request(request)?;
+        let conn = history::open_read_only(self.history_db)?;
+        let record = load_session(&conn, host, session)?;
+        let results = load_results(&conn, host, session)?;
+        let signatures = load_signatures(&conn, host, session)?;
+        let runs = load_runs(&conn, host, session, &filter)?;
+
+        let session_url = |extra: &[(&str, &str)]| -> String {
+            let mut params = vec![("host", host), ("session", session)];
+            params.extend_from_slice(extra);
+            url("/session", &params)
+        };
+        let mut body = table(
+            &["host", "snapshot", "started", "ended", "outcome"],
+            vec![vec![
+                escape(host),
+                escape(&record.snapshot),
+                format_time(&record.start_time),
+                record.end_time.as_deref().map(format_time).unwrap_or_default(),
+                result_cell(record.outcome.as_deref().unwrap_or("")),
+            ]],
+        );
+        body.push_str(&format!(
+            "<details><summary>parameters</summary><pre>{}</pre></details>",
+            escape(&pretty_json(&record.parameters))
+        ));
+
+        body.push_str("<h2>results</h2>");
+        body.push_str(&table(
+            &["result", "runs"],
+            results
+                .iter()
+                .map(|(result, count)| {
+                    vec![
+                        link(&session_url(&[("result", result)]), result),
+                        count.to_string(),
+                    ]
+                })
+                .collect(),
+        ));
+
+        if !signatures.is_empty() {
+            body.push_str("<h2>failures by signature</h2>");
+            body.push_str(&table(
+                &["signature", "runs", "first run"],
+                signatures
+                    .iter()
+                    .map(|(signature, count, first)| {
+                        vec![
+                            link(
+                                &session_url(&[("signature", signature)]),
+                                signature,
+                            ),
+                            count.to_string(),
+                            link(
+                                &run_url(&RunKey {
+                                    host,
+                                    session,
+                                    run_id: *first,
+                                }),
+                                &first.to_string(),
+                            ),
+                        ]
+                    })
+                    .collect(),
+            ));
+        }
+
+        body.push_str("<h2>runs</h2>");
+        body.push_str(&filter.form(host, session, &results));
+        body.push_str(&table(
+            &[
+                "run",
+                "thread",
+                "attempt",
+                "started",
+                "took",
+                "result",
+                "signature",
+            ],
+            runs.iter()
+                .map(|run| {
+                    vec![
+                        link(
+                            &run_url(&RunKey {
+                                host,
+                                session,
+                                run_id: run.run_id,
+                            }),
+                            &run.run_id.to_string(),
+                        ),
+                        run.thread.to_string(),
+                        run.attempt.to_string(),
+                        format_time(&run.start_time),
+                        format_elapsed(&run.start_time, &run.end_time),
+                        result_cell(&run.result),
+                        escape(run.signature.as_deref().unwrap_or("")),
+                    ]
+                })
+                .collect(),
+        ));
+        Ok(page(&format!("session {}", session), &body))
+    }
+
+    /// Describes one run, shows the end of its output, and lists its files
+    fn run_page(&self, request: &Request) -> Result<Response, HttpError> {
+        let key = run_key(request)?;
+        let conn = history::open_read_only(self.history_db)?;
+        let record = load_session(&conn, key.host, key.session)?;
+        let run = load_run(&conn, &key)?;
+        let metadata = load_run_metadata(&conn, &key)?;
+        let phases = load_phases(&conn, &key)?;
+        // For failed runs, compare against the passing run nominated as the
+        // control (see `pairing.rs`).
+        let control = metadata
+            .iter()
+            .find(|(name, _)| name == "control-run")
+            .and_then(|(_, value)| value.parse().ok())
+            .map(|run_id| RunKey {
+                host: key.host,
+                session: key.session,
+                run_id,
+            });
+        let control_phases = match &control {
+            Some(control) => load_phases(&conn, control)?,
+            None => Vec::new(),
+        };
+        let locations = self.run_locations(&key, &record.parameters, &run);
+
+        let mut body = format!(
+            "<p>{}</p>",
+            link(
+                &url(
+                    "/session",
+                    &[("host", key.host), ("session", key.session)]
+                ),
+                &format!("session {}", key.session),
+            )
+        );
+        body.push_str(&table(
+            &[
+                "thread",
+                "attempt",
+                "dataset",
+                "started",
+                "took",
+                "result",
+                "signature",
+            ],
+            vec![vec![
+                run.thread.to_string(),
+                run.attempt.to_string(),
+                escape(&run.dataset),
+                format_time(&run.start_time),
+                format_elapsed(&run.start_time, &run.end_time),
+                result_cell(&run.result),
+                escape(run.signature.as_deref().unwrap_or("")),
+            ]],
+        ));
+
+        if !metadata.is_empty() {
+            body.push_str("<h2>metadata</h2>");
+            body.push_str(&table(
+                &["name", "value"],
+                metadata
+                    .iter()
+                    .map(|(name, value)| match &control {
+                        Some(control) if name == "control-run" => vec![
+                            escape(name),
+                            link(
+                                &run_url(control),
+                                &format!("run {}", control.run_id),
+                            ),
+                        ],
+                        _ => vec![escape(name), escape(value)],
+                    })
+                    .collect(),
+            ));
+        }
+
+        if !phases.is_empty() && !control_phases.is_empty() {
+            body.push_str("<h2>phases (compared with the control run)</h2>");
+            body.push_str(&table(
+                &["phase", "took", "control took"],
+                phases
+                    .iter()
+                    .map(|(phase, secs)| {
+                        vec![
+                            escape(phase),
+                            format!("{:.1}s", secs),
+                            control_phases
+                                .iter()
+                                .find(|(p, _)| p == phase)
+                                .map(|(_, secs)| format!("{:.1}s", secs))
+                                .unwrap_or_default(),
+                        ]
+                    })
+                    .collect(),
+            ));
+        } else if !phases.is_empty() {
+            body.push_str("<h2>phases</h2>");
+            body.push_str(&table(
+                &["phase", "took"],
+                phases
+                    .iter()
+                    .map(|(phase, secs)| {
+                        vec![escape(phase), format!("{:.1}s", secs)]
+                    })
+                    .collect(),
+            ));
+        }
+
+        for log in LOGS {
+            let Some(location) = locations.iter().find(|l| l.has_file(log))
+            else {
+                continue;
+            };
+            let excerpt = match tail(&location.dir.join(log), EXCERPT_LINES) {
+                Ok(excerpt) => excerpt,
+                Err(error) => format!("error: {:#}", error),
+            };
+            body.push_str(&format!(
+                "<h2>{} (last {} lines, from {})</h2><pre>{}</pre>",
+                log,
+                EXCERPT_LINES,
+                location.kind,
+                escape(&excerpt)
+            ));
+        }
+
+        body.push_str("<h2>files</h2>");
+        if locations.is_empty() {
+            body.push_str(
+                "<p>This run's files are not available here (its dataset is \
+                 gone or on another host, and it was not archived).</p>",
+            );
+        }
+        for location in &locations {
+            body.push_str(&format!(
+                "<h3>{} ({})</h3>",
+                location.kind,
+                escape(&location.dir.display().to_string())
+            ));
+            body.push_str(&table(
+                &["file", "bytes"],
+                location
+                    .files
+                    .iter()
+                    .map(|(path, bytes)| {
+                        vec![
+                            link(&file_url(&key, location.kind, path), path),
+                            bytes.to_string(),
+                        ]
+                    })
+                    .collect(),
+            ));
+        }
+
+        Ok(page(&format!("run {} of {}", key.run_id, key.session), &body))
+    }
+
+    /// Sends one of the files listed on a run's page
+    fn file(&self, request: &Request) -> Result<Response, HttpError> {
+        let key = run_key(request)?;
+        let kind = param(request, "from")?;
+        let path = param(request, "path")?;
+        let conn = history::open_read_only(self.history_db)?;
+        let record = load_session(&conn, key.host, key.session)?;
+        let run = load_run(&conn, &key)?;
+        let locations = self.run_locations(&key, &record.parameters, &run);
+        let location = locations
+            .iter()
+            .find(|l| l.kind == kind && l.has_file(path))
+            .ok_or_else(|| {
+                HttpError::not_found(format!(
+                    "run {} has no file {:?} in its {}",
+                    key.run_id, path, kind
+                ))
+            })?;
+
+        let full_path = location.dir.join(path);
+        let mut file = std::fs::File::open(&full_path)
+            .with_context(|| format!("open {:?}", full_path))?;
+        let length = file
+            .metadata()
+            .with_context(|| format!("stat {:?}", full_path))?
+            .len();
+        let is_text = looks_like_text(&mut file)
+            .with_context(|| format!("read {:?}", full_path))?;
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let response = if is_text {
+            Response::new(
+                200,
+                "text/plain; charset=utf-8",
+                http::Body::File(file, length),
+            )
+        } else {
+            Response::new(
+                200,
+                "application/octet-stream",
+                http::Body::File(file, length),
+            )
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", name.replace('"', "")),
+            )
+        };
+        Ok(response)
+    }
+
+    /// Returns the places where a run's files can be found
+    fn run_locations(
+        &self,
+        key: &RunKey,
+        parameters: &serde_json::Value,
+        run: &RunRow,
+    ) -> Vec<Location> {
+        let mut locations = Vec::new();
+
+        if let Some(archive_dir) =
+            parameters.get("archive-dir").and_then(|v| v.as_str())
+        {
+            let run_key = run.dataset.rsplit('/').next().unwrap();
+            let dir = Path::new(archive_dir).join(key.session).join(run_key);
+            if dir.is_dir() {
+                match list_archived_files(&dir) {
+                    Ok(files) => {
+                        locations.push(Location { kind: "archive", dir, files })
+                    }
+                    Err(error) => eprintln!("warning: {:#}", error),
+                }
+            }
+        }
+
+        if key.host == self.host {
+            let backend = parameters
+                .get("backend")
+                .and_then(|v| {
+                    serde_json::from_value::<backend::Backend>(v.clone()).ok()
+                })
+                .unwrap_or_default();
+            let mountpoint = backend
+                .clones()
+                .mountpoint(&run.dataset)
+                .ok()
+                .filter(|m| m.is_dir());
+            if let Some(dir) = mountpoint {
+                match list_dataset_files(&dir) {
+                    Ok(files) => {
+                        locations.push(Location { kind: "dataset", dir, files })
+                    }
+                    Err(error) => eprintln!("warning: {:#}", error),
+                }
+            }
+        }
+
+        locations
+    }
+}
+
+/// One place where a run's files can be found
+struct Location {
+    /// "archive" or "dataset"
+    kind: &'static str,
+    dir: PathBuf,
+    /// paths (relative to `dir`) and sizes of the files that can be downloaded
+    files: Vec<(String, u64)>,
+}
+
+impl Location {
+    fn has_file(&self, path: &str) -> bool {
+        self.files.iter().any(|(p, _)| p == path)
+    }
+}
+
+/// Lists the files in a run's archive directory
+fn list_archived_files(
+    dir: &Path,
+) -> Result<Vec<(String, u64)>, anyhow::Error> {
+    match store::read_manifest(dir)? {
+        Some(manifest) => {
+            Ok(manifest.into_iter().map(|e| (e.path, e.bytes)).collect())
+        }
+        // Runs archived before manifests were recorded still have their logs
+        // at the top level.
+        None => list_files(dir, "", false),
+    }
+}
+
+/// Lists the files in a run's dataset that are worth downloading: the ones
+/// at the top level (mostly logs) and whatever the test suite saved in
+/// `RUN_OUTPUT_DIR`
+fn list_dataset_files(dir: &Path) -> Result<Vec<(String, u64)>, anyhow::Error> {
+    let mut files = list_files(dir, "", false)?;
+    if dir.join(crate::RUN_OUTPUT_DIR).is_dir() {
+        files.extend(list_files(dir, crate::RUN_OUTPUT_DIR, true)?);
+    }
+    Ok(files)
+}
+
+/// Lists the regular files in `dir/relative` (and, if `recurse`, its
+/// subdirectories), with their paths relative to `dir`
+fn list_files(
+    dir: &Path,
+    relative: &str,
+    recurse: bool,
+) -> Result<Vec<(String, u64)>, anyhow::Error> {
+    let mut files = Vec::new();
+    let path = dir.join(relative);
+    let entries = std::fs::read_dir(&path)
+        .with_context(|| format!("read directory {:?}", path))?;
+    for entry in entries {
+        let entry =
+            entry.with_context(|| format!("read directory {:?}", path))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let entry_relative = if relative.is_empty() {
+            name
+        } else {
+            format!("{}/{}", relative, name)
+        };
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("stat {:?}", entry.path()))?;
+        if file_type.is_file() {
+            if entry_relative == store::MANIFEST_NAME {
+                continue;
+            }
+            let bytes = entry
+                .metadata()
+                .with_context(|| format!("stat {:?}", entry.path()))?
+                .len();
+            files.push((entry_relative, bytes));
+        } else if file_type.is_dir() && recurse {
+            files.extend(list_files(dir, &entry_relative, true)?);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Returns the last `nlines` lines of the file at `path`
+fn tail(path: &Path, nlines: usize) -> Result<String, anyhow::Error> {
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("open {:?}", path))?;
+    let length =
+        file.metadata().with_context(|| format!("stat {:?}", path))?.len();
+    let start = length.saturating_sub(EXCERPT_MAX_BYTES);
+    file.seek(std::io::SeekFrom::Start(start))
+        .with_context(|| format!("seek {:?}", path))?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)
+        .with_context(|| format!("read {:?}", path))?;
+    let contents = String::from_utf8_lossy(&contents);
+    let mut lines = contents.lines().collect::<Vec<_>>();
+    // If we started partway through the file, the first line is probably
+    // incomplete.
+    if start > 0 && !lines.is_empty() {
+        lines.remove(0);
+    }
+    let first = lines.len().saturating_sub(nlines);
+    Ok(lines[first..].join("\n"))
+}
+
+/// Returns whether the beginning of `file` looks like text (leaving the file
+/// positioned at the start)
+fn looks_like_text(file: &mut std::fs::File) -> Result<bool, anyhow::Error> {
+    let mut buf = Vec::new();
+    file.by_ref().take(8192).read_to_end(&mut buf)?;
+    file.rewind()?;
+    let valid = match std::str::from_utf8(&buf) {
+        Ok(_) => true,
+        // The sample may have cut a character in half.
+        Err(error) => error.error_len().is_none(),
+    };
+    Ok(valid && !buf.contains(&0))
+}
+
+/// Which of a session's runs to list, from the query parameters of a request
+/// for its page
+#[derive(Default)]
+struct RunFilter {
+    result: Option<String>,
+    thread: Option<u8>,
+    /// signature (exactly)
+    signature: Option<String>,
+    /// text that the signature must contain
+    text: Option<String>,
+}
+
+impl RunFilter {
+    fn from_request(request: &Request) -> Result<RunFilter, HttpError> {
+        let get = |name: &str| {
+            request.query.get(name).filter(|v| !v.is_empty()).cloned()
+        };
+        let thread = get("thread")
+            .map(|t| {
+                t.parse().map_err(|_| {
+                    HttpError::bad_request(format!("bad thread: {:?}", t))
+                })
+            })
+            .transpose()?;
+        Ok(RunFilter {
+            result: get("result"),
+            thread,
+            signature: get("signature"),
+            text: get("text"),
+        })
+    }
+
+    /// Returns the form for changing the filter
+    fn form(
+        &self,
+        host: &str,
+        session: &str,
+        results: &[(String, usize)],
+    ) -> String {
+        let mut options = String::from("<option value=\"\">any</option>");
+        for (result, _) in results {
+            options.push_str(&format!(
+                "<option{}>{}</option>",
+                if self.result.as_ref() == Some(result) {
+                    " selected"
+                } else {
+                    ""
+                },
+                escape(result)
+            ));
+        }
+        format!(
+            "<form method=\"get\" action=\"/session\">\
+             <input type=\"hidden\" name=\"host\" value=\"{}\">\
+             <input type=\"hidden\" name=\"session\" value=\"{}\">\
+             <input type=\"hidden\" name=\"signature\" value=\"{}\">\
+             result <select name=\"result\">{}</select> \
+             thread <input name=\"thread\" size=\"3\" value=\"{}\"> \
+             signature contains <input name=\"text\" value=\"{}\"> \
+             <input type=\"submit\" value=\"filter\"></form><p></p>",
+            escape(host),
+            escape(session),
+            escape(self.signature.as_deref().unwrap_or("")),
+            options,
+            self.thread.map(|t| t.to_string()).unwrap_or_default(),
+            escape(self.text.as_deref().unwrap_or("")),
+        )
+    }
+}
+
+/// One row of the sessions page
+struct SessionRow {
+    host: String,
+    session: String,
+    snapshot: String,
+    start_time: String,
+    end_time: Option<String>,
+    outcome: Option<String>,
+    nruns: usize,
+    nfailed: usize,
+}
+
+/// What we show about a session on its own page
+struct SessionDetail {
+    snapshot: String,
+    start_time: String,
+    end_time: Option<String>,
+    outcome: Option<String>,
+    parameters: serde_json::Value,
+}
+
+/// One run, as recorded in the database
+struct RunRow {
+    run_id: usize,
+    thread: u8,
+    attempt: usize,
+    dataset: String,
+    start_time: String,
+    end_time: String,
+    result: String,
+    signature: Option<String>,
+}
+
+fn load_sessions(
+    conn: &rusqlite::Connection,
+) -> Result<Vec<SessionRow>, anyhow::Error> {
+    let failing = FAILING_RESULTS
+        .iter()
+        .map(|r| format!("'{}'", r))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut stmt = conn.prepare(&format!(
+        "SELECT s.host, s.session, s.snapshot, s.start_time, s.end_time,
+                s.outcome, COUNT(r.run_id),
+                COUNT(CASE WHEN r.result IN ({}) THEN 1 END)
+            FROM sessions s LEFT JOIN runs r
+                ON r.host = s.host AND r.session = s.session
+            GROUP BY s.host, s.session
+            ORDER BY s.start_time DESC",
+        failing
+    ))?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(SessionRow {
+                host: row.get(0)?,
+                session: row.get(1)?,
+                snapshot: row.get(2)?,
+                start_time: row.get(3)?,
+                end_time: row.get(4)?,
+                outcome: row.get(5)?,
+                nruns: row.get(6)?,
+                nfailed: row.get(7)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .context("read sessions")?;
+    Ok(rows)
+}
+
+/// Loads one session, returning a 404 error if it doesn't exist
+fn load_session(
+    conn: &rusqlite::Connection,
+    host: &str,
+    session: &str,
+) -> Result<SessionDetail, HttpError> {
+    let row = conn
+        .query_row(
+            "SELECT snapshot, start_time, end_time, outcome, parameters
+                FROM sessions WHERE host = ?1 AND session = ?2",
+            params![host, session],
+            |row| {
+                let parameters: String = row.get(4)?;
+                Ok(SessionDetail {
+                    snapshot: row.get(0)?,
+                    start_time: row.get(1)?,
+                    end_time: row.get(2)?,
+                    outcome: row.get(3)?,
+                    parameters: serde_json::from_str(&parameters)
+                        .unwrap_or(serde_json::Value::Null),
+                })
+            },
+        )
+        .map_err(|error| match error {
+            rusqlite::Error::QueryReturnedNoRows => HttpError::not_found(
+                format!("no session {:?} from host {:?}", session, host),
+            ),
+            error => anyhow::Error::new(error)
+                .context(format!("read session {:?}", session))
+                .into(),
+        })?;
+    Ok(row)
+}
+
+/// Returns the number of runs in a session with each result
+fn load_results(
+    conn: &rusqlite::Connection,
+    host: &str,
+    session: &str,
+) -> Result<Vec<(String, usize)>, anyhow::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT result, COUNT(*) FROM runs
+            WHERE host = ?1 AND session = ?2
+            GROUP BY result ORDER BY result",
+    )?;
+    let rows = stmt
+        .query_map(params![host, session], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .context("read results")?;
+    Ok(rows)
+}
+
+/// Returns each failure signature seen in a session, how many runs had it,
+/// and the first run that did, most common first
+fn load_signatures(
+    conn: &rusqlite::Connection,
+    host: &str,
+    session: &str,
+) -> Result<Vec<(String, usize, usize)>, anyhow::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT signature, COUNT(*), MIN(run_id) FROM runs
+            WHERE host = ?1 AND session = ?2 AND signature IS NOT NULL
+            GROUP BY signature ORDER BY COUNT(*) DESC, MIN(run_id)",
+    )?;
+    let rows = stmt
+        .query_map(params![host, session], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .context("read signatures")?;
+    Ok(rows)
+}
+
+const RUN_COLUMNS: &str = "run_id, thread, attempt, dataset, start_time, \
+                           end_time, result, signature";
+
+fn run_row(row: &rusqlite::Row) -> Result<RunRow, rusqlite::Error> {
+    Ok(RunRow {
+        run_id: row.get(0)?,
+        thread: row.get(1)?,
+        attempt: row.get(2)?,
+        dataset: row.get(3)?,
+        start_time: row.get(4)?,
+        end_time: row.get(5)?,
+        result: row.get(6)?,
+        signature: row.get(7)?,
+    })
+}
+
+/// Returns the runs in a session that match `filter`
+fn load_runs(
+    conn: &rusqlite::Connection,
+    host: &str,
+    session: &str,
+    filter: &RunFilter,
+) -> Result<Vec<RunRow>, anyhow::Error> {
+    let mut sql = format!(
+        "SELECT {} FROM runs WHERE host = ?1 AND session = ?2",
+        RUN_COLUMNS
+    );
+    let mut values =
+        vec![Value::from(host.to_string()), Value::from(session.to_string())];
+    if let Some(result) = &filter.result {
+        values.push(result.clone().into());
+        sql.push_str(&format!(" AND result = ?{}", values.len()));
+    }
+    if let Some(thread) = filter.thread {
+        values.push(i64::from(thread).into());
+        sql.push_str(&format!(" AND thread = ?{}", values.len()));
+    }
+    if let Some(signature) = &filter.signature {
+        values.push(signature.clone().into());
+        sql.push_str(&format!(" AND signature = ?{}", values.len()));
+    }
+    if let Some(text) = &filter.text {
+        values.push(text.clone().into());
+        sql.push_str(&format!(" AND instr(signature, ?{}) > 0", values.len()));
+    }
+    sql.push_str(" ORDER BY run_id");
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt
+        .query_map(rusqlite::params_from_iter(values), run_row)?
+        .collect::<Result<Vec<_>, _>>()
+        .context("read runs")?;
+    Ok(rows)
+}
+
+/// Loads one run, returning a 404 error if it doesn't exist
+fn load_run(
+    conn: &rusqlite::Connection,
+    key: &RunKey,
+) -> Result<RunRow, HttpError> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM runs
+                WHERE host = ?1 AND session = ?2 AND run_id = ?3",
+            RUN_COLUMNS
+        ),
+        params![key.host, key.session, key.run_id],
+        run_row,
+    )
+    .map_err(|error| match error {
+        rusqlite::Error::QueryReturnedNoRows => HttpError::not_found(format!(
+            "no run {} in session {:?}",
+            key.run_id, key.session
+        )),
+        error => anyhow::Error::new(error)
+            .context(format!("read run {}", key.run_id))
+            .into(),
+    })
+}
+
+fn load_run_metadata(
+    conn: &rusqlite::Connection,
+    key: &RunKey,
+) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT name, value FROM run_metadata
+            WHERE host = ?1 AND session = ?2 AND run_id = ?3 ORDER BY name",
+    )?;
+    let rows = stmt
+        .query_map(params![key.host, key.session, key.run_id], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .context("read run metadata")?;
+    Ok(rows)
+}
+
+fn load_phases(
+    conn: &rusqlite::Connection,
+    key: &RunKey,
+) -> Result<Vec<(String, f64)>, anyhow::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT phase, duration_secs FROM phase_timings
+            WHERE host = ?1 AND session = ?2 AND run_id = ?3 ORDER BY seq",
+    )?;
+    let rows = stmt
+        .query_map(params![key.host, key.session, key.run_id], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .context("read phase timings")?;
+    Ok(rows)
+}
+
+/// Returns the value of query parameter `name`
+fn param<'a>(request: &'a Request, name: &str) -> Result<&'a str, HttpError> {
+    request.query.get(name).map(|v| v.as_str()).ok_or_else(|| {
+        HttpError::bad_request(format!("missing parameter {:?}", name))
+    })
+}
+
+/// Returns the run identified by the query parameters of `request`
+fn run_key(request: &Request) -> Result<RunKey<'_>, HttpError> {
+    let run = param(request, "run")?;
+    Ok(RunKey {
+        host: param(request, "host")?,
+        session: param(request, "session")?,
+        run_id: run.parse().map_err(|_| {
+            HttpError::bad_request(format!("bad run id: {:?}", run))
+        })?,
+    })
+}
+
+/// Returns a link to `path` with the given query parameters (escaped for use
+/// in an HTML attribute)
+fn url(path: &str, params: &[(&str, &str)]) -> String {
+    let query = params
+        .iter()
+        .map(|(name, value)| {
+            format!("{}={}", name, http::percent_encode(value))
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    escape(&format!("{}?{}", path, query))
+}
+
+fn run_url(key: &RunKey) -> String {
+    url(
+        "/run",
+        &[
+            ("host", key.host),
+            ("session", key.session),
+            ("run", &key.run_id.to_string()),
+        ],
+    )
+}
+
+fn file_url(key: &RunKey, kind: &str, path: &str) -> String {
+    url(
+        "/file",
+        &[
+            ("host", key.host),
+            ("session", key.session),
+            ("run", &key.run_id.to_string()),
+            ("from", kind),
+            ("path", path),
+        ],
+    )
+}
+
+/// Returns a link to `href` (already escaped) with text `text`
+fn link(href: &str, text: &str) -> String {
+    format!("<a href=\"{}\">{}</a>", href, escape(text))
+}
+
+/// Returns a table cell's contents for a run result or session outcome,
+/// colored according to how it turned out
+fn result_cell(result: &str) -> String {
+    format!("<span class=\"{}\">{}</span>", escape(result), escape(result))
+}
+
+/// Returns an HTML table with the given column headings and rows (whose cells
+/// are already HTML)
+fn table(headings: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut html = String::from("<table><tr>");
+    for heading in headings {
+        html.push_str(&format!("<th>{}</th>", escape(heading)));
+    }
+    html.push_str("</tr>");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            html.push_str(&format!("<td>{}</td>", cell));
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</table>");
+    html
+}
+
+/// Returns a whole page with title `title` and body `body` (already HTML)
+fn page(title: &str, body: &str) -> Response {
+    Response::html(format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <title>{title}</title><style>{style}</style></head><body>\
+         <p><a href=\"/\">all sessions</a></p><h1>{title}</h1>{body}\
+         </body></html>\n",
+        title = escape(title),
+        style = STYLE,
+        body = body
+    ))
+}
+
+/// Escapes `s` for use in HTML text or attribute values
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn pretty_json(value: &serde_json::Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_default()
+}
+
+/// Formats a time from the database (RFC 3339) for display
+fn format_time(time: &str) -> String {
+    match chrono::DateTime::parse_from_rfc3339(time) {
+        Ok(time) => time
+            .with_timezone(&chrono::Utc)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        Err(_) => escape(time),
+    }
+}
+
+/// Formats the time between two times from the database (RFC 3339)
+fn format_elapsed(start: &str, end: &str) -> String {
+    let parse = chrono::DateTime::parse_from_rfc3339;
+    match (parse(start), parse(end)) {
+        (Ok(start), Ok(end)) => {
+            timing::format_duration((end - start).to_std().unwrap_or_default())
+        }
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,165 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Destroying a working dataset with a ZFS channel program (`zfs program`)
+//!
+//! Destroying a working dataset the usual way takes one `zfs destroy` for
+//! each run that was kept, plus one for the working dataset itself.  Where
+//! ZFS supports channel programs, we instead unmount the whole tree with one
+//! command and destroy all of it with one Lua program, which ZFS runs
+//! atomically (no other administrative operation can see the tree half
+//! destroyed).  The program checks everything it can before destroying
+//! anything (every snapshot, and every dataset that has no snapshots), so
+//! that a dataset that can't be destroyed usually means that nothing is.
+//!
+//! Channel programs can destroy datasets and set properties, but they can't
+//! create clones or unmount filesystems, so each run's clone is still created
+//! (with its properties) by one `zfs clone`, and each run is still destroyed
+//! by one `zfs destroy`.  Where `zfs program` isn't available (or fails),
+//! working datasets are destroyed one dataset at a time as before.  Which way
+//! it was done is recorded in `session.json` (see `backend::Teardown`).
+
+use crate::run_command;
+use crate::zfs;
+use anyhow::Context;
+use std::io::Write;
+use std::process::Command;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// Program that checks that a (sanity-checked) working dataset can be
+/// destroyed and then destroys it, along with everything in it
+const DESTROY_TREE_PROGRAM: &str = r#"
+args = ...
+root = args["argv"][1]
+
+-- Collect everything in the tree, in the order it needs to be destroyed.
+function collect(fs, targets, checks)
+    for child in zfs.list.children(fs) do
+        collect(child, targets, checks)
+    end
+    local nsnapshots = 0
+    for snapshot in zfs.list.snapshots(fs) do
+        table.insert(targets, snapshot)
+        table.insert(checks, snapshot)
+        nsnapshots = nsnapshots + 1
+    end
+    table.insert(targets, fs)
+    if nsnapshots == 0 and fs ~= root then
+        table.insert(checks, fs)
+    end
+end
+
+targets = {}
+checks = {}
+collect(root, targets, checks)
+for _, target in ipairs(checks) do
+    err = zfs.check.destroy(target)
+    if err ~= 0 then
+        error("cannot destroy " .. target .. ": error " .. err)
+    end
+end
+for _, target in ipairs(targets) do
+    err = zfs.sync.destroy(target)
+    if err ~= 0 then
+        error("cannot destroy " .. target .. ": error " .. err)
+    end
+end
+return #targets
+"#;
+
+/// Program that does nothing, used to check whether channel programs work
+const NOOP_PROGRAM: &str = "return 0\n";
+
+/// Destroys `dataset` and everything in it using a channel program
+///
+/// Returns `Ok(false)` without changing anything if channel programs aren't
+/// available on `dataset`'s pool.
+pub fn destroy_tree(dataset: &str) -> Result<bool, anyhow::Error> {
+    let pool = dataset.split('/').next().unwrap_or(dataset);
+    if run_program(pool, NOOP_PROGRAM, &["-n"], &[]).is_err() {
+        return Ok(false);
+    }
+
+    zfs::run(Command::new("pfexec").arg("zfs").arg("unmount").arg(dataset))?;
+    run_program(pool, DESTROY_TREE_PROGRAM, &[], &[dataset])?;
+    Ok(true)
+}
+
+/// Runs channel program `program` on `pool` with `zfs program` options
+/// `options` and program arguments `args`, returning what it printed
+fn run_program(
+    pool: &str,
+    program: &str,
+    options: &[&str],
+    args: &[&str],
+) -> Result<String, anyhow::Error> {
+    // `zfs program` reads the program from a file, which it then runs with
+    // privileges.  Anyone who could replace the file could run what they
+    // liked, so it goes in a new directory that only we can write to.
+    let dir = private_temp_dir()?;
+    let path = dir.join("program.lua");
+    let result = write_private(&path, program).and_then(|()| {
+        run_command(
+            Command::new("pfexec")
+                .arg("zfs")
+                .arg("program")
+                .args(options)
+                .arg(pool)
+                .arg(&path)
+                .args(args),
+        )
+    });
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_dir(&dir);
+    result
+}
+
+/// Creates a new directory under the system's temporary directory that only
+/// we can use
+fn private_temp_dir() -> Result<std::path::PathBuf, anyhow::Error> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    loop {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let path = std::env::temp_dir().join(format!(
+            "gocrash-zcp-{}-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst),
+            nanos
+        ));
+        // Creating the directory fails if anything's already there, so
+        // there's no way for someone else to have made it first.
+        match builder.create(&path) {
+            Ok(()) => return Ok(path),
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {
+                continue;
+            }
+            Err(error) => {
+                return Err(error).with_context(|| format!("mkdir {:?}", path));
+            }
+        }
+    }
+}
+
+/// Writes `contents` to a new file at `path` that only we can read or write
+fn write_private(
+    path: &std::path::Path,
+    contents: &str,
+) -> Result<(), anyhow::Error> {
+    let mut options = std::fs::OpenOptions::new();
+    options.create_new(true).write(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file =
+        options.open(path).with_context(|| format!("create {:?}", path))?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("write {:?}", path))
+}
@@ -0,0 +1,79 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+// Copyright 2022 Oxide Computer Company
+
+//! Retrying ZFS commands that fail for transient reasons
+//!
+//! `zfs destroy` fails with "dataset is busy" if something still has a file
+//! open in the dataset, which happens when a process that a run started is
+//! slow to exit.  That usually clears up within a few seconds, so rather than
+//! treating it as an infrastructure error (which stops the session), we try
+//! the command again, waiting twice as long before each new attempt
+//! (`--zfs-retries` and `--zfs-retry-delay`).  Other failures, like a dataset
+//! that doesn't exist or a permissions problem, won't go away by themselves
+//! and are reported right away.
+
+use crate::command_label;
+use crate::run_command;
+use crate::timing;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Messages from `zfs` (or from unmounting, which `zfs` does before
+/// destroying a dataset) that mean the command might work if we try again
+const TRANSIENT_MESSAGES: &[&str] =
+    &["dataset is busy", "target is busy", "Device busy", "resource busy"];
+
+/// Longest we'll wait between attempts
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How many times to retry ZFS commands that fail for transient reasons, and
+/// how long to wait before the first retry
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+static POLICY: Mutex<RetryPolicy> =
+    Mutex::new(RetryPolicy { retries: 4, delay: Duration::from_secs(1) });
+
+/// Sets the policy used for all ZFS commands from now on
+pub fn set_policy(policy: RetryPolicy) {
+    *POLICY.lock().unwrap() = policy;
+}
+
+/// Runs `cmd` (a `zfs` command) as `run_command()` does, retrying it if it
+/// fails for a transient reason
+pub fn run(cmd: &mut Command) -> Result<String, anyhow::Error> {
+    let policy = *POLICY.lock().unwrap();
+    let mut delay = policy.delay;
+    let mut nretries = 0;
+    loop {
+        match run_command(cmd) {
+            Err(error) if nretries < policy.retries => {
+                let message = format!("{:#}", error);
+                let Some(reason) = TRANSIENT_MESSAGES
+                    .iter()
+                    .find(|pattern| message.contains(*pattern))
+                else {
+                    return Err(error);
+                };
+                nretries += 1;
+                eprintln!(
+                    "warning: {}: {} (retry {} of {} in {})",
+                    command_label(cmd),
+                    reason,
+                    nretries,
+                    policy.retries,
+                    timing::format_duration(delay)
+                );
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(MAX_DELAY);
+            }
+            result => return result,
+        }
+    }
+}